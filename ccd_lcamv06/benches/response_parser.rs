@@ -1,17 +1,42 @@
-use utilities::{MockIO, SINGLE_PACKAGE};
+use utilities::{MockIO, MULTIPLE_PACKAGES, SINGLE_PACKAGE};
 use std::io::Write;
-use ccd_lcamv06::{IoAdapter, StdIoAdapter};
-use criterion::{criterion_group, criterion_main, Criterion};
+use ccd_lcamv06::{IoAdapter, StdIoAdapter, FRAME_PIXEL_COUNT};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 
+/// Size of a single frame package on the wire: 5 bytes of head, pixels and CRC
+const PACKAGE_SIZE: u64 = 5 + FRAME_PIXEL_COUNT as u64 * 2 + 2;
+
+// For reference, continuous reading at 921600 baud delivers ~92 KB/s (8N1 framing), so decoding
+// throughput has to stay well above that to never fall behind
 fn bench_decoding_packages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decoding");
+    group.throughput(Throughput::Bytes(PACKAGE_SIZE));
+
     let mut mock_io = MockIO::new();
     mock_io.expect_write().returning(|msg| Ok(msg.len()));
     mock_io.expect_read().returning(move |mut buf| {
         buf.write(&SINGLE_PACKAGE)
     });
     let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    group.bench_function("single package", |b| b.iter(|| ccd.get_frame()));
+
+    // Endlessly replays a recorded stream of packages
+    let mut mock_io = MockIO::new();
+    let mut pos = 0;
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        let written = buf.write(&MULTIPLE_PACKAGES[pos..])?;
+        pos = (pos + written) % MULTIPLE_PACKAGES.len();
+        Ok(written)
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    let mut frames = ccd.frames_iter().unwrap();
+    let mut frame = [0; FRAME_PIXEL_COUNT];
+    group.bench_function("continuous stream", |b| {
+        b.iter(|| frames.next_into(&mut frame))
+    });
 
-    c.bench_function("single package", |b| b.iter(|| ccd.get_frame()));
+    group.finish();
 }
 
 criterion_group!(benches, bench_decoding_packages);
@@ -0,0 +1,332 @@
+use crate::{
+    ccd::{CCD, DEFAULT_MAX_CONSECUTIVE_FAILURES, DEFAULT_TIMEOUT},
+    error::Error,
+    flags::BaudRate,
+    io_adapter::IoAdapter,
+    response::SensorLayout,
+    retry::RetryPolicy,
+};
+#[cfg(feature = "std")]
+use crate::{io_adapter::std_io::StdIoAdapter, transport::Transport};
+use core::{result::Result as CoreResult, time::Duration};
+use num_traits::ToPrimitive;
+
+/// How long CCD gets to answer at each baud rate during auto-detection
+const DEFAULT_DETECT_TIMEOUT: Duration = Duration::from_millis(300);
+/// Timeout for a single read from serial port, CCD keeps retrying reads until its own timeout
+#[cfg(feature = "serialport")]
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Collects CCD settings in one place before it's opened, which is handy when the same settings
+/// are applied to several connections, e.g. while looking for the right baud rate
+#[derive(Debug, Clone)]
+pub struct CCDBuilder {
+    timeout: Option<Duration>,
+    retry: RetryPolicy,
+    verify_crc: bool,
+    max_consecutive_failures: u32,
+    layout: SensorLayout,
+    restore_baud: Option<BaudRate>,
+    baud: BaudRate,
+    auto_detect: bool,
+    /// Baud rates tried during auto-detection, `None` entries are unused
+    baud_order: [Option<BaudRate>; BaudRate::ALL.len()],
+    detect_timeout: Duration,
+    detect_attempts: u32,
+    negotiate: bool,
+    #[cfg(feature = "serialport")]
+    path: Option<String>,
+}
+
+impl Default for CCDBuilder {
+    fn default() -> Self {
+        CCDBuilder {
+            timeout: Some(DEFAULT_TIMEOUT),
+            retry: RetryPolicy::default(),
+            verify_crc: false,
+            max_consecutive_failures: DEFAULT_MAX_CONSECUTIVE_FAILURES,
+            layout: SensorLayout::default(),
+            restore_baud: None,
+            baud: BaudRate::default(),
+            auto_detect: false,
+            baud_order: [None; BaudRate::ALL.len()],
+            detect_timeout: DEFAULT_DETECT_TIMEOUT,
+            detect_attempts: 1,
+            negotiate: false,
+            #[cfg(feature = "serialport")]
+            path: None,
+        }
+    }
+}
+
+impl CCDBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits time spent waiting for a single response, `None` waits forever
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Shorthand for retrying failed exchanges `retries` times with default backoff
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry.max_attempts = retries + 1;
+        self
+    }
+
+    pub fn verify_crc(mut self, verify: bool) -> Self {
+        self.verify_crc = verify;
+        self
+    }
+
+    pub fn max_consecutive_failures(mut self, max: u32) -> Self {
+        self.max_consecutive_failures = max;
+        self
+    }
+
+    /// Layout of frame packages, replaced once `get_version` recognizes sensor type
+    pub fn sensor_layout(mut self, layout: SensorLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// UART baud rate restored once CCD is dropped, for opening port again after switching rates
+    pub fn restore_baudrate(mut self, baud: BaudRate) -> Self {
+        self.restore_baud = Some(baud);
+        self
+    }
+
+    /// Baud rate port is opened at, and the first one tried during auto-detection
+    pub fn baud(mut self, baud: BaudRate) -> Self {
+        self.baud = baud;
+        self
+    }
+
+    /// Try every supported baud rate until CCD responds, instead of relying on configured one
+    pub fn auto_detect_baud(mut self, detect: bool) -> Self {
+        self.auto_detect = detect;
+        self
+    }
+
+    /// Order in which baud rates are tried during auto-detection, overrides starting with
+    /// configured rate. Repeated rates are only tried once, empty order restores the default
+    pub fn baud_order(mut self, order: &[BaudRate]) -> Self {
+        self.baud_order = [None; BaudRate::ALL.len()];
+        for (slot, baud) in self.baud_order.iter_mut().zip(dedup(order.iter().copied())) {
+            *slot = Some(baud);
+        }
+        self
+    }
+
+    /// How long to wait for CCD to respond at each baud rate during auto-detection
+    pub fn detect_timeout(mut self, timeout: Duration) -> Self {
+        self.detect_timeout = timeout;
+        self
+    }
+
+    /// How many times each baud rate is tried during auto-detection before moving on
+    pub fn detect_attempts(mut self, attempts: u32) -> Self {
+        self.detect_attempts = attempts;
+        self
+    }
+
+    /// Once CCD is found, switch UART to the fastest baud rate it still responds at. Rate CCD was
+    /// found at is restored once it's dropped
+    pub fn negotiate_baud(mut self, negotiate: bool) -> Self {
+        self.negotiate = negotiate;
+        self
+    }
+
+    /// Baud rates in order they are tried during auto-detection, each one once
+    pub fn baud_candidates(&self) -> impl Iterator<Item = BaudRate> {
+        let order = if self.baud_order[0].is_some() {
+            self.baud_order
+        } else {
+            let mut order = [None; BaudRate::ALL.len()];
+            let all = core::iter::once(self.baud).chain(BaudRate::ALL);
+            for (slot, baud) in order.iter_mut().zip(dedup(all)) {
+                *slot = Some(baud);
+            }
+            order
+        };
+        order.into_iter().flatten()
+    }
+
+    /// Opens CCD on top of given adapter with collected settings
+    pub fn open<IO: IoAdapter>(&self, io: IO) -> CCD<IO> {
+        let mut ccd = io.open_ccd();
+        ccd.set_timeout(self.timeout);
+        ccd.set_retry_policy(self.retry);
+        ccd.set_verify_crc(self.verify_crc);
+        ccd.set_max_consecutive_failures(self.max_consecutive_failures);
+        ccd.set_sensor_layout(self.layout);
+        if let Some(baud) = self.restore_baud {
+            ccd.restore_baudrate_on_drop(baud);
+        }
+        ccd
+    }
+
+    /// Opens CCD on top of adapters `connect` returns for a given baud rate. With auto-detection
+    /// every candidate rate is tried in turn until CCD answers a version query, each attempt
+    /// bounded by its own timeout so a silent device can't stall detection. Returns CCD along
+    /// with the rate it was opened at, or negotiated to
+    pub fn connect_with<IO, E, F>(&self, mut connect: F) -> CoreResult<(CCD<IO>, BaudRate), E>
+    where
+        IO: IoAdapter,
+        E: From<Error>,
+        F: FnMut(BaudRate) -> CoreResult<IO, E>,
+    {
+        let (ccd, baud) = if self.auto_detect {
+            self.detect(&mut connect)?
+        } else {
+            (self.open(connect(self.baud)?), self.baud)
+        };
+        if self.negotiate {
+            return self.negotiate(ccd, baud, &mut connect);
+        }
+        Ok((ccd, baud))
+    }
+
+    fn detect<IO, E, F>(&self, connect: &mut F) -> CoreResult<(CCD<IO>, BaudRate), E>
+    where
+        IO: IoAdapter,
+        E: From<Error>,
+        F: FnMut(BaudRate) -> CoreResult<IO, E>,
+    {
+        let probing = self.probing();
+        for baud in self.baud_candidates() {
+            tracing::debug!("Trying baud rate {}", baud);
+            let mut ccd = probing.open(connect(baud)?);
+            match ccd.get_version() {
+                Ok(_) => {
+                    tracing::info!("CCD responded at baud rate {}", baud);
+                    self.stop_probing(&mut ccd);
+                    return Ok((ccd, baud));
+                }
+                Err(e) => tracing::info!("CCD didn't respond at baud rate {}: {}", baud, e),
+            }
+        }
+        Err(Error::NoBaudRate.into())
+    }
+
+    /// Switches UART up through every faster baud rate, checking each with a version query, and
+    /// settles on the fastest one CCD still responds at
+    fn negotiate<IO, E, F>(
+        &self,
+        mut ccd: CCD<IO>,
+        found: BaudRate,
+        connect: &mut F,
+    ) -> CoreResult<(CCD<IO>, BaudRate), E>
+    where
+        IO: IoAdapter,
+        E: From<Error>,
+        F: FnMut(BaudRate) -> CoreResult<IO, E>,
+    {
+        let probing = self.probing().restore_baudrate(found);
+        let mut current = found;
+        let faster = BaudRate::ALL
+            .into_iter()
+            .filter(|baud| baud.to_u32() > found.to_u32());
+        for baud in faster {
+            tracing::debug!("Switching UART to baud rate {}", baud);
+            match ccd.set_baudrate(baud) {
+                Err(e @ Error::UnsupportedByFirmware { .. }) => {
+                    tracing::warn!("Staying at baud rate {}: {}", current, e);
+                    break;
+                }
+                res => res?,
+            }
+            // Reopened port takes over restoring the rate
+            ccd.keep_baudrate();
+            drop(ccd);
+            let mut switched = probing.open(connect(baud)?);
+            if let Err(e) = switched.get_version() {
+                tracing::warn!(
+                    "CCD didn't respond at baud rate {}, staying at {}: {}",
+                    baud,
+                    current,
+                    e
+                );
+                // Only responses might have been lost, so CCD is asked to switch back at new rate
+                switched.restore_baudrate_on_drop(current);
+                drop(switched);
+                ccd = probing.open(connect(current)?);
+                ccd.get_version()?;
+                break;
+            }
+            ccd = switched;
+            current = baud;
+        }
+        tracing::info!("Negotiated baud rate {}", current);
+        self.stop_probing(&mut ccd);
+        Ok((ccd, current))
+    }
+
+    /// Settings for connections that are only expected to answer if baud rate is right, so
+    /// they give up early
+    fn probing(&self) -> CCDBuilder {
+        self.clone()
+            .timeout(Some(self.detect_timeout))
+            .retry_policy(RetryPolicy {
+                max_attempts: self.detect_attempts.max(1),
+                ..self.retry
+            })
+    }
+
+    /// Restores configured timeout and retries on CCD opened with [CCDBuilder::probing]. Sensor
+    /// layout is kept, since it was just learned from version query
+    fn stop_probing<IO: IoAdapter>(&self, ccd: &mut CCD<IO>) {
+        ccd.set_timeout(self.timeout);
+        ccd.set_retry_policy(self.retry);
+    }
+}
+
+/// Drops repeated baud rates, keeping the first occurrence of each
+fn dedup(bauds: impl Iterator<Item = BaudRate>) -> impl Iterator<Item = BaudRate> {
+    let mut seen = [false; BaudRate::ALL.len()];
+    bauds.filter(move |baud| {
+        let idx = BaudRate::ALL.iter().position(|b| b == baud);
+        idx.is_none_or(|idx| !core::mem::replace(&mut seen[idx], true))
+    })
+}
+
+#[cfg(feature = "std")]
+impl CCD<StdIoAdapter<Box<dyn Transport>>> {
+    /// Starts collecting settings for CCD, see [CCDBuilder]
+    pub fn builder() -> CCDBuilder {
+        CCDBuilder::new()
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl CCDBuilder {
+    /// Name of serial port [CCDBuilder::connect] opens
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Opens serial port set with [CCDBuilder::path], detecting its baud rate if enabled
+    pub fn connect(&self) -> crate::error::Result<CCD<StdIoAdapter<Box<dyn Transport>>>> {
+        use std::io;
+
+        let path = self.path.as_deref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Serial port path is not set")
+        })?;
+        let (ccd, _) = self.connect_with(|baud| {
+            let port = serialport::new(path, baud.to_u32().unwrap())
+                .timeout(READ_TIMEOUT)
+                .open()
+                .map_err(io::Error::from)?;
+            Ok::<_, Error>(StdIoAdapter::new(Box::new(port) as Box<dyn Transport>))
+        })?;
+        Ok(ccd)
+    }
+}
@@ -3,16 +3,61 @@ use crate::{
     error::{Error, Result},
     flags::{BaudRate, TriggerMode},
     response::{
-        parser::{align_response, parse_response},
-        Frame, Response, VersionDetails,
+        parser::{align_response, parse_response, PackageError},
+        FirmwareVersion, Frame, FrameView, Response, ResponseView, SensorLayout, VersionDetails,
+        FRAME_PIXEL_COUNT, MAX_PACKAGE_SIZE,
     },
+    quality::{QualityFlags, QualityThresholds},
+    retry::RetryPolicy,
+    sensors,
+    stats::StreamStats,
     IoAdapter,
 };
-use core::{mem::size_of, iter, iter::Extend};
-use scopeguard::guard;
+use core::{iter, iter::Extend, time::Duration};
 
-// Sized as 2 responses in case of really unfortunate initial misalignment
-const READ_BUF_SIZE: usize = size_of::<Response>() * 2;
+// Sized as 2 packages in case of really unfortunate initial misalignment
+const READ_BUF_SIZE: usize = MAX_PACKAGE_SIZE * 2;
+
+/// Timeout for receiving a single response used unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Amount of parsing failures in a row after which receiving a response is abandoned, unless
+/// configured otherwise
+pub const DEFAULT_MAX_CONSECUTIVE_FAILURES: u32 = 16;
+
+// Longest package head, "HdInfo:" of version details
+pub(crate) const MAX_HEAD_LEN: usize = 7;
+
+/// Point in time after which waiting for a response should be abandoned. There is no clock
+/// available without std, so in that case it never expires
+struct Deadline {
+    #[cfg(feature = "std")]
+    at: Option<std::time::Instant>,
+}
+
+impl Deadline {
+    #[cfg(feature = "std")]
+    fn after(timeout: Option<Duration>) -> Self {
+        Deadline {
+            at: timeout.map(|t| std::time::Instant::now() + t),
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn after(_timeout: Option<Duration>) -> Self {
+        Deadline {}
+    }
+
+    #[cfg(feature = "std")]
+    fn expired(&self) -> bool {
+        self.at.is_some_and(|at| std::time::Instant::now() >= at)
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn expired(&self) -> bool {
+        false
+    }
+}
 
 pub struct CCD<IO>
 where
@@ -23,8 +68,28 @@ where
     buf: [u8; READ_BUF_SIZE],
     // Points to the top of buffer
     top: usize,
-    // Keeps track if buffer was aligned after latest buffer read
-    aligned: bool,
+    // Top at which package being received is complete, known once its head is parsed. Parsing is
+    // skipped until then, so a frame isn't parsed again after every read
+    complete_at: usize,
+    // Parsing failures since the last successfully received package
+    failures: u32,
+    // Limit for `failures`, after which receiving is abandoned
+    max_failures: u32,
+    stats: StreamStats,
+    // Limits time spent waiting for a single response
+    timeout: Option<Duration>,
+    // Describes how failed exchanges should be retried
+    retry: RetryPolicy,
+    // Reject frames with mismatching CRC
+    verify_crc: bool,
+    // UART baud rate from before it was changed, restored on drop
+    original_baud: Option<BaudRate>,
+    // Firmware version of the device, used to refuse commands it doesn't support
+    firmware: Option<FirmwareVersion>,
+    // How pixels are laid out in frame packages
+    layout: SensorLayout,
+    // "Average time" last set or read, used to flag averaged frames
+    avg_time: Option<u8>,
 }
 
 impl<IO> CCD<IO>
@@ -36,175 +101,623 @@ where
             io,
             buf: [0; READ_BUF_SIZE],
             top: 0,
-            aligned: false,
+            complete_at: 0,
+            failures: 0,
+            max_failures: DEFAULT_MAX_CONSECUTIVE_FAILURES,
+            stats: StreamStats::default(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            retry: RetryPolicy::default(),
+            verify_crc: false,
+            original_baud: None,
+            firmware: None,
+            layout: SensorLayout::default(),
+            avg_time: None,
         }
     }
 
+    /// Sets how commands are retried after a failure
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Sets how long to wait for each response before failing with `Error::Timeout`, `None`
+    /// waits indefinitely. Only has an effect with `std` feature enabled, since it requires a clock
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Enables rejecting frames with `Error::CrcMismatch` when their CRC doesn't match. Off by
+    /// default, since CCD is known to send a wrong CRC with a lot of otherwise valid frames
+    pub fn set_verify_crc(&mut self, verify: bool) {
+        self.verify_crc = verify;
+    }
+
+    pub fn verify_crc(&self) -> bool {
+        self.verify_crc
+    }
+
+    /// Sets how many broken packages or chunks of garbage in a row are skipped while waiting for
+    /// a response, before giving up with the latest parsing error
+    pub fn set_max_consecutive_failures(&mut self, max: u32) {
+        self.max_failures = max;
+    }
+
+    pub fn max_consecutive_failures(&self) -> u32 {
+        self.max_failures
+    }
+
+    /// "Average time" from the last `set_avg_time` or `get_avg_time` call, if any
+    pub fn avg_time(&self) -> Option<u8> {
+        self.avg_time
+    }
+
+    /// Firmware version learned from the last `get_version` call, if any
+    pub fn firmware_version(&self) -> Option<FirmwareVersion> {
+        self.firmware
+    }
+
+    /// Sets layout of frame packages, for sensors that `get_version` doesn't recognize
+    pub fn set_sensor_layout(&mut self, layout: SensorLayout) {
+        self.layout = layout;
+        self.complete_at = 0;
+    }
+
+    pub fn sensor_layout(&self) -> SensorLayout {
+        self.layout
+    }
+
+    /// Counters of received packages and data that had to be skipped
+    pub fn stats(&self) -> &StreamStats {
+        &self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = StreamStats::default();
+    }
+
     fn fill_buffer(&mut self) -> Result<()> {
-        self.aligned = false;
         let read_bytes = self.io.read(&mut self.buf[self.top..])?;
         self.top += read_bytes;
         Ok(())
     }
 
-    // Tries to align data in read buffer to a recognized package head
-    fn align_buffer(&mut self) {
-        if let Ok((tail, _)) = align_response(&self.buf[..self.top]) {
-            let consumed = self.top - tail.len();
-            self.buf.rotate_left(consumed);
-            self.top -= consumed;
-            self.aligned = true;
+    // Drops data from read buffer up to the next recognized package head. Data at the start of
+    // buffer already failed to parse, so it's always skipped. Frame that only failed CRC check
+    // is known to be complete, so it's dropped whole instead of looking for heads in its pixels.
+    // Returns whether anything was dropped, a short buffer may have to wait for more data first
+    fn resync(&mut self, e: PackageError) -> bool {
+        let skipped = match e {
+            PackageError::CrcMismatch { .. } => self.layout.package_size(),
+            _ => match align_response(&self.buf[1..self.top]) {
+                Ok((tail, _)) => self.top - tail.len(),
+                // Keep only what may turn out to be a beginning of a head once more data arrives
+                Err(_) => self.top.saturating_sub(MAX_HEAD_LEN - 1),
+            },
+        };
+        if skipped > 0 {
+            tracing::trace!("Skipping {} bytes to resynchronize", skipped);
+            self.consume(skipped);
+            self.stats.bytes_skipped += skipped as u64;
+            self.stats.resyncs += 1;
         }
+        skipped > 0
+    }
+
+    // Drops `n` bytes from the start of read buffer, only moving data that is still unprocessed
+    fn consume(&mut self, n: usize) {
+        self.buf.copy_within(n..self.top, 0);
+        self.top -= n;
+        self.complete_at = 0;
     }
 
     fn send_package(&mut self, cmd: Command) -> Result<()> {
+        cmd.ensure_supported(self.firmware)?;
         self.io.write_all(&cmd.encode())?;
         Ok(())
     }
 
-    fn receive_package(&mut self) -> Result<Response> {
+    /// Waits for a package and passes it to `extract` while it's still in the read buffer
+    fn receive_package<T>(
+        &mut self,
+        extract: impl FnOnce(ResponseView<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let _span = tracing::trace_span!("receive").entered();
+        let deadline = Deadline::after(self.timeout);
         loop {
-            log::trace!("Filling read buffer");
+            if deadline.expired() {
+                tracing::debug!("Timed out waiting for a response");
+                return Err(Error::Timeout);
+            }
+            tracing::trace!("Filling read buffer");
             self.fill_buffer()?;
-            log::trace!("Parsing response");
-            match parse_response(&self.buf[..self.top]) {
+            if self.top < self.complete_at {
+                continue;
+            }
+            tracing::trace!("Parsing response");
+            match parse_response(&self.buf[..self.top], self.layout, self.verify_crc) {
                 Ok((tail, resp)) => {
-                    log::trace!("Successfuly parsed a package, freeing space in read buffer");
                     let consumed = self.top - tail.len();
-                    self.buf.rotate_left(consumed);
-                    self.top -= consumed;
-                    return Ok(resp);
+                    let res = extract(resp);
+                    tracing::trace!("Successfuly parsed a package, freeing space in read buffer");
+                    self.consume(consumed);
+                    self.failures = 0;
+                    self.stats.packages += 1;
+                    return res;
                 }
                 Err(nom::Err::Incomplete(needed)) => {
-                    log::trace!("Response is incomplete, amount of data needed: {:?}", needed);
-                    // TODO: Add a timeout / retry count if package never fully arrives
+                    tracing::trace!(?needed, "Response is incomplete");
+                    if let nom::Needed::Size(n) = needed {
+                        self.complete_at = self.top + n.get();
+                    }
                     continue;
                 }
-                // TODO: Pass through parser errors when implemented correctly
-                Err(_) => {
-                    if !self.aligned {
-                        log::trace!("Failed to parse a package, trying to realign");
-                        self.align_buffer();
-                    } else {
-                        return Err(Error::InvalidData);
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                    if !self.resync(e) {
+                        tracing::trace!(error = ?e, "Waiting for more data to resynchronize");
+                        continue;
                     }
+                    match e {
+                        PackageError::CrcMismatch { .. } => {
+                            self.stats.crc_failures += 1;
+                            self.stats.dropped_frames += 1;
+                        }
+                        PackageError::TruncatedFrame => self.stats.dropped_frames += 1,
+                        _ => {}
+                    }
+                    self.failures += 1;
+                    if self.failures >= self.max_failures {
+                        tracing::debug!(
+                            failures = self.failures,
+                            error = ?e,
+                            "Failed to parse a package too many times in a row"
+                        );
+                        self.failures = 0;
+                        return Err(e.into());
+                    }
+                    tracing::trace!(error = ?e, "Failed to parse a package, resynchronizing");
+                }
+            }
+        }
+    }
+
+    /// Sends a command, retrying according to retry policy
+    fn command(&mut self, cmd: Command) -> Result<()> {
+        self.with_retries(|s| s.send_package(cmd))
+    }
+
+    /// Sends a command and extracts expected value from its response, retrying whole exchange
+    /// according to retry policy
+    fn query<T>(&mut self, cmd: Command, extract: fn(Response) -> Result<T>) -> Result<T> {
+        self.query_view(cmd, |r| match r {
+            ResponseView::Other(r) => extract(r),
+            r => Err(Error::UnexpectedResponse(r.name())),
+        })
+    }
+
+    /// Same as `query`, but gives access to frame data without copying it out of read buffer
+    fn query_view<T>(
+        &mut self,
+        cmd: Command,
+        mut extract: impl FnMut(ResponseView<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let _span = tracing::debug_span!("query", ?cmd).entered();
+        self.with_retries(|s| {
+            tracing::debug!("Sending a package");
+            s.send_package(cmd)?;
+            tracing::debug!("Waiting for a response");
+            s.receive_package(&mut extract)
+        })
+    }
+
+    fn with_retries<T>(&mut self, mut op: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            match op(self) {
+                Err(e) if self.retry.should_retry(attempt, &e) => {
+                    let backoff = self.retry.backoff(attempt);
+                    tracing::debug!(attempt, error = %e, ?backoff, "Exchange failed, retrying");
+                    // Leftovers of a failed exchange would only get in a way of the next one
+                    self.top = 0;
+                    self.complete_at = 0;
+                    self.failures = 0;
+                    self.io.delay(backoff);
+                    attempt += 1;
                 }
+                res => return res,
             }
         }
     }
 
     pub fn set_avg_time(&mut self, t: u8) -> Result<()> {
-        log::debug!("Sending a SetAverageTime package with t = {}", t);
-        self.send_package(Command::SetAverageTime(t))
+        tracing::debug!("Sending a SetAverageTime package with t = {}", t);
+        self.command(Command::SetAverageTime(t))?;
+        self.avg_time = Some(t);
+        Ok(())
     }
 
     pub fn get_avg_time(&mut self) -> Result<u8> {
-        log::debug!("Sending a GetAverageTime package");
-        self.send_package(Command::GetAverageTime)?;
-        log::debug!("Waiting for a response");
-        match self.receive_package()? {
+        self.query(Command::GetAverageTime, |r| match r {
             Response::AverageTime(t) => {
-                log::debug!("Recieved a AverageTime package with t = {}", t);
+                tracing::debug!("Recieved a AverageTime package with t = {}", t);
                 Ok(t)
             },
             r => Err(Error::UnexpectedResponse(r.into())),
-        }
+        })
+        .inspect(|t| self.avg_time = Some(*t))
     }
 
     // TODO: Figure out difference between Average, Integration and Exposure time
     pub fn set_exp_time(&mut self, t: u16) -> Result<()> {
-        log::debug!("Sending a SetIntegrationTime package with t = {}", t);
-        self.send_package(Command::SetIntegrationTime(t))
+        tracing::debug!("Sending a SetIntegrationTime package with t = {}", t);
+        self.command(Command::SetIntegrationTime(t))
     }
 
     pub fn get_exp_time(&mut self) -> Result<u16> {
-        log::debug!("Sending a GetExposureTime package");
-        self.send_package(Command::GetExposureTime)?;
-        log::debug!("Waiting for a response");
-        match self.receive_package()? {
+        self.query(Command::GetExposureTime, |r| match r {
             Response::ExposureTime(t) => {
-                log::debug!("Recieved a ExposureTime package with t = {}", t);
+                tracing::debug!("Recieved a ExposureTime package with t = {}", t);
                 Ok(t)
             },
             r => Err(Error::UnexpectedResponse(r.into())),
-        }
+        })
     }
 
     pub fn set_trigger_mode(&mut self, mode: TriggerMode) -> Result<()> {
-        log::debug!("Sending a SetTrigerMode package with mode = {:?}", mode);
-        self.send_package(Command::SetTrigerMode(mode))
+        tracing::debug!("Sending a SetTrigerMode package with mode = {:?}", mode);
+        self.command(Command::SetTrigerMode(mode))
     }
 
-    /// Sets baud rate on UART pins (does not affect USB ACM)
+    /// Sets baud rate on UART pins (does not affect USB ACM). Original rate is restored once CCD
+    /// is dropped, unless change is made permanent with `keep_baudrate`
     pub fn set_baudrate(&mut self, baud: BaudRate) -> Result<()> {
-        log::debug!("Sending a SetSerialBaudRate package");
-        self.send_package(Command::SetSerialBaudRate(baud))
+        // Checked upfront, so an unsupported change doesn't start with querying current rate
+        Command::SetSerialBaudRate(baud).ensure_supported(self.firmware)?;
+        if self.original_baud.is_none() {
+            self.original_baud = Some(self.get_baudrate()?);
+        }
+        tracing::debug!("Sending a SetSerialBaudRate package");
+        self.command(Command::SetSerialBaudRate(baud))
+    }
+
+    /// Restores UART baud rate from before the first `set_baudrate` call
+    pub fn revert_baudrate(&mut self) -> Result<()> {
+        if let Some(baud) = self.original_baud.take() {
+            tracing::debug!("Restoring baud rate {}", baud);
+            self.command(Command::SetSerialBaudRate(baud))?;
+        }
+        Ok(())
+    }
+
+    /// Restores UART baud rate to `baud` once CCD is dropped, same as if rate was changed with
+    /// `set_baudrate`. Meant for connections reopened at a new rate after switching to it
+    pub fn restore_baudrate_on_drop(&mut self, baud: BaudRate) {
+        self.original_baud = Some(baud);
+    }
+
+    /// Keeps UART baud rate set by `set_baudrate` after CCD is dropped
+    pub fn keep_baudrate(&mut self) {
+        self.original_baud = None;
     }
 
     /// Gets current baud rate on UART pins
     pub fn get_baudrate(&mut self) -> Result<BaudRate> {
-        log::debug!("Sending a GetSerialBaudRate package");
-        self.send_package(Command::GetSerialBaudRate)?;
-        log::debug!("Waiting for a response");
-        match self.receive_package()? {
+        self.query(Command::GetSerialBaudRate, |r| match r {
             Response::SerialBaudRate(b) => {
-                log::debug!("Recieved a SerialBaudRate package");
+                tracing::debug!("Recieved a SerialBaudRate package");
                 Ok(b)
             },
             r => Err(Error::UnexpectedResponse(r.into())),
-        }
+        })
     }
 
     /// Gets CCD version details
     pub fn get_version(&mut self) -> Result<VersionDetails> {
-        log::debug!("Sending a GetVersion package");
-        self.send_package(Command::GetVersion)?;
-        log::debug!("Waiting for a response");
-        match self.receive_package()? {
+        self.query(Command::GetVersion, |r| match r {
             Response::VersionInfo(d) => {
-                log::debug!("Recieved a VersionInfo package");
+                tracing::debug!("Recieved a VersionInfo package");
                 Ok(d)
             },
             r => Err(Error::UnexpectedResponse(r.into())),
-        }
+        })
+        .inspect(|d| {
+            match d.parsed_firmware_version() {
+                Ok(firmware) => self.firmware = Some(firmware),
+                Err(_) => tracing::warn!("Unrecognized firmware version {}", d.firmware_version()),
+            }
+            match sensors::lookup(d.sensor_type()) {
+                Some(sensor) => self.layout = sensor.layout,
+                None => tracing::warn!("Unrecognized sensor type {}", d.sensor_type()),
+            }
+        })
     }
 
     /// Takes a single frame from CCD
     pub fn get_frame(&mut self) -> Result<Frame> {
-        log::debug!("Sending a SingleRead package");
-        self.send_package(Command::SingleRead)?;
-        log::debug!("Waiting for a response");
-        match self.receive_package()? {
-            Response::SingleReading(f) => {
-                log::debug!("Recieved a SingleReading package");
-                Ok(f)
-            },
-            r => Err(Error::UnexpectedResponse(r.into())),
-        }
+        let mut frame = [0; FRAME_PIXEL_COUNT];
+        self.get_frame_into(&mut frame)?;
+        Ok(frame)
+    }
+
+    /// Takes a single frame from CCD and decodes it directly into `frame`, which allows reusing
+    /// the same buffer for every read
+    pub fn get_frame_into(&mut self, frame: &mut Frame) -> Result<()> {
+        self.with_frame(|view| view.decode_into(frame))
+    }
+
+    /// Takes a single frame from CCD and passes it to `f` without copying it out of read buffer
+    pub fn with_frame<T>(&mut self, mut f: impl FnMut(FrameView<'_>) -> T) -> Result<T> {
+        self.query_view(Command::SingleRead, |r| expect_frame(r).map(&mut f))
+    }
+
+    /// Starts continuous reading and returns an iterator over captured frames. Reading is paused
+    /// once iterator is dropped or stopped explicitly with `FramesIter::stop`
+    pub fn frames_iter(&mut self) -> Result<FramesIter<'_, IO>> {
+        tracing::debug!("Sending a ContinuousRead package");
+        self.command(Command::ContinuousRead)?;
+        let resyncs = self.stats.resyncs;
+        Ok(FramesIter {
+            ccd: self,
+            failed: false,
+            stopped: false,
+            decimation: Decimation::default(),
+            thresholds: QualityThresholds::default(),
+            resyncs,
+        })
+    }
+
+    fn receive_frame<T>(&mut self, f: impl FnOnce(FrameView<'_>) -> T) -> Result<T> {
+        tracing::debug!("Waiting for a response");
+        self.receive_package(|r| expect_frame(r).map(f))
     }
 
     /// Takes `count` frames from CCD and pushes them into buffer, or exits early on an error
     pub fn extend_with_frames<B: Extend<Frame>>(&mut self, buf: &mut B, count: usize) -> Result<()> {
-        log::debug!("Sending a ContinuousRead package");
-        self.send_package(Command::ContinuousRead)?;
-        let mut s = guard(self, |s| {
-            log::debug!("Sending a PauseRead package");
-            // FIXME: Is it really unrecoverable? Maybe at least add retries or something like that
-            s.send_package(Command::PauseRead)
-                .expect("Failed to stop continious CCD reading, unrecoverable state");
-        });
-        log::debug!("Capturing {} frames", count);
-        for _ in 0..count {
-            log::debug!("Waiting for a response");
-            let frame = match s.receive_package()? {
-                Response::SingleReading(f) => {
-                    log::debug!("Recieved a SingleReading package");
-                    f
-                },
-                r => return Err(Error::UnexpectedResponse(r.into())),
-            };
-            buf.extend(iter::once(frame))
+        self.extend_with_frames_while(buf, count, || true)
+    }
+
+    /// Same as `extend_with_frames`, but also stops early once `keep_going` returns false, which
+    /// is checked before every frame
+    pub fn extend_with_frames_while<B, F>(
+        &mut self,
+        buf: &mut B,
+        count: usize,
+        mut keep_going: F,
+    ) -> Result<()>
+    where
+        B: Extend<Frame>,
+        F: FnMut() -> bool,
+    {
+        tracing::debug!("Capturing {} frames", count);
+        let mut frames = self.frames_iter()?;
+        // Zipping in this order checks `keep_going` before waiting for the next frame
+        let _span = tracing::debug_span!("capture", count).entered();
+        let res = (0..count)
+            .take_while(|_| keep_going())
+            .zip(frames.by_ref())
+            .try_for_each(|(_, frame)| {
+                buf.extend(iter::once(frame?));
+                Ok(())
+            });
+        // Error that interrupted capture is more relevant than a failure to pause
+        res.and(frames.stop())
+    }
+}
+
+fn expect_frame(r: ResponseView<'_>) -> Result<FrameView<'_>> {
+    match r {
+        ResponseView::SingleReading(f) => {
+            tracing::debug!("Recieved a SingleReading package");
+            Ok(f)
+        },
+        r => Err(Error::UnexpectedResponse(r.name())),
+    }
+}
+
+/// Frames captured in continuous reading mode, created by `CCD::frames_iter`. Iteration ends
+/// after the first error.
+///
+/// Commands that don't expect a response can be sent between frames, all of them go through the
+/// same `IoAdapter`, so packages are never interleaved
+pub struct FramesIter<'a, IO>
+where
+    IO: IoAdapter,
+{
+    ccd: &'a mut CCD<IO>,
+    failed: bool,
+    stopped: bool,
+    decimation: Decimation,
+    thresholds: QualityThresholds,
+    // Resyncs counted by CCD when the last frame was passed on
+    resyncs: u64,
+}
+
+/// Decides which of continuously read frames are passed on and which are skipped
+struct Decimation {
+    every: usize,
+    received: usize,
+    #[cfg(feature = "std")]
+    min_interval: Option<Duration>,
+    #[cfg(feature = "std")]
+    last_kept: Option<std::time::Instant>,
+}
+
+impl Default for Decimation {
+    fn default() -> Self {
+        Decimation {
+            every: 1,
+            received: 0,
+            #[cfg(feature = "std")]
+            min_interval: None,
+            #[cfg(feature = "std")]
+            last_kept: None,
+        }
+    }
+}
+
+impl Decimation {
+    // Called once for every received frame
+    fn keep(&mut self) -> bool {
+        let n = self.received;
+        self.received += 1;
+        if !n.is_multiple_of(self.every) {
+            return false;
+        }
+        #[cfg(feature = "std")]
+        if let Some(interval) = self.min_interval {
+            let now = std::time::Instant::now();
+            if self.last_kept.is_some_and(|t| now - t < interval) {
+                return false;
+            }
+            self.last_kept = Some(now);
+        }
+        true
+    }
+}
+
+impl<IO> FramesIter<'_, IO>
+where
+    IO: IoAdapter,
+{
+    /// Changes exposure time without interrupting continuous reading
+    pub fn set_exp_time(&mut self, t: u16) -> Result<()> {
+        self.ccd.set_exp_time(t)
+    }
+
+    /// Changes "average time" without interrupting continuous reading
+    pub fn set_avg_time(&mut self, t: u8) -> Result<()> {
+        self.ccd.set_avg_time(t)
+    }
+
+    /// Changes trigger mode without interrupting continuous reading
+    pub fn set_trigger_mode(&mut self, mode: TriggerMode) -> Result<()> {
+        self.ccd.set_trigger_mode(mode)
+    }
+
+    /// Waits for the next frame and decodes it directly into `frame`, avoiding a copy per frame
+    /// compared to `next`. Returns `None` under the same conditions as `next`
+    pub fn next_into(&mut self, frame: &mut Frame) -> Option<Result<()>> {
+        self.next_with(|view| view.decode_into(frame))
+    }
+
+    /// Waits for the next frame and passes it to `f` while it's still in the read buffer
+    pub fn next_with<T>(&mut self, f: impl FnOnce(FrameView<'_>) -> T) -> Option<Result<T>> {
+        if self.failed || self.stopped {
+            return None;
+        }
+        let mut f = Some(f);
+        loop {
+            // Skipped frames are never decoded
+            let decimation = &mut self.decimation;
+            let res = self.ccd.receive_frame(|view| {
+                if decimation.keep() {
+                    let _span = tracing::trace_span!("decode").entered();
+                    f.take().map(|f| f(view))
+                } else {
+                    None
+                }
+            });
+            match res {
+                Ok(None) => tracing::trace!("Skipping a frame"),
+                Ok(Some(v)) => return Some(Ok(v)),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+
+    /// Same as `next`, along with quality flags of the frame. Frames are flagged as averaged when
+    /// "average time" last set or read through CCD is above 1
+    pub fn next_flagged(&mut self) -> Option<Result<(Frame, QualityFlags)>> {
+        let frame = match self.next()? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+        let resyncs = self.ccd.stats.resyncs;
+        let flags = QualityFlags {
+            crc_resynced: resyncs > self.resyncs,
+            averaged: self.ccd.avg_time.is_some_and(|t| t > 1),
+            ..QualityFlags::assess(&frame[..self.ccd.layout.pixels()], &self.thresholds)
+        };
+        self.resyncs = resyncs;
+        Some(Ok((frame, flags)))
+    }
+
+    /// Sets levels `next_flagged` checks frames against
+    pub fn set_quality_thresholds(&mut self, thresholds: QualityThresholds) {
+        self.thresholds = thresholds;
+    }
+
+    /// Only passes on every `n`th received frame, skipping the rest. `n` of 0 is treated as 1
+    pub fn keep_every(&mut self, n: usize) {
+        self.decimation.every = n.max(1);
+    }
+
+    /// Skips frames that arrive sooner than `1 / fps` seconds after the last passed on one,
+    /// `None` removes the limit
+    #[cfg(feature = "std")]
+    pub fn set_max_fps(&mut self, fps: Option<f64>) {
+        self.decimation.min_interval = fps.map(|fps| Duration::from_secs_f64(1.0 / fps));
+    }
+
+    /// Stream statistics of underlying CCD, which allows noticing dropped frames while reading
+    pub fn stats(&self) -> &StreamStats {
+        self.ccd.stats()
+    }
+
+    /// Pauses continuous reading, reporting an error if CCD could not be stopped
+    pub fn stop(mut self) -> Result<()> {
+        self.pause()
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.stopped = true;
+        tracing::debug!("Sending a PauseRead package");
+        self.ccd.command(Command::PauseRead)
+    }
+}
+
+impl<IO> Iterator for FramesIter<'_, IO>
+where
+    IO: IoAdapter,
+{
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with(|view| view.to_frame())
+    }
+}
+
+impl<IO> Drop for CCD<IO>
+where
+    IO: IoAdapter,
+{
+    fn drop(&mut self) {
+        if let Err(e) = self.revert_baudrate() {
+            tracing::error!("Failed to restore CCD baud rate: {}", e);
+        }
+    }
+}
+
+impl<IO> Drop for FramesIter<'_, IO>
+where
+    IO: IoAdapter,
+{
+    fn drop(&mut self) {
+        if self.stopped {
+            return;
+        }
+        if let Err(e) = self.pause() {
+            tracing::error!("Failed to stop continuous CCD reading: {}", e);
         }
-        Ok(())
     }
 }
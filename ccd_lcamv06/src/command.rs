@@ -1,8 +1,19 @@
-use crate::flags::{TriggerMode, BaudRate};
+use crate::{
+    error::{Error, Result},
+    flags::{BaudRate, TriggerMode},
+    response::FirmwareVersion,
+};
+
+/// Codes of commands that older firmware doesn't understand, along with the version they first
+/// appeared in. Commands that aren't listed work on every firmware
+const MIN_FIRMWARE: [(u8, FirmwareVersion); 1] = [
+    // UART of earlier firmware is fixed at 115200
+    (0x13, FirmwareVersion::new(4, 0, 0)),
+];
 
 /// Package that can be sent to CCD
-#[derive(PartialEq, Eq, Debug)]
-pub(crate) enum Command {
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Command {
     SingleRead,
     ContinuousRead,
     PauseRead,
@@ -35,6 +46,30 @@ impl Command {
         }
     }
 
+    /// Oldest firmware that understands the command, `None` if every firmware does
+    pub fn min_firmware(&self) -> Option<FirmwareVersion> {
+        let code = self.code();
+        MIN_FIRMWARE
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, version)| *version)
+    }
+
+    /// Refuses commands that firmware of the device is known to be too old for, since device
+    /// would silently ignore them and leave caller waiting for a timeout
+    pub(crate) fn ensure_supported(&self, firmware: Option<FirmwareVersion>) -> Result<()> {
+        match (self.min_firmware(), firmware) {
+            (Some(required), Some(found)) if found < required => {
+                Err(Error::UnsupportedByFirmware {
+                    command: self.code(),
+                    required,
+                    found,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn encode(&self) -> [u8; 5] {
         use Command::*;
         let [data1, data2] = match self {
@@ -46,4 +81,61 @@ impl Command {
         };
         [0x81, self.code(), data1, data2, 0xFF]
     }
+
+    /// Inverse of `encode`, for looking at packages from the device side. `None` if package isn't
+    /// a known command
+    pub fn decode(package: [u8; 5]) -> Option<Command> {
+        use Command::*;
+        let [head, code, data1, data2, tail] = package;
+        if head != 0x81 || tail != 0xFF {
+            return None;
+        }
+        Some(match code {
+            0x01 => SingleRead,
+            0x02 => ContinuousRead,
+            0x03 => SetIntegrationTime(u16::from_be_bytes([data1, data2])),
+            0x06 => PauseRead,
+            0x07 => SetTrigerMode(match data1 {
+                0x00 => TriggerMode::SoftTrigger,
+                0x01 => TriggerMode::ContiniousHardTrigger,
+                0x02 => TriggerMode::SingleHardTrigger,
+                _ => return None,
+            }),
+            0x09 => GetVersion,
+            0x0a => GetExposureTime,
+            0x0c => SetAverageTime(data1),
+            0x0e => GetAverageTime,
+            0x13 => SetSerialBaudRate(BaudRate::try_from_code(data1).ok()?),
+            0x16 => GetSerialBaudRate,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encoded() {
+        let commands = [
+            Command::SingleRead,
+            Command::ContinuousRead,
+            Command::PauseRead,
+            Command::SetIntegrationTime(0x0102),
+            Command::SetTrigerMode(TriggerMode::SingleHardTrigger),
+            Command::GetExposureTime,
+            Command::GetVersion,
+            Command::SetAverageTime(4),
+            Command::GetAverageTime,
+            Command::SetSerialBaudRate(BaudRate::Baud921600),
+            Command::GetSerialBaudRate,
+        ];
+        for cmd in commands {
+            assert_eq!(Command::decode(cmd.encode()), Some(cmd));
+        }
+        assert_eq!(Command::decode([0x81, 0x42, 0x00, 0x00, 0xFF]), None);
+        assert_eq!(Command::decode([0x81, 0x13, 0x07, 0x00, 0xFF]), None);
+        assert_eq!(Command::decode([0x81, 0x01, 0x00, 0x00, 0x00]), None);
+    }
 }
@@ -0,0 +1,206 @@
+#[cfg(feature = "std")]
+use crate::{ccd::MAX_HEAD_LEN, error::Result};
+use crate::{
+    error::Error,
+    response::{
+        parser::{align_response, parse_response, PackageError},
+        FrameView, Response, ResponseView, SensorLayout,
+    },
+};
+
+/// Package found in a recorded stream
+#[derive(Debug, PartialEq, Eq)]
+pub enum Package<'a> {
+    Frame(FrameView<'a>),
+    Response(Response),
+}
+
+impl<'a> From<ResponseView<'a>> for Package<'a> {
+    fn from(view: ResponseView<'a>) -> Self {
+        match view {
+            ResponseView::SingleReading(frame) => Package::Frame(frame),
+            ResponseView::Other(response) => Package::Response(response),
+        }
+    }
+}
+
+/// Single item found while decoding a recorded stream, offsets are relative to its start
+#[derive(Debug)]
+pub enum Decoded<'a> {
+    /// Package that was parsed successfully
+    Package { offset: usize, package: Package<'a> },
+    /// Data that was rejected by parser, `skipped` bytes were dropped to get to the next package
+    Invalid {
+        offset: usize,
+        skipped: usize,
+        error: Error,
+    },
+    /// Stream ends in the middle of a package
+    Incomplete { offset: usize, len: usize },
+}
+
+/// Splits a recorded byte stream into packages the same way CCD does while reading, which allows
+/// inspecting captured traffic without hardware attached
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    offset: usize,
+    layout: SensorLayout,
+    verify_crc: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8], verify_crc: bool) -> Self {
+        Decoder {
+            data,
+            offset: 0,
+            layout: SensorLayout::default(),
+            verify_crc,
+        }
+    }
+
+    /// Decodes frames recorded from a sensor with a different layout
+    pub fn with_layout(mut self, layout: SensorLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Decoded<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let input = &self.data[self.offset..];
+        if input.is_empty() {
+            return None;
+        }
+        let offset = self.offset;
+        match parse_response(input, self.layout, self.verify_crc) {
+            Ok((tail, view)) => {
+                self.offset = self.data.len() - tail.len();
+                Some(Decoded::Package {
+                    offset,
+                    package: view.into(),
+                })
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                self.offset = self.data.len();
+                Some(Decoded::Incomplete {
+                    offset,
+                    len: input.len(),
+                })
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                // Nothing else is coming, so unlike CCD there's no point in keeping a partial head
+                let skipped = match e {
+                    PackageError::CrcMismatch { .. } => self.layout.package_size(),
+                    _ => match align_response(&input[1..]) {
+                        Ok((tail, _)) => input.len() - tail.len(),
+                        Err(_) => input.len(),
+                    },
+                };
+                self.offset += skipped;
+                Some(Decoded::Invalid {
+                    offset,
+                    skipped,
+                    error: e.into(),
+                })
+            }
+        }
+    }
+}
+
+/// Package produced by `ResponseParser`, which owns frame data since parser reuses its buffer
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Parsed {
+    Frame(Vec<u16>),
+    Response(Response),
+}
+
+/// Push based parser for live streams that arrive in arbitrary chunks, e.g. from DMA buffers,
+/// RTOS queues or packet captures. Handles garbage and partial packages the same way CCD does,
+/// but leaves reading and writing to the caller
+#[cfg(feature = "std")]
+pub struct ResponseParser {
+    buf: Vec<u8>,
+    /// Buffer length at which package being received is complete, known once its head is parsed
+    complete_at: usize,
+    layout: SensorLayout,
+    verify_crc: bool,
+}
+
+#[cfg(feature = "std")]
+impl ResponseParser {
+    pub fn new(verify_crc: bool) -> Self {
+        ResponseParser {
+            buf: Vec::new(),
+            complete_at: 0,
+            layout: SensorLayout::default(),
+            verify_crc,
+        }
+    }
+
+    /// Parses frames from a sensor with a different layout
+    pub fn with_layout(mut self, layout: SensorLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Amount of bytes kept until the rest of a package arrives
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Takes the next chunk of a stream and returns every package completed by it, along with
+    /// errors for data that was skipped to get to them
+    pub fn push_bytes(&mut self, data: &[u8]) -> Vec<Result<Parsed>> {
+        self.buf.extend_from_slice(data);
+        let mut out = Vec::new();
+        // Rest of a frame is still arriving, no point in parsing its head again
+        if self.buf.len() < self.complete_at {
+            return out;
+        }
+        self.complete_at = 0;
+        let mut consumed = 0;
+        while consumed < self.buf.len() {
+            let input = &self.buf[consumed..];
+            match parse_response(input, self.layout, self.verify_crc) {
+                Ok((tail, view)) => {
+                    out.push(Ok(match view {
+                        ResponseView::SingleReading(frame) => Parsed::Frame(frame.iter().collect()),
+                        ResponseView::Other(response) => Parsed::Response(response),
+                    }));
+                    consumed += input.len() - tail.len();
+                }
+                Err(nom::Err::Incomplete(needed)) => {
+                    // Relative to the buffer once packages before this one are drained from it
+                    if let nom::Needed::Size(n) = needed {
+                        self.complete_at = input.len() + n.get();
+                    }
+                    break;
+                }
+                Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                    out.push(Err(e.into()));
+                    // Unlike CCD, a byte that already failed to parse is never kept, so the same
+                    // error isn't reported again on the next chunk
+                    consumed += match e {
+                        PackageError::CrcMismatch { .. } => self.layout.package_size(),
+                        _ => match align_response(&input[1..]) {
+                            Ok((tail, _)) => input.len() - tail.len(),
+                            Err(_) => input.len().saturating_sub(MAX_HEAD_LEN - 1).max(1),
+                        },
+                    };
+                }
+            }
+        }
+        self.buf.drain(..consumed);
+        out
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for ResponseParser {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
@@ -1,3 +1,4 @@
+use crate::response::FirmwareVersion;
 use thiserror::Error;
 use core::result::Result as CoreResult;
 
@@ -12,17 +13,66 @@ pub enum Error {
     InvalidData,
     #[error("Unexpected end of package")]
     UnexpectedEop,
+    #[error("Package does not start with a known head")]
+    BadHead,
+    #[error("Unknown command code in package: {0:#04X}")]
+    BadCommandCode(u8),
+    #[error("Package CRC mismatch, expected {expected:#06X}, got {got:#06X}")]
+    CrcMismatch { expected: u16, got: u16 },
+    #[error("Package ends with an unexpected tail")]
+    BadTail,
+    #[error("Frame package has unexpected length")]
+    TruncatedFrame,
     #[error("{0} is longer than expected")]
     VersionDetailTooLong(&'static str),
     #[error("Recieved an unexpected type of response: {0}")]
     UnexpectedResponse(&'static str),
+    #[error("Timed out waiting for a response")]
+    Timeout,
+    #[error("Frame package of {0} pixels does not fit into read buffer")]
+    UnsupportedLayout(usize),
+    #[error("Line {0} of traffic dump is not a valid record")]
+    InvalidDump(usize),
+    #[error("CCD didn't respond at any of tried baud rates")]
+    NoBaudRate,
+    #[error("Command {command:#04X} requires firmware {required} or newer, device has {found}")]
+    UnsupportedByFirmware {
+        command: u8,
+        required: FirmwareVersion,
+        found: FirmwareVersion,
+    },
 
     #[cfg(feature = "std")]
     #[error("{0}")]
     StdIoError(#[from] std::io::Error),
 
+    #[cfg(feature = "proto")]
+    #[error("Could not decode protobuf message: {0}")]
+    ProtoDecode(#[from] prost::DecodeError),
+    #[cfg(feature = "proto")]
+    #[error("Frame message has {0} pixels, sensor has {}", crate::FRAME_PIXEL_COUNT)]
+    PixelCount(usize),
+
     // TODO: Include contents of original error
     #[cfg(feature = "embedded-hal-nb")]
     #[error("Serial communication failed")]
     EmbeddedHalNbError,
 }
+
+impl Error {
+    /// Errors caused by data being lost or corrupted in transmission, which may not happen again
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidData
+                | Error::UnexpectedEop
+                | Error::BadHead
+                | Error::BadCommandCode(_)
+                | Error::CrcMismatch { .. }
+                | Error::BadTail
+                | Error::TruncatedFrame
+                | Error::UnexpectedResponse(_)
+                | Error::Timeout
+        )
+    }
+}
@@ -21,6 +21,13 @@ pub enum BaudRate {
 }
 
 impl BaudRate {
+    /// Every baud rate supported by CCD
+    pub const ALL: [BaudRate; 3] = [
+        BaudRate::Baud115200,
+        BaudRate::Baud384000,
+        BaudRate::Baud921600,
+    ];
+
     pub(crate) fn try_from_code(c: u8) -> Result<Self, Error> {
         use BaudRate::*;
         match c {
@@ -32,3 +32,9 @@ impl<IO: Read + Write> IoAdapter for EmbeddedHalNbAdapter<IO> {
         Ok(buf.len())
     }
 }
+
+impl<IO: Read + Write> EmbeddedHalNbAdapter<IO> {
+    pub fn new(io: IO) -> Self {
+        EmbeddedHalNbAdapter { io }
+    }
+}
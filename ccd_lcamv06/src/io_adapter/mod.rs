@@ -4,11 +4,16 @@ pub(crate) mod std_io;
 pub(crate) mod embedded_hal;
 
 use crate::{error::Result, ccd::CCD};
+use core::time::Duration;
 
 pub trait IoAdapter {
     fn write_all(&mut self, buf: &[u8]) -> Result<()>;
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
 
+    /// Pauses before retrying a failed exchange. Adapters without access to a clock may
+    /// leave it as is, retrying immediately
+    fn delay(&mut self, _duration: Duration) {}
+
     fn open_ccd(self) -> CCD<Self>
     where
         Self: Sized
@@ -1,11 +1,19 @@
 use super::IoAdapter;
 use crate::error::Result;
-use std::io::{Read, Write};
+use core::time::Duration;
+use std::io::{ErrorKind, Read, Write};
 
 pub struct StdIoAdapter<IO: Read + Write> {
     io: IO,
 }
 
+fn is_no_data(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+    )
+}
+
 impl<IO: Read + Write> IoAdapter for StdIoAdapter<IO> {
     fn write_all(&mut self, buf: &[u8]) -> Result<()> {
         self.io.write_all(buf)?;
@@ -13,8 +21,16 @@ impl<IO: Read + Write> IoAdapter for StdIoAdapter<IO> {
     }
 
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let count = self.io.read(buf)?;
-        Ok(count)
+        match self.io.read(buf) {
+            Ok(count) => Ok(count),
+            // Absence of data is not an error by itself, CCD keeps track of overall timeout
+            Err(e) if is_no_data(e.kind()) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delay(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
     }
 }
 
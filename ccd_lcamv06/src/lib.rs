@@ -9,11 +9,45 @@ pub mod io_adapter;
 pub use io_adapter::IoAdapter;
 #[cfg(feature = "std")]
 pub use io_adapter::std_io::StdIoAdapter;
-#[cfg(feature = "embedded_hal")]
-pub use io_adapter::embedded_hal::EmbeddedHalAdapter;
+#[cfg(feature = "embedded-hal-nb")]
+pub use io_adapter::embedded_hal::EmbeddedHalNbAdapter;
 
 pub mod ccd;
-pub use ccd::CCD;
+pub use ccd::{FramesIter, CCD};
 
+pub mod builder;
+pub use builder::CCDBuilder;
+
+pub mod sensors;
+
+pub mod spectrometer;
+pub use spectrometer::Spectrometer;
+
+pub mod retry;
+pub use retry::RetryPolicy;
+
+pub mod stats;
+pub use stats::StreamStats;
+
+pub mod quality;
+pub use quality::{QualityFlags, QualityThresholds};
+
+pub mod decoder;
+pub use decoder::Decoder;
+#[cfg(feature = "std")]
+pub use decoder::ResponseParser;
+
+#[cfg(feature = "std")]
+pub mod transport;
+
+#[cfg(feature = "std")]
+pub mod testing;
+
+#[cfg(feature = "proto")]
+pub mod proto;
+
+pub use command::Command;
 pub use flags::{BaudRate, TriggerMode};
-pub use response::{Frame, FRAME_PIXEL_COUNT, VersionDetails};
+pub use response::{
+    FirmwareVersion, Frame, FrameView, Response, SensorLayout, VersionDetails, FRAME_PIXEL_COUNT,
+};
@@ -0,0 +1,118 @@
+//! Protobuf messages for storing and sending frames, matching `proto/lcamv06.proto`
+
+use crate::error::{Error, Result};
+use crate::quality::QualityFlags;
+use crate::response::{Frame as PixelFrame, FRAME_PIXEL_COUNT};
+use prost::Message;
+use std::collections::HashMap;
+
+/// Schema these messages are generated from, for consumers that bring their own code generator
+pub const SCHEMA: &str = include_str!("../proto/lcamv06.proto");
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Capture {
+    #[prost(map = "string, string", tag = "1")]
+    pub metadata: HashMap<String, String>,
+    #[prost(double, repeated, tag = "2")]
+    pub wavelength: Vec<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Frame {
+    #[prost(int64, tag = "1")]
+    pub timestamp_us: i64,
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+    #[prost(uint32, repeated, tag = "3")]
+    pub pixels: Vec<u32>,
+    #[prost(uint32, tag = "4")]
+    pub quality: u32,
+}
+
+impl Capture {
+    /// Builds metadata out of `key: value` lines, lines without a separator are skipped
+    pub fn from_lines<S: AsRef<str>>(lines: &[S], wavelength: Vec<f64>) -> Self {
+        let metadata = lines
+            .iter()
+            .filter_map(|line| line.as_ref().split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Capture {
+            metadata,
+            wavelength,
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(frame: &[u16], seq: u64, timestamp_us: i64) -> Self {
+        Frame {
+            timestamp_us,
+            seq,
+            pixels: frame.iter().map(|&px| px as u32).collect(),
+            quality: 0,
+        }
+    }
+
+    /// Quality flags of the frame, unknown bits are ignored
+    pub fn quality(&self) -> QualityFlags {
+        QualityFlags::from_bits(self.quality as u8)
+    }
+
+    /// Pixels as a frame read from CCD, fails if there are not exactly as many as sensor has or
+    /// they don't fit into 16 bits
+    pub fn to_frame(&self) -> Result<PixelFrame> {
+        if self.pixels.len() != FRAME_PIXEL_COUNT {
+            return Err(Error::PixelCount(self.pixels.len()));
+        }
+        let mut frame = [0; FRAME_PIXEL_COUNT];
+        for (dst, &px) in frame.iter_mut().zip(&self.pixels) {
+            *dst = u16::try_from(px).map_err(|_| Error::InvalidData)?;
+        }
+        Ok(frame)
+    }
+}
+
+/// Appends message prefixed with its length, so that several can be written back to back
+pub fn encode<M: Message>(message: &M, out: &mut Vec<u8>) {
+    // Writing into Vec only fails when it runs out of memory
+    message
+        .encode_length_delimited(out)
+        .expect("Vec has enough capacity");
+}
+
+/// Reads a single length prefixed message from the start of `buf`, advancing past it
+pub fn decode<M: Message + Default>(buf: &mut &[u8]) -> Result<M> {
+    Ok(M::decode_length_delimited(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_stream_roundtrip() {
+        let capture = Capture::from_lines(&["exposure time: 10", "no separator"], vec![500.0, 0.5]);
+        let mut pixels = [0; FRAME_PIXEL_COUNT];
+        pixels[7] = 65535;
+        let mut stream = Vec::new();
+        encode(&capture, &mut stream);
+        let mut message = Frame::new(&pixels, 1, 1_700_000_000_000_000);
+        message.quality = QualityFlags::SATURATED as u32;
+        encode(&message, &mut stream);
+
+        let mut buf = stream.as_slice();
+        let decoded: Capture = decode(&mut buf).unwrap();
+        assert_eq!(decoded, capture);
+        assert_eq!(decoded.metadata.len(), 1);
+        let frame: Frame = decode(&mut buf).unwrap();
+        assert!(buf.is_empty());
+        assert_eq!(frame.seq, 1);
+        assert_eq!(frame.to_frame().unwrap(), pixels);
+        assert!(frame.quality().saturated);
+
+        let short = Frame::new(&pixels[..10], 2, 0);
+        assert!(matches!(short.to_frame(), Err(Error::PixelCount(10))));
+        assert!(decode::<Frame>(&mut &stream[..5]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use core::fmt::{self, Display};
+
+/// Conditions that make a frame less trustworthy, so that downstream processing can filter out
+/// dubious frames without looking at pixels again
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QualityFlags {
+    /// At least one pixel reached saturation level
+    pub saturated: bool,
+    /// Brightest pixel stayed below underexposure level, so frame is mostly dark signal
+    pub underexposed: bool,
+    /// Broken data was skipped right before this frame, so some packages around it were lost
+    pub crc_resynced: bool,
+    /// Frame is an average of several readings, either by device or in software
+    pub averaged: bool,
+}
+
+/// Levels frames are checked against when flagged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityThresholds {
+    /// Pixel values at or above this are saturated
+    pub saturation: u16,
+    /// Frames with no pixel reaching this are underexposed
+    pub underexposure: u16,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        QualityThresholds {
+            saturation: u16::MAX,
+            underexposure: 2048,
+        }
+    }
+}
+
+impl QualityFlags {
+    pub const SATURATED: u8 = 1 << 0;
+    pub const UNDEREXPOSED: u8 = 1 << 1;
+    pub const CRC_RESYNCED: u8 = 1 << 2;
+    pub const AVERAGED: u8 = 1 << 3;
+
+    /// Flags that can be told from pixels alone, the rest is left unset
+    pub fn assess(frame: &[u16], thresholds: &QualityThresholds) -> Self {
+        let peak = frame.iter().copied().max().unwrap_or_default();
+        QualityFlags {
+            saturated: peak >= thresholds.saturation,
+            underexposed: peak < thresholds.underexposure,
+            ..Default::default()
+        }
+    }
+
+    /// Nothing is wrong with the frame
+    pub fn is_clean(&self) -> bool {
+        *self == QualityFlags::default()
+    }
+
+    /// Flags packed into a single byte, for compact storage
+    pub fn bits(&self) -> u8 {
+        self.flags()
+            .iter()
+            .filter(|(_, set, _)| *set)
+            .fold(0, |bits, (bit, _, _)| bits | bit)
+    }
+
+    /// Reverses `bits`, unknown bits are ignored
+    pub fn from_bits(bits: u8) -> Self {
+        QualityFlags {
+            saturated: bits & Self::SATURATED != 0,
+            underexposed: bits & Self::UNDEREXPOSED != 0,
+            crc_resynced: bits & Self::CRC_RESYNCED != 0,
+            averaged: bits & Self::AVERAGED != 0,
+        }
+    }
+
+    /// Names of set flags, in the same order as bits
+    pub fn names(&self) -> impl Iterator<Item = &'static str> {
+        self.flags()
+            .into_iter()
+            .filter(|(_, set, _)| *set)
+            .map(|(_, _, name)| name)
+    }
+
+    fn flags(&self) -> [(u8, bool, &'static str); 4] {
+        [
+            (Self::SATURATED, self.saturated, "saturated"),
+            (Self::UNDEREXPOSED, self.underexposed, "underexposed"),
+            (Self::CRC_RESYNCED, self.crc_resynced, "crc_resynced"),
+            (Self::AVERAGED, self.averaged, "averaged"),
+        ]
+    }
+}
+
+/// Names of set flags separated by `|`, empty for a clean frame
+impl Display for QualityFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, name) in self.names().enumerate() {
+            if i > 0 {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_frames() {
+        let thresholds = QualityThresholds {
+            saturation: 1000,
+            underexposure: 100,
+        };
+        let flags = QualityFlags::assess(&[50, 1000, 20], &thresholds);
+        assert!(flags.saturated && !flags.underexposed);
+        assert!(QualityFlags::assess(&[50, 99], &thresholds).underexposed);
+        assert!(QualityFlags::assess(&[500], &thresholds).is_clean());
+
+        let flags = QualityFlags {
+            underexposed: true,
+            averaged: true,
+            ..Default::default()
+        };
+        assert_eq!(flags.bits(), 0b1010);
+        assert_eq!(QualityFlags::from_bits(flags.bits()), flags);
+        assert_eq!(flags.to_string(), "underexposed|averaged");
+    }
+}
@@ -0,0 +1,84 @@
+use super::{parser::checksum, Response, SensorLayout};
+
+impl Response {
+    /// Package exactly as CCD sends it, for emulating a device or generating test data
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Response::ExposureTime(time) => {
+                let [high, low] = time.to_be_bytes();
+                vec![0x81, 0x02, high, low, 0xFF]
+            }
+            Response::AverageTime(time) => vec![0x81, 0x0E, *time, 0x00, 0xFF],
+            Response::SerialBaudRate(baud) => vec![0x81, 0x16, baud.to_code(), 0x00, 0xFF],
+            // Only response sent as plain text
+            Response::VersionInfo(details) => format!(
+                "HdInfo:{},{},{},{}",
+                details.hardware_version(),
+                details.sensor_type(),
+                details.firmware_version(),
+                details.serial_number()
+            )
+            .into_bytes(),
+        }
+    }
+
+    /// SingleReading package with `pixels` surrounded by zeroed ghost pixels of `layout`, head
+    /// holding data length and wrapping sum of data bytes as CRC.
+    ///
+    /// Panics if amount of pixels doesn't match layout
+    pub fn encode_frame(pixels: &[u16], layout: SensorLayout) -> Vec<u8> {
+        assert_eq!(
+            pixels.len(),
+            layout.pixels(),
+            "frame doesn't match sensor layout"
+        );
+        let mut data = vec![0; layout.prefix() * 2];
+        data.extend(pixels.iter().flat_map(|px| px.to_be_bytes()));
+        data.resize(layout.total() * 2, 0);
+
+        let mut package = vec![0x81, 0x01];
+        package.extend((data.len() as u16).to_be_bytes());
+        package.push(0x00);
+        package.extend(&data);
+        package.extend(checksum(&data).to_be_bytes());
+        package
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        flags::BaudRate,
+        response::{parser::parse_response, ResponseView, VersionDetails},
+    };
+
+    #[test]
+    fn encode_round_trip() {
+        let responses = [
+            Response::ExposureTime(0xABCD),
+            Response::AverageTime(7),
+            Response::SerialBaudRate(BaudRate::Baud384000),
+            Response::VersionInfo(
+                VersionDetails::try_new("LCAM_V8.4.2", "S11639", "V4.2", "202111161548").unwrap(),
+            ),
+        ];
+        for response in responses {
+            let encoded = response.encode();
+            let (rest, parsed) = parse_response(&encoded, SensorLayout::default(), true).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(parsed, ResponseView::Other(response));
+        }
+
+        let layout = SensorLayout::new(3, 2, 1).unwrap();
+        let encoded = Response::encode_frame(&[1, 0x1234, 0xFFFF], layout);
+        assert_eq!(encoded[..5], [0x81, 0x01, 0x00, 12, 0x00]);
+        let (_, parsed) = parse_response(&encoded, layout, true).unwrap();
+        match parsed {
+            ResponseView::SingleReading(frame) => {
+                assert_eq!(frame.iter().collect::<Vec<_>>(), [1, 0x1234, 0xFFFF])
+            }
+            other => panic!("Expected a frame, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use super::{Frame, FRAME_PIXEL_COUNT};
+
+/// Frame borrowed straight from the read buffer. Pixels are decoded on access, so nothing is
+/// copied until it's actually needed
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct FrameView<'a> {
+    // Big endian pixel values
+    bytes: &'a [u8],
+}
+
+impl<'a> FrameView<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        debug_assert_eq!(bytes.len() % 2, 0);
+        FrameView { bytes }
+    }
+
+    /// Raw pixel data as it was received, 2 big endian bytes per pixel
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<u16> {
+        if idx >= self.len() {
+            return None;
+        }
+        Some(u16::from_be_bytes([self.bytes[idx * 2], self.bytes[idx * 2 + 1]]))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + 'a {
+        self.bytes
+            .chunks_exact(2)
+            .map(|px| u16::from_be_bytes([px[0], px[1]]))
+    }
+
+    /// Decodes pixels into an existing frame, which allows reusing it between reads. Pixels that
+    /// don't fit are dropped, and ones this frame doesn't have are zeroed
+    pub fn decode_into(&self, frame: &mut [u16]) {
+        let mut pixels = self.iter();
+        for dst in frame.iter_mut() {
+            *dst = pixels.next().unwrap_or(0);
+        }
+    }
+
+    pub fn to_frame(&self) -> Frame {
+        let mut frame = [0; FRAME_PIXEL_COUNT];
+        self.decode_into(&mut frame);
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_view() {
+        let bytes: [u8; FRAME_PIXEL_COUNT * 2] =
+            core::array::from_fn(|i| if i % 2 == 0 { 0x12 } else { (i / 2) as u8 });
+        let view = FrameView::new(&bytes);
+        assert_eq!(view.get(0), Some(0x1200));
+        assert_eq!(view.get(3), Some(0x1203));
+        assert_eq!(view.get(FRAME_PIXEL_COUNT), None);
+
+        let mut frame = [0; FRAME_PIXEL_COUNT];
+        view.decode_into(&mut frame);
+        assert_eq!(frame, view.to_frame());
+        assert!(view.iter().eq(frame.iter().copied()));
+    }
+}
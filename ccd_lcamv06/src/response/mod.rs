@@ -1,31 +1,108 @@
+#[cfg(feature = "std")]
+mod encoder;
+mod frame_view;
 pub mod parser;
 mod version_details;
 mod version_parser;
 
-use crate::flags::BaudRate;
+use crate::{error::Error, flags::BaudRate};
 use strum_macros::IntoStaticStr;
-pub use version_details::VersionDetails;
+pub use frame_view::FrameView;
+pub use version_details::{FirmwareVersion, VersionDetails};
 
-// While there is a large difference in response sizes, all of the small ones usually come one at a
-// time, while SingleReading may come as a stream. Plus Box<_> cannot be used because of no_std
-#[allow(clippy::large_enum_variant)]
-/// Package that can be received from CCD
+/// Package that can be received from CCD, except for frames, which are handled by `ResponseView`
 #[derive(PartialEq, Eq, Debug, Clone, IntoStaticStr)]
 pub enum Response {
-    SingleReading(Frame),
     ExposureTime(u16),
     AverageTime(u8),
     SerialBaudRate(BaudRate),
     VersionInfo(VersionDetails),
 }
 
-/// Amount of real pixels in a single frame
+/// Response that keeps frame data in the read buffer, so it can be decoded directly into its
+/// final destination
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum ResponseView<'a> {
+    SingleReading(FrameView<'a>),
+    Other(Response),
+}
+
+impl ResponseView<'_> {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ResponseView::SingleReading(_) => "SingleReading",
+            ResponseView::Other(r) => r.into(),
+        }
+    }
+}
+
+/// Amount of real pixels in a single frame of LCAM V06
 pub const FRAME_PIXEL_COUNT: usize = 3694;
-/// Each reading is prefixed and postfixed with "ghost" pixels, which can be dropped
-const FRAME_PIXEL_PREFIX: usize = 0;
-const FRAME_PIXEL_POSTFIX: usize = 0;
-/// Amount of pixels in a single package
-const FRAME_TOTAL_COUNT: usize = FRAME_PIXEL_PREFIX + FRAME_PIXEL_COUNT + FRAME_PIXEL_POSTFIX;
+/// Amount of pixels in the largest frame package read buffer has room for
+const MAX_FRAME_TOTAL_COUNT: usize = FRAME_PIXEL_COUNT;
+
+/// Size of the largest package, SingleReading: 5 bytes of head, pixels and CRC
+pub(crate) const MAX_PACKAGE_SIZE: usize = 5 + MAX_FRAME_TOTAL_COUNT * 2 + 2;
+
+/// Describes how pixels are laid out in a frame package. Each reading may be prefixed and
+/// postfixed with "ghost" pixels, which are dropped while parsing
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SensorLayout {
+    pixels: usize,
+    prefix: usize,
+    postfix: usize,
+}
+
+impl SensorLayout {
+    /// Layout of frames sent by LCAM V06
+    pub const LCAM_V06: SensorLayout = SensorLayout {
+        pixels: FRAME_PIXEL_COUNT,
+        prefix: 0,
+        postfix: 0,
+    };
+
+    /// Fails if frame package with such layout wouldn't fit into read buffer
+    pub fn new(pixels: usize, prefix: usize, postfix: usize) -> Result<Self, Error> {
+        let total = pixels + prefix + postfix;
+        if pixels == 0 || total > MAX_FRAME_TOTAL_COUNT {
+            return Err(Error::UnsupportedLayout(total));
+        }
+        Ok(SensorLayout {
+            pixels,
+            prefix,
+            postfix,
+        })
+    }
+
+    /// Amount of real pixels in a frame
+    pub fn pixels(&self) -> usize {
+        self.pixels
+    }
+
+    pub fn prefix(&self) -> usize {
+        self.prefix
+    }
+
+    pub fn postfix(&self) -> usize {
+        self.postfix
+    }
+
+    /// Amount of pixels in a package, including ghost ones
+    pub fn total(&self) -> usize {
+        self.prefix + self.pixels + self.postfix
+    }
+
+    /// Size of a frame package: 5 bytes of head, every pixel and CRC
+    pub(crate) fn package_size(&self) -> usize {
+        5 + self.total() * 2 + 2
+    }
+}
+
+impl Default for SensorLayout {
+    fn default() -> Self {
+        Self::LCAM_V06
+    }
+}
 
-/// CCD captured data
+/// CCD captured data. Sensors with fewer pixels only fill the beginning of it
 pub type Frame = [u16; FRAME_PIXEL_COUNT];
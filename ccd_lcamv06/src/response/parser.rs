@@ -3,17 +3,58 @@ use core::{num::NonZeroUsize, ops::RangeFrom};
 use nom::{
     branch::alt,
     combinator::{map, peek},
-    multi::fill,
+    error::{ErrorKind, ParseError},
     number::streaming::{be_u16, be_u8},
     IResult, InputIter, InputLength, Slice,
 };
 
-use crate::flags::BaudRate;
+use crate::{error::Error, flags::BaudRate};
 use super::version_parser::*;
-use super::{Response, FRAME_TOTAL_COUNT};
+use super::{FrameView, Response, ResponseView, SensorLayout};
+
+/// Reason why a package was rejected by parser, converted into a matching `Error` variant
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub(crate) enum PackageError {
+    BadHead,
+    BadCommandCode(u8),
+    CrcMismatch { expected: u16, got: u16 },
+    BadTail,
+    TruncatedFrame,
+    InvalidData,
+}
+
+impl<I> ParseError<I> for PackageError {
+    fn from_error_kind(_input: I, _kind: ErrorKind) -> Self {
+        PackageError::InvalidData
+    }
+
+    // Keep the innermost error, since it's the most specific one
+    fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl From<PackageError> for Error {
+    fn from(e: PackageError) -> Self {
+        match e {
+            PackageError::BadHead => Error::BadHead,
+            PackageError::BadCommandCode(code) => Error::BadCommandCode(code),
+            PackageError::CrcMismatch { expected, got } => Error::CrcMismatch { expected, got },
+            PackageError::BadTail => Error::BadTail,
+            PackageError::TruncatedFrame => Error::TruncatedFrame,
+            PackageError::InvalidData => Error::InvalidData,
+        }
+    }
+}
+
+type PResult<'a, T> = IResult<&'a [u8], T, PackageError>;
+
+fn fail<T>(e: PackageError) -> PResult<'static, T> {
+    Err(nom::Err::Error(e))
+}
 
 /// byte version of nom::character::streaming::satisfy
-fn u8_satisfy<F, I, E: nom::error::ParseError<I>>(cond: F) -> impl Fn(I) -> IResult<I, u8, E>
+fn u8_satisfy<F, I, E: ParseError<I>>(cond: F) -> impl Fn(I) -> IResult<I, u8, E>
 where
     I: Slice<RangeFrom<usize>> + InputIter<Item = u8> + InputLength,
     F: Fn(u8) -> bool,
@@ -22,100 +63,129 @@ where
         None => Err(nom::Err::Incomplete(nom::Needed::new(1))),
         Some((_, false)) => Err(nom::Err::Error(E::from_error_kind(
             i,
-            nom::error::ErrorKind::Digit,
+            ErrorKind::Digit,
         ))),
         Some((b, true)) => Ok((i.slice(1..), b)),
     }
 }
 
-fn package_prefix(input: &[u8]) -> IResult<&[u8], ()> {
-    map(u8_satisfy(|b| b == 0x81), |_| ())(input)
+/// Expects a specific byte, failing with `err` on any other
+fn u8_expect(expected: u8, err: PackageError) -> impl Fn(&[u8]) -> PResult<'_, ()> {
+    move |i| match u8_satisfy(|b| b == expected)(i) {
+        Ok((i, _)) => Ok((i, ())),
+        Err(nom::Err::Error(PackageError::InvalidData)) => fail(err),
+        Err(e) => Err(e),
+    }
+}
+
+fn package_prefix(input: &[u8]) -> PResult<'_, ()> {
+    u8_expect(0x81, PackageError::BadHead)(input)
 }
 
-fn package_parser(input: &[u8]) -> IResult<&[u8], Response> {
+fn package_parser(
+    input: &[u8],
+    layout: SensorLayout,
+    verify_crc: bool,
+) -> PResult<'_, ResponseView<'_>> {
     let (input, _) = package_prefix(input)?;
     let (input, cmd) = be_u8(input)?;
     match cmd {
-        0x01 => single_frame_parser(input),
-        0x02 => exposure_time_parser(input),
-        0x0E => average_time_parser(input),
-        0x16 => serial_baud_rate_parser(input),
-        _ => Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Digit,
-        ))),
+        0x01 => map(
+            |i| single_frame_parser(i, layout, verify_crc),
+            ResponseView::SingleReading,
+        )(input),
+        0x02 => map(exposure_time_parser, ResponseView::Other)(input),
+        0x0E => map(average_time_parser, ResponseView::Other)(input),
+        0x16 => map(serial_baud_rate_parser, ResponseView::Other)(input),
+        code => fail(PackageError::BadCommandCode(code)),
     }
 }
 
-fn single_frame_parser(input: &[u8]) -> IResult<&[u8], Response> {
+fn single_frame_parser(
+    input: &[u8],
+    layout: SensorLayout,
+    verify_crc: bool,
+) -> PResult<'_, FrameView<'_>> {
     // Parse head
     let (input, scan_size) = be_u16(input)?;
-    if scan_size != (FRAME_TOTAL_COUNT as u16 * 2) {
-        return Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Digit,
-        )));
+    if scan_size as usize != layout.total() * 2 {
+        return fail(PackageError::TruncatedFrame);
     }
-    let (input, _) = u8_satisfy(|b| b == 0x00)(input)?;
+    let (input, _) = u8_expect(0x00, PackageError::BadHead)(input)?;
     // Check if buffer has all data required + a byte for CRC
-    const REMAINING_LEN: usize = (FRAME_TOTAL_COUNT + 1) * 2;
-    if input.len() < REMAINING_LEN {
+    let remaining_len = (layout.total() + 1) * 2;
+    if input.len() < remaining_len {
         // Can safely unwrap due to check
-        let needed = NonZeroUsize::new(REMAINING_LEN - input.len()).unwrap();
+        let needed = NonZeroUsize::new(remaining_len - input.len()).unwrap();
         return Err(nom::Err::Incomplete(nom::Needed::Size(needed)));
     }
 
-    // Calculate CRC on individual bytes, each pixel is 2 bytes long
-    let _crc = input[..FRAME_TOTAL_COUNT * 2]
-        .iter()
-        .fold(0u16, |accum, val| accum.wrapping_add(*val as u16));
-
-    // Parse data
-    let mut data = [0u16; FRAME_TOTAL_COUNT];
-    let (input, ()) = fill(be_u16, &mut data)(input)?;
-    let (input, _expected_crc) = be_u16(input)?;
-    // TODO: Figure out why some packages include wrong CRC
-    /*
-    if crc != expected_crc {
-        return Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Digit,
-        )));
+    // Pixels are decoded lazily by FrameView, only skip over ghost pixels here
+    let (data, input) = input.split_at(layout.total() * 2);
+    let (input, expected_crc) = be_u16(input)?;
+    // Some packages come with a wrong CRC for unknown reason, so it's only checked on demand
+    if verify_crc {
+        // Calculate CRC on individual bytes, each pixel is 2 bytes long
+        let crc = checksum(data);
+        if crc != expected_crc {
+            return fail(PackageError::CrcMismatch {
+                expected: expected_crc,
+                got: crc,
+            });
+        }
     }
-    */
-    Ok((input, Response::SingleReading(data)))
+    let data = &data[layout.prefix() * 2..(layout.prefix() + layout.pixels()) * 2];
+    Ok((input, FrameView::new(data)))
+}
+
+/// Wrapping sum of all bytes, which is what CCD uses as a package CRC
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    // Sum of this many bytes always fits into u32, so the inner loop doesn't need wrapping
+    // arithmetic and gets vectorized by the compiler
+    const CHUNK: usize = (u32::MAX / u8::MAX as u32) as usize;
+    data.chunks(CHUNK).fold(0u16, |accum, chunk| {
+        let sum: u32 = chunk.iter().map(|b| *b as u32).sum();
+        accum.wrapping_add(sum as u16)
+    })
 }
 
-fn exposure_time_parser(input: &[u8]) -> IResult<&[u8], Response> {
+fn exposure_time_parser(input: &[u8]) -> PResult<'_, Response> {
     let (input, exposure_time) = be_u16(input)?;
-    let (input, _) = u8_satisfy(|b| b == 0xFF)(input)?;
+    let (input, _) = u8_expect(0xFF, PackageError::BadTail)(input)?;
     Ok((input, Response::ExposureTime(exposure_time)))
 }
 
-fn average_time_parser(input: &[u8]) -> IResult<&[u8], Response> {
+fn average_time_parser(input: &[u8]) -> PResult<'_, Response> {
     let (input, average_time) = be_u8(input)?;
-    let (input, _) = u8_satisfy(|b| b == 0x00)(input)?;
-    let (input, _) = u8_satisfy(|b| b == 0xFF)(input)?;
+    let (input, _) = u8_expect(0x00, PackageError::BadTail)(input)?;
+    let (input, _) = u8_expect(0xFF, PackageError::BadTail)(input)?;
     Ok((input, Response::AverageTime(average_time)))
 }
 
-fn serial_baud_rate_parser(input: &[u8]) -> IResult<&[u8], Response> {
+fn serial_baud_rate_parser(input: &[u8]) -> PResult<'_, Response> {
     let (input, baud_rate_code) = be_u8(input)?;
-    let (input, _) = u8_satisfy(|b| b == 0x00)(input)?;
-    let (input, _) = u8_satisfy(|b| b == 0xFF)(input)?;
+    let (input, _) = u8_expect(0x00, PackageError::BadTail)(input)?;
+    let (input, _) = u8_expect(0xFF, PackageError::BadTail)(input)?;
 
     if let Ok(baud_rate) = BaudRate::try_from_code(baud_rate_code) {
         Ok((input, Response::SerialBaudRate(baud_rate)))
     } else {
-        Err(nom::Err::Error(nom::error::Error::new(
-            input,
-            nom::error::ErrorKind::Digit,
-        )))
+        fail(PackageError::InvalidData)
     }
 }
 
-fn prefix_parser(input: &[u8]) -> IResult<&[u8], ()> {
-    alt((package_prefix, version_details_prefix))(input)
+/// Package head followed by a code of a known response, or by nothing yet. Pixel data is full
+/// of 0x81 bytes, so this keeps resynchronization from stopping at each of them
+fn plausible_package_head(input: &[u8]) -> PResult<'_, ()> {
+    let (input, _) = package_prefix(input)?;
+    match input.first() {
+        None | Some(0x01 | 0x02 | 0x0E | 0x16) => Ok((input, ())),
+        Some(&code) => fail(PackageError::BadCommandCode(code)),
+    }
+}
+
+fn prefix_parser(input: &[u8]) -> PResult<'_, ()> {
+    alt((plausible_package_head, version_details_prefix))(input)
 }
 
 /// Takes a byte slice and drops bytes until first valid prefix of a response
@@ -129,12 +199,21 @@ pub(crate) fn align_response(input: &[u8]) -> IResult<&[u8], ()> {
 }
 
 /// Takes aligned input and parses it as either as a byte stream, or as plain text in case of
-/// version info response
-pub(crate) fn parse_response(input: &[u8]) -> IResult<&[u8], Response> {
-    alt((
-        package_parser,
-        map(version_details_parser, Response::VersionInfo),
-    ))(input)
+/// version info response. Frame data is left borrowed from input
+pub(crate) fn parse_response(
+    input: &[u8],
+    layout: SensorLayout,
+    verify_crc: bool,
+) -> PResult<'_, ResponseView<'_>> {
+    match package_parser(input, layout, verify_crc) {
+        // Not a binary package, might still be a plain text one
+        Err(nom::Err::Error(PackageError::BadHead)) if input.first() != Some(&0x81) => {
+            map(version_details_parser, |d| {
+                ResponseView::Other(Response::VersionInfo(d))
+            })(input)
+        }
+        res => res,
+    }
 }
 
 #[cfg(test)]
@@ -142,7 +221,9 @@ mod tests {
     use super::*;
     use BaudRate::*;
     use claims::*;
-    use nom::{Err::Incomplete, Needed};
+    use nom::{Err::{Error, Incomplete}, Needed};
+
+    const LAYOUT: SensorLayout = SensorLayout::LCAM_V06;
 
     #[test]
     fn decode_package_prefix() {
@@ -157,31 +238,96 @@ mod tests {
     #[test]
     fn decode_baud_rate() {
         assert_ok_eq!(
-            package_parser(&[0x81u8, 0x16, 0x01, 0x00, 0xFF]),
-            (&[] as &[u8], Response::SerialBaudRate(Baud115200))
+            package_parser(&[0x81u8, 0x16, 0x01, 0x00, 0xFF], LAYOUT, false),
+            (&[] as &[u8], ResponseView::Other(Response::SerialBaudRate(Baud115200)))
         );
         // Invalid baud rate code
-        assert_err!(package_parser(&[0x81u8, 0x16, 0xFF, 0x00, 0xFF]));
+        assert_err!(package_parser(&[0x81u8, 0x16, 0xFF, 0x00, 0xFF], LAYOUT, false));
     }
 
     #[test]
     fn decode_exposure_time() {
         assert_ok_eq!(
-            package_parser(&[0x81u8, 0x02, 0xAB, 0xCD, 0xFF]),
-            (&[] as &[u8], Response::ExposureTime(0xABCD))
+            package_parser(&[0x81u8, 0x02, 0xAB, 0xCD, 0xFF], LAYOUT, false),
+            (&[] as &[u8], ResponseView::Other(Response::ExposureTime(0xABCD)))
         );
         // Invalid suffix
-        assert_err!(package_parser(&[0x81, 0x02, 0xAB, 0xCD, 0x00]));
+        assert_err_eq!(
+            package_parser(&[0x81, 0x02, 0xAB, 0xCD, 0x00], LAYOUT, false),
+            Error(PackageError::BadTail)
+        );
     }
 
     #[test]
     fn decode_average_time() {
         assert_ok_eq!(
-            package_parser(&[0x81u8, 0x0E, 0xAB, 0x00, 0xFF]),
-            (&[] as &[u8], Response::AverageTime(0xAB))
+            package_parser(&[0x81u8, 0x0E, 0xAB, 0x00, 0xFF], LAYOUT, false),
+            (&[] as &[u8], ResponseView::Other(Response::AverageTime(0xAB)))
         );
         // Incorrect low byte
-        assert_err!(package_parser(&[0x81u8, 0x0E, 0xAB, 0xCD, 0xFF]));
+        assert_err!(package_parser(&[0x81u8, 0x0E, 0xAB, 0xCD, 0xFF], LAYOUT, false));
+    }
+
+    #[test]
+    fn report_package_errors() {
+        assert_err_eq!(
+            parse_response(&[0x80u8, 0x02], LAYOUT, false),
+            Error(PackageError::BadHead)
+        );
+        assert_err_eq!(
+            parse_response(&[0x81u8, 0x42, 0x00], LAYOUT, false),
+            Error(PackageError::BadCommandCode(0x42))
+        );
+        assert_err_eq!(
+            parse_response(&[0x81u8, 0x01, 0x00, 0x10, 0x00], LAYOUT, false),
+            Error(PackageError::TruncatedFrame)
+        );
+
+        let mut frame = vec![0x81u8, 0x01];
+        frame.extend_from_slice(&(LAYOUT.total() as u16 * 2).to_be_bytes());
+        frame.push(0x00);
+        frame.resize(frame.len() + LAYOUT.total() * 2, 0x01);
+        frame.extend_from_slice(&[0x00, 0x00]);
+        assert_ok!(parse_response(&frame, LAYOUT, false));
+        assert_err_eq!(
+            parse_response(&frame, LAYOUT, true),
+            Error(PackageError::CrcMismatch {
+                expected: 0,
+                got: LAYOUT.total() as u16 * 2
+            })
+        );
+    }
+
+    #[test]
+    fn strip_ghost_pixels() {
+        let layout = SensorLayout::new(2, 1, 1).unwrap();
+        let package = [
+            0x81, 0x01, 0x00, 0x08, 0x00, 0xAA, 0xAA, 0x00, 0x01, 0x00, 0x02, 0xBB, 0xBB, 0x00,
+            0x00,
+        ];
+        assert_ok_eq!(
+            parse_response(&package, layout, false),
+            (
+                &[] as &[u8],
+                ResponseView::SingleReading(FrameView::new(&[0x00, 0x01, 0x00, 0x02]))
+            )
+        );
+        // Same package is truncated for a sensor without ghost pixels
+        assert_err_eq!(
+            parse_response(&package, SensorLayout::new(2, 0, 0).unwrap(), false),
+            Error(PackageError::TruncatedFrame)
+        );
+    }
+
+    #[test]
+    fn calculate_checksum() {
+        let data: Vec<u8> = (0..20_000u32).map(|i| (i * 7) as u8).collect();
+        let expected = data
+            .iter()
+            .fold(0u16, |accum, val| accum.wrapping_add(*val as u16));
+        assert_eq!(checksum(&data), expected);
+        assert_eq!(checksum(&[]), 0);
+        assert_eq!(checksum(&[0xFF; 258]), (0xFF * 258 % 0x10000) as u16);
     }
 
     #[test]
@@ -199,6 +345,11 @@ mod tests {
             align_response(&([0xDE, 0xAD, 0xBE, 0xEF, 0x81] as [u8; 5])),
             (&[0x81u8] as &[u8], ())
         );
+        // 0x81 in the middle of data is skipped unless it's followed by a known response code
+        assert_ok_eq!(
+            align_response(&([0x81, 0x42, 0x81, 0x16] as [u8; 4])),
+            (&[0x81u8, 0x16] as &[u8], ())
+        );
         // Allow any kind of garbage until known valid response arrives
         assert_err_eq!(
             align_response("   HDInfo:".as_bytes()),
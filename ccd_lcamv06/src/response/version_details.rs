@@ -2,6 +2,7 @@ use crate::error::Error;
 use core::{
     fmt,
     fmt::{Debug, Display},
+    str::FromStr,
 };
 
 use arraystring::SmallString;
@@ -15,7 +16,8 @@ pub struct VersionDetails {
 }
 
 impl VersionDetails {
-    pub(crate) fn try_new(
+    /// Fails if one of the details is too long to be stored
+    pub fn try_new(
         hw_ver: &str,
         sensor: &str,
         fw_ver: &str,
@@ -32,6 +34,71 @@ impl VersionDetails {
                 .map_err(|_| Error::VersionDetailTooLong("Serial number"))?,
         })
     }
+
+    pub fn hardware_version(&self) -> &str {
+        &self.hardware_version
+    }
+
+    pub fn sensor_type(&self) -> &str {
+        &self.sensor_type
+    }
+
+    pub fn firmware_version(&self) -> &str {
+        &self.firmware_version
+    }
+
+    /// Firmware version in a form that can be compared against
+    pub fn parsed_firmware_version(&self) -> Result<FirmwareVersion, Error> {
+        self.firmware_version.parse()
+    }
+
+    pub fn serial_number(&self) -> &str {
+        &self.serial_number
+    }
+}
+
+/// Firmware version as reported by CCD, e.g. `V4.2`. Missing components are treated as zeroes
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Default)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        FirmwareVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let s = s.trim_start_matches(['V', 'v']);
+        let mut parts = s.split('.').map(|p| p.parse::<u16>().map_err(|_| Error::InvalidData));
+        let major = parts.next().ok_or(Error::InvalidData)??;
+        let minor = parts.next().transpose()?.unwrap_or(0);
+        let patch = parts.next().transpose()?.unwrap_or(0);
+        if parts.next().is_some() {
+            return Err(Error::InvalidData);
+        }
+        Ok(FirmwareVersion::new(major, minor, patch))
+    }
+}
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "V{}.{}", self.major, self.minor)?;
+        if self.patch != 0 {
+            write!(f, ".{}", self.patch)?;
+        }
+        Ok(())
+    }
 }
 
 impl Display for VersionDetails {
@@ -47,3 +114,18 @@ impl Display for VersionDetails {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_firmware_version() {
+        assert_eq!("V4.2".parse::<FirmwareVersion>().unwrap(), FirmwareVersion::new(4, 2, 0));
+        assert_eq!("3".parse::<FirmwareVersion>().unwrap(), FirmwareVersion::new(3, 0, 0));
+        assert!("V4.x".parse::<FirmwareVersion>().is_err());
+        assert!("V1.2.3.4".parse::<FirmwareVersion>().is_err());
+        assert!(FirmwareVersion::new(4, 10, 0) > FirmwareVersion::new(4, 2, 1));
+        assert_eq!(FirmwareVersion::new(4, 2, 0).to_string(), "V4.2");
+    }
+}
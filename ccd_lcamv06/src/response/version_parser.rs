@@ -1,29 +1,48 @@
 use core::str::from_utf8;
 
 use nom::{
-    bytes::streaming::{tag, take, take_till1, take_while1},
+    bytes::streaming::{tag, take_while1, take_while_m_n},
     combinator::map,
     sequence::{terminated, tuple},
     IResult,
 };
 
-use super::version_details::VersionDetails;
+use super::{parser::PackageError, version_details::VersionDetails};
+
+type PResult<'a, T> = IResult<&'a [u8], T, PackageError>;
+
+/// Longest detail that fits into `VersionDetails`. Words are bounded by it, so that a binary
+/// frame following something that looks like a prefix is rejected instead of waited through
+const MAX_DETAIL_LEN: usize = 23;
+/// Serial number is a timestamp, e.g. 202111161548
+const SERIAL_LEN: usize = 12;
 
 fn is_separator(c: u8) -> bool {
     c == b' ' || c == b','
 }
 
-fn word_with_separator(input: &[u8]) -> IResult<&[u8], &str> {
-    let (input, b) = terminated(take_till1(is_separator), take_while1(is_separator))(input)?;
-    // TODO: Handle error
-    Ok((input, from_utf8(b).unwrap()))
+fn is_detail_char(c: u8) -> bool {
+    c.is_ascii_graphic() && !is_separator(c)
+}
+
+/// Only ever called on ASCII, so conversion can't actually fail
+fn ascii(b: &[u8]) -> Result<&str, nom::Err<PackageError>> {
+    from_utf8(b).map_err(|_| nom::Err::Error(PackageError::InvalidData))
+}
+
+fn word_with_separator(input: &[u8]) -> PResult<'_, &str> {
+    let (input, b) = terminated(
+        take_while_m_n(1, MAX_DETAIL_LEN, is_detail_char),
+        take_while1(is_separator),
+    )(input)?;
+    Ok((input, ascii(b)?))
 }
 
-pub(crate) fn version_details_prefix(input: &[u8]) -> IResult<&[u8], ()> {
-    map(tag("HdInfo:"), |_| ())(input)
+pub(crate) fn version_details_prefix(input: &[u8]) -> PResult<'_, ()> {
+    map(tag("HdInfo:"), |_| ())(input).map_err(|e: nom::Err<()>| e.map(|_| PackageError::BadHead))
 }
 
-pub(crate) fn version_details_parser(input: &[u8]) -> IResult<&[u8], VersionDetails> {
+pub(crate) fn version_details_parser(input: &[u8]) -> PResult<'_, VersionDetails> {
     let (input, (_, hw_ver, sensor, fw_ver, serial)) = tuple((
         // Prefix
         version_details_prefix,
@@ -33,18 +52,13 @@ pub(crate) fn version_details_parser(input: &[u8]) -> IResult<&[u8], VersionDeta
         word_with_separator,
         // Firmware version
         word_with_separator,
-        // Serial number, should be a timestamp
-        take("202111161548".len()),
+        // Serial number
+        take_while_m_n(SERIAL_LEN, SERIAL_LEN, is_detail_char),
     ))(input)?;
 
-    // TODO: Handle error
-    let serial = from_utf8(serial).unwrap();
-
-    // TODO: Handle error
-    Ok((
-        input,
-        VersionDetails::try_new(hw_ver, sensor, fw_ver, serial).unwrap(),
-    ))
+    let details = VersionDetails::try_new(hw_ver, sensor, fw_ver, ascii(serial)?)
+        .map_err(|_| nom::Err::Error(PackageError::InvalidData))?;
+    Ok((input, details))
 }
 
 #[cfg(test)]
@@ -62,4 +76,24 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn reject_binary_after_prefix() {
+        let rejected = |input: &[u8]| {
+            matches!(
+                version_details_parser(input),
+                Err(nom::Err::Error(PackageError::InvalidData))
+            )
+        };
+        // Frame package right after something that looks like a prefix
+        assert!(rejected(b"HdInfo:\x81\x01\x1c\xdc\x00"));
+        assert!(rejected(b"HdInfo:LCAM\xff,S11639,V4.2,202111161548"));
+        // Word that wouldn't fit is rejected without waiting for a separator
+        assert!(rejected(&[b"HdInfo:".as_slice(), &[b'A'; 24]].concat()));
+        // Valid details that didn't fully arrive yet
+        assert!(matches!(
+            version_details_parser(b"HdInfo:LCAM_V8.4.2,S11"),
+            Err(nom::Err::Incomplete(_))
+        ));
+    }
 }
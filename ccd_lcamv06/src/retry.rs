@@ -0,0 +1,77 @@
+use crate::error::Error;
+use core::time::Duration;
+
+/// Describes how failed command/response exchanges with CCD are retried
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Total amount of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after each following one
+    pub initial_backoff: Duration,
+    /// Upper bound for delay between retries
+    pub max_backoff: Duration,
+    /// Decides if an exchange that failed with given error is worth retrying
+    pub retryable: fn(&Error) -> bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            retryable: Error::is_transient,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that gives up after the first failure
+    pub fn none() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before retrying after `attempt` attempts have already failed
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+
+    /// Checks if another attempt should be made after `attempt` attempts failed with `err`
+    pub fn should_retry(&self, attempt: u32, err: &Error) -> bool {
+        attempt < self.max_attempts && (self.retryable)(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn retry_only_transient_errors() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(1, &Error::Timeout));
+        assert!(!policy.should_retry(policy.max_attempts, &Error::Timeout));
+        assert!(!policy.should_retry(1, &Error::InvalidBaudRate));
+        assert!(!RetryPolicy::none().should_retry(1, &Error::Timeout));
+    }
+}
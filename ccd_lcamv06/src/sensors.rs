@@ -0,0 +1,44 @@
+use crate::response::SensorLayout;
+
+/// Properties of an image sensor, as reported in `VersionDetails::sensor_type`
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct SensorInfo {
+    /// Sensor type as reported by device
+    pub name: &'static str,
+    /// How pixels arrive in frame packages
+    pub layout: SensorLayout,
+    /// Distance between centers of neighbouring pixels, in μm
+    pub pixel_pitch_um: f32,
+    /// Range of wavelengths sensor responds to, in nm
+    pub spectral_range_nm: (u16, u16),
+}
+
+/// Sensors known to be used in supported spectrometers
+pub const SENSORS: &[SensorInfo] = &[
+    // LCAM V06 reports this sensor, but sends 3694 pixels per frame instead of 2048 the sensor
+    // has, so layout describes packages as they arrive
+    SensorInfo {
+        name: "S11639",
+        layout: SensorLayout::LCAM_V06,
+        pixel_pitch_um: 14.0,
+        spectral_range_nm: (200, 1000),
+    },
+];
+
+/// Looks up a sensor by type reported by device
+pub fn lookup(sensor_type: &str) -> Option<&'static SensorInfo> {
+    SENSORS.iter().find(|s| s.name == sensor_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_PIXEL_COUNT;
+
+    #[test]
+    fn known_sensors() {
+        let sensor = lookup("S11639").unwrap();
+        assert_eq!(sensor.layout.pixels(), FRAME_PIXEL_COUNT);
+        assert_eq!(lookup("S11640"), None);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::{
+    ccd::CCD,
+    error::{Error, Result},
+    io_adapter::IoAdapter,
+    response::VersionDetails,
+};
+
+/// Operations every supported spectrometer model provides, so tools can work with any of them
+/// without knowing which one is connected. Model specific features stay on concrete types
+pub trait Spectrometer {
+    /// Amount of pixels in a single frame
+    fn pixel_count(&self) -> usize;
+
+    /// Takes a single frame, `frame` has to be exactly `pixel_count` long
+    fn read_frame(&mut self, frame: &mut [u16]) -> Result<()>;
+
+    fn exposure_time(&mut self) -> Result<u16>;
+
+    fn set_exposure_time(&mut self, t: u16) -> Result<()>;
+
+    fn version(&mut self) -> Result<VersionDetails>;
+}
+
+impl<IO> Spectrometer for CCD<IO>
+where
+    IO: IoAdapter,
+{
+    fn pixel_count(&self) -> usize {
+        self.sensor_layout().pixels()
+    }
+
+    fn read_frame(&mut self, frame: &mut [u16]) -> Result<()> {
+        if frame.len() != self.pixel_count() {
+            return Err(Error::InvalidData);
+        }
+        self.with_frame(|view| view.decode_into(frame))
+    }
+
+    fn exposure_time(&mut self) -> Result<u16> {
+        self.get_exp_time()
+    }
+
+    fn set_exposure_time(&mut self, t: u16) -> Result<()> {
+        self.set_exp_time(t)
+    }
+
+    fn version(&mut self) -> Result<VersionDetails> {
+        self.get_version()
+    }
+}
@@ -0,0 +1,14 @@
+/// Counters describing health of the data stream received from CCD
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamStats {
+    /// Packages parsed successfully
+    pub packages: u64,
+    /// Bytes dropped while looking for the next package head
+    pub bytes_skipped: u64,
+    /// Times garbage or a broken package had to be skipped to get back in sync with the stream
+    pub resyncs: u64,
+    /// Frames rejected because of a wrong CRC, only counted with CRC verification enabled
+    pub crc_failures: u64,
+    /// Frame packages that were recognized, but skipped because they were broken
+    pub dropped_frames: u64,
+}
@@ -0,0 +1,187 @@
+//! Helpers for testing code that talks to CCD without a device attached
+
+use crate::{
+    command::Command,
+    response::{Response, SensorLayout},
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Command expected to be sent next, along with bytes sent back once it arrives
+struct Exchange {
+    command: Command,
+    reply: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    script: VecDeque<Exchange>,
+    /// Sent bytes that don't form a complete command yet
+    sent: Vec<u8>,
+    /// Replies not read yet
+    replies: VecDeque<u8>,
+    failures: Vec<String>,
+}
+
+/// In-memory transport that checks every command sent through it against a script and answers
+/// with scripted responses. Clones share the script, so one can be kept to check it was followed
+/// after the other one is handed over to `CCD`:
+///
+/// ```
+/// use ccd_lcamv06::{testing::MockTransport, Command, IoAdapter, Response, StdIoAdapter};
+///
+/// let mock = MockTransport::new()
+///     .expect(Command::SetIntegrationTime(20), [])
+///     .expect(Command::GetExposureTime, [Response::ExposureTime(20)]);
+/// let mut ccd = StdIoAdapter::new(mock.clone()).open_ccd();
+/// ccd.set_exp_time(20).unwrap();
+/// assert_eq!(ccd.get_exp_time().unwrap(), 20);
+/// mock.assert_done();
+/// ```
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // Panics in a test holding the lock shouldn't hide the original failure
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Expects `command` to be sent next and answers it with raw bytes, which don't have to be
+    /// valid packages
+    pub fn expect_raw(self, command: Command, reply: impl Into<Vec<u8>>) -> Self {
+        self.state().script.push_back(Exchange {
+            command,
+            reply: reply.into(),
+        });
+        self
+    }
+
+    /// Expects `command` to be sent next and answers it with `responses`, commands that set
+    /// something are answered with none
+    pub fn expect(self, command: Command, responses: impl IntoIterator<Item = Response>) -> Self {
+        let reply = responses
+            .into_iter()
+            .flat_map(|r| r.encode())
+            .collect::<Vec<_>>();
+        self.expect_raw(command, reply)
+    }
+
+    /// Expects `command` to be sent next and answers it with a frame package for each of
+    /// `frames`, laid out as LCAM V06 sends them
+    pub fn expect_frames<F: AsRef<[u16]>>(
+        self,
+        command: Command,
+        frames: impl IntoIterator<Item = F>,
+    ) -> Self {
+        let reply = frames
+            .into_iter()
+            .flat_map(|f| Response::encode_frame(f.as_ref(), SensorLayout::LCAM_V06))
+            .collect::<Vec<_>>();
+        self.expect_raw(command, reply)
+    }
+
+    /// Checks that every expected command was sent and nothing else was
+    pub fn assert_done(&self) {
+        let state = self.state();
+        assert!(
+            state.failures.is_empty(),
+            "Unexpected commands: {}",
+            state.failures.join(", ")
+        );
+        let missing: Vec<_> = state
+            .script
+            .iter()
+            .map(|e| format!("{:?}", e.command))
+            .collect();
+        assert!(
+            missing.is_empty(),
+            "Expected commands weren't sent: {}",
+            missing.join(", ")
+        );
+    }
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.replies.is_empty() {
+            // Same as a serial port with nothing to read, CCD keeps track of timeout itself
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        state.replies.read(buf)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        state.sent.extend_from_slice(buf);
+        while state.sent.len() >= 5 {
+            let sent: Vec<_> = state.sent.drain(..5).collect();
+            let exchange = match state.script.front() {
+                Some(exchange) if exchange.command.encode()[..] == sent[..] => {
+                    state.script.pop_front()
+                }
+                _ => None,
+            };
+            match exchange {
+                Some(exchange) => state.replies.extend(exchange.reply),
+                None => {
+                    let failure = match state.script.front() {
+                        Some(e) => format!("{sent:02X?} instead of {:?}", e.command),
+                        None => format!("{sent:02X?} after the end of script"),
+                    };
+                    state.failures.push(failure.clone());
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, failure));
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{flags::BaudRate, IoAdapter, StdIoAdapter, FRAME_PIXEL_COUNT};
+    use core::time::Duration;
+
+    #[test]
+    fn follow_script() {
+        let mut frame = vec![100; FRAME_PIXEL_COUNT];
+        frame[42] = 4000;
+        let mock = MockTransport::new()
+            .expect(
+                Command::GetSerialBaudRate,
+                [Response::SerialBaudRate(BaudRate::Baud384000)],
+            )
+            .expect_frames(Command::SingleRead, [&frame]);
+        let mut ccd = StdIoAdapter::new(mock.clone()).open_ccd();
+        ccd.set_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(ccd.get_baudrate().unwrap(), BaudRate::Baud384000);
+        assert_eq!(ccd.get_frame().unwrap()[..], frame[..]);
+        mock.assert_done();
+
+        // Command that wasn't expected fails right away
+        assert!(ccd.get_avg_time().is_err());
+        let mock = MockTransport::new().expect(Command::PauseRead, []);
+        let mut ccd = StdIoAdapter::new(mock.clone()).open_ccd();
+        assert!(ccd.get_version().is_err());
+        let res = std::panic::catch_unwind(|| mock.assert_done());
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::error::{Error, Result};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Byte stream CCD can be talked to over, such as a serial port, a TCP connection to a serial
+/// bridge or a `Replay`. Boxing it allows picking transport at runtime while keeping a single
+/// `CCD` type
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send + ?Sized> Transport for T {}
+
+/// Direction of recorded traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from CCD
+    Read,
+    /// Sent to CCD
+    Write,
+}
+
+impl Direction {
+    /// Tag used for this direction in dump files
+    pub fn tag(&self) -> char {
+        match self {
+            Direction::Read => 'R',
+            Direction::Write => 'W',
+        }
+    }
+}
+
+/// Chunk of traffic, as it was passed through a single read or write call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Time since start of recording
+    pub at: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let mut fields = line.split_whitespace();
+    let micros = fields.next()?.parse().ok()?;
+    let direction = match fields.next()? {
+        "R" => Direction::Read,
+        "W" => Direction::Write,
+        _ => return None,
+    };
+    let bytes = parse_hex(fields.next().unwrap_or_default())?;
+    Some(Record {
+        at: Duration::from_micros(micros),
+        direction,
+        bytes,
+    })
+}
+
+/// Parses a traffic dump. Every line holds a single chunk: time since start in microseconds,
+/// `R` or `W` for direction and bytes in hex. Lines starting with `#` are comments
+pub fn parse_dump(data: &str) -> Result<Vec<Record>> {
+    data.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| parse_record(line).ok_or(Error::InvalidDump(i + 1)))
+        .collect()
+}
+
+/// Transport that plays back data received in a recorded session instead of talking to a device,
+/// so problems can be reproduced without hardware. Everything written to it is collected, which
+/// allows checking commands that were sent
+pub struct Replay {
+    chunks: VecDeque<(Duration, Vec<u8>)>,
+    // Amount of bytes already read from the front chunk
+    pos: usize,
+    realtime: bool,
+    started: Option<Instant>,
+    written: Vec<u8>,
+}
+
+/// Longest a single read waits for the next chunk in realtime mode
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+impl Replay {
+    /// Replays chunks of data, each one available `Duration` after the first read if realtime
+    /// playback is enabled
+    pub fn new(chunks: impl IntoIterator<Item = (Duration, Vec<u8>)>) -> Self {
+        Replay {
+            chunks: chunks.into_iter().collect(),
+            pos: 0,
+            realtime: false,
+            started: None,
+            written: Vec::new(),
+        }
+    }
+
+    /// Replays data as if it all arrived at once
+    pub fn from_bytes(data: impl Into<Vec<u8>>) -> Self {
+        Replay::new([(Duration::ZERO, data.into())])
+    }
+
+    /// Replays received part of recorded traffic
+    pub fn from_records<'a>(records: impl IntoIterator<Item = &'a Record>) -> Self {
+        Replay::new(
+            records
+                .into_iter()
+                .filter(|r| r.direction == Direction::Read)
+                .map(|r| (r.at, r.bytes.clone())),
+        )
+    }
+
+    /// Replays received part of a dump, in format accepted by `parse_dump`
+    pub fn from_dump(data: &str) -> Result<Self> {
+        Ok(Replay::from_records(&parse_dump(data)?))
+    }
+
+    /// Makes every chunk available only once as much time has passed as in the recording
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// Everything written so far
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Checks if all recorded data was read
+    pub fn is_finished(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let Some((at, chunk)) = self.chunks.front() else {
+            return Ok(0);
+        };
+        if self.realtime {
+            let elapsed = started.elapsed();
+            if *at > elapsed {
+                let wait = *at - elapsed;
+                thread::sleep(wait.min(MAX_WAIT));
+                if wait > MAX_WAIT {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+        let n = buf.len().min(chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        if self.pos == chunk.len() {
+            self.chunks.pop_front();
+            self.pos = 0;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dump_records() {
+        let records = parse_dump("# started: now\n0 W 8101\n1500 R ff00\n\n2000 R\n").unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].direction, Direction::Write);
+        assert_eq!(records[1].at, Duration::from_micros(1500));
+        assert_eq!(records[1].bytes, vec![0xff, 0x00]);
+        assert!(records[2].bytes.is_empty());
+        assert!(matches!(parse_dump("0 X 00"), Err(Error::InvalidDump(1))));
+        assert!(matches!(parse_dump("0 R 0"), Err(Error::InvalidDump(1))));
+    }
+
+    #[test]
+    fn replay_in_realtime() {
+        let mut replay = Replay::new([
+            (Duration::ZERO, vec![1, 2, 3]),
+            (Duration::from_millis(20), vec![4]),
+        ]);
+        replay.set_realtime(true);
+        let started = Instant::now();
+        let mut buf = [0; 2];
+        assert_eq!(replay.read(&mut buf).unwrap(), 2);
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 4);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert!(replay.is_finished());
+        assert_eq!(replay.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -1,8 +1,26 @@
+// Lint postdates these tests, literals are cast the way they were written
+#![allow(clippy::unnecessary_cast)]
+
 use utilities::{
     SINGLE_PACKAGE, MockIO
 };
-use ccd_lcamv06::{IoAdapter, StdIoAdapter};
-use std::io::Write;
+use ccd_lcamv06::{
+    decoder::{Decoded, Package, Parsed},
+    error::Error,
+    testing::MockTransport,
+    transport::Replay,
+    BaudRate, CCDBuilder, Command, Decoder, FirmwareVersion, IoAdapter, QualityFlags,
+    QualityThresholds, Response, ResponseParser, RetryPolicy, SensorLayout, Spectrometer,
+    StdIoAdapter, VersionDetails, CCD, FRAME_PIXEL_COUNT,
+};
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 #[test]
 fn decode_single_package() {
@@ -34,3 +52,547 @@ fn decode_single_package() {
         .sqrt();
     assert!(deviation < 100 as f32);
 }
+
+#[test]
+fn receive_frame_in_small_reads() {
+    let sent = AtomicUsize::new(0);
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        let start = sent.load(Ordering::SeqCst).min(SINGLE_PACKAGE.len());
+        let end = (start + 50).min(SINGLE_PACKAGE.len());
+        sent.store(end, Ordering::SeqCst);
+        buf.write(&SINGLE_PACKAGE[start..end])
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    let frame = ccd.get_frame().unwrap();
+
+    let mut whole = StdIoAdapter::new(Replay::from_bytes(SINGLE_PACKAGE.clone())).open_ccd();
+    assert_eq!(frame, whole.get_frame().unwrap());
+}
+
+#[test]
+fn decode_into_existing_frame() {
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        buf.write(&SINGLE_PACKAGE)
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+
+    let expected = ccd.get_frame().unwrap();
+    let mut frame = [0; FRAME_PIXEL_COUNT];
+    ccd.get_frame_into(&mut frame).unwrap();
+    assert_eq!(frame, expected);
+    let first_pixel = ccd.with_frame(|view| view.get(0)).unwrap();
+    assert_eq!(first_pixel, Some(expected[0]));
+
+    let mut frames = ccd.frames_iter().unwrap();
+    frame.fill(0);
+    frames.next_into(&mut frame).unwrap().unwrap();
+    assert_eq!(frame, expected);
+}
+
+#[test]
+fn give_up_on_garbage() {
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io
+        .expect_read()
+        .returning(|mut buf| buf.write(&[0x42; 64]));
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    // Without a timeout garbage would keep CCD waiting forever
+    ccd.set_timeout(None);
+    ccd.set_retry_policy(RetryPolicy::none());
+    ccd.set_max_consecutive_failures(4);
+
+    assert!(matches!(ccd.get_frame(), Err(Error::BadHead)));
+    assert_eq!(ccd.stats().packages, 0);
+    assert!(ccd.stats().resyncs > 0);
+}
+
+#[test]
+fn skip_garbage_before_package() {
+    let reads = AtomicUsize::new(0);
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        if reads.fetch_add(1, Ordering::SeqCst) == 0 {
+            buf.write(&[0x12, 0x34, 0x56])
+        } else {
+            buf.write(&SINGLE_PACKAGE)
+        }
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+
+    assert!(ccd.get_frame().is_ok());
+    assert_eq!(ccd.stats().packages, 1);
+    assert_eq!(ccd.stats().bytes_skipped, 3);
+    assert_eq!(ccd.stats().resyncs, 1);
+}
+
+#[test]
+fn count_dropped_frames() {
+    let mut broken = SINGLE_PACKAGE.clone();
+    *broken.last_mut().unwrap() ^= 0xFF;
+    let reads = AtomicUsize::new(0);
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        if reads.fetch_add(1, Ordering::SeqCst) == 0 {
+            buf.write(&broken)
+        } else {
+            buf.write(&SINGLE_PACKAGE)
+        }
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    ccd.set_verify_crc(true);
+
+    let mut frames = ccd.frames_iter().unwrap();
+    assert!(frames.next().unwrap().is_ok());
+    assert_eq!(frames.stats().dropped_frames, 1);
+    assert_eq!(frames.stats().crc_failures, 1);
+}
+
+#[test]
+fn skip_frame_with_bad_crc() {
+    // Every pixel of broken frame looks like a head of another frame
+    let mut broken = Response::encode_frame(&[0x0181; FRAME_PIXEL_COUNT], SensorLayout::LCAM_V06);
+    *broken.last_mut().unwrap() ^= 0xFF;
+    broken.extend_from_slice(&SINGLE_PACKAGE);
+    let mock = MockTransport::new().expect_raw(Command::SingleRead, broken);
+    let mut ccd = StdIoAdapter::new(mock).open_ccd();
+    ccd.set_verify_crc(true);
+    ccd.set_retry_policy(RetryPolicy::none());
+    ccd.set_max_consecutive_failures(2);
+
+    assert!(ccd.get_frame().is_ok());
+    assert_eq!(ccd.stats().crc_failures, 1);
+    assert_eq!(ccd.stats().resyncs, 1);
+    let frame_size = 5 + FRAME_PIXEL_COUNT * 2 + 2;
+    assert_eq!(ccd.stats().bytes_skipped, frame_size as u64);
+}
+
+#[test]
+fn flag_frame_after_resync() {
+    let mut broken = SINGLE_PACKAGE.clone();
+    *broken.last_mut().unwrap() ^= 0xFF;
+    let reads = AtomicUsize::new(0);
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        if reads.fetch_add(1, Ordering::SeqCst) == 0 {
+            buf.write(&broken)
+        } else {
+            buf.write(&SINGLE_PACKAGE)
+        }
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    ccd.set_verify_crc(true);
+    ccd.set_avg_time(4).unwrap();
+
+    let mut frames = ccd.frames_iter().unwrap();
+    frames.set_quality_thresholds(QualityThresholds {
+        saturation: u16::MAX,
+        underexposure: 0,
+    });
+    let (_, flags) = frames.next_flagged().unwrap().unwrap();
+    assert!(flags.crc_resynced && flags.averaged);
+    let (_, flags) = frames.next_flagged().unwrap().unwrap();
+    assert_eq!(
+        flags,
+        QualityFlags {
+            averaged: true,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn keep_every_nth_frame() {
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        buf.write(&SINGLE_PACKAGE)
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+
+    let mut frames = ccd.frames_iter().unwrap();
+    frames.keep_every(3);
+    assert!(frames.next().unwrap().is_ok());
+    assert!(frames.next().unwrap().is_ok());
+    // First and fourth frames are kept
+    assert_eq!(frames.stats().packages, 4);
+}
+
+#[test]
+fn timeout_on_silent_device() {
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io
+        .expect_read()
+        .returning(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    ccd.set_timeout(Some(Duration::from_millis(10)));
+    ccd.set_retry_policy(RetryPolicy::none());
+
+    assert!(matches!(ccd.get_frame(), Err(Error::Timeout)));
+}
+
+#[test]
+fn retry_after_timeout() {
+    // Device only responds to the second request
+    let writes = Arc::new(AtomicUsize::new(0));
+    let mut mock_io = MockIO::new();
+    let counter = writes.clone();
+    mock_io.expect_write().returning(move |msg| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(msg.len())
+    });
+    mock_io.expect_read().returning(move |mut buf| {
+        if writes.load(Ordering::SeqCst) < 2 {
+            Err(io::Error::from(io::ErrorKind::TimedOut))
+        } else {
+            buf.write(&SINGLE_PACKAGE)
+        }
+    });
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+    ccd.set_timeout(Some(Duration::from_millis(10)));
+    ccd.set_retry_policy(RetryPolicy {
+        max_attempts: 2,
+        initial_backoff: Duration::from_millis(1),
+        ..Default::default()
+    });
+
+    assert!(ccd.get_frame().is_ok());
+}
+
+#[test]
+fn pause_continuous_read_on_drop() {
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let mut mock_io = MockIO::new();
+    let log = writes.clone();
+    mock_io.expect_write().returning(move |msg| {
+        log.lock().unwrap().push(msg.to_vec());
+        Ok(msg.len())
+    });
+    mock_io
+        .expect_read()
+        .returning(move |mut buf| buf.write(&SINGLE_PACKAGE));
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+
+    let frames: Vec<_> = ccd.frames_iter().unwrap().take(3).collect();
+    assert_eq!(frames.len(), 3);
+    assert!(frames.iter().all(Result::is_ok));
+    // ContinuousRead followed by PauseRead
+    let writes = writes.lock().unwrap();
+    assert_eq!(writes.len(), 2);
+    assert_eq!(writes[0][1], 0x02);
+    assert_eq!(writes[1][1], 0x06);
+}
+
+#[test]
+fn change_settings_mid_stream() {
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let mut mock_io = MockIO::new();
+    let log = writes.clone();
+    mock_io.expect_write().returning(move |msg| {
+        log.lock().unwrap().push(msg.to_vec());
+        Ok(msg.len())
+    });
+    mock_io
+        .expect_read()
+        .returning(move |mut buf| buf.write(&SINGLE_PACKAGE));
+    let mut ccd = StdIoAdapter::new(mock_io).open_ccd();
+
+    let mut frames = ccd.frames_iter().unwrap();
+    assert!(frames.next().unwrap().is_ok());
+    frames.set_exp_time(0x0102).unwrap();
+    assert!(frames.next().unwrap().is_ok());
+    frames.stop().unwrap();
+
+    let writes = writes.lock().unwrap();
+    assert_eq!(writes[1], vec![0x81, 0x03, 0x01, 0x02, 0xFF]);
+}
+
+#[test]
+fn decode_recorded_stream() {
+    let mut data = b"junk".to_vec();
+    data.extend_from_slice(&SINGLE_PACKAGE);
+    data.extend_from_slice(&SINGLE_PACKAGE[..10]);
+    let decoded: Vec<_> = Decoder::new(&data, false).collect();
+    assert_eq!(decoded.len(), 3);
+    assert!(matches!(
+        decoded[0],
+        Decoded::Invalid { offset: 0, skipped: 4, error: Error::BadHead }
+    ));
+    assert!(matches!(
+        decoded[1],
+        Decoded::Package { offset: 4, package: Package::Frame(_) }
+    ));
+    assert!(matches!(decoded[2], Decoded::Incomplete { len: 10, .. }));
+}
+
+#[test]
+fn parse_stream_in_chunks() {
+    let mut data = b"junk".to_vec();
+    data.extend_from_slice(&SINGLE_PACKAGE);
+    data.extend(Response::AverageTime(4).encode());
+    data.extend_from_slice(b"HdInfo:LCAM_V8.4.2,S11639,V4.2,202111161548");
+    let mut parser = ResponseParser::new(false);
+    let mut parsed = Vec::new();
+    for chunk in data.chunks(100) {
+        parsed.extend(parser.push_bytes(chunk));
+    }
+    assert_eq!(parser.buffered(), 0);
+    // Package split between chunks is kept until the rest of it arrives
+    let exposure = Response::ExposureTime(10).encode();
+    assert!(parser.push_bytes(&exposure[..2]).is_empty());
+    assert_eq!(parser.buffered(), 2);
+    parsed.extend(parser.push_bytes(&exposure[2..]));
+    assert_eq!(parser.buffered(), 0);
+
+    assert_eq!(parsed.len(), 5);
+    assert!(matches!(parsed[0], Err(Error::BadHead)));
+    assert!(matches!(&parsed[1], Ok(Parsed::Frame(f)) if f.len() == FRAME_PIXEL_COUNT));
+    let responses: Vec<_> = parsed[2..].iter().map(|p| p.as_ref().unwrap()).collect();
+    assert_eq!(responses[0], &Parsed::Response(Response::AverageTime(4)));
+    assert!(matches!(
+        responses[1],
+        Parsed::Response(Response::VersionInfo(_))
+    ));
+    assert_eq!(responses[2], &Parsed::Response(Response::ExposureTime(10)));
+}
+
+#[test]
+fn replay_recorded_session() {
+    let mut ccd = StdIoAdapter::new(Replay::from_bytes(SINGLE_PACKAGE.clone())).open_ccd();
+    ccd.set_timeout(Some(Duration::from_millis(10)));
+    assert!(ccd.get_frame().is_ok());
+    // Nothing left to replay, so the next query times out
+    assert!(matches!(ccd.get_frame(), Err(Error::Timeout)));
+}
+
+#[test]
+fn flag_only_real_pixels() {
+    let layout = SensorLayout::new(2048, 0, 0).unwrap();
+    let mock = MockTransport::new().expect_raw(
+        Command::ContinuousRead,
+        Response::encode_frame(&[3000; 2048], layout),
+    );
+    let mut ccd = StdIoAdapter::new(mock).open_ccd();
+    ccd.set_sensor_layout(layout);
+    let mut frames = ccd.frames_iter().unwrap();
+    // Padding after real pixels would count as underexposed
+    let (_, flags) = frames.next_flagged().unwrap().unwrap();
+    assert!(flags.is_clean());
+}
+
+#[test]
+fn build_with_settings() {
+    let builder = CCDBuilder::new()
+        .timeout(Some(Duration::from_millis(10)))
+        .retries(4)
+        .verify_crc(true);
+    let ccd = builder.open(StdIoAdapter::new(Replay::from_bytes(Vec::new())));
+    assert_eq!(ccd.timeout(), Some(Duration::from_millis(10)));
+    assert_eq!(ccd.retry_policy().max_attempts, 5);
+    assert!(ccd.verify_crc());
+}
+
+#[test]
+fn restore_baud_rate_on_drop() {
+    let mock = MockTransport::new()
+        .expect(
+            Command::GetSerialBaudRate,
+            [Response::SerialBaudRate(BaudRate::Baud115200)],
+        )
+        .expect(Command::SetSerialBaudRate(BaudRate::Baud921600), [])
+        .expect(Command::SetSerialBaudRate(BaudRate::Baud115200), []);
+    let mut ccd = StdIoAdapter::new(mock.clone()).open_ccd();
+    ccd.set_baudrate(BaudRate::Baud921600).unwrap();
+    drop(ccd);
+    mock.assert_done();
+
+    // Port reopened at a new rate still restores the one CCD was found at
+    let mock = MockTransport::new().expect(Command::SetSerialBaudRate(BaudRate::Baud115200), []);
+    drop(
+        CCDBuilder::new()
+            .restore_baudrate(BaudRate::Baud115200)
+            .open(StdIoAdapter::new(mock.clone())),
+    );
+    mock.assert_done();
+}
+
+#[test]
+fn auto_detect_baud_order() {
+    let builder = CCD::builder().baud(BaudRate::Baud921600);
+    assert_eq!(
+        builder.baud_candidates().collect::<Vec<_>>(),
+        [
+            BaudRate::Baud921600,
+            BaudRate::Baud115200,
+            BaudRate::Baud384000
+        ]
+    );
+    let builder = builder.baud_order(&[BaudRate::Baud384000, BaudRate::Baud384000]);
+    assert_eq!(
+        builder.baud_candidates().collect::<Vec<_>>(),
+        [BaudRate::Baud384000]
+    );
+}
+
+#[test]
+fn auto_detect_baud_rate() {
+    let builder = CCD::builder()
+        .baud(BaudRate::Baud384000)
+        .timeout(Some(Duration::from_secs(1)))
+        .retries(2)
+        .auto_detect_baud(true)
+        .detect_timeout(Duration::from_millis(10));
+    let mut tried = Vec::new();
+    let (ccd, baud) = builder
+        .connect_with(|baud| {
+            tried.push(baud);
+            let reply = match baud {
+                BaudRate::Baud921600 => b"HdInfo:LCAM_V8.4.2,S11639,V4.2,202111161548".to_vec(),
+                _ => Vec::new(),
+            };
+            Ok::<_, Error>(StdIoAdapter::new(Replay::from_bytes(reply)))
+        })
+        .unwrap();
+    assert_eq!(baud, BaudRate::Baud921600);
+    assert_eq!(
+        tried,
+        [
+            BaudRate::Baud384000,
+            BaudRate::Baud115200,
+            BaudRate::Baud921600
+        ]
+    );
+    // Probing settings are gone, layout learned from version query stays
+    assert_eq!(ccd.timeout(), Some(Duration::from_secs(1)));
+    assert_eq!(ccd.retry_policy().max_attempts, 3);
+    assert_eq!(ccd.sensor_layout(), SensorLayout::LCAM_V06);
+
+    let silent =
+        builder.connect_with(|_| Ok::<_, Error>(StdIoAdapter::new(Replay::from_bytes([]))));
+    assert!(matches!(silent, Err(Error::NoBaudRate)));
+}
+
+#[test]
+fn negotiate_fastest_baud_rate() {
+    let version = VersionDetails::try_new("LCAM_V8.4.2", "S11639", "V4.2", "202111161548").unwrap();
+    let mock = MockTransport::new()
+        .expect(
+            Command::GetSerialBaudRate,
+            [Response::SerialBaudRate(BaudRate::Baud115200)],
+        )
+        .expect(Command::SetSerialBaudRate(BaudRate::Baud384000), [])
+        .expect(
+            Command::GetVersion,
+            [Response::VersionInfo(version.clone())],
+        )
+        .expect(Command::SetSerialBaudRate(BaudRate::Baud921600), [])
+        // Nothing arrives at the fastest rate, so CCD is switched back
+        .expect(Command::GetVersion, [])
+        .expect(Command::SetSerialBaudRate(BaudRate::Baud384000), [])
+        .expect(Command::GetVersion, [Response::VersionInfo(version)])
+        // Rate CCD was found at is restored on drop
+        .expect(Command::SetSerialBaudRate(BaudRate::Baud115200), []);
+    let mut opened = Vec::new();
+    let (ccd, baud) = CCD::builder()
+        .timeout(Some(Duration::from_secs(1)))
+        .negotiate_baud(true)
+        .detect_timeout(Duration::from_millis(10))
+        .connect_with(|baud| {
+            opened.push(baud);
+            Ok::<_, Error>(StdIoAdapter::new(mock.clone()))
+        })
+        .unwrap();
+    assert_eq!(baud, BaudRate::Baud384000);
+    assert_eq!(
+        opened,
+        [
+            BaudRate::Baud115200,
+            BaudRate::Baud384000,
+            BaudRate::Baud921600,
+            BaudRate::Baud384000
+        ]
+    );
+    assert_eq!(ccd.timeout(), Some(Duration::from_secs(1)));
+    drop(ccd);
+    mock.assert_done();
+}
+
+#[test]
+fn remember_device_version() {
+    let mut ccd = StdIoAdapter::new(Replay::from_bytes(
+        b"HdInfo:LCAM_V8.4.2,S11639,V4.2,202111161548".to_vec(),
+    ))
+    .open_ccd();
+    ccd.set_sensor_layout(SensorLayout::new(2048, 0, 0).unwrap());
+    assert_eq!(ccd.firmware_version(), None);
+    ccd.get_version().unwrap();
+    assert_eq!(ccd.firmware_version(), Some(FirmwareVersion::new(4, 2, 0)));
+    // Layout follows reported sensor type
+    assert_eq!(ccd.sensor_layout(), SensorLayout::LCAM_V06);
+}
+
+#[test]
+fn read_frame_through_trait() {
+    let ccd = StdIoAdapter::new(Replay::from_bytes(SINGLE_PACKAGE.clone())).open_ccd();
+    let mut spectrometer: Box<dyn Spectrometer> = Box::new(ccd);
+    let mut frame = vec![0; spectrometer.pixel_count()];
+    assert!(spectrometer.read_frame(&mut frame).is_ok());
+    assert!(matches!(
+        spectrometer.read_frame(&mut frame[1..]),
+        Err(Error::InvalidData)
+    ));
+}
+
+/// Mock that hands out `data` one byte per read
+fn one_byte_reads(data: Vec<u8>) -> MockIO {
+    let sent = AtomicUsize::new(0);
+    let mut mock_io = MockIO::new();
+    mock_io.expect_write().returning(|msg| Ok(msg.len()));
+    mock_io.expect_read().returning(move |mut buf| {
+        let at = sent.fetch_add(1, Ordering::SeqCst).min(data.len());
+        buf.write(&data[at..(at + 1).min(data.len())])
+    });
+    mock_io
+}
+
+#[test]
+fn receive_package_one_byte_at_a_time() {
+    let mut ccd = StdIoAdapter::new(one_byte_reads(SINGLE_PACKAGE.clone())).open_ccd();
+    ccd.set_retry_policy(RetryPolicy::none());
+    ccd.set_max_consecutive_failures(1);
+    assert!(ccd.get_frame().is_ok());
+    assert_eq!(ccd.stats().resyncs, 0);
+
+    // Stray byte is one failure, waiting for the rest of a head after it isn't
+    let mut data = vec![0x42];
+    data.extend_from_slice(b"HdInfo:LCAM_V8.4.2,S11639,V4.2,202111161548");
+    let mut ccd = StdIoAdapter::new(one_byte_reads(data)).open_ccd();
+    ccd.set_retry_policy(RetryPolicy::none());
+    ccd.set_max_consecutive_failures(2);
+    assert!(ccd.get_version().is_ok());
+    assert_eq!(ccd.stats().bytes_skipped, 1);
+}
+
+#[test]
+fn refuse_command_unsupported_by_firmware() {
+    let version = VersionDetails::try_new("LCAM_V8.4.2", "S11639", "V3.1", "202111161548").unwrap();
+    let mock = MockTransport::new().expect(Command::GetVersion, [Response::VersionInfo(version)]);
+    let mut ccd = StdIoAdapter::new(mock.clone()).open_ccd();
+    ccd.get_version().unwrap();
+
+    // Refused before current rate is even queried, so nothing else reaches the device
+    assert!(matches!(
+        ccd.set_baudrate(BaudRate::Baud921600),
+        Err(Error::UnsupportedByFirmware { command: 0x13, .. })
+    ));
+    drop(ccd);
+    mock.assert_done();
+}
@@ -0,0 +1,199 @@
+use crate::spectrum::Spectrum;
+use ccd_lcamv06::{BaudRate, Command, Response, SensorLayout, VersionDetails, FRAME_PIXEL_COUNT};
+use num_traits::ToPrimitive;
+use std::time::{Duration, Instant};
+/// Bytes in a frame package: 5 bytes of head, 2 bytes per pixel and CRC
+const FRAME_PACKAGE_SIZE: u64 = 5 + FRAME_PIXEL_COUNT as u64 * 2 + 2;
+/// UART sends a start and a stop bit along with every byte
+const BITS_PER_BYTE: u64 = 10;
+
+/// State of emulated CCD, with the same settings a freshly powered device starts with
+pub struct Device {
+    spectrum: Spectrum,
+    version: VersionDetails,
+    /// "Exposure time", which simulator treats as milliseconds
+    exposure: u16,
+    average: u8,
+    baud: BaudRate,
+    /// When the next frame is due while streaming, `None` if frames aren't streamed
+    next_frame: Option<Instant>,
+    /// Received bytes that don't form a complete command yet
+    input: Vec<u8>,
+}
+
+impl Device {
+    pub fn new(spectrum: Spectrum) -> Self {
+        Device {
+            spectrum,
+            // Same version info as reported by a real LCAM V06
+            version: VersionDetails::try_new("LCAM_V8.4.2", "S11639", "V4.2", "202111161548")
+                .expect("version details fit"),
+            exposure: 10,
+            average: 1,
+            baud: BaudRate::default(),
+            next_frame: None,
+            input: Vec::new(),
+        }
+    }
+
+    /// Takes bytes received from host, returning responses to every command completed by them
+    pub fn receive(&mut self, data: &[u8]) -> Vec<u8> {
+        self.input.extend_from_slice(data);
+        let mut out = Vec::new();
+        loop {
+            // Every command starts with 0x81, anything before it is line noise
+            match self.input.iter().position(|&b| b == 0x81) {
+                Some(start) => drop(self.input.drain(..start)),
+                None => self.input.clear(),
+            }
+            if self.input.len() < 5 {
+                break;
+            }
+            if self.input[4] != 0xFF {
+                self.input.remove(0);
+                continue;
+            }
+            let package = [0, 1, 2, 3, 4].map(|i| self.input[i]);
+            self.input.drain(..5);
+            match Command::decode(package) {
+                Some(cmd) => self.handle(cmd, &mut out),
+                None => tracing::warn!("Ignoring unknown command {package:02x?}"),
+            }
+        }
+        out
+    }
+
+    fn handle(&mut self, cmd: Command, out: &mut Vec<u8>) {
+        match cmd {
+            Command::SingleRead => out.extend(self.frame()),
+            Command::ContinuousRead => {
+                tracing::debug!("Streaming frames");
+                self.next_frame = Some(Instant::now() + self.frame_period());
+            }
+            Command::SetIntegrationTime(exposure) => self.exposure = exposure,
+            Command::PauseRead => {
+                tracing::debug!("Streaming paused");
+                self.next_frame = None;
+            }
+            // Frames are never triggered externally, so trigger mode doesn't change anything
+            Command::SetTrigerMode(mode) => tracing::debug!("Trigger mode set to {mode:?}"),
+            Command::GetVersion => out.extend(Response::VersionInfo(self.version.clone()).encode()),
+            Command::GetExposureTime => out.extend(Response::ExposureTime(self.exposure).encode()),
+            Command::SetAverageTime(average) => self.average = average,
+            Command::GetAverageTime => out.extend(Response::AverageTime(self.average).encode()),
+            Command::SetSerialBaudRate(baud) => self.baud = baud,
+            Command::GetSerialBaudRate => out.extend(Response::SerialBaudRate(self.baud).encode()),
+        }
+    }
+
+    fn frame(&mut self) -> Vec<u8> {
+        let pixels = self.spectrum.frame(self.exposure, self.average);
+        Response::encode_frame(&pixels, SensorLayout::LCAM_V06)
+    }
+
+    /// Time between streamed frames, limited by both exposure and how long UART takes to carry
+    /// a frame at selected baud rate
+    fn frame_period(&self) -> Duration {
+        let exposure = Duration::from_millis(u64::from(self.exposure) * u64::from(self.average));
+        let baud = self.baud.to_u64().unwrap_or(115200);
+        let transfer = Duration::from_micros(FRAME_PACKAGE_SIZE * BITS_PER_BYTE * 1_000_000 / baud);
+        exposure.max(transfer)
+    }
+
+    /// How long until the next streamed frame is due, `None` if frames aren't streamed
+    pub fn next_frame_in(&self, now: Instant) -> Option<Duration> {
+        self.next_frame
+            .map(|due| due.saturating_duration_since(now))
+    }
+
+    /// Next streamed frame, if it's already due
+    pub fn poll_stream(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let due = self.next_frame?;
+        if now < due {
+            return None;
+        }
+        // Frames that were missed while host was slow aren't sent in a burst afterwards
+        self.next_frame = Some((due + self.frame_period()).max(now));
+        Some(self.frame())
+    }
+
+    /// Host closed the port, so there is nobody to stream to
+    pub fn disconnect(&mut self) {
+        self.next_frame = None;
+        self.input.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ccd_lcamv06::{IoAdapter, StdIoAdapter};
+    use std::{
+        collections::VecDeque,
+        io::{self, Read, Write},
+    };
+
+    /// Connects device straight to CCD driver, responses are ready as soon as command is written
+    struct Loopback {
+        device: Device,
+        responses: VecDeque<u8>,
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.responses.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.responses.extend(self.device.receive(buf));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn talk_to_driver() {
+        let device = Device::new(Spectrum::new(vec![1000], 10.0, Some(1)));
+        let mut ccd = StdIoAdapter::new(Loopback {
+            device,
+            responses: VecDeque::new(),
+        })
+        .open_ccd();
+        ccd.set_verify_crc(true);
+        assert_eq!(ccd.get_version().unwrap().serial_number(), "202111161548");
+        ccd.set_exp_time(20).unwrap();
+        assert_eq!(ccd.get_exp_time().unwrap(), 20);
+        ccd.set_avg_time(4).unwrap();
+        assert_eq!(ccd.get_avg_time().unwrap(), 4);
+        ccd.set_baudrate(BaudRate::Baud921600).unwrap();
+        assert_eq!(ccd.get_baudrate().unwrap(), BaudRate::Baud921600);
+
+        let frame = ccd.get_frame().unwrap();
+        let peak = (0..frame.len()).max_by_key(|&px| frame[px]).unwrap();
+        assert_eq!(peak, 1000);
+        assert!(frame[0] < 1600, "far from peaks only dark level is left");
+    }
+
+    #[test]
+    fn stream_at_uart_speed() {
+        let mut device = Device::new(Spectrum::new(Vec::new(), 0.0, Some(1)));
+        // Garbage before command is skipped
+        assert!(device.receive(&[0x00, 0x81, 0x02, 0x00, 0x00]).is_empty());
+        assert!(device.receive(&[0xFF]).is_empty());
+        // 10 ms exposure is shorter than 642 ms it takes to send a frame at 115200 baud
+        let now = Instant::now();
+        let next = device.next_frame_in(now).unwrap();
+        assert!(next > Duration::from_millis(600) && next <= Duration::from_micros(641_927));
+        assert!(device.poll_stream(now).is_none());
+        let frame = device.poll_stream(now + next).unwrap();
+        assert_eq!(frame.len() as u64, FRAME_PACKAGE_SIZE);
+        assert!(device.poll_stream(now + next).is_none());
+        device.receive(&[0x81, 0x06, 0x00, 0x00, 0xFF]);
+        assert_eq!(device.next_frame_in(now), None);
+    }
+}
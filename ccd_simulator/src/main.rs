@@ -0,0 +1,131 @@
+#[cfg(unix)]
+mod device;
+#[cfg(unix)]
+mod pty;
+#[cfg(unix)]
+mod spectrum;
+
+#[cfg(unix)]
+use {
+    clap::Parser,
+    device::Device,
+    pty::Event,
+    simple_eyre::Result,
+    spectrum::Spectrum,
+    std::{
+        fs::{self, File},
+        io::{self, Read, Write},
+        path::PathBuf,
+        thread,
+        time::{Duration, Instant},
+    },
+    tracing_subscriber::{filter::LevelFilter, EnvFilter},
+};
+
+/// Emulates LCAM V06 CCD on a pseudo-terminal, so that CLI and library can be tried out without
+/// hardware. Prints path of the port to open and serves until killed
+#[cfg(unix)]
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Args {
+    /// Also make port available under this path, as a symlink replaced on every start
+    #[clap(long, value_parser)]
+    link: Option<PathBuf>,
+
+    /// Pixels that emission lines in synthetic frames are centered on
+    #[clap(
+        long,
+        value_parser,
+        use_value_delimiter = true,
+        default_value = "600,1400,2950"
+    )]
+    peaks: Vec<usize>,
+
+    /// Standard deviation of noise in a single frame, in counts
+    #[clap(long, value_parser, default_value = "25")]
+    noise: f64,
+
+    /// Makes noise the same on every run
+    #[clap(long, value_parser)]
+    seed: Option<u64>,
+}
+
+/// How often the port is checked while nobody has it open
+#[cfg(unix)]
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+#[cfg(unix)]
+fn main() -> Result<()> {
+    simple_eyre::install()?;
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with_writer(io::stderr)
+        .init();
+    let args = Args::parse();
+    let pty = pty::open()?;
+    if let Some(link) = &args.link {
+        if link.symlink_metadata().is_ok_and(|m| m.is_symlink()) {
+            fs::remove_file(link)?;
+        }
+        std::os::unix::fs::symlink(&pty.path, link)?;
+    }
+    // Path goes to stdout alone, so scripts can pick it up
+    println!("{}", pty.path.display());
+    let mut device = Device::new(Spectrum::new(args.peaks, args.noise, args.seed));
+    serve(pty.master, &mut device)
+}
+
+/// Turns errors caused by host closing the port into `true`
+#[cfg(unix)]
+fn hung_up(res: io::Result<()>) -> Result<bool> {
+    match res {
+        Ok(()) => Ok(false),
+        Err(e) if pty::is_hangup(&e) => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn serve(mut master: File, device: &mut Device) -> Result<()> {
+    let mut buf = [0; 256];
+    let mut connected = false;
+    loop {
+        let event = pty::wait(&master, device.next_frame_in(Instant::now()))?;
+        let mut hangup = event == Event::Hangup;
+        if event == Event::Data {
+            let received = master.read(&mut buf).map(|n| device.receive(&buf[..n]));
+            match received {
+                Ok(response) => {
+                    if !connected {
+                        tracing::info!("Host connected");
+                        connected = true;
+                    }
+                    hangup = hung_up(master.write_all(&response))?;
+                }
+                Err(e) if pty::is_hangup(&e) => hangup = true,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if let Some(frame) = device.poll_stream(Instant::now()) {
+            hangup |= hung_up(master.write_all(&frame))?;
+        }
+        if hangup {
+            if connected {
+                tracing::info!("Host disconnected");
+                connected = false;
+                device.disconnect();
+            }
+            thread::sleep(IDLE_POLL);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("Simulator needs pseudo-terminals, which are only available on Unix");
+    std::process::exit(1);
+}
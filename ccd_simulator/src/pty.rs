@@ -0,0 +1,102 @@
+use std::{
+    ffi::CStr,
+    fs::File,
+    io,
+    os::fd::{AsRawFd, FromRawFd},
+    path::PathBuf,
+    ptr,
+    time::Duration,
+};
+
+/// Pseudo-terminal, master side is kept by simulator and host opens the other one as a serial
+/// port
+pub struct Pty {
+    pub master: File,
+    /// Path of the side host opens
+    pub path: PathBuf,
+}
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn open() -> io::Result<Pty> {
+    let (mut master, mut slave) = (0, 0);
+    let mut name = [0; 256];
+    // SAFETY: pointers are valid for the duration of each call, and the name buffer is large
+    // enough for any path of a pseudo-terminal
+    unsafe {
+        check(libc::openpty(
+            &mut master,
+            &mut slave,
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+        ))?;
+        let master = File::from_raw_fd(master);
+        // Raw mode, so that bytes like 0x0D or 0x11 aren't translated or swallowed before host
+        // sets its own mode
+        let mut termios = std::mem::zeroed();
+        check(libc::tcgetattr(slave, &mut termios))?;
+        libc::cfmakeraw(&mut termios);
+        check(libc::tcsetattr(slave, libc::TCSANOW, &termios))?;
+        let res = libc::ttyname_r(slave, name.as_mut_ptr(), name.len());
+        // Simulator doesn't keep the other side open, otherwise it couldn't tell when host
+        // disconnects
+        libc::close(slave);
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+        let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+        Ok(Pty {
+            master,
+            path: path.into(),
+        })
+    }
+}
+
+/// What happened on master side while waiting
+#[derive(Debug, PartialEq, Eq)]
+pub enum Event {
+    Data,
+    /// Nobody has the other side open
+    Hangup,
+    Timeout,
+}
+
+/// Waits until host sends something, `None` waits forever
+pub fn wait(master: &File, timeout: Option<Duration>) -> io::Result<Event> {
+    let mut fd = libc::pollfd {
+        fd: master.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Rounded up, so that a frame due in less than a millisecond isn't busy waited for
+    let timeout = timeout.map_or(-1, |t| {
+        t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+    });
+    // SAFETY: fd points to a single valid pollfd
+    let res = unsafe { libc::poll(&mut fd, 1, timeout) };
+    if res < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() == io::ErrorKind::Interrupted {
+            return Ok(Event::Timeout);
+        }
+        return Err(e);
+    }
+    Ok(if res == 0 {
+        Event::Timeout
+    } else if fd.revents & libc::POLLIN != 0 {
+        Event::Data
+    } else {
+        Event::Hangup
+    })
+}
+
+/// Reading or writing master side fails with EIO once host closes its side
+pub fn is_hangup(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::EIO)
+}
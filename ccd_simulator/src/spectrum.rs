@@ -0,0 +1,55 @@
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use std::f64::consts::TAU;
+
+/// Counts every pixel reads with light blocked
+const DARK_LEVEL: f64 = 1500.0;
+/// Counts added to the top of each peak per millisecond of exposure
+const PEAK_COUNTS_PER_MS: f64 = 2000.0;
+/// Standard deviation of peaks, in pixels
+const PEAK_WIDTH: f64 = 4.0;
+
+/// Synthetic light source, a few Gaussian emission lines on top of dark level with noise
+pub struct Spectrum {
+    pub peaks: Vec<usize>,
+    /// Standard deviation of noise in a single frame, in counts
+    pub noise: f64,
+    rng: fastrand::Rng,
+}
+
+impl Spectrum {
+    pub fn new(peaks: Vec<usize>, noise: f64, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+        Spectrum { peaks, noise, rng }
+    }
+
+    /// Standard normal sample, Box-Muller transform
+    fn gaussian(&mut self) -> f64 {
+        let u = 1.0 - self.rng.f64();
+        let v = self.rng.f64();
+        (-2.0 * u.ln()).sqrt() * (TAU * v).cos()
+    }
+
+    /// Frame captured with `exposure` milliseconds, where averaging `averaged` frames lowers noise
+    pub fn frame(&mut self, exposure: u16, averaged: u8) -> Vec<u16> {
+        let noise = self.noise / f64::from(averaged.max(1)).sqrt();
+        (0..FRAME_PIXEL_COUNT)
+            .map(|px| {
+                let light: f64 = self
+                    .peaks
+                    .iter()
+                    .map(|&center| {
+                        let distance = (px as f64 - center as f64) / PEAK_WIDTH;
+                        (-distance * distance / 2.0).exp()
+                    })
+                    .sum();
+                let counts = DARK_LEVEL
+                    + light * PEAK_COUNTS_PER_MS * f64::from(exposure)
+                    + noise * self.gaussian();
+                counts.round().clamp(0.0, f64::from(u16::MAX)) as u16
+            })
+            .collect()
+    }
+}
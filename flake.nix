@@ -51,7 +51,7 @@
           };
 
           packages = {
-            inherit (legacyPackages.pkgsCross.${localSystem}) spectrometer_cli;
+            inherit (legacyPackages.pkgsCross.${localSystem}) spectrometer_cli ccd_simulator;
             default = packages.spectrometer_cli;
           };
 
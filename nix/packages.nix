@@ -9,6 +9,10 @@
 }:
 rec {
   ccd_lcamv06 = callPackage ./cargoPackage.nix { package = "ccd_lcamv06"; };
+  ccd_simulator = callPackage ./cargoPackage.nix {
+    cargoArtifacts = ccd_lcamv06;
+    package = "ccd_simulator";
+  };
   spectrometer_cli = callPackage ./cargoPackage.nix {
     cargoArtifacts = ccd_lcamv06;
     package = "spectrometer_cli";
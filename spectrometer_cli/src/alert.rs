@@ -0,0 +1,223 @@
+use crate::{hook, output, remote};
+use ccd_lcamv06::QualityFlags;
+use serde::Deserialize;
+use simple_eyre::{eyre::eyre, Result};
+use std::{collections::HashSet, fs, path::Path, thread, time::Duration};
+use time::format_description::well_known::Rfc3339;
+
+/// Time webhook is given to accept a notification
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rule checked against every captured frame, actions run when it starts and stops matching
+///
+/// ```toml
+/// [[alert]]
+/// name = "lamp-on"
+/// band = [500, 520]
+/// above = 1000000
+/// webhook = "http://127.0.0.1:8080/lamp"
+/// command = "gpioset gpiochip0 17=$SPECTRO_ALERT_ACTIVE"
+/// ```
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AlertRule {
+    pub name: String,
+    /// Matches frames with saturated pixels
+    #[serde(default)]
+    pub saturated: bool,
+    /// First and last pixel, inclusive, whose counts are summed up and compared against levels
+    pub band: Option<[usize; 2]>,
+    /// Matches when counts in band are above this
+    pub above: Option<u64>,
+    /// Matches when counts in band are below this
+    pub below: Option<u64>,
+    /// Plain HTTP URL JSON notifications are posted to
+    pub webhook: Option<String>,
+    /// Shell command started with details in `SPECTRO_ALERT*` environment variables
+    pub command: Option<String>,
+}
+
+/// Contents of file passed to `--alerts`, same tables as in daemon config
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AlertsFile {
+    #[serde(rename = "alert", default)]
+    alerts: Vec<AlertRule>,
+}
+
+/// Reads rules from a TOML file with `[[alert]]` tables
+pub fn load(path: &Path) -> Result<Vec<AlertRule>> {
+    let file: AlertsFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| eyre!("Could not parse alerts {path:?}: {e}"))?;
+    if file.alerts.is_empty() {
+        return Err(eyre!("No alerts configured in {path:?}"));
+    }
+    validate(&file.alerts)?;
+    Ok(file.alerts)
+}
+
+pub fn validate(rules: &[AlertRule]) -> Result<()> {
+    let mut names = HashSet::new();
+    for rule in rules {
+        if !names.insert(&rule.name) {
+            return Err(eyre!("Alert {:?} is defined twice", rule.name));
+        }
+        let levels = rule.above.is_some() || rule.below.is_some();
+        match rule.band {
+            Some([first, last]) if first > last => {
+                return Err(eyre!("Band of alert {:?} ends before it starts", rule.name))
+            }
+            Some(_) if !levels => {
+                return Err(eyre!(
+                    "Alert {:?} needs `above` or `below` for its band",
+                    rule.name
+                ))
+            }
+            None if levels => return Err(eyre!("Alert {:?} needs a `band` of pixels", rule.name)),
+            None if !rule.saturated => {
+                return Err(eyre!("Alert {:?} has nothing to check", rule.name))
+            }
+            _ => {}
+        }
+        if rule.webhook.is_none() && rule.command.is_none() {
+            return Err(eyre!(
+                "Alert {:?} has neither webhook nor command",
+                rule.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl AlertRule {
+    /// Counts summed over band, if there is one
+    fn band_counts(&self, frame: &[u16]) -> Option<u64> {
+        let [first, last] = self.band?;
+        let band = frame.get(first..=last.min(frame.len().saturating_sub(1)))?;
+        Some(band.iter().map(|&px| px as u64).sum())
+    }
+
+    fn matches(&self, frame: &[u16], flags: QualityFlags) -> bool {
+        if self.saturated && flags.saturated {
+            return true;
+        }
+        match self.band_counts(frame) {
+            Some(counts) => {
+                self.above.is_some_and(|above| counts > above)
+                    || self.below.is_some_and(|below| counts < below)
+            }
+            None => false,
+        }
+    }
+
+    /// Runs actions in background, so that capture isn't held up by slow receivers
+    fn fire(&self, active: bool, counts: Option<u64>) {
+        let timestamp = output::now().format(&Rfc3339).unwrap_or_default();
+        tracing::info!(
+            "Alert {:?} {}",
+            self.name,
+            if active { "triggered" } else { "cleared" }
+        );
+        if let Some(url) = &self.webhook {
+            let body = serde_json::json!({
+                "alert": self.name,
+                "active": active,
+                "counts": counts,
+                "timestamp": timestamp,
+            })
+            .to_string();
+            let url = url.clone();
+            thread::spawn(move || {
+                if let Err(e) = remote::post_json(&url, WEBHOOK_TIMEOUT, &body) {
+                    tracing::error!("Could not notify webhook: {e}");
+                }
+            });
+        }
+        if let Some(command) = &self.command {
+            let counts = counts.map(|c| c.to_string()).unwrap_or_default();
+            let envs = [
+                ("SPECTRO_ALERT", self.name.as_str()),
+                ("SPECTRO_ALERT_ACTIVE", if active { "1" } else { "0" }),
+                ("SPECTRO_ALERT_COUNTS", &counts),
+                ("SPECTRO_ALERT_TIME", &timestamp),
+            ];
+            if let Err(e) = hook::spawn_shell(command, &envs) {
+                tracing::error!("Could not run command of alert {:?}: {e}", self.name);
+            }
+        }
+    }
+}
+
+/// Rules along with whether each of them matched the previous frame, so that actions only run
+/// when that changes
+pub struct Alerts<'a> {
+    rules: &'a [AlertRule],
+    active: Vec<bool>,
+}
+
+impl<'a> Alerts<'a> {
+    pub fn new(rules: &'a [AlertRule]) -> Self {
+        Alerts {
+            rules,
+            active: vec![false; rules.len()],
+        }
+    }
+
+    /// Checks frame against every rule, returning names of rules that changed state
+    pub fn check(&mut self, frame: &[u16], flags: QualityFlags) -> Vec<&'a str> {
+        let mut changed = Vec::new();
+        for (rule, active) in self.rules.iter().zip(&mut self.active) {
+            let matches = rule.matches(frame, flags);
+            if matches != *active {
+                *active = matches;
+                rule.fire(matches, rule.band_counts(frame));
+                changed.push(rule.name.as_str());
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trigger_on_change() {
+        let file: AlertsFile = toml::from_str(
+            r#"
+            [[alert]]
+            name = "bright"
+            band = [1, 2]
+            above = 100
+            command = "true"
+
+            [[alert]]
+            name = "clipped"
+            saturated = true
+            command = "true"
+            "#,
+        )
+        .unwrap();
+        validate(&file.alerts).unwrap();
+        let mut alerts = Alerts::new(&file.alerts);
+        let clean = QualityFlags::default();
+        assert!(alerts.check(&[0, 50, 50, 500], clean).is_empty());
+        assert_eq!(alerts.check(&[0, 50, 51, 0], clean), vec!["bright"]);
+        assert!(alerts.check(&[0, 60, 60, 0], clean).is_empty());
+        let saturated = QualityFlags {
+            saturated: true,
+            ..Default::default()
+        };
+        assert_eq!(alerts.check(&[0; 4], saturated), vec!["bright", "clipped"]);
+
+        let no_levels = r#"
+            [[alert]]
+            name = "band"
+            band = [1, 2]
+            webhook = "http://127.0.0.1/"
+        "#;
+        let file: AlertsFile = toml::from_str(no_levels).unwrap();
+        assert!(validate(&file.alerts).is_err());
+    }
+}
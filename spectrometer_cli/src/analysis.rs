@@ -0,0 +1,469 @@
+use clap::ArgEnum;
+use simple_eyre::{eyre::eyre, Report, Result};
+use std::{ops::Range, str::FromStr};
+
+/// Peaks closer than this many pixels to a higher one are considered part of it
+const MIN_PEAK_DISTANCE: usize = 20;
+
+/// Top of a peak is looked for this many pixels around its expected position
+const PEAK_SEARCH_RADIUS: usize = 20;
+
+/// Baseline under a peak is the lowest value this many pixels around its top
+const BASELINE_RADIUS: usize = 50;
+
+/// Frame together with an optional pixel to wavelength calibration
+pub struct Spectrum<'a> {
+    pub pixels: &'a [u16],
+    /// Polynomial coefficients converting pixel index into wavelength, lowest order first
+    pub wavelength: &'a [f64],
+    /// Excitation laser wavelength in nm, which turns x axis into Raman shift when calibrated
+    pub laser: Option<f64>,
+}
+
+/// What position along a spectrum is expressed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Pixel,
+    Wavelength,
+    RamanShift,
+}
+
+impl Axis {
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Axis::Pixel => "px",
+            Axis::Wavelength => "nm",
+            Axis::RamanShift => "cm⁻¹",
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Axis::Pixel => "Pixel #",
+            Axis::Wavelength => "Wavelength, nm",
+            Axis::RamanShift => "Raman shift, cm⁻¹",
+        }
+    }
+}
+
+impl Spectrum<'_> {
+    /// Most physical axis calibration allows
+    pub fn axis(&self) -> Axis {
+        match (self.wavelength.is_empty(), self.laser) {
+            (true, _) => Axis::Pixel,
+            (false, None) => Axis::Wavelength,
+            (false, Some(_)) => Axis::RamanShift,
+        }
+    }
+
+    /// Position of pixel along [Spectrum::axis]
+    pub fn x_at(&self, pixel: f64) -> f64 {
+        if self.wavelength.is_empty() {
+            return pixel;
+        }
+        let nm = wavelength_at(self.wavelength, pixel);
+        self.laser.map_or(nm, |laser| raman_shift(laser, nm))
+    }
+}
+
+/// Evaluates calibration polynomial, coefficients go from lowest order. Pixel can be fractional
+/// for positions between pixels
+pub fn wavelength_at(coeffs: &[f64], pixel: f64) -> f64 {
+    coeffs.iter().rev().fold(0.0, |acc, c| acc * pixel + c)
+}
+
+/// Raman shift in cm⁻¹ of light at `wavelength` scattered from laser at `laser`, both in nm.
+/// Stokes lines come out positive
+pub fn raman_shift(laser: f64, wavelength: f64) -> f64 {
+    1e7 / laser - 1e7 / wavelength
+}
+
+/// Parses laser wavelength given as `532nm` or just `532`
+pub fn parse_laser(s: &str) -> Result<f64, String> {
+    let nm = s
+        .strip_suffix("nm")
+        .unwrap_or(s)
+        .trim()
+        .parse::<f64>()
+        .map_err(|e| format!("Invalid laser wavelength {s:?}: {e}"))?;
+    if !(nm.is_finite() && nm > 0.0) {
+        return Err(format!("Laser wavelength has to be above zero, got {s:?}"));
+    }
+    Ok(nm)
+}
+
+/// Indices of up to `count` highest peaks, highest first
+pub fn find_peaks(pixels: &[u16], count: usize) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..pixels.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(pixels[i]));
+    let mut peaks: Vec<usize> = Vec::with_capacity(count);
+    for i in order {
+        if peaks.len() == count {
+            break;
+        }
+        if peaks.iter().all(|&p| p.abs_diff(i) >= MIN_PEAK_DISTANCE) {
+            peaks.push(i);
+        }
+    }
+    peaks
+}
+
+/// Amount of pixels falling into each of equally wide ranges of counts, together spanning every
+/// value ADC can produce, so histograms of different frames line up
+#[derive(Debug, PartialEq)]
+pub struct Histogram {
+    /// Counts covered by every bin
+    pub width: u32,
+    pub bins: Vec<usize>,
+}
+
+impl Histogram {
+    /// Counts falling into bin, end excluded
+    pub fn range(&self, bin: usize) -> Range<u32> {
+        let start = bin as u32 * self.width;
+        start..start + self.width
+    }
+}
+
+/// Splits pixel values into `bins` equally wide bins. Bins span a whole amount of counts, so there
+/// may be a few less than requested, and never more than 65536
+pub fn histogram(pixels: &[u16], bins: usize) -> Histogram {
+    const VALUES: u32 = u16::MAX as u32 + 1;
+    let width = VALUES.div_ceil(bins.clamp(1, VALUES as usize) as u32);
+    let mut histogram = Histogram {
+        width,
+        bins: vec![0; VALUES.div_ceil(width) as usize],
+    };
+    for &px in pixels {
+        histogram.bins[(px as u32 / width) as usize] += 1;
+    }
+    histogram
+}
+
+/// Extent of a peak at half of its height above local baseline
+#[derive(Debug, PartialEq)]
+pub struct PeakWidth {
+    /// Highest pixel of the peak
+    pub top: usize,
+    /// Positions where peak crosses half of its height, interpolated between pixels
+    pub left: f64,
+    pub right: f64,
+}
+
+impl PeakWidth {
+    /// Full width at half maximum, in pixels
+    pub fn fwhm(&self) -> f64 {
+        self.right - self.left
+    }
+
+    pub fn center(&self) -> f64 {
+        (self.left + self.right) / 2.0
+    }
+}
+
+/// Finds peak closest to given pixel and measures its width
+pub fn peak_width(values: &[f64], near: usize) -> Result<PeakWidth> {
+    let window =
+        near.saturating_sub(PEAK_SEARCH_RADIUS)..(near + PEAK_SEARCH_RADIUS + 1).min(values.len());
+    let top = window
+        .clone()
+        .max_by(|&a, &b| values[a].total_cmp(&values[b]))
+        .ok_or_else(|| eyre!("Pixel {near} is outside of frame"))?;
+    if (top == window.start && top != 0) || (top + 1 == window.end && top + 1 != values.len()) {
+        return Err(eyre!(
+            "No peak within {PEAK_SEARCH_RADIUS} pixels of {near}, intensity keeps rising past \
+             pixel {top}"
+        ));
+    }
+    let baseline = values
+        [top.saturating_sub(BASELINE_RADIUS)..(top + BASELINE_RADIUS + 1).min(values.len())]
+        .iter()
+        .copied()
+        .fold(f64::INFINITY, f64::min);
+    let half = baseline + (values[top] - baseline) / 2.0;
+    if values[top] <= baseline {
+        return Err(eyre!("Peak at pixel {top} doesn't rise above baseline"));
+    }
+
+    let left = (0..top)
+        .rev()
+        .find(|&i| values[i] < half)
+        .ok_or_else(|| eyre!("Peak at pixel {top} doesn't fall to half height on the left"))?;
+    let right = (top + 1..values.len())
+        .find(|&i| values[i] < half)
+        .ok_or_else(|| eyre!("Peak at pixel {top} doesn't fall to half height on the right"))?;
+    Ok(PeakWidth {
+        top,
+        left: left as f64 + (half - values[left]) / (values[left + 1] - values[left]),
+        right: (right - 1) as f64
+            + (values[right - 1] - half) / (values[right - 1] - values[right]),
+    })
+}
+
+/// Least squares polynomial fit of `ys` by `xs`, coefficients go from lowest order
+pub fn fit_polynomial(xs: &[f64], ys: &[f64], degree: usize) -> Result<Vec<f64>> {
+    let n = degree + 1;
+    if xs.len() < n {
+        return Err(eyre!(
+            "Polynomial of degree {degree} needs at least {n} points, got {}",
+            xs.len()
+        ));
+    }
+    // Positions are scaled into -1..1, otherwise powers of pixel indices make system of normal
+    // equations badly conditioned
+    let scale = xs.iter().fold(1.0, |max: f64, x| max.max(x.abs()));
+    let mut system = vec![vec![0.0; n + 1]; n];
+    for (&x, &y) in xs.iter().zip(ys) {
+        let t = x / scale;
+        let powers: Vec<f64> = (0..2 * n - 1).map(|p| t.powi(p as i32)).collect();
+        for (r, row) in system.iter_mut().enumerate() {
+            for (c, cell) in row[..n].iter_mut().enumerate() {
+                *cell += powers[r + c];
+            }
+            row[n] += y * powers[r];
+        }
+    }
+    // Gauss-Jordan elimination with partial pivoting
+    for col in 0..n {
+        let pivot = (col..n)
+            .max_by(|&a, &b| system[a][col].abs().total_cmp(&system[b][col].abs()))
+            .unwrap_or(col);
+        if system[pivot][col].abs() < 1e-12 {
+            return Err(eyre!(
+                "Points don't determine a polynomial of degree {degree}"
+            ));
+        }
+        system.swap(col, pivot);
+        let pivot_row = system[col].clone();
+        for (r, row) in system.iter_mut().enumerate() {
+            if r != col {
+                let factor = row[col] / pivot_row[col];
+                for (cell, p) in row.iter_mut().zip(&pivot_row) {
+                    *cell -= factor * p;
+                }
+            }
+        }
+    }
+    Ok((0..n)
+        .map(|i| system[i][n] / system[i][i] / scale.powi(i as i32))
+        .collect())
+}
+
+/// Evenly spaced wavelengths, given as `start:end:step` in nm with both ends included
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+}
+
+impl FromStr for Grid {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split(':')
+            .map(|part| {
+                part.trim()
+                    .parse::<f64>()
+                    .map_err(|_| eyre!("{part:?} is not a number"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let [start, end, step] = parts[..] else {
+            return Err(eyre!("Grid should be given as start:end:step, got {s:?}"));
+        };
+        if parts.iter().any(|part| !part.is_finite()) || step <= 0.0 || end <= start {
+            return Err(eyre!(
+                "Grid {s:?} should go up from start to end in positive steps"
+            ));
+        }
+        Ok(Grid { start, end, step })
+    }
+}
+
+impl Grid {
+    pub fn points(&self) -> impl Iterator<Item = f64> + '_ {
+        // Small margin so end isn't lost to rounding when it's a whole number of steps away
+        let count = ((self.end - self.start) / self.step + 1e-9).floor() as usize + 1;
+        (0..count).map(|i| self.start + i as f64 * self.step)
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Interpolation {
+    #[default]
+    Linear,
+    /// Catmull-Rom spline, keeps peak shapes smoother than linear
+    Cubic,
+}
+
+/// Intensity at each point of grid, interpolated between neighbouring pixels. Points outside of
+/// wavelength range covered by spectrum are NaN, so spectra from different devices can still be
+/// put on one grid
+pub fn resample(spectrum: &Spectrum, grid: &Grid, method: Interpolation) -> Result<Vec<f64>> {
+    if spectrum.wavelength.is_empty() {
+        return Err(eyre!("Resampling needs wavelength calibration"));
+    }
+    let pixels = spectrum.pixels;
+    if pixels.len() < 2 {
+        return Err(eyre!("Resampling needs at least 2 pixels"));
+    }
+    let wavelengths: Vec<f64> = (0..pixels.len())
+        .map(|i| wavelength_at(spectrum.wavelength, i as f64))
+        .collect();
+    let rising = wavelengths[1] > wavelengths[0];
+    if wavelengths
+        .windows(2)
+        .any(|w| (w[1] > w[0]) != rising || w[1] == w[0])
+    {
+        return Err(eyre!("Wavelength calibration is not monotonic over sensor"));
+    }
+    let value = |i: isize| pixels[i.clamp(0, pixels.len() as isize - 1) as usize] as f64;
+
+    Ok(grid
+        .points()
+        .map(|nm| {
+            // Index of the first pixel past `nm`
+            let next = wavelengths.partition_point(|&w| if rising { w <= nm } else { w >= nm });
+            let i = match next {
+                0 => return f64::NAN,
+                // Exactly at the last pixel
+                n if n == pixels.len() && nm == wavelengths[n - 1] => n - 2,
+                n if n == pixels.len() => return f64::NAN,
+                n => n - 1,
+            };
+            let t = (nm - wavelengths[i]) / (wavelengths[i + 1] - wavelengths[i]);
+            let i = i as isize;
+            let (p0, p1, p2, p3) = (value(i - 1), value(i), value(i + 1), value(i + 2));
+            match method {
+                Interpolation::Linear => p1 + t * (p2 - p1),
+                Interpolation::Cubic => {
+                    p1 + 0.5
+                        * t
+                        * (p2 - p0
+                            + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3
+                                + t * (3.0 * (p1 - p2) + p3 - p0)))
+                }
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peaks_and_wavelengths() {
+        let mut pixels = vec![10; 200];
+        pixels[50] = 100;
+        pixels[55] = 90;
+        pixels[150] = 80;
+        pixels[120] = 20;
+        // Shoulder next to the highest peak is skipped
+        assert_eq!(find_peaks(&pixels, 3), [50, 150, 120]);
+        assert_eq!(wavelength_at(&[200.0, 0.5, 0.25], 4.0), 206.0);
+    }
+
+    #[test]
+    fn raman_axis() {
+        assert_eq!(parse_laser("532nm"), Ok(532.0));
+        assert_eq!(parse_laser("785"), Ok(785.0));
+        assert!(parse_laser("0nm").is_err());
+        // 1 nm per pixel starting at the laser line
+        let mut spectrum = Spectrum {
+            pixels: &[0; 10],
+            wavelength: &[532.0, 1.0],
+            laser: Some(532.0),
+        };
+        assert_eq!(spectrum.axis(), Axis::RamanShift);
+        assert_eq!(spectrum.x_at(0.0), 0.0);
+        // Well known 1332 cm⁻¹ diamond line shows up near 572.5 nm
+        assert!((spectrum.x_at(40.5) - 1329.7).abs() < 0.1);
+        spectrum.laser = None;
+        assert_eq!(spectrum.axis(), Axis::Wavelength);
+        assert_eq!(spectrum.x_at(2.0), 534.0);
+    }
+
+    #[test]
+    fn count_histogram() {
+        let histogram = histogram(&[0, 1023, 1024, 65535, 65535], 64);
+        assert_eq!(histogram.width, 1024);
+        assert_eq!(histogram.bins.len(), 64);
+        assert_eq!(histogram.bins[..2], [2, 1]);
+        assert_eq!(histogram.bins[63], 2);
+        assert_eq!(histogram.range(63), 64512..65536);
+        assert_eq!(super::histogram(&[], 100).bins.len(), 100);
+        // 66 counts wide bins already cover all values with 993 of them
+        assert_eq!(super::histogram(&[], 1000).bins.len(), 993);
+        assert_eq!(super::histogram(&[7], 1 << 20).bins[7], 1);
+        assert_eq!(super::histogram(&[7], 0).bins, [1]);
+    }
+
+    #[test]
+    fn measure_peak_width() {
+        let mut values = vec![10.0; 100];
+        values[49..54].copy_from_slice(&[10.0, 110.0, 60.0, 30.0, 10.0]);
+        // Half height is 60, crossed halfway between 49 and 50 on the left and right at 51
+        let width = peak_width(&values, 45).unwrap();
+        assert_eq!(width.top, 50);
+        assert_eq!(width.left, 49.5);
+        assert_eq!(width.right, 51.0);
+        assert_eq!(width.fwhm(), 1.5);
+        // Slope without a top nearby
+        let slope: Vec<f64> = (0..100).map(|i| i as f64).collect();
+        assert!(peak_width(&slope, 30).is_err());
+    }
+
+    #[test]
+    fn fit_quadratic() {
+        let xs: Vec<f64> = (0..10).map(|i| i as f64 * 400.0).collect();
+        let ys: Vec<f64> = xs
+            .iter()
+            .map(|&x| wavelength_at(&[350.0, 0.2, -1e-5], x))
+            .collect();
+        let coeffs = fit_polynomial(&xs, &ys, 2).unwrap();
+        for (fitted, expected) in coeffs.iter().zip([350.0, 0.2, -1e-5]) {
+            assert!((fitted - expected).abs() < 1e-9 * expected.abs().max(1.0));
+        }
+        assert!(fit_polynomial(&xs[..2], &ys[..2], 2).is_err());
+        assert!(fit_polynomial(&[1.0, 1.0, 1.0], &[1.0, 2.0, 3.0], 2).is_err());
+    }
+
+    #[test]
+    fn resample_onto_grid() {
+        let grid: Grid = "400:402:0.5".parse().unwrap();
+        assert_eq!(
+            grid.points().collect::<Vec<_>>(),
+            [400.0, 400.5, 401.0, 401.5, 402.0]
+        );
+        assert!("400:300:1".parse::<Grid>().is_err());
+        assert!("400:500".parse::<Grid>().is_err());
+
+        // Pixels go down in wavelength, one nm apart
+        let spectrum = Spectrum {
+            pixels: &[0, 10, 20, 20, 0],
+            wavelength: &[402.0, -1.0],
+            laser: None,
+        };
+        let grid: Grid = "397.5:402:0.5".parse().unwrap();
+        let linear = resample(&spectrum, &grid, Interpolation::Linear).unwrap();
+        assert!(linear[0].is_nan());
+        assert_eq!(
+            linear[1..],
+            [0.0, 10.0, 20.0, 20.0, 20.0, 15.0, 10.0, 5.0, 0.0]
+        );
+        let cubic = resample(&spectrum, &grid, Interpolation::Cubic).unwrap();
+        // Goes through pixels themselves, but overshoots a bit between the two highest ones
+        assert_eq!(cubic[3], 20.0);
+        assert_eq!(cubic[4], 21.875);
+        assert_eq!(cubic[9], 0.0);
+
+        let flat = Spectrum {
+            pixels: &[1, 2, 3],
+            wavelength: &[400.0],
+            laser: None,
+        };
+        assert!(resample(&flat, &grid, Interpolation::Linear).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{cli::BenchmarkConf, interrupt, serial::SerialCCD};
+use ccd_lcamv06::{BaudRate, StreamStats, FRAME_PIXEL_COUNT};
+use num_traits::ToPrimitive;
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+/// Bytes in a frame package: 5 bytes of head, 2 bytes per pixel and CRC
+const FRAME_PACKAGE_SIZE: u64 = 5 + FRAME_PIXEL_COUNT as u64 * 2 + 2;
+/// UART sends a start and a stop bit along with every byte
+const BITS_PER_BYTE: f64 = 10.0;
+
+/// Result of streaming at a single baud rate
+#[derive(Debug, PartialEq)]
+struct Measurement {
+    baud: BaudRate,
+    frames: u64,
+    elapsed: Duration,
+    stats: StreamStats,
+}
+
+impl Measurement {
+    fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Bytes received per second, including garbage skipped while resyncing
+    fn throughput(&self) -> f64 {
+        let bytes = self.stats.packages * FRAME_PACKAGE_SIZE + self.stats.bytes_skipped;
+        bytes as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Share of what UART could carry at this baud rate that was actually used
+    fn line_use(&self) -> f64 {
+        let capacity = self.baud.to_f64().unwrap_or(f64::NAN) / BITS_PER_BYTE;
+        self.throughput() / capacity
+    }
+
+    /// Share of received frames that were rejected because of a wrong CRC
+    fn crc_error_rate(&self) -> f64 {
+        let received = self.frames + self.stats.crc_failures;
+        self.stats.crc_failures as f64 / received.max(1) as f64
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>7} {:>8.2} {:>9.1} {:>8.1}% {:>6} ({:.2}%) {:>8}",
+            self.baud.to_u32().unwrap_or_default(),
+            self.fps(),
+            self.throughput() / 1024.0,
+            self.line_use() * 100.0,
+            self.stats.crc_failures,
+            self.crc_error_rate() * 100.0,
+            self.stats.dropped_frames,
+        )
+    }
+}
+
+/// Time it takes UART to carry a single frame package
+fn frame_time(baud: BaudRate) -> Duration {
+    let bits = FRAME_PACKAGE_SIZE as f64 * BITS_PER_BYTE;
+    Duration::from_secs_f64(bits / baud.to_f64().unwrap_or(f64::NAN))
+}
+
+/// Fastest baud rate that didn't produce a single CRC error
+fn fastest_clean(measurements: &[Measurement]) -> Option<BaudRate> {
+    measurements
+        .iter()
+        .filter(|m| m.frames > 0 && m.stats.crc_failures == 0)
+        .max_by(|a, b| a.fps().total_cmp(&b.fps()))
+        .map(|m| m.baud)
+}
+
+/// Streams frames with CRC verification for `duration`
+fn measure(ccd: &mut SerialCCD, baud: BaudRate, duration: Duration) -> Result<Measurement> {
+    ccd.set_verify_crc(true);
+    // Responses to earlier queries would otherwise count towards throughput
+    ccd.reset_stats();
+    let mut frames = ccd.frames_iter()?;
+    let started = Instant::now();
+    let mut count = 0;
+    while started.elapsed() < duration && !interrupt::interrupted() {
+        match frames.next_flagged() {
+            Some(Ok(_)) => count += 1,
+            Some(Err(e)) => return Err(e.into()),
+            None => break,
+        }
+    }
+    let elapsed = started.elapsed();
+    let stats = *frames.stats();
+    frames.stop()?;
+    Ok(Measurement {
+        baud,
+        frames: count,
+        elapsed,
+        stats,
+    })
+}
+
+/// `benchmark` subcommand, streams for a while at every baud rate and reports how fast frames
+/// arrive. Only UART baud rate is changed, so over USB all rates are expected to perform the same
+pub fn run(conf: &BenchmarkConf) -> Result<()> {
+    let bauds = if conf.bauds.is_empty() {
+        BaudRate::ALL.to_vec()
+    } else {
+        conf.bauds.clone()
+    };
+    interrupt::install_handler()?;
+    let mut ccd = conf.serial.open_ccd()?;
+    let original = ccd.get_baudrate()?;
+    let mut current = original;
+    let mut measurements = Vec::new();
+    println!(
+        "{:>7} {:>8} {:>9} {:>9} {:>15} {:>8}",
+        "baud", "fps", "KiB/s", "line use", "CRC errors", "dropped"
+    );
+    for baud in bauds {
+        if interrupt::interrupted() {
+            break;
+        }
+        if baud != current {
+            tracing::info!("Switching UART to {baud} baud");
+            ccd.set_baudrate(baud)?;
+            // Rate is restored explicitly at the end, dropping CCD at old rate can't do it
+            ccd.keep_baudrate();
+            drop(ccd);
+            current = baud;
+            ccd = conf.serial.open_ccd_at(baud)?;
+            ccd.get_version().map_err(|e| {
+                eyre!("CCD stopped responding at {baud} baud, find it with --auto-baud: {e}")
+            })?;
+        }
+        let measurement = measure(&mut ccd, baud, conf.duration)?;
+        println!("{measurement}");
+        measurements.push(measurement);
+        // Pausing doesn't discard frames already on their way, which would then be taken as
+        // responses to the next command. Waiting them out and reconnecting gets rid of them
+        drop(ccd);
+        thread::sleep(frame_time(current) * 2);
+        ccd = conf.serial.open_ccd_at(current)?;
+    }
+    if current != original {
+        tracing::info!("Restoring UART to {original} baud");
+        ccd.set_baudrate(original)?;
+        ccd.keep_baudrate();
+    }
+    match fastest_clean(&measurements) {
+        Some(baud) => println!("Fastest baud rate without CRC errors: {baud}"),
+        None => println!("Every baud rate had CRC errors, check cabling"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        let measurement = |baud, frames, crc_failures| Measurement {
+            baud,
+            frames,
+            elapsed: Duration::from_secs(10),
+            stats: StreamStats {
+                packages: frames,
+                crc_failures,
+                ..Default::default()
+            },
+        };
+        let slow = measurement(BaudRate::Baud115200, 15, 0);
+        assert_eq!(slow.fps(), 1.5);
+        assert!((slow.line_use() - 0.963).abs() < 1e-3);
+        let fast = measurement(BaudRate::Baud921600, 110, 10);
+        assert_eq!(fast.crc_error_rate(), 10.0 / 120.0);
+        let medium = measurement(BaudRate::Baud384000, 50, 0);
+        assert_eq!(
+            fastest_clean(&[slow, medium, fast]),
+            Some(BaudRate::Baud384000)
+        );
+    }
+}
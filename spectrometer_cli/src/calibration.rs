@@ -0,0 +1,393 @@
+use crate::{cli::SetCalibrationConf, input};
+use ccd_lcamv06::{Frame, FRAME_PIXEL_COUNT};
+use serde::{Deserialize, Serialize};
+use simple_eyre::{eyre::eyre, Result};
+use std::{fs, path::PathBuf};
+
+/// Calibration of a single device, stored in
+/// `~/.local/share/spectrometer/calibrations/<serial>.toml`
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DeviceCalibration {
+    /// Polynomial coefficients converting pixel index into wavelength in nm, lowest order first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wavelength: Vec<f64>,
+    /// Polynomial coefficients mapping raw counts onto counts proportional to light, lowest order
+    /// first. Applied before any other correction, since response compresses near full scale
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nonlinearity: Vec<f64>,
+    /// Frame captured with no light reaching the sensor
+    pub dark: Option<Vec<u16>>,
+    /// Dark signal as a function of exposure time, measured with `characterize dark`. Replaces
+    /// dark frame whenever exposure time is set for capture
+    pub dark_current: Option<DarkCurrent>,
+    /// Frame captured under uniform illumination, used to even out pixel sensitivity
+    pub flat: Option<Vec<u16>>,
+    /// Per pixel factors evening out spectral response, computed with `calibration intensity`.
+    /// Only applied when asked for with --relative-intensity
+    pub intensity: Option<Vec<f64>>,
+}
+
+/// Dark counts of every pixel as a linear function of exposure time
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DarkCurrent {
+    /// Exposure times dark frames were captured at
+    pub exposures: Vec<u16>,
+    /// Dark counts at zero exposure time, mostly bias of ADC
+    pub offset: Vec<f64>,
+    /// Dark counts gained per unit of exposure time
+    pub slope: Vec<f64>,
+}
+
+impl DarkCurrent {
+    /// Least squares fit of dark frames averaged at different exposure times
+    pub fn fit(darks: &[(u16, Vec<f64>)]) -> Result<Self> {
+        let n = darks.len() as f64;
+        let mean_t = darks.iter().map(|(t, _)| *t as f64).sum::<f64>() / n;
+        let var_t: f64 = darks.iter().map(|(t, _)| (*t as f64 - mean_t).powi(2)).sum();
+        if darks.len() < 2 || var_t == 0.0 {
+            return Err(eyre!(
+                "Dark current needs dark frames at two or more different exposure times"
+            ));
+        }
+        let mut offset = Vec::with_capacity(FRAME_PIXEL_COUNT);
+        let mut slope = Vec::with_capacity(FRAME_PIXEL_COUNT);
+        for px in 0..FRAME_PIXEL_COUNT {
+            let mean_v = darks.iter().map(|(_, frame)| frame[px]).sum::<f64>() / n;
+            let cov: f64 = darks
+                .iter()
+                .map(|(t, frame)| (*t as f64 - mean_t) * (frame[px] - mean_v))
+                .sum();
+            let k = cov / var_t;
+            slope.push(k);
+            offset.push(mean_v - k * mean_t);
+        }
+        Ok(DarkCurrent {
+            exposures: darks.iter().map(|(t, _)| *t).collect(),
+            offset,
+            slope,
+        })
+    }
+
+    /// Dark frame expected at given exposure time
+    pub fn dark(&self, exposure: u16) -> Vec<u16> {
+        self.offset
+            .iter()
+            .zip(&self.slope)
+            .map(|(offset, slope)| {
+                (offset + slope * exposure as f64)
+                    .round()
+                    .clamp(0.0, u16::MAX as f64) as u16
+            })
+            .collect()
+    }
+}
+
+fn store_dir() -> Result<PathBuf> {
+    dirs::data_dir()
+        .map(|dir| dir.join("spectrometer").join("calibrations"))
+        .ok_or_else(|| eyre!("Could not find data directory for calibration store"))
+}
+
+/// Serial number reported by device is used as file name, so anything that could escape
+/// calibrations directory is replaced
+fn store_path(serial: &str) -> Result<PathBuf> {
+    let name = serial.replace(|c: char| !c.is_ascii_alphanumeric() && c != '-', "_");
+    Ok(store_dir()?.join(format!("{name}.toml")))
+}
+
+impl DeviceCalibration {
+    /// Loads calibration stored for device with given serial number, if there is one
+    pub fn load(serial: &str) -> Result<Option<Self>> {
+        let path = store_path(serial)?;
+        if !path.try_exists()? {
+            tracing::debug!("No calibration stored for device {serial}");
+            return Ok(None);
+        }
+        tracing::debug!("Loading calibration from {path:?}");
+        let calibration: Self = toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| eyre!("Could not parse calibration file {path:?}: {e}"))?;
+        calibration.validate()?;
+        Ok(Some(calibration))
+    }
+
+    pub fn save(&self, serial: &str) -> Result<PathBuf> {
+        self.validate()?;
+        let path = store_path(serial)?;
+        tracing::debug!("Saving calibration to {path:?}");
+        fs::create_dir_all(store_dir()?)?;
+        fs::write(&path, toml::to_string(self)?)?;
+        Ok(path)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (name, frame) in [("dark", &self.dark), ("flat", &self.flat)] {
+            match frame {
+                Some(frame) if frame.len() != FRAME_PIXEL_COUNT => {
+                    return Err(eyre!(
+                        "Calibration {name} frame has {} pixels, expected {FRAME_PIXEL_COUNT}",
+                        frame.len()
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if !self.nonlinearity.is_empty() {
+            let table = linearization_table(&self.nonlinearity);
+            if let Some(raw) = table.windows(2).position(|pair| pair[1] < pair[0]) {
+                return Err(eyre!(
+                    "Nonlinearity polynomial {:?} decreases after {raw} counts",
+                    self.nonlinearity
+                ));
+            }
+        }
+        if let Some(dark_current) = &self.dark_current {
+            if dark_current.offset.len() != FRAME_PIXEL_COUNT
+                || dark_current.slope.len() != FRAME_PIXEL_COUNT
+            {
+                return Err(eyre!(
+                    "Calibration dark current has {} offsets and {} slopes, expected \
+                     {FRAME_PIXEL_COUNT} of each",
+                    dark_current.offset.len(),
+                    dark_current.slope.len()
+                ));
+            }
+        }
+        match &self.intensity {
+            Some(intensity) if intensity.len() != FRAME_PIXEL_COUNT => Err(eyre!(
+                "Calibration intensity curve has {} pixels, expected {FRAME_PIXEL_COUNT}",
+                intensity.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lines describing applied calibration, recorded in capture header
+    pub fn metadata(&self) -> Vec<String> {
+        let mut metadata = Vec::new();
+        if !self.wavelength.is_empty() {
+            metadata.push(format!("wavelength coefficients: {:?}", self.wavelength));
+        }
+        if !self.nonlinearity.is_empty() {
+            metadata.push(format!(
+                "nonlinearity coefficients: {:?}",
+                self.nonlinearity
+            ));
+        }
+        if let Some(dark_current) = &self.dark_current {
+            metadata.push(format!(
+                "dark current characterized at exposure times: {:?}",
+                dark_current.exposures
+            ));
+        }
+        if self.dark.is_some() {
+            metadata.push("dark frame subtracted".to_string());
+        }
+        if self.flat.is_some() {
+            metadata.push("flat field corrected".to_string());
+        }
+        if self.intensity.is_some() {
+            metadata.push("relative intensity corrected".to_string());
+        }
+        metadata
+    }
+
+    /// Precomputes per pixel corrections, so applying them to every frame stays cheap
+    pub fn correction(&self) -> Correction {
+        let linear =
+            (!self.nonlinearity.is_empty()).then(|| linearization_table(&self.nonlinearity));
+        // Reference frames are raw readings too, so they are linearized just like measurements
+        let linearize = |frame: &Vec<u16>| -> Vec<u16> {
+            match &linear {
+                Some(table) => frame.iter().map(|&px| table[px as usize]).collect(),
+                None => frame.clone(),
+            }
+        };
+        let dark = self.dark.as_ref().map(linearize);
+        let flat = self.flat.as_ref().map(|flat| {
+            let signal: Vec<f64> = linearize(flat)
+                .iter()
+                .enumerate()
+                .map(|(i, px)| {
+                    let dark = dark.as_ref().map_or(0, |dark| dark[i]);
+                    px.saturating_sub(dark) as f64
+                })
+                .collect();
+            let mean = signal.iter().sum::<f64>() / signal.len() as f64;
+            // Dead pixels in flat frame are left as is rather than blown up
+            signal
+                .iter()
+                .map(|&px| if px > 0.0 { mean / px } else { 1.0 })
+                .collect::<Vec<f64>>()
+        });
+        let gain = match (flat, &self.intensity) {
+            (Some(flat), Some(intensity)) => {
+                Some(flat.iter().zip(intensity).map(|(f, i)| f * i).collect())
+            }
+            (flat, intensity) => flat.or_else(|| intensity.clone()),
+        };
+        Correction { linear, dark, gain }
+    }
+
+    /// Updates stored calibration with values passed on command line
+    pub fn update(conf: &SetCalibrationConf) -> Result<()> {
+        let mut calibration = Self::load(&conf.serial_number)?.unwrap_or_default();
+        if !conf.wavelength_coeffs.is_empty() {
+            calibration.wavelength = conf.wavelength_coeffs.clone();
+        }
+        if !conf.nonlinearity_coeffs.is_empty() {
+            calibration.nonlinearity = conf.nonlinearity_coeffs.clone();
+        }
+        if let Some(path) = &conf.dark {
+            calibration.dark = Some(input::read_frame(path)?);
+        }
+        if let Some(path) = &conf.flat {
+            calibration.flat = Some(input::read_frame(path)?);
+        }
+        let path = calibration.save(&conf.serial_number)?;
+        println!("Saved calibration to {}", path.display());
+        Ok(())
+    }
+
+    pub fn show(serial: &str) -> Result<()> {
+        let calibration = Self::load(serial)?
+            .ok_or_else(|| eyre!("No calibration stored for device {serial}"))?;
+        println!("Stored in: {}", store_path(serial)?.display());
+        for line in calibration.metadata() {
+            println!("{line}");
+        }
+        Ok(())
+    }
+}
+
+/// Dark subtraction and flat field correction ready to be applied to frames
+pub struct Correction {
+    /// Linearized value for every possible raw count
+    linear: Option<Vec<u16>>,
+    dark: Option<Vec<u16>>,
+    gain: Option<Vec<f64>>,
+}
+
+impl Correction {
+    pub fn apply(&self, frame: &mut Frame) {
+        if let Some(linear) = &self.linear {
+            for px in frame.iter_mut() {
+                *px = linear[*px as usize];
+            }
+        }
+        if let Some(dark) = &self.dark {
+            subtract_dark(frame, dark);
+        }
+        if let Some(gain) = &self.gain {
+            for (px, gain) in frame.iter_mut().zip(gain) {
+                *px = (*px as f64 * gain).round().min(u16::MAX as f64) as u16;
+            }
+        }
+    }
+}
+
+/// Evaluates nonlinearity polynomial for every raw count, clamped to u16 range
+fn linearization_table(coeffs: &[f64]) -> Vec<u16> {
+    (0..=u16::MAX)
+        .map(|raw| {
+            let raw = raw as f64;
+            let linear = coeffs.iter().rev().fold(0.0, |acc, c| acc * raw + c);
+            linear.round().clamp(0.0, u16::MAX as f64) as u16
+        })
+        .collect()
+}
+
+/// Subtracts dark frame pixel by pixel, clamping at zero
+pub fn subtract_dark(frame: &mut Frame, dark: &[u16]) {
+    for (px, dark) in frame.iter_mut().zip(dark) {
+        *px = px.saturating_sub(*dark);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtract_dark_frame() {
+        let mut frame: Frame = [100; FRAME_PIXEL_COUNT];
+        let mut dark = vec![30; FRAME_PIXEL_COUNT];
+        dark[0] = 500;
+        subtract_dark(&mut frame, &dark);
+        assert_eq!(frame[0], 0);
+        assert_eq!(frame[1], 70);
+    }
+
+    #[test]
+    fn flat_field_correction() {
+        let mut flat = vec![200; FRAME_PIXEL_COUNT];
+        flat[0] = 400;
+        flat[1] = 0;
+        let calibration = DeviceCalibration {
+            dark: Some(vec![10; FRAME_PIXEL_COUNT]),
+            flat: Some(flat),
+            ..Default::default()
+        };
+        let mut frame: Frame = [110; FRAME_PIXEL_COUNT];
+        calibration.correction().apply(&mut frame);
+        // Twice as sensitive pixel is halved, dead one is only dark subtracted
+        assert!(frame[0] < frame[2]);
+        assert_eq!(frame[1], 100);
+        assert_eq!(
+            toml::from_str::<DeviceCalibration>(&toml::to_string(&calibration).unwrap()).unwrap(),
+            calibration
+        );
+    }
+
+    #[test]
+    fn dark_current_fit() {
+        let darks: Vec<(u16, Vec<f64>)> = [10, 20, 40]
+            .into_iter()
+            .map(|t| {
+                let mut frame = vec![100.0 + 2.0 * t as f64; FRAME_PIXEL_COUNT];
+                frame[5] = 300.0;
+                (t, frame)
+            })
+            .collect();
+        let dark_current = DarkCurrent::fit(&darks).unwrap();
+        assert!((dark_current.slope[0] - 2.0).abs() < 1e-9);
+        assert!(dark_current.slope[5].abs() < 1e-9);
+        let dark = dark_current.dark(100);
+        assert_eq!((dark[0], dark[5]), (300, 300));
+        assert!(DarkCurrent::fit(&darks[..1]).is_err());
+
+        let calibration = DeviceCalibration {
+            dark_current: Some(dark_current),
+            flat: Some(vec![200; FRAME_PIXEL_COUNT]),
+            ..Default::default()
+        };
+        calibration.validate().unwrap();
+        assert_eq!(
+            toml::from_str::<DeviceCalibration>(&toml::to_string(&calibration).unwrap()).unwrap(),
+            calibration
+        );
+    }
+
+    #[test]
+    fn nonlinearity_correction() {
+        // Response compressing towards full scale, 40000 raw counts are really 48000
+        let calibration = DeviceCalibration {
+            nonlinearity: vec![0.0, 1.0, 5e-6],
+            dark: Some(vec![1000; FRAME_PIXEL_COUNT]),
+            ..Default::default()
+        };
+        calibration.validate().unwrap();
+        let mut frame: Frame = [40000; FRAME_PIXEL_COUNT];
+        frame[0] = 1000;
+        calibration.correction().apply(&mut frame);
+        assert_eq!(frame[0], 0);
+        assert_eq!(frame[1], 48000 - 1005);
+
+        let decreasing = DeviceCalibration {
+            nonlinearity: vec![0.0, 1.0, -1e-4],
+            ..Default::default()
+        };
+        assert!(decreasing.validate().is_err());
+    }
+}
@@ -0,0 +1,524 @@
+#[cfg(feature = "script")]
+use crate::script::FrameScript;
+use crate::{
+    alert::{AlertRule, Alerts},
+    calibration::{Correction, DeviceCalibration},
+    cli::ProcessingConf,
+    interrupt,
+    output::{self, FrameWriter, Header, Output},
+    pipeline::{self, Pipeline},
+    serial::{CaptureConf, SerialCCD, SerialConf, StreamConf},
+};
+use ccd_lcamv06::{sensors, Frame, QualityFlags, QualityThresholds, StreamStats, VersionDetails};
+use indicatif::{ProgressBar, ProgressStyle};
+use simple_eyre::{Report, Result};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// How often `interrupted` is checked while waiting for the next scheduled reading
+const INTERRUPT_POLL: Duration = Duration::from_millis(100);
+
+/// Summary of a continuous capture, frames themselves are passed on as soon as they arrive
+pub struct Capture {
+    /// Amount of frames passed on and kept by sink
+    pub captured: usize,
+    /// Times at which broken frames were noticed and skipped
+    pub dropped: Vec<OffsetDateTime>,
+    /// Error that ended capture early
+    pub error: Option<Report>,
+}
+
+impl Capture {
+    /// Continuously reads up to `count` frames, or until stopped without one, and passes them to
+    /// `sink` along with their quality flags, stopping early on Ctrl-C or an error. Only frames
+    /// `sink` returns true for, meaning they were kept, count towards `count`
+    pub fn run<F>(
+        ccd: &mut SerialCCD,
+        count: Option<usize>,
+        stream: &StreamConf,
+        thresholds: QualityThresholds,
+        sink: F,
+    ) -> Capture
+    where
+        F: FnMut(Frame, QualityFlags) -> simple_eyre::Result<bool>,
+    {
+        Self::run_until(ccd, count, stream, thresholds, || false, sink)
+    }
+
+    /// Same as [Capture::run], but also stops once `stopped` returns true, which is checked
+    /// before every frame
+    pub fn run_until<F>(
+        ccd: &mut SerialCCD,
+        count: Option<usize>,
+        stream: &StreamConf,
+        thresholds: QualityThresholds,
+        stopped: impl Fn() -> bool,
+        mut sink: F,
+    ) -> Capture
+    where
+        F: FnMut(Frame, QualityFlags) -> simple_eyre::Result<bool>,
+    {
+        let mut capture = Capture {
+            captured: 0,
+            dropped: Vec::new(),
+            error: None,
+        };
+        let mut frames = match ccd.frames_iter() {
+            Ok(frames) => frames,
+            Err(e) => {
+                capture.error = Some(e.into());
+                return capture;
+            }
+        };
+        stream.apply(&mut frames);
+        frames.set_quality_thresholds(thresholds);
+        let progress = match count {
+            Some(count) => progress_bar(count),
+            None => ProgressBar::hidden(),
+        };
+        let mut dropped = frames.stats().dropped_frames;
+        while count.is_none_or(|count| capture.captured < count)
+            && !interrupt::interrupted()
+            && !stopped()
+        {
+            let res = frames.next_flagged();
+            // Drops are only noticed once the next good frame or an error arrives
+            let now_dropped = frames.stats().dropped_frames;
+            capture.record_dropped(now_dropped - dropped);
+            dropped = now_dropped;
+            let res = match res {
+                Some(Ok((frame, flags))) => sink(frame, flags),
+                Some(Err(e)) => Err(e.into()),
+                None => break,
+            };
+            match res {
+                Ok(true) => {
+                    capture.captured += 1;
+                    progress.inc(1);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    capture.error = Some(e);
+                    break;
+                }
+            }
+            progress.set_message(status(frames.stats()));
+        }
+        progress.finish();
+        // Error that interrupted capture is more relevant than a failure to pause
+        if let Err(e) = frames.stop() {
+            capture.error.get_or_insert(e.into());
+        }
+        capture
+    }
+
+    /// Takes a single frame every `every`, up to `count` frames or for as long as `until` since
+    /// start, stopping early on Ctrl-C or an error. Readings that would fall on a slot already
+    /// passed, because previous one took too long, are skipped instead of bunched up
+    pub fn run_interval<F>(
+        ccd: &mut SerialCCD,
+        every: Duration,
+        count: Option<usize>,
+        until: Option<Duration>,
+        thresholds: QualityThresholds,
+        mut sink: F,
+    ) -> Capture
+    where
+        F: FnMut(Frame, QualityFlags) -> simple_eyre::Result<bool>,
+    {
+        let mut capture = Capture {
+            captured: 0,
+            dropped: Vec::new(),
+            error: None,
+        };
+        let slots = until.map(|until| until.as_nanos().div_ceil(every.as_nanos()) as usize);
+        let total = match (count, slots) {
+            (Some(count), Some(slots)) => Some(count.min(slots)),
+            (count, slots) => count.or(slots),
+        };
+        let progress = match total {
+            Some(total) => progress_bar(total),
+            None => ProgressBar::hidden(),
+        };
+        let start = Instant::now();
+        let mut slot = 0;
+        let pixels = ccd.sensor_layout().pixels();
+        while total.is_none_or(|total| capture.captured < total) {
+            if !sleep_until(start + every * slot as u32) {
+                break;
+            }
+            let resyncs = ccd.stats().resyncs;
+            let res = match ccd.get_frame() {
+                Ok(frame) => {
+                    let flags = QualityFlags {
+                        crc_resynced: ccd.stats().resyncs > resyncs,
+                        averaged: ccd.avg_time().is_some_and(|t| t > 1),
+                        ..QualityFlags::assess(&frame[..pixels], &thresholds)
+                    };
+                    sink(frame, flags)
+                }
+                Err(e) => Err(e.into()),
+            };
+            match res {
+                Ok(true) => {
+                    capture.captured += 1;
+                    progress.inc(1);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    capture.error = Some(e);
+                    break;
+                }
+            }
+            slot = (start.elapsed().as_nanos() / every.as_nanos()) as usize + 1;
+            if slots.is_some_and(|slots| slot >= slots) {
+                break;
+            }
+        }
+        progress.finish();
+        capture
+    }
+
+    fn record_dropped(&mut self, count: u64) {
+        if count == 0 {
+            return;
+        }
+        tracing::debug!("Skipped {count} broken frames");
+        let now = output::now();
+        self.dropped
+            .extend(std::iter::repeat_n(now, count as usize));
+    }
+
+    /// Human readable summary of dropped frames, if there were any
+    pub fn dropped_note(&self) -> Option<String> {
+        if self.dropped.is_empty() {
+            return None;
+        }
+        let timestamps: Vec<_> = self
+            .dropped
+            .iter()
+            .map(|t| t.format(&Rfc3339).unwrap_or_else(|_| t.to_string()))
+            .collect();
+        Some(format!(
+            "Dropped {} frames at: {}",
+            self.dropped.len(),
+            timestamps.join(", ")
+        ))
+    }
+}
+
+/// Frame after calibration and processing, ready to be written out
+pub struct Processed {
+    pub frame: Frame,
+    /// Frame as it came from device, kept when calibration changed it
+    pub raw: Option<Frame>,
+    pub flags: QualityFlags,
+}
+
+/// Steps every captured frame goes through before it's written: stored calibration, then
+/// pipeline stages, script and alert rules given with [ProcessingConf]
+pub struct FrameProcessor<'a> {
+    correction: Option<Correction>,
+    pipeline: Pipeline,
+    #[cfg(feature = "script")]
+    script: Option<FrameScript>,
+    alerts: Alerts<'a>,
+    /// Pixels filled by sensor, pipeline leaves the rest of a frame alone
+    pixels: usize,
+    /// Frames processed so far, dropped ones included
+    seq: usize,
+}
+
+impl<'a> FrameProcessor<'a> {
+    /// Loads pipeline and script, noting pipeline in `header` before script gets to see it
+    pub fn new(
+        conf: &ProcessingConf,
+        rules: &'a [AlertRule],
+        calibration: Option<&DeviceCalibration>,
+        pixels: usize,
+        header: &mut Header,
+    ) -> Result<Self> {
+        let pipeline = conf
+            .pipeline
+            .as_deref()
+            .map(pipeline::load)
+            .transpose()?
+            .unwrap_or_default();
+        pipeline.note(&mut header.metadata);
+        #[cfg(feature = "script")]
+        let script = conf
+            .script
+            .as_deref()
+            .map(|path| FrameScript::load(path, &header.metadata))
+            .transpose()?;
+        Ok(FrameProcessor {
+            correction: calibration.map(DeviceCalibration::correction),
+            pipeline,
+            #[cfg(feature = "script")]
+            script,
+            alerts: Alerts::new(rules),
+            pixels,
+            seq: 0,
+        })
+    }
+
+    /// Runs frame through every step, `None` if script dropped it
+    pub fn process(
+        &mut self,
+        mut frame: Frame,
+        mut flags: QualityFlags,
+    ) -> Result<Option<Processed>> {
+        let raw = self.correction.as_ref().map(|correction| {
+            let raw = frame;
+            correction.apply(&mut frame);
+            raw
+        });
+        self.pipeline.apply(&mut frame[..self.pixels]);
+        self.seq += 1;
+        if !self.run_script(&mut frame, &mut flags)? {
+            return Ok(None);
+        }
+        self.alerts.check(&frame, flags);
+        Ok(Some(Processed { frame, raw, flags }))
+    }
+
+    /// Passes frame to script, if there is one, false if script dropped it
+    #[cfg(feature = "script")]
+    fn run_script(&mut self, frame: &mut Frame, flags: &mut QualityFlags) -> Result<bool> {
+        match &mut self.script {
+            Some(script) => script.process(frame, flags, self.seq, output::now()),
+            None => Ok(true),
+        }
+    }
+
+    /// Without `script` feature frames are never dropped
+    #[cfg(not(feature = "script"))]
+    fn run_script(&mut self, _: &mut Frame, _: &mut QualityFlags) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Applies capture settings to CCD and looks up what frames are described and corrected with:
+/// connected device and its calibration
+pub fn prepare_capture(
+    ccd: &mut SerialCCD,
+    capture: &CaptureConf,
+) -> Result<(VersionDetails, Option<DeviceCalibration>)> {
+    capture.apply(ccd)?;
+    // Average time is only known to CCD once read, frames taken with it above 1 are flagged
+    ccd.get_avg_time()?;
+    let version = ccd.get_version()?;
+    let calibration = capture.calibration(&version)?;
+    Ok((version, calibration))
+}
+
+/// Header describing capture settings, connected device and its calibration. Frames have
+/// `pixels` real pixels, which is less than [Frame] has room for on smaller sensors
+pub fn capture_header(
+    serial: &SerialConf,
+    capture: &CaptureConf,
+    version: &VersionDetails,
+    calibration: Option<&DeviceCalibration>,
+    pixels: usize,
+) -> Header {
+    let mut metadata = vec![
+        format!("software version: {}", env!("CARGO_PKG_VERSION")),
+        format!("serial port: {}", serial.serial),
+    ];
+    metadata.extend(device_metadata(version));
+    if let Some(exposure_time) = capture.exposure_time {
+        metadata.push(format!("exposure time: {exposure_time}"));
+    }
+    if let Some(calibration) = calibration {
+        metadata.extend(calibration.metadata());
+    }
+    let wavelength = calibration.map_or_else(Vec::new, |c| c.wavelength.clone());
+    let laser = match capture.laser {
+        Some(_) if wavelength.is_empty() => {
+            tracing::warn!("Raman shift needs wavelength calibration, --laser is ignored");
+            None
+        }
+        laser => laser,
+    };
+    if let Some(laser) = laser {
+        metadata.push(format!("laser wavelength: {laser} nm"));
+    }
+    Header {
+        metadata,
+        wavelength,
+        laser,
+        pixels,
+        #[cfg(feature = "hdf5")]
+        dark: calibration.and_then(|c| c.dark.clone()),
+        #[cfg(feature = "hdf5")]
+        flat: calibration.and_then(|c| c.flat.clone()),
+    }
+}
+
+/// Closes output and notes in it how capture went, if it was cut short
+pub fn finish_capture(
+    output: &Output,
+    writer: FrameWriter,
+    capture: Capture,
+    count: Option<usize>,
+) -> Result<()> {
+    // Whatever was captured before an error is still worth saving. Failure to write also stops
+    // capture, in which case writer has the actual reason
+    let written = writer.finish()?;
+    tracing::debug!("Written {} frames", written.frames);
+    if let Some(count) = count.filter(|count| capture.captured < *count) {
+        let note = format!(
+            "Capture interrupted after {} of {} frames",
+            capture.captured,
+            count
+        );
+        tracing::warn!("{note}");
+        output.append_note(&written.path, &note)?;
+    }
+    if let Some(note) = capture.dropped_note() {
+        tracing::warn!("{note}");
+        output.append_note(&written.path, &note)?;
+    }
+
+    capture.error.map_or(Ok(()), Err)
+}
+
+/// Describes connected device for capture header, including sensor properties if it's a known one
+pub fn device_metadata(version: &VersionDetails) -> Vec<String> {
+    let mut metadata = vec![
+        format!("device serial number: {}", version.serial_number()),
+        format!("firmware version: {}", version.firmware_version()),
+        format!("sensor: {}", version.sensor_type()),
+    ];
+    if let Some(sensor) = sensors::lookup(version.sensor_type()) {
+        metadata.push(format!("pixel pitch: {} um", sensor.pixel_pitch_um));
+        let (from, to) = sensor.spectral_range_nm;
+        metadata.push(format!("spectral range: {from}-{to} nm"));
+    }
+    metadata
+}
+
+/// Waits until `deadline`, returning false if interrupted with Ctrl-C in the meantime
+pub fn sleep_until(deadline: Instant) -> bool {
+    loop {
+        if interrupt::interrupted() {
+            return false;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(INTERRUPT_POLL));
+    }
+}
+
+/// Progress line on stderr, hidden when it isn't an interactive terminal
+fn progress_bar(count: usize) -> ProgressBar {
+    if !atty::is(atty::Stream::Stderr) {
+        return ProgressBar::hidden();
+    }
+    let progress = ProgressBar::new(count as u64);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{elapsed_precise} [{bar:30}] {pos}/{len} frames, {per_sec}, ETA {eta}, {msg}",
+        )
+        .expect("progress template is valid")
+        .progress_chars("=> "),
+    );
+    progress
+}
+
+fn status(stats: &StreamStats) -> String {
+    format!(
+        "{} CRC errors, {} dropped",
+        stats.crc_failures, stats.dropped_frames
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sniff::TeePort;
+    use ccd_lcamv06::{
+        testing::MockTransport, transport::Transport, Command, IoAdapter, StdIoAdapter,
+        FRAME_PIXEL_COUNT,
+    };
+    use std::num::NonZeroUsize;
+    use time::macros::datetime;
+
+    #[test]
+    fn describe_dropped_frames() {
+        let mut capture = Capture {
+            captured: 0,
+            dropped: Vec::new(),
+            error: None,
+        };
+        assert_eq!(capture.dropped_note(), None);
+        capture.dropped = vec![
+            datetime!(2023-05-01 12:00:00 UTC),
+            datetime!(2023-05-01 12:00:01.5 UTC),
+        ];
+        assert_eq!(
+            capture.dropped_note().unwrap(),
+            "Dropped 2 frames at: 2023-05-01T12:00:00Z, 2023-05-01T12:00:01.5Z"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "script")]
+    fn count_dropped_frames_in_sequence() {
+        let path = std::env::temp_dir().join(format!("processor-{}.rhai", std::process::id()));
+        std::fs::write(&path, "pixels[0] = frame; drop = frame == 1;").unwrap();
+        let conf = ProcessingConf {
+            pipeline: None,
+            alerts: None,
+            script: Some(path.clone()),
+        };
+        let mut header = Header::default();
+        let mut processor =
+            FrameProcessor::new(&conf, &[], None, FRAME_PIXEL_COUNT, &mut header).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let flags = QualityFlags::default();
+        let frame = [100; FRAME_PIXEL_COUNT];
+        assert!(processor.process(frame, flags).unwrap().is_none());
+        let processed = processor.process(frame, flags).unwrap().unwrap();
+        assert_eq!(processed.frame[0], 2);
+        // Without calibration there's nothing to keep raw frame for
+        assert_eq!(processed.raw, None);
+    }
+
+    #[test]
+    fn dropped_frames_are_replaced() {
+        let frames = (0..4).map(|i| [i; FRAME_PIXEL_COUNT]);
+        let mock = MockTransport::new()
+            .expect_frames(Command::ContinuousRead, frames)
+            .expect(Command::PauseRead, []);
+        let port: Box<dyn Transport> = Box::new(mock.clone());
+        let mut ccd = StdIoAdapter::new(TeePort::new(port)).open_ccd();
+        let stream = StreamConf {
+            every: NonZeroUsize::MIN,
+            max_fps: None,
+        };
+        let mut kept = Vec::new();
+        let capture = Capture::run(
+            &mut ccd,
+            Some(2),
+            &stream,
+            QualityThresholds::default(),
+            |frame, _| {
+                let keep = frame[0] % 2 == 1;
+                if keep {
+                    kept.push(frame[0]);
+                }
+                Ok(keep)
+            },
+        );
+        assert!(capture.error.is_none());
+        assert_eq!(capture.captured, 2);
+        assert_eq!(kept, [1, 3]);
+        mock.assert_done();
+    }
+}
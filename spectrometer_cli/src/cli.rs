@@ -1,11 +1,35 @@
 use ccd_lcamv06::{BaudRate, error::Error};
 use clap::{Args, Parser, Subcommand};
 use num_traits::FromPrimitive;
-use crate::{output::Output, serial::SerialConf};
+use crate::{
+    alert::{self, AlertRule},
+    analysis::{parse_laser, Grid, Interpolation},
+    compress::Compression,
+    config,
+    csv::CsvDialect,
+    input::InputFormat,
+    logging::LogConf,
+    output::{output_path_parser, unique_path_parser, Output, OutputFormat},
+    plot::{plot_path_parser, waterfall_path_parser, PlotConf},
+    reference::ReferenceKind,
+    resolution::Line,
+    rotate::{parse_duration, Rotation},
+    serial::{CaptureConf, QualityConf, SerialConf, StreamConf},
+    trace::{parse_band, Band},
+    wavelength::{Lamp, Span},
+};
+use std::{path::PathBuf, time::Duration};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 pub struct Cli {
+    /// Named profile from config file providing defaults for other options
+    #[clap(long, global = true, value_parser, env = config::PROFILE_ENV)]
+    pub profile: Option<String>,
+
+    #[clap(flatten)]
+    pub log: LogConf,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -13,9 +37,17 @@ pub struct Cli {
 #[derive(Subcommand)]
 pub enum Commands {
     /// Lists connected serial devices
-    List,
+    List(ListConf),
+    /// Watch for serial devices being connected and disconnected
+    Watch(WatchConf),
     /// Get version info from CCD
     CCDVersion(SerialConf),
+    /// Report version, timing settings, baud rate and stored calibration of a device at once
+    Info(InfoConf),
+    /// Run a self-test of port, baud rate, settings and reading, printing a pass/fail report
+    Doctor(DoctorConf),
+    /// Measure frame rate and CRC errors at every UART baud rate, to pick one cabling can handle
+    Benchmark(BenchmarkConf),
     /// Get readings from spectrometer
     Read(ReadCommand),
     /// Configure baud rate for UART, which is separate from USB port
@@ -24,6 +56,124 @@ pub enum Commands {
     AverageTime(AvgTimeCommand),
     /// "Exposure time" related commands, not sure how that's different from "average time"
     ExposureTime(ExpTimeCommand),
+    /// Save or restore all writable device settings as a TOML profile
+    Settings(SettingsCommand),
+    /// Bundle readings together with device metadata into a single session file
+    Session(SessionCommand),
+    /// Convert previously captured files into another format
+    Convert(ConvertConf),
+    /// Replay traffic recorded with --dump-raw through response parser
+    Decode(DecodeConf),
+    /// Manage calibrations applied automatically to devices with matching serial number
+    Calibration(CalibrationCommand),
+    /// Capture dark and white reference frames, later applied with --apply-reference
+    Reference(ReferenceCommand),
+    /// Measure how sensor behaves across settings and store results with calibration
+    Characterize(CharacterizeCommand),
+    /// Run acquisitions on cron-like schedules from a config file until stopped
+    Daemon(DaemonConf),
+    /// Run commands on a spectrometer attached to a spectrometer_sbc server
+    Remote(RemoteConf),
+    /// Render a frame from capture file into PNG or SVG plot
+    Plot(PlotFileConf),
+    /// Serve a minimal SCPI dialect over TCP for lab automation software
+    Scpi(ScpiConf),
+    /// Summarize intensity, saturation and peak drift of a capture file
+    Stats(StatsConf),
+    /// Compare a spectrum against a reference one, reporting deviation and peak shifts
+    Compare(CompareConf),
+    /// Measure FWHM of a spectral line in a capture file
+    Resolution(ResolutionConf),
+    /// Show distribution of pixel values in a capture file, as text or PNG/SVG plot
+    Histogram(HistogramConf),
+    /// Keep checking spectrometer over time, alerting when it drifts away from expected state
+    Monitor(MonitorCommand),
+    /// Check output files against manifests written next to them, finding truncated ones
+    Verify(VerifyConf),
+}
+
+#[derive(Args)]
+pub struct ListConf {
+    /// Query every port for CCD version info to find out which of them are spectrometers
+    #[clap(short, long)]
+    pub probe: bool,
+
+    /// Print listing as JSON
+    #[clap(long)]
+    pub json: bool,
+
+    #[clap(flatten)]
+    pub probe_conf: ProbeConf,
+}
+
+#[derive(Args)]
+pub struct InfoConf {
+    /// Print report as JSON
+    #[clap(long)]
+    pub json: bool,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct DoctorConf {
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct BenchmarkConf {
+    /// How long frames are streamed at each baud rate, e.g. 10s
+    #[clap(long, value_parser = parse_duration, default_value = "10s")]
+    pub duration: Duration,
+
+    /// Baud rates to measure, all supported ones if omitted
+    #[clap(long, value_parser = parse_baud_rate, use_value_delimiter = true)]
+    pub bauds: Vec<BaudRate>,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct ProbeConf {
+    /// How long to wait for a response from each port while probing, in milliseconds
+    #[clap(long, value_parser, default_value = "500")]
+    pub probe_timeout: u64,
+
+    /// Baud rate used while probing
+    #[clap(short, long, value_parser = parse_baud_rate, default_value_t, env = "SPECTRO_BAUD")]
+    pub baud: BaudRate,
+}
+
+impl ProbeConf {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout)
+    }
+}
+
+#[derive(Args)]
+pub struct WatchConf {
+    /// How often list of serial ports is checked, in milliseconds
+    #[clap(long, value_parser, default_value = "1000")]
+    pub interval: u64,
+
+    /// Query newly connected ports for CCD version info
+    #[clap(short, long)]
+    pub probe: bool,
+
+    /// Only react to a CCD with this serial number, implies --probe
+    #[clap(long, value_parser)]
+    pub serial_number: Option<String>,
+
+    /// Shell command started when a CCD is connected, port path is passed in SPECTRO_SERIAL
+    /// environment variable. Implies --probe
+    #[clap(long, value_parser)]
+    pub on_connect: Option<String>,
+
+    #[clap(flatten)]
+    pub probe_conf: ProbeConf,
 }
 
 #[derive(Args)]
@@ -37,7 +187,15 @@ pub enum ReadCommands {
     /// Get a single frame
     Single(SingleReadingConf),
     /// Get multiple frames
-    Multi(MultiReadingConf)
+    Multi(MultiReadingConf),
+    /// Get single frames on a schedule, for processes too slow for continuous reading
+    Interval(IntervalReadingConf),
+    /// Show continuously updated spectrum in terminal
+    Live(LiveReadingConf),
+    /// Capture at several exposure times and merge them into a high dynamic range spectrum
+    Hdr(HdrReadingConf),
+    /// Record integrated intensity of a band over time, e.g. for reaction monitoring
+    Trace(TraceReadingConf),
 }
 
 #[derive(Args)]
@@ -45,10 +203,49 @@ pub struct SingleReadingConf {
     #[clap(flatten)]
     pub output: Output,
 
+    #[clap(flatten)]
+    pub plot: PlotConf,
+
+    #[clap(flatten)]
+    pub processing: ProcessingConf,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub quality: QualityConf,
+
     #[clap(flatten)]
     pub serial: SerialConf,
 }
 
+/// Processing applied to every captured frame, after stored calibration
+#[derive(Args)]
+pub struct ProcessingConf {
+    /// TOML file with `[pipeline]` of processing stages applied to every frame, same as in
+    /// daemon config
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub pipeline: Option<PathBuf>,
+
+    /// TOML file with `[[alert]]` rules checked against every frame, same as in daemon config
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub alerts: Option<PathBuf>,
+
+    /// Rhai script run on every frame before it's written, which can change its pixels and
+    /// flags or drop it. Dropped frames don't count towards frame count, more are read instead
+    #[cfg(feature = "script")]
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub script: Option<PathBuf>,
+}
+
+impl ProcessingConf {
+    /// Rules from --alerts, none if it wasn't given
+    pub fn alert_rules(&self) -> simple_eyre::Result<Vec<AlertRule>> {
+        let rules = self.alerts.as_deref().map(alert::load).transpose()?;
+        Ok(rules.unwrap_or_default())
+    }
+}
+
 #[derive(Args)]
 pub struct MultiReadingConf {
     /// Amount of frames captured
@@ -58,6 +255,222 @@ pub struct MultiReadingConf {
     #[clap(flatten)]
     pub output: Output,
 
+    #[clap(flatten)]
+    pub plot: PlotConf,
+
+    /// Also render a PNG heatmap of intensity by pixel and time, showing drift over the capture
+    #[clap(long, value_parser = waterfall_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub waterfall: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub quality: QualityConf,
+
+    #[clap(flatten)]
+    pub stream: StreamConf,
+
+    /// Roll over to a new output file after given interval (e.g. 30s, 10min, 1h) or amount of
+    /// frames (e.g. 1000frames). Output path has to contain `{seq}` or `{date}` placeholder
+    #[clap(long, value_parser)]
+    pub rotate: Option<Rotation>,
+
+    /// Carry on with an interrupted rotated capture into the same output path: segments already
+    /// there are kept, numbering continues after them and frames in complete ones count
+    /// towards frame count
+    #[clap(long, requires = "rotate")]
+    pub resume: bool,
+
+    #[clap(flatten)]
+    pub processing: ProcessingConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct IntervalReadingConf {
+    /// Time between readings, e.g. 500ms, 5s, 10min
+    #[clap(long, value_parser = parse_duration)]
+    pub every: Duration,
+
+    /// Stop after this many frames
+    #[clap(long, value_parser)]
+    pub count: Option<usize>,
+
+    /// Stop once this much time has passed since the first reading, e.g. 2h
+    #[clap(long, value_parser = parse_duration)]
+    pub until: Option<Duration>,
+
+    #[clap(flatten)]
+    pub output: Output,
+
+    #[clap(flatten)]
+    pub plot: PlotConf,
+
+    /// Also render a PNG heatmap of intensity by pixel and time, showing drift over the capture
+    #[clap(long, value_parser = waterfall_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub waterfall: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub processing: ProcessingConf,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub quality: QualityConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct HdrReadingConf {
+    /// Exposure times to capture at, e.g. 2,20,200
+    #[clap(long, value_parser, use_value_delimiter = true, required = true)]
+    pub exposures: Vec<u16>,
+
+    /// Frames averaged at every exposure time
+    #[clap(short, long, value_parser, default_value = "1")]
+    pub count: usize,
+
+    /// Raw counts at which pixel is treated as saturated
+    #[clap(long, value_parser, default_value = "65535")]
+    pub saturation: u16,
+
+    /// CSV file merged spectrum is written to, `-` for stdout
+    #[clap(short, long, value_parser = output_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub output: PathBuf,
+
+    #[clap(flatten)]
+    pub csv: CsvDialect,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct TraceReadingConf {
+    /// Pixels, e.g. 1200:1300, or wavelengths, e.g. 500nm:520nm, whose counts are summed up.
+    /// Both ends are included
+    #[clap(long, value_parser = parse_band)]
+    pub band: Band,
+
+    /// Take a single frame this often, e.g. 500ms, 5s, instead of reading continuously
+    #[clap(long, value_parser = parse_duration)]
+    pub interval: Option<Duration>,
+
+    /// Stop after this many frames, otherwise keep going until Ctrl-C
+    #[clap(long, value_parser)]
+    pub count: Option<usize>,
+
+    /// Stop once this much time has passed since the first reading, e.g. 2h
+    #[clap(long, value_parser = parse_duration, requires = "interval")]
+    pub until: Option<Duration>,
+
+    /// CSV file time series is written to, `-` for stdout
+    #[clap(short, long, value_parser = output_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub output: PathBuf,
+
+    #[clap(flatten)]
+    pub csv: CsvDialect,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub quality: QualityConf,
+
+    #[clap(flatten)]
+    pub stream: StreamConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct DaemonConf {
+    /// TOML file with acquisitions to run and their schedules
+    #[clap(short, long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub config: PathBuf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct RemoteConf {
+    /// Address of spectrometer_sbc server, e.g. http://raspberrypi.local:3000
+    #[clap(long, value_parser, env = "SPECTRO_REMOTE_URL")]
+    pub url: String,
+
+    /// How long to wait for server to respond, in milliseconds
+    #[clap(long, value_parser, default_value = "10000")]
+    pub timeout: u64,
+
+    #[clap(subcommand)]
+    pub command: RemoteCommands,
+}
+
+/// Subset of local subcommands that server supports, with the same arguments
+#[derive(Subcommand)]
+pub enum RemoteCommands {
+    /// Lists serial devices connected to server
+    List,
+    /// Get readings from spectrometer connected to server
+    Read(RemoteReadCommand),
+}
+
+#[derive(Args)]
+pub struct RemoteReadCommand {
+    #[clap(subcommand)]
+    pub command: RemoteReadCommands,
+}
+
+#[derive(Subcommand)]
+pub enum RemoteReadCommands {
+    /// Get a single frame
+    Single(RemoteSingleReadingConf),
+}
+
+#[derive(Args)]
+pub struct RemoteSingleReadingConf {
+    /// Name of serial port on server
+    #[clap(short, long, value_parser)]
+    pub serial: String,
+
+    #[clap(flatten)]
+    pub output: Output,
+}
+
+#[derive(Args)]
+pub struct ScpiConf {
+    /// Address to listen on, 5025 is the port commonly used for SCPI over raw sockets
+    #[clap(long, value_parser, default_value = "127.0.0.1:5025")]
+    pub listen: String,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct LiveReadingConf {
+    /// Where snapshots are saved, has to contain `{seq}` or `{date}` placeholder
+    #[clap(long, value_parser, default_value = "snapshot_{date}_{seq}.csv")]
+    pub snapshot: PathBuf,
+
+    /// File format for snapshots
+    #[clap(long, value_enum, default_value = "csv")]
+    pub snapshot_format: OutputFormat,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
     #[clap(flatten)]
     pub serial: SerialConf,
 }
@@ -85,7 +498,7 @@ pub struct SetBaudRateConf {
     pub serial: SerialConf,
 }
 
-fn parse_baud_rate(s: &str) -> Result<BaudRate, Error> {
+pub fn parse_baud_rate(s: &str) -> Result<BaudRate, Error> {
     s.parse()
         .or(Err(()))
         .and_then(|n| FromPrimitive::from_u32(n).ok_or(()))
@@ -137,3 +550,551 @@ pub struct SetExpTimeConf {
     #[clap(flatten)]
     pub serial: SerialConf,
 }
+
+#[derive(Args)]
+pub struct SessionCommand {
+    #[clap(subcommand)]
+    pub command: SessionCommands,
+}
+
+// Parsed once at startup, so variant sizes don't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+pub enum SessionCommands {
+    /// Capture frames and store them together with device metadata
+    Create(CreateSessionConf),
+    /// Print summary of a session file
+    Inspect(InspectSessionConf),
+    /// Unpack session contents into separate CSV files
+    Extract(ExtractSessionConf),
+}
+
+#[derive(Args)]
+pub struct CreateSessionConf {
+    /// Path to a file where session should be stored
+    #[clap(short, long, value_parser = unique_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub output: PathBuf,
+
+    /// Amount of frames captured
+    #[clap(short, long, value_parser, default_value = "1")]
+    pub count: usize,
+
+    /// CSV file with a dark frame, `-` reads it from stdin
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub dark: Option<PathBuf>,
+
+    /// CSV file with a reference frame, `-` reads it from stdin
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub reference: Option<PathBuf>,
+
+    /// Polynomial coefficients converting pixel index into wavelength, lowest order first
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub wavelength_coeffs: Vec<f64>,
+
+    /// Free form description of the measurement
+    #[clap(long, value_parser)]
+    pub note: Option<String>,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct InspectSessionConf {
+    /// Path to a session file
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub session: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ExtractSessionConf {
+    /// Path to a session file
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub session: PathBuf,
+
+    /// Directory where session contents should be unpacked
+    #[clap(short, long, value_parser = unique_path_parser, value_hint = clap::ValueHint::DirPath)]
+    pub output: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ConvertConf {
+    /// Capture files, directories or glob patterns matching them
+    #[clap(value_parser, required = true, value_hint = clap::ValueHint::AnyPath)]
+    pub inputs: Vec<String>,
+
+    /// Format of input files, guessed from extension if omitted
+    #[clap(long, value_enum)]
+    pub from: Option<InputFormat>,
+
+    /// Directory where converted files are written, named after inputs
+    #[clap(short, long, value_parser, value_hint = clap::ValueHint::DirPath)]
+    pub output_dir: PathBuf,
+
+    /// File format converted into
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+
+    /// Compress converted files, not supported for charts
+    #[clap(long, value_enum, default_value_t)]
+    pub compress: Compression,
+
+    #[clap(flatten)]
+    pub csv: CsvDialect,
+
+    /// CSV file with a dark frame subtracted from every converted frame, before pipeline stages
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub dark: Option<PathBuf>,
+
+    /// TOML file with `[pipeline]` of processing stages applied to every converted frame, same
+    /// as in daemon config
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub pipeline: Option<PathBuf>,
+
+    /// Polynomial coefficients converting pixel index into wavelength, recorded in CSV header
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub wavelength_coeffs: Vec<f64>,
+
+    /// Excitation laser wavelength, e.g. 532nm, adds Raman shift next to wavelength
+    #[clap(long, value_parser = parse_laser, requires = "wavelength-coeffs")]
+    pub laser: Option<f64>,
+
+    /// Resample frames onto an evenly spaced wavelength grid given as start:end:step in nm, so
+    /// captures from differently calibrated devices line up. Written as CSV, with grid in header
+    #[clap(long, value_parser, requires = "wavelength-coeffs")]
+    pub resample: Option<Grid>,
+
+    /// Interpolation used between pixels when resampling
+    #[clap(long, value_enum, default_value_t)]
+    pub interpolation: Interpolation,
+
+    /// Amount of threads converting files and frames within them, defaults to amount of CPUs
+    #[clap(short, long, value_parser)]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Args)]
+pub struct PlotFileConf {
+    /// Capture file to plot
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub input: PathBuf,
+
+    /// Image file, PNG or SVG depending on extension
+    #[clap(short, long, value_parser = plot_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub output: PathBuf,
+
+    /// Format of input file, guessed from extension if omitted
+    #[clap(long, value_enum)]
+    pub from: Option<InputFormat>,
+
+    /// Number of frame in capture to plot, starting from 1
+    #[clap(long, value_parser, default_value = "1")]
+    pub frame: usize,
+
+    /// Polynomial coefficients converting pixel index into wavelength, lowest order first
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub wavelength_coeffs: Vec<f64>,
+
+    /// Excitation laser wavelength, e.g. 532nm, plots against Raman shift instead of wavelength
+    #[clap(long, value_parser = parse_laser, requires = "wavelength-coeffs")]
+    pub laser: Option<f64>,
+
+    /// Amount of highest peaks annotated on plot
+    #[clap(long, value_parser, default_value = "3")]
+    pub peaks: usize,
+}
+
+#[derive(Args)]
+pub struct StatsConf {
+    /// Capture file to summarize
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub input: PathBuf,
+
+    /// Format of input file, guessed from extension if omitted
+    #[clap(long, value_enum)]
+    pub from: Option<InputFormat>,
+
+    /// Pixels at or above this value are counted as saturated
+    #[clap(long, value_parser, default_value = "65535")]
+    pub saturation: u16,
+}
+
+#[derive(Args)]
+pub struct VerifyConf {
+    /// Output files or their manifests
+    #[clap(value_parser, required = true, value_hint = clap::ValueHint::FilePath)]
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct CompareConf {
+    /// CSV file with reference spectrum
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub reference: PathBuf,
+
+    /// CSV file with spectrum compared to reference
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub sample: PathBuf,
+
+    /// Amount of highest reference peaks checked for shifts
+    #[clap(long, value_parser, default_value = "5")]
+    pub peaks: usize,
+
+    /// Fail if any of peaks shifted by more than this many pixels
+    #[clap(long, value_parser)]
+    pub max_shift: Option<f64>,
+
+    /// Write difference spectrum, sample minus reference, into CSV file
+    #[clap(long, value_parser = unique_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub diff: Option<PathBuf>,
+
+    /// Plot both spectra overlaid, PNG or SVG depending on extension
+    #[clap(long, value_parser = plot_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub plot: Option<PathBuf>,
+
+    /// Polynomial coefficients converting pixel index into wavelength for the plot, lowest order
+    /// first
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub wavelength_coeffs: Vec<f64>,
+
+    /// Excitation laser wavelength, e.g. 532nm, plots against Raman shift instead of wavelength
+    #[clap(long, value_parser = parse_laser, requires = "wavelength-coeffs")]
+    pub laser: Option<f64>,
+}
+
+#[derive(Args)]
+pub struct ResolutionConf {
+    /// Capture file with the line
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub input: PathBuf,
+
+    /// Expected position of the line, as wavelength (585.25nm) or pixel (1230px). Closest peak
+    /// to it is measured
+    #[clap(long, value_parser)]
+    pub line: Line,
+
+    /// Format of input file, guessed from extension if omitted
+    #[clap(long, value_enum)]
+    pub from: Option<InputFormat>,
+
+    /// Number of frame to measure, starting from 1. All frames are averaged if omitted
+    #[clap(long, value_parser)]
+    pub frame: Option<usize>,
+
+    /// Polynomial coefficients converting pixel index into wavelength, lowest order first
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub wavelength_coeffs: Vec<f64>,
+}
+
+#[derive(Args)]
+pub struct HistogramConf {
+    /// Capture file to inspect
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub input: PathBuf,
+
+    /// Format of input file, guessed from extension if omitted
+    #[clap(long, value_enum)]
+    pub from: Option<InputFormat>,
+
+    /// Number of frame to inspect, starting from 1. Pixels of all frames are pooled if omitted
+    #[clap(long, value_parser)]
+    pub frame: Option<usize>,
+
+    /// Amount of equally wide bins the full range of counts is split into
+    #[clap(long, value_parser, default_value = "64")]
+    pub bins: usize,
+
+    /// Render histogram into an image, PNG or SVG depending on extension, instead of printing it
+    #[clap(long, value_parser = plot_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub plot: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct DecodeConf {
+    /// File recorded with --dump-raw
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub dump: PathBuf,
+
+    /// Reject frames with mismatching checksum
+    #[clap(long)]
+    pub verify_crc: bool,
+}
+
+#[derive(Args)]
+pub struct CalibrationCommand {
+    #[clap(subcommand)]
+    pub command: CalibrationCommands,
+}
+
+#[derive(Subcommand)]
+pub enum CalibrationCommands {
+    /// Print calibration stored for a device
+    Show(ShowCalibrationConf),
+    /// Store calibration for a device, values that are not passed are kept as is
+    Set(SetCalibrationConf),
+    /// Calibrate wavelengths against lines of a reference lamp and store the result
+    Wavelength(WavelengthCalibrationConf),
+    /// Measure spectral response against a lamp with certified output and store its correction
+    Intensity(IntensityCalibrationConf),
+}
+
+#[derive(Args)]
+pub struct ShowCalibrationConf {
+    /// Serial number of CCD, as reported by ccd-version
+    #[clap(value_parser)]
+    pub serial_number: String,
+}
+
+#[derive(Args)]
+pub struct SetCalibrationConf {
+    /// Serial number of CCD, as reported by ccd-version
+    #[clap(value_parser)]
+    pub serial_number: String,
+
+    /// Polynomial coefficients converting pixel index into wavelength, lowest order first
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub wavelength_coeffs: Vec<f64>,
+
+    /// Polynomial coefficients mapping raw counts onto linear ones, lowest order first
+    #[clap(long, value_parser, use_value_delimiter = true)]
+    pub nonlinearity_coeffs: Vec<f64>,
+
+    /// CSV file with a dark frame, `-` reads it from stdin
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub dark: Option<PathBuf>,
+
+    /// CSV file with a frame captured under uniform illumination, `-` reads it from stdin
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub flat: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct WavelengthCalibrationConf {
+    /// Lamps lighting the spectrometer, lines of all of them are matched
+    #[clap(long, value_enum, required = true, use_value_delimiter = true)]
+    pub lamp: Vec<Lamp>,
+
+    /// Rough wavelengths at the first and the last pixel as start:end in nm, used to tell lines
+    /// apart. Stored calibration is used if omitted
+    #[clap(long, value_parser)]
+    pub span: Option<Span>,
+
+    /// Degree of fitted polynomial
+    #[clap(long, value_parser, default_value = "2")]
+    pub degree: usize,
+
+    /// Largest distance in nm between a peak and a known line for them to be matched
+    #[clap(long, value_parser, default_value = "3")]
+    pub tolerance: f64,
+
+    /// Peaks rising above background by less than this fraction of the highest one are ignored
+    #[clap(long, value_parser, default_value = "0.05")]
+    pub threshold: f64,
+
+    /// Amount of frames averaged before looking for peaks
+    #[clap(short, long, value_parser, default_value = "10")]
+    pub count: usize,
+
+    /// Don't wait for Enter before capturing and save result without asking
+    #[clap(short, long)]
+    pub yes: bool,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct IntensityCalibrationConf {
+    /// Lamp certificate with wavelength in nm and irradiance per line, `-` reads it from stdin
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub certificate: PathBuf,
+
+    /// Amount of frames averaged before measuring response
+    #[clap(short, long, value_parser, default_value = "10")]
+    pub count: usize,
+
+    /// Don't wait for Enter before capturing and save result without asking
+    #[clap(short, long)]
+    pub yes: bool,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct CharacterizeCommand {
+    #[clap(subcommand)]
+    pub command: CharacterizeCommands,
+}
+
+#[derive(Subcommand)]
+pub enum CharacterizeCommands {
+    /// Fit dark current of every pixel from darks at several exposure times, so dark frame can
+    /// be synthesized for any exposure time passed with --exposure-time
+    Dark(DarkCharacterizationConf),
+}
+
+#[derive(Args)]
+pub struct DarkCharacterizationConf {
+    /// "Exposure times" darks are captured at, at least two different ones
+    #[clap(long, value_parser, use_value_delimiter = true, required = true)]
+    pub exposures: Vec<u16>,
+
+    /// Amount of frames averaged at every exposure time
+    #[clap(short, long, value_parser, default_value = "10")]
+    pub count: usize,
+
+    /// Don't wait for Enter before capturing and save result without asking
+    #[clap(short, long)]
+    pub yes: bool,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct SettingsCommand {
+    #[clap(subcommand)]
+    pub command: SettingsCommands,
+}
+
+#[derive(Subcommand)]
+pub enum SettingsCommands {
+    /// Save exposure time, "average time" and UART baud rate of a device into a file
+    Export(SettingsExportConf),
+    /// Apply settings from a previously exported or hand written file to a device
+    Import(SettingsImportConf),
+}
+
+#[derive(Args)]
+pub struct SettingsExportConf {
+    /// TOML file settings are saved into
+    #[clap(value_parser = unique_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub path: PathBuf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct SettingsImportConf {
+    /// TOML file with settings, `trigger_mode` may be added by hand as it can't be exported
+    #[clap(value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub path: PathBuf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct ReferenceCommand {
+    #[clap(subcommand)]
+    pub command: ReferenceCommands,
+}
+
+#[derive(Subcommand)]
+pub enum ReferenceCommands {
+    /// Capture a reference frame averaged over several readings
+    Capture(CaptureReferenceConf),
+}
+
+#[derive(Args)]
+pub struct CaptureReferenceConf {
+    /// What reference is captured against
+    #[clap(long, value_enum)]
+    pub kind: ReferenceKind,
+
+    /// File where reference is stored, format is picked by extension: .bin or .raw, .csv, .npy
+    #[clap(short, long, value_parser = unique_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub output: PathBuf,
+
+    /// Amount of frames averaged into reference
+    #[clap(short, long, value_parser, default_value = "10")]
+    pub count: usize,
+
+    /// "Exposure time" set before capturing, should match one used for measurements
+    #[clap(long, value_parser, env = "SPECTRO_EXPOSURE_TIME")]
+    pub exposure_time: Option<u16>,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[derive(Args)]
+pub struct MonitorCommand {
+    #[clap(subcommand)]
+    pub command: MonitorCommands,
+}
+
+#[derive(Subcommand)]
+pub enum MonitorCommands {
+    /// Periodically compare captured spectrum against a reference, failing when peaks shift or
+    /// intensity drifts too far
+    Drift(DriftMonitorConf),
+}
+
+#[derive(Args)]
+pub struct DriftMonitorConf {
+    /// CSV file with reference spectrum, e.g. from `read single`
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub reference: PathBuf,
+
+    /// Time between checks, e.g. 30s, 10min
+    #[clap(long, value_parser = parse_duration, default_value = "60s")]
+    pub interval: Duration,
+
+    /// Stop after this many checks
+    #[clap(long, value_parser)]
+    pub count: Option<usize>,
+
+    /// Frames averaged into spectrum for every check
+    #[clap(long, value_parser, default_value = "5")]
+    pub frames: usize,
+
+    /// Amount of highest reference peaks checked for shifts
+    #[clap(long, value_parser, default_value = "3")]
+    pub peaks: usize,
+
+    /// Alert when any of peaks shifts by more than this many pixels
+    #[clap(long, value_parser, default_value = "1.0")]
+    pub max_shift: f64,
+
+    /// Alert when mean intensity changes by more than this many percent
+    #[clap(long, value_parser, default_value = "5.0")]
+    pub max_intensity_drift: f64,
+
+    /// Append result of every check to this CSV file
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub log: Option<PathBuf>,
+
+    /// POST alerts as JSON to this plain HTTP URL and keep monitoring, instead of exiting with
+    /// an error
+    #[clap(long, value_parser)]
+    pub webhook: Option<String>,
+
+    #[clap(flatten)]
+    pub capture: CaptureConf,
+
+    #[clap(flatten)]
+    pub serial: SerialConf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+}
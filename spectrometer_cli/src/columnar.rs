@@ -0,0 +1,210 @@
+use crate::{compress::Encoder, output::Header};
+use arrow_array::{
+    ArrayRef, FixedSizeListArray, RecordBatch, TimestampMicrosecondArray, UInt16Array, UInt64Array,
+    UInt8Array,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use ccd_lcamv06::QualityFlags;
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use simple_eyre::Result;
+use std::{collections::HashMap, io::BufWriter, sync::Arc};
+use time::OffsetDateTime;
+
+/// Frames collected into a single record batch, and so into a single parquet row group at least
+const BATCH_FRAMES: usize = 256;
+
+type Out = BufWriter<Encoder>;
+
+enum Inner {
+    Parquet(ArrowWriter<Out>),
+    Arrow(StreamWriter<Out>),
+}
+
+/// Writes frames as rows of timestamp, frame number, fixed size list of pixels and quality flags
+/// packed into bits. Capture metadata ends up in schema metadata
+pub struct ColumnarWriter {
+    inner: Inner,
+    schema: SchemaRef,
+    pixels: usize,
+    timestamps: Vec<i64>,
+    indices: Vec<u64>,
+    values: Vec<u16>,
+    flags: Vec<u8>,
+    written: usize,
+}
+
+/// Type of a single value within pixel lists
+fn pixel_field() -> Arc<Field> {
+    Arc::new(Field::new("item", DataType::UInt16, false))
+}
+
+fn schema(header: &Header, pixels: usize) -> SchemaRef {
+    let metadata: HashMap<String, String> = header
+        .metadata
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Arc::new(Schema::new_with_metadata(
+        vec![
+            Field::new(
+                "timestamp",
+                DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+                false,
+            ),
+            Field::new("frame", DataType::UInt64, false),
+            Field::new(
+                "pixels",
+                DataType::FixedSizeList(pixel_field(), pixels as i32),
+                false,
+            ),
+            Field::new("flags", DataType::UInt8, false),
+        ],
+        metadata,
+    ))
+}
+
+impl ColumnarWriter {
+    /// Parquet file, columns are always zstd compressed
+    pub fn parquet(out: Out, header: &Header, pixels: usize) -> Result<Self> {
+        let schema = schema(header, pixels);
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        let writer = ArrowWriter::try_new(out, schema.clone(), Some(properties))?;
+        Ok(Self::new(Inner::Parquet(writer), schema, pixels))
+    }
+
+    /// Arrow IPC stream, which can be read while it's still being written
+    pub fn arrow(out: Out, header: &Header, pixels: usize) -> Result<Self> {
+        let schema = schema(header, pixels);
+        let writer = StreamWriter::try_new(out, &schema)?;
+        Ok(Self::new(Inner::Arrow(writer), schema, pixels))
+    }
+
+    fn new(inner: Inner, schema: SchemaRef, pixels: usize) -> Self {
+        ColumnarWriter {
+            inner,
+            schema,
+            pixels,
+            timestamps: Vec::with_capacity(BATCH_FRAMES),
+            indices: Vec::with_capacity(BATCH_FRAMES),
+            values: Vec::with_capacity(BATCH_FRAMES * pixels),
+            flags: Vec::with_capacity(BATCH_FRAMES),
+            written: 0,
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        frame: &[u16],
+        flags: QualityFlags,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.written += 1;
+        self.timestamps
+            .push((timestamp.unix_timestamp_nanos() / 1000) as i64);
+        self.indices.push(self.written as u64);
+        self.values.extend_from_slice(&frame[..self.pixels]);
+        self.flags.push(flags.bits());
+        if self.indices.len() >= BATCH_FRAMES {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> Result<()> {
+        if self.indices.is_empty() {
+            return Ok(());
+        }
+        let values = UInt16Array::from(std::mem::take(&mut self.values));
+        let pixels =
+            FixedSizeListArray::try_new(pixel_field(), self.pixels as i32, Arc::new(values), None)?;
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(
+                TimestampMicrosecondArray::from(std::mem::take(&mut self.timestamps))
+                    .with_timezone("UTC"),
+            ),
+            Arc::new(UInt64Array::from(std::mem::take(&mut self.indices))),
+            Arc::new(pixels),
+            Arc::new(UInt8Array::from(std::mem::take(&mut self.flags))),
+        ];
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        match &mut self.inner {
+            Inner::Parquet(writer) => writer.write(&batch)?,
+            Inner::Arrow(writer) => writer.write(&batch)?,
+        }
+        Ok(())
+    }
+
+    /// Writes out remaining frames and footer, returning underlying output
+    pub fn finish(mut self) -> Result<(Out, usize)> {
+        self.flush_batch()?;
+        let out = match self.inner {
+            // Parquet footer is written along the way
+            Inner::Parquet(writer) => writer.into_inner()?,
+            Inner::Arrow(writer) => writer.into_inner()?,
+        };
+        Ok((out, self.written))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{
+        cast::AsArray,
+        types::{UInt16Type, UInt64Type, UInt8Type},
+        Array,
+    };
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::File;
+
+    #[test]
+    fn parquet_rows() {
+        let path = std::env::temp_dir().join(format!("frames-{}.parquet", std::process::id()));
+        let header = Header {
+            metadata: vec!["exposure time: 10".to_string()],
+            ..Default::default()
+        };
+        let out = BufWriter::new(Encoder::Plain(File::create(&path).unwrap().into()));
+        let mut writer = ColumnarWriter::parquet(out, &header, 3).unwrap();
+        for i in 0..BATCH_FRAMES as u16 + 2 {
+            writer
+                .write(
+                    &[i, i + 1, i + 2, 0],
+                    QualityFlags::from_bits(i as u8 & QualityFlags::SATURATED),
+                    OffsetDateTime::UNIX_EPOCH,
+                )
+                .unwrap();
+        }
+        let (_, written) = writer.finish().unwrap();
+        assert_eq!(written, BATCH_FRAMES + 2);
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            reader.schema().metadata().get("exposure time"),
+            Some(&"10".to_string())
+        );
+        let batches: Vec<_> = reader.build().unwrap().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+        let rows: usize = batches.iter().map(RecordBatch::num_rows).sum();
+        assert_eq!(rows, BATCH_FRAMES + 2);
+        let last = batches.last().unwrap();
+        let frames = last.column(1).as_primitive::<UInt64Type>();
+        assert_eq!(frames.value(frames.len() - 1), BATCH_FRAMES as u64 + 2);
+        let pixels = last.column(2).as_fixed_size_list();
+        let pixel = pixels.value(pixels.len() - 1);
+        assert_eq!(
+            pixel.as_primitive::<UInt16Type>().values().to_vec(),
+            vec![257, 258, 259]
+        );
+        let flags = last.column(3).as_primitive::<UInt8Type>();
+        assert_eq!(flags.value(flags.len() - 1), QualityFlags::SATURATED);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::{
+    analysis::{self, Spectrum},
+    cli::CompareConf,
+    input,
+    plot::{self, Overlay},
+};
+use simple_eyre::{eyre::eyre, Result};
+use std::{fmt, fs};
+
+/// Peak in sample is looked for this many pixels around its position in reference
+const SEARCH_RADIUS: usize = 10;
+
+/// Pixels on each side of the highest one used to find peak center
+const CENTROID_RADIUS: usize = 3;
+
+/// Center of a peak with subpixel precision, as intensity weighted mean position of pixels around
+/// it, with the lowest of them taken as baseline
+fn centroid(frame: &[u16], at: usize) -> f64 {
+    let window = at.saturating_sub(CENTROID_RADIUS)..(at + CENTROID_RADIUS + 1).min(frame.len());
+    let baseline = frame[window.clone()].iter().copied().min().unwrap_or(0);
+    let (weighted, total) = window.fold((0.0, 0.0), |(weighted, total), i| {
+        let v = (frame[i] - baseline) as f64;
+        (weighted + i as f64 * v, total + v)
+    });
+    if total == 0.0 {
+        at as f64
+    } else {
+        weighted / total
+    }
+}
+
+/// Position of a reference peak in both spectra
+#[derive(Debug, PartialEq)]
+struct PeakShift {
+    reference: f64,
+    sample: f64,
+}
+
+impl PeakShift {
+    fn shift(&self) -> f64 {
+        self.sample - self.reference
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Comparison {
+    /// Sample minus reference, pixel by pixel
+    difference: Vec<i32>,
+    pub rms: f64,
+    reference_mean: f64,
+    peaks: Vec<PeakShift>,
+}
+
+impl Comparison {
+    pub fn new(reference: &[u16], sample: &[u16], peaks: usize) -> Result<Self> {
+        if reference.len() != sample.len() {
+            return Err(eyre!(
+                "Spectra have different amount of pixels: {} and {}",
+                reference.len(),
+                sample.len()
+            ));
+        }
+        let difference: Vec<i32> = reference
+            .iter()
+            .zip(sample)
+            .map(|(&a, &b)| b as i32 - a as i32)
+            .collect();
+        let len = difference.len().max(1) as f64;
+        let rms = (difference.iter().map(|&d| (d as f64).powi(2)).sum::<f64>() / len).sqrt();
+        let peaks = analysis::find_peaks(reference, peaks)
+            .into_iter()
+            .map(|peak| {
+                let window = peak.saturating_sub(SEARCH_RADIUS)
+                    ..(peak + SEARCH_RADIUS + 1).min(sample.len());
+                let start = window.start;
+                let moved = sample[window]
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(_, v)| **v)
+                    .map_or(peak, |(i, _)| start + i);
+                PeakShift {
+                    reference: centroid(reference, peak),
+                    sample: centroid(sample, moved),
+                }
+            })
+            .collect();
+        Ok(Comparison {
+            difference,
+            rms,
+            reference_mean: reference.iter().map(|&v| v as f64).sum::<f64>() / len,
+            peaks,
+        })
+    }
+
+    pub fn max_shift(&self) -> f64 {
+        self.peaks
+            .iter()
+            .map(|peak| peak.shift().abs())
+            .fold(0.0, f64::max)
+    }
+
+    /// Change of mean intensity in percent of reference mean, positive when sample is brighter
+    pub fn intensity_drift(&self) -> f64 {
+        if self.reference_mean == 0.0 {
+            return 0.0;
+        }
+        let len = self.difference.len().max(1) as f64;
+        let mean = self.difference.iter().map(|&d| d as f64).sum::<f64>() / len;
+        mean / self.reference_mean * 100.0
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let relative = if self.reference_mean == 0.0 {
+            0.0
+        } else {
+            self.rms / self.reference_mean * 100.0
+        };
+        writeln!(
+            f,
+            "RMS deviation: {:.2} ({relative:.3}% of reference mean)",
+            self.rms
+        )?;
+        if let Some((at, diff)) = self
+            .difference
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, d)| d.abs())
+        {
+            writeln!(f, "Largest difference: {diff:+} at pixel {at}")?;
+        }
+        writeln!(f, "Peak shifts:")?;
+        write!(f, "{:>10} {:>10} {:>8}", "reference", "sample", "shift")?;
+        for peak in &self.peaks {
+            write!(
+                f,
+                "\n{:>10.2} {:>10.2} {:>+8.3}",
+                peak.reference,
+                peak.sample,
+                peak.shift()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `compare` subcommand, checks a spectrum against a stored reference
+pub fn run(conf: &CompareConf) -> Result<()> {
+    let reference = input::read_frame(&conf.reference)?;
+    let sample = input::read_frame(&conf.sample)?;
+    let comparison = Comparison::new(&reference, &sample, conf.peaks)?;
+    println!("{comparison}");
+
+    if let Some(path) = &conf.diff {
+        let line: Vec<_> = comparison.difference.iter().map(i32::to_string).collect();
+        fs::write(path, line.join(",") + "\n")?;
+    }
+    if let Some(path) = &conf.plot {
+        let name = |path: &std::path::Path| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        };
+        let (reference_name, sample_name) = (name(&conf.reference), name(&conf.sample));
+        let spectrum = Spectrum {
+            pixels: &reference,
+            wavelength: &conf.wavelength_coeffs,
+            laser: conf.laser,
+        };
+        let title = format!("{sample_name} compared to {reference_name}");
+        let overlay = Overlay {
+            pixels: &sample,
+            labels: (&reference_name, &sample_name),
+        };
+        plot::write_plot(path, &spectrum, &title, Some(&overlay), conf.peaks)?;
+    }
+    match conf.max_shift {
+        Some(max) if comparison.max_shift() > max => Err(eyre!(
+            "Peak shifted by {:.3} pixels, more than allowed {max}",
+            comparison.max_shift()
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_spectra() {
+        let mut reference = vec![10; 100];
+        reference[40..43].copy_from_slice(&[50, 100, 50]);
+        let mut sample = vec![10; 100];
+        // Same peak, moved to the right by a pixel and a quarter
+        sample[41..44].copy_from_slice(&[15, 75, 40]);
+        assert_eq!(centroid(&reference, 41), 41.0);
+        let comparison = Comparison::new(&reference, &sample, 1).unwrap();
+        assert_eq!(comparison.peaks.len(), 1);
+        assert!((comparison.peaks[0].shift() - 1.25).abs() < 1e-9);
+        assert_eq!(comparison.difference[40], -40);
+        assert_eq!(comparison.difference[43], 30);
+        // Sample lost 70 counts in total, reference mean is 11.7
+        assert!((comparison.intensity_drift() + 0.7 / 11.7 * 100.0).abs() < 1e-9);
+        assert!(Comparison::new(&reference, &sample[1..], 1).is_err());
+    }
+}
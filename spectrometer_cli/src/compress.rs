@@ -0,0 +1,162 @@
+use clap::ArgEnum;
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Stdout, Write},
+};
+
+#[derive(ArgEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    /// Extension appended to names of compressed files
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Zstd => Some("zst"),
+            Compression::Gzip => Some("gz"),
+        }
+    }
+}
+
+/// Level used for zstd, default one already compresses spectra really well
+const ZSTD_LEVEL: i32 = 0;
+
+/// Where encoded data ends up
+pub enum Destination {
+    File(File),
+    Stdout(Stdout),
+}
+
+impl From<File> for Destination {
+    fn from(file: File) -> Self {
+        Destination::File(file)
+    }
+}
+
+impl From<Stdout> for Destination {
+    fn from(stdout: Stdout) -> Self {
+        Destination::Stdout(stdout)
+    }
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::File(file) => file.write(buf),
+            Destination::Stdout(stdout) => stdout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::File(file) => file.flush(),
+            Destination::Stdout(stdout) => stdout.flush(),
+        }
+    }
+}
+
+/// Writer that compresses data on the fly
+pub enum Encoder {
+    Plain(Destination),
+    Zstd(zstd::Encoder<'static, Destination>),
+    Gzip(flate2::write::GzEncoder<Destination>),
+}
+
+impl Encoder {
+    pub fn new(out: impl Into<Destination>, compression: Compression) -> io::Result<Self> {
+        let out = out.into();
+        Ok(match compression {
+            Compression::None => Encoder::Plain(out),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(out, ZSTD_LEVEL)?),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                out,
+                flate2::Compression::default(),
+            )),
+        })
+    }
+
+    /// Writes out remaining compressed data, errors here would be lost if encoder was just dropped
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(mut out) => out.flush(),
+            Encoder::Zstd(encoder) => encoder.finish()?.flush(),
+            Encoder::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(out) => out.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(out) => out.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Only uncompressed file output can be rewritten in place
+impl Seek for Encoder {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Encoder::Plain(Destination::File(file)) => file.seek(pos),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Compressed or streamed output can't be rewritten",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn roundtrip(compression: Compression, decode: impl Fn(File) -> Vec<u8>) {
+        let path = std::env::temp_dir().join(format!(
+            "compressed-{:?}-{}",
+            compression,
+            std::process::id()
+        ));
+        let data = "1,2,3\n".repeat(1000);
+        let mut encoder = Encoder::new(File::create(&path).unwrap(), compression).unwrap();
+        encoder.write_all(data.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let compressed_len = std::fs::metadata(&path).unwrap().len();
+        let decoded = decode(File::open(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(compressed_len < data.len() as u64 / 10);
+        assert_eq!(decoded, data.as_bytes());
+    }
+
+    #[test]
+    fn compress_zstd() {
+        roundtrip(Compression::Zstd, |f| zstd::decode_all(f).unwrap());
+    }
+
+    #[test]
+    fn compress_gzip() {
+        roundtrip(Compression::Gzip, |f| {
+            let mut data = Vec::new();
+            flate2::read::GzDecoder::new(f)
+                .read_to_end(&mut data)
+                .unwrap();
+            data
+        });
+    }
+}
@@ -0,0 +1,195 @@
+use clap::Command;
+use serde::Deserialize;
+use simple_eyre::{eyre::eyre, Result};
+use std::{collections::HashMap, env, ffi::OsString, fs, path::PathBuf};
+
+/// Environment variable selecting a profile when `--profile` is not passed
+pub const PROFILE_ENV: &str = "SPECTRO_PROFILE";
+
+/// Contents of `~/.config/spectrometer/config.toml`
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+/// Named set of defaults for command line arguments
+#[derive(Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    pub serial: Option<String>,
+    pub baud: Option<u32>,
+    pub data_bits: Option<u8>,
+    pub parity: Option<String>,
+    pub stop_bits: Option<u8>,
+    pub flow_control: Option<String>,
+    pub exposure_time: Option<u16>,
+    pub timeout: Option<u64>,
+    pub format: Option<String>,
+    pub output_dir: Option<PathBuf>,
+    pub compress: Option<String>,
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("spectrometer").join("config.toml"))
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let path = match config_path() {
+            Some(path) if path.try_exists()? => path,
+            _ => {
+                tracing::debug!("Config file not found, using empty config");
+                return Ok(Config::default());
+            }
+        };
+        tracing::debug!("Loading config from {path:?}");
+        toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| eyre!("Could not parse config file {path:?}: {e}"))
+    }
+
+    pub fn profile(&self, name: &str) -> Result<Profile> {
+        self.profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| eyre!("Profile {name:?} is not defined in config file"))
+    }
+}
+
+impl Profile {
+    /// Pairs of argument id and value that profile provides defaults for
+    fn defaults(&self) -> Vec<(&'static str, String)> {
+        let mut defaults = Vec::new();
+        if let Some(serial) = &self.serial {
+            defaults.push(("serial", serial.clone()));
+        }
+        if let Some(baud) = self.baud {
+            defaults.push(("baud", baud.to_string()));
+        }
+        if let Some(data_bits) = self.data_bits {
+            defaults.push(("data_bits", data_bits.to_string()));
+        }
+        if let Some(parity) = &self.parity {
+            defaults.push(("parity", parity.clone()));
+        }
+        if let Some(stop_bits) = self.stop_bits {
+            defaults.push(("stop_bits", stop_bits.to_string()));
+        }
+        if let Some(flow_control) = &self.flow_control {
+            defaults.push(("flow_control", flow_control.clone()));
+        }
+        if let Some(exposure_time) = self.exposure_time {
+            defaults.push(("exposure_time", exposure_time.to_string()));
+        }
+        if let Some(timeout) = self.timeout {
+            defaults.push(("timeout", timeout.to_string()));
+        }
+        if let Some(format) = &self.format {
+            defaults.push(("format", format.clone()));
+        }
+        if let Some(output_dir) = &self.output_dir {
+            defaults.push(("output_dir", output_dir.to_string_lossy().into_owned()));
+        }
+        if let Some(compress) = &self.compress {
+            defaults.push(("compress", compress.clone()));
+        }
+        defaults
+    }
+}
+
+/// Extracts value of `--profile` before the rest of arguments are parsed, since profile affects
+/// how they are parsed. Falls back to `SPECTRO_PROFILE` environment variable
+pub fn profile_name(args: &[OsString]) -> Option<String> {
+    profile_name_from_args(args).or_else(|| env::var(PROFILE_ENV).ok())
+}
+
+fn profile_name_from_args(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--profile" {
+            return args.next().map(str::to_string);
+        }
+        if let Some(name) = arg.strip_prefix("--profile=") {
+            return Some(name.to_string());
+        }
+    }
+    None
+}
+
+fn apply_defaults(
+    mut cmd: Command<'static>,
+    defaults: &[(&'static str, &'static str)],
+) -> Command<'static> {
+    for (id, value) in defaults {
+        let known = cmd
+            .get_arguments()
+            .any(|arg| arg.get_id() == *id && !arg.is_positional());
+        if known {
+            cmd = cmd.mut_arg(*id, |arg| arg.default_value(value).required(false));
+        }
+    }
+    for subcmd in cmd.get_subcommands_mut() {
+        *subcmd = apply_defaults(std::mem::take(subcmd), defaults);
+    }
+    cmd
+}
+
+/// Replaces default values of options in every subcommand with values from profile
+pub fn apply_profile(cmd: Command<'static>, profile: &Profile) -> Command<'static> {
+    // Command requires defaults to live as long as itself, which is the whole program run
+    let defaults: Vec<_> = profile
+        .defaults()
+        .into_iter()
+        .map(|(id, value)| (id, &*Box::leak(value.into_boxed_str())))
+        .collect();
+    apply_defaults(cmd, &defaults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [profiles.lab-a]
+            serial = "/dev/ttyUSB0"
+            exposure-time = 20
+            flow-control = "hardware"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.profile("lab-a").unwrap(),
+            Profile {
+                serial: Some("/dev/ttyUSB0".to_string()),
+                exposure_time: Some(20),
+                flow_control: Some("hardware".to_string()),
+                ..Default::default()
+            }
+        );
+        assert!(config.profile("lab-b").is_err());
+    }
+
+    #[test]
+    fn find_profile_name() {
+        let args = |s: &str| s.split(' ').map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            profile_name_from_args(&args("cli --profile lab-a read")),
+            Some("lab-a".into())
+        );
+        assert_eq!(
+            profile_name_from_args(&args("cli read --profile=lab-b")),
+            Some("lab-b".into())
+        );
+        assert_eq!(
+            profile_name_from_args(&args("cli read -- --profile lab-a")),
+            None
+        );
+    }
+}
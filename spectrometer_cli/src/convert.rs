@@ -0,0 +1,260 @@
+use crate::{
+    analysis::{self, Spectrum},
+    cli::ConvertConf,
+    compress::Encoder,
+    input::{self, InputFormat},
+    manifest,
+    output::{self, Header, Output, OutputFormat},
+    pipeline::{self, Pipeline, Stage},
+};
+use ccd_lcamv06::Frame;
+use rayon::prelude::*;
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Expands directories and glob patterns into a sorted list of files
+pub fn collect_inputs(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut inputs = Vec::new();
+    for pattern in patterns {
+        let path = Path::new(pattern);
+        if path.is_dir() {
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                // Manifests sit next to every output file, but aren't captures themselves
+                if entry.file_type()?.is_file() && !manifest::is_manifest(&entry.path()) {
+                    inputs.push(entry.path());
+                }
+            }
+        } else if path.is_file() {
+            inputs.push(path.to_path_buf());
+        } else {
+            let matched = glob::glob(pattern)?.collect::<Result<Vec<_>, _>>()?;
+            if matched.is_empty() {
+                return Err(eyre!("{pattern:?} doesn't match any files"));
+            }
+            inputs.extend(matched.into_iter().filter(|p| p.is_file()));
+        }
+    }
+    inputs.sort();
+    inputs.dedup();
+    Ok(inputs)
+}
+
+/// Path of converted file, relative to output directory
+fn target_name(input: &Path, conf: &ConvertConf) -> Result<PathBuf> {
+    let stem = input
+        .file_stem()
+        .ok_or_else(|| eyre!("{input:?} has no file name"))?;
+    let mut name = PathBuf::from(stem);
+    name.set_extension(conf.format.extension());
+    if let Some(ext) = conf.compress.extension() {
+        let mut with_ext = name.into_os_string();
+        with_ext.push(".");
+        with_ext.push(ext);
+        name = with_ext.into();
+    }
+    Ok(name)
+}
+
+struct Job {
+    input: PathBuf,
+    format: InputFormat,
+    output: Output,
+}
+
+fn convert_file(
+    job: &Job,
+    conf: &ConvertConf,
+    pipeline: &Pipeline,
+    header: &Header,
+) -> Result<usize> {
+    let mut frames = input::read_capture(&job.input, job.format)?;
+    // Frames don't depend on each other, so long captures are processed on every core, while
+    // writing stays in order
+    frames
+        .par_iter_mut()
+        .for_each(|pixels| pipeline.apply(pixels));
+    let mut header = header.clone();
+    header
+        .metadata
+        .push(format!("converted from: {}", job.input.display()));
+    if conf.resample.is_some() {
+        return write_resampled(job, conf, &frames, &header.metadata);
+    }
+    let writer = job.output.frame_writer(None, header)?;
+    for pixels in frames {
+        // Pixel count is already checked while reading
+        let frame: Frame = pixels.try_into().expect("frame has wrong size");
+        writer.write(frame)?;
+    }
+    Ok(writer.finish()?.frames)
+}
+
+/// Writes frames resampled onto wavelength grid as CSV, with grid itself as the last header line
+fn write_resampled(
+    job: &Job,
+    conf: &ConvertConf,
+    frames: &[Vec<u16>],
+    metadata: &[String],
+) -> Result<usize> {
+    let grid = conf.resample.as_ref().expect("resampling wasn't requested");
+    let mut out = BufWriter::new(Encoder::new(
+        output::create_new(&job.output.path())?,
+        conf.compress,
+    )?);
+    let csv = &conf.csv;
+    if !csv.no_header {
+        for line in metadata {
+            writeln!(out, "# {line}")?;
+        }
+        writeln!(out, "# interpolation: {:?}", conf.interpolation)?;
+        let separator = if csv.decimal_comma { "," } else { "." };
+        let points: Vec<_> = grid
+            .points()
+            .map(|nm| nm.to_string().replace('.', separator))
+            .collect();
+        writeln!(out, "# wavelength: {}", csv.row(&points))?;
+    }
+    let rows = frames
+        .par_iter()
+        .map(|pixels| {
+            let spectrum = Spectrum {
+                pixels,
+                wavelength: &conf.wavelength_coeffs,
+                // Grid is in nm, so resampling doesn't need Raman shift
+                laser: None,
+            };
+            let values: Vec<_> = analysis::resample(&spectrum, grid, conf.interpolation)?
+                .into_iter()
+                // Points not covered by sensor are left empty, which CSV readers take as missing
+                .map(|v| {
+                    if v.is_nan() {
+                        String::new()
+                    } else {
+                        csv.number(v, 2)
+                    }
+                })
+                .collect();
+            Ok(csv.row(&values))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for row in &rows {
+        writeln!(out, "{row}")?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(rows.len())
+}
+
+/// Converts every input file into output directory in parallel. Failure of a single file doesn't
+/// stop the rest from being converted
+pub fn convert(conf: &ConvertConf) -> Result<()> {
+    if conf.resample.is_some() && !matches!(conf.format, OutputFormat::Csv) {
+        return Err(eyre!("Resampled frames can only be written as CSV"));
+    }
+    if conf.resample.is_some() && !conf.csv.columns.is_empty() {
+        return Err(eyre!(
+            "Resampled frames are always written as a row per frame"
+        ));
+    }
+    conf.csv.validate()?;
+    let inputs = collect_inputs(&conf.inputs)?;
+    let mut pipeline = conf
+        .pipeline
+        .as_deref()
+        .map(pipeline::load)
+        .transpose()?
+        .unwrap_or_default();
+    if let Some(path) = &conf.dark {
+        // Same as a dark-subtract stage, which is resolved against working directory instead
+        let mut dark = Pipeline {
+            stages: vec![Stage::DarkSubtract {
+                dark: path.clone(),
+                frame: Vec::new(),
+            }],
+        };
+        dark.prepare(None)?;
+        pipeline.stages.splice(0..0, dark.stages);
+    }
+    let mut metadata = Vec::new();
+    pipeline.note(&mut metadata);
+    if !conf.wavelength_coeffs.is_empty() {
+        let coeffs: Vec<_> = conf.wavelength_coeffs.iter().map(f64::to_string).collect();
+        metadata.push(format!("wavelength coefficients: {}", coeffs.join(",")));
+    }
+    if let Some(laser) = conf.laser {
+        metadata.push(format!("laser wavelength: {laser} nm"));
+    }
+    let header = Header {
+        metadata,
+        wavelength: conf.wavelength_coeffs.clone(),
+        laser: conf.laser,
+        // Input files are checked to hold full frames
+        ..Default::default()
+    };
+
+    fs::create_dir_all(&conf.output_dir)?;
+    let mut targets = HashSet::new();
+    let mut jobs = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        let format = match conf.from.or_else(|| InputFormat::from_path(&input)) {
+            Some(format) => format,
+            None => {
+                tracing::warn!("Skipping {input:?}, format can't be guessed from extension");
+                continue;
+            }
+        };
+        let target = target_name(&input, conf)?;
+        if !targets.insert(target.clone()) {
+            return Err(eyre!(
+                "More than one input would be converted into {target:?}"
+            ));
+        }
+        if conf.output_dir.join(&target).try_exists()? {
+            return Err(eyre!("{:?} already exists", conf.output_dir.join(&target)));
+        }
+        jobs.push(Job {
+            input,
+            format,
+            output: Output {
+                output: target,
+                format: conf.format,
+                output_dir: Some(conf.output_dir.clone()),
+                compress: conf.compress,
+                csv: conf.csv.clone(),
+            },
+        });
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(conf.jobs.unwrap_or(0))
+        .build()?;
+    let results: Vec<_> = pool.install(|| {
+        jobs.par_iter()
+            .map(|job| convert_file(job, conf, &pipeline, &header))
+            .collect()
+    });
+
+    let mut failed = 0;
+    for (job, result) in jobs.iter().zip(results) {
+        match result {
+            Ok(frames) => println!(
+                "{} -> {} ({frames} frames)",
+                job.input.display(),
+                job.output.path().display()
+            ),
+            Err(e) => {
+                failed += 1;
+                tracing::error!("Failed to convert {:?}: {e}", job.input);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(eyre!("{failed} of {} files failed to convert", jobs.len()));
+    }
+    Ok(())
+}
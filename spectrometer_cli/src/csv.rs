@@ -0,0 +1,301 @@
+use crate::analysis::{raman_shift, wavelength_at};
+use ccd_lcamv06::QualityFlags;
+use clap::{ArgEnum, Args};
+use simple_eyre::{eyre::eyre, Result};
+use std::io::{self, Write};
+
+/// Value written into every row of per pixel CSV layout
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsvColumn {
+    /// Number of the frame within output, starting from 1
+    Frame,
+    /// Pixel index
+    Index,
+    /// Wavelength of the pixel in nm, left empty without wavelength calibration
+    Wavelength,
+    /// Raman shift of the pixel in cm⁻¹, left empty without wavelength calibration or --laser
+    RamanShift,
+    /// Counts as read from sensor, before stored calibration is applied
+    Raw,
+    /// Counts after stored calibration is applied, same as raw without one
+    Corrected,
+    /// Quality flags of the frame separated by `|`, empty when nothing is wrong with it
+    Flags,
+}
+
+impl CsvColumn {
+    fn name(&self) -> &'static str {
+        match self {
+            CsvColumn::Frame => "frame",
+            CsvColumn::Index => "index",
+            CsvColumn::Wavelength => "wavelength",
+            CsvColumn::RamanShift => "raman_shift",
+            CsvColumn::Raw => "raw",
+            CsvColumn::Corrected => "corrected",
+            CsvColumn::Flags => "flags",
+        }
+    }
+}
+
+/// Layout of CSV output, defaults match what other tools of this crate read back
+#[derive(Args, Clone, Default)]
+pub struct CsvDialect {
+    /// Character separating CSV fields, `\t` for tab. Defaults to `;` with --decimal-comma and
+    /// to `,` otherwise
+    #[clap(long, value_parser = delimiter_parser)]
+    pub delimiter: Option<char>,
+
+    /// Leave out comment lines with capture details and the row naming columns
+    #[clap(long)]
+    pub no_header: bool,
+
+    /// Write fractional numbers with a decimal comma, as spreadsheets in most of Europe expect
+    #[clap(long)]
+    pub decimal_comma: bool,
+
+    /// Write a row per pixel with these columns instead of a row per frame
+    #[clap(long, value_enum, use_value_delimiter = true)]
+    pub columns: Vec<CsvColumn>,
+}
+
+fn delimiter_parser(s: &str) -> Result<char> {
+    let delimiter = match s {
+        "\\t" | "tab" => '\t',
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(eyre!("Delimiter has to be a single character")),
+            }
+        }
+    };
+    if delimiter.is_ascii_alphanumeric() || matches!(delimiter, '.' | '#' | '"' | '\n' | '\r') {
+        return Err(eyre!("{delimiter:?} can't be used as a delimiter"));
+    }
+    Ok(delimiter)
+}
+
+/// Pixels of a single frame along with everything needed for per pixel columns
+pub struct CsvFrame<'a> {
+    /// Number of the frame within output, starting from 1
+    pub idx: usize,
+    pub pixels: &'a [u16],
+    /// Pixels before calibration, `None` when no calibration was applied
+    pub raw: Option<&'a [u16]>,
+    pub flags: QualityFlags,
+    /// Polynomial coefficients converting pixel index into wavelength, empty when uncalibrated
+    pub wavelength: &'a [f64],
+    /// Excitation laser wavelength in nm
+    pub laser: Option<f64>,
+}
+
+impl CsvDialect {
+    /// Nothing was changed from the default layout
+    pub fn is_default(&self) -> bool {
+        self.delimiter.is_none()
+            && !self.no_header
+            && !self.decimal_comma
+            && self.columns.is_empty()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.decimal_comma && self.delimiter == Some(',') {
+            return Err(eyre!("Decimal comma can't be used with comma as delimiter"));
+        }
+        if self.delimiter == Some('|') && self.columns.contains(&CsvColumn::Flags) {
+            return Err(eyre!(
+                "Flags are separated by `|`, so it can't be used as delimiter"
+            ));
+        }
+        Ok(())
+    }
+
+    fn delimiter(&self) -> char {
+        match self.delimiter {
+            Some(delimiter) => delimiter,
+            None if self.decimal_comma => ';',
+            None => ',',
+        }
+    }
+
+    /// Fractional number with the configured decimal separator
+    pub fn number(&self, value: f64, precision: usize) -> String {
+        let formatted = format!("{value:.precision$}");
+        if self.decimal_comma {
+            formatted.replace('.', ",")
+        } else {
+            formatted
+        }
+    }
+
+    /// Fields joined into a single row, without line break
+    pub fn row<S: AsRef<str>>(&self, fields: &[S]) -> String {
+        let mut delimiter = [0; 4];
+        let delimiter = self.delimiter().encode_utf8(&mut delimiter);
+        fields
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>()
+            .join(delimiter)
+    }
+
+    /// Whether a row naming columns is written at the start, which frames then have to follow on
+    /// a new line
+    pub fn header_row(&self) -> bool {
+        !self.no_header && !self.columns.is_empty()
+    }
+
+    /// Metadata as comment lines, followed by wavelength and Raman shift of every pixel when
+    /// they're known and frames are written as rows, or by column names for per pixel layout.
+    /// Column names are left without line break, same as frame rows
+    pub fn write_header(
+        &self,
+        out: &mut impl Write,
+        metadata: &[String],
+        wavelengths: &[f64],
+        raman_shifts: &[f64],
+    ) -> io::Result<()> {
+        if self.no_header {
+            return Ok(());
+        }
+        for line in metadata {
+            writeln!(out, "# {line}")?;
+        }
+        if self.columns.is_empty() && !wavelengths.is_empty() {
+            let values: Vec<_> = wavelengths.iter().map(|nm| self.number(*nm, 3)).collect();
+            writeln!(out, "# wavelength: {}", self.row(&values))?;
+        }
+        if self.columns.is_empty() && !raman_shifts.is_empty() {
+            let values: Vec<_> = raman_shifts.iter().map(|cm| self.number(*cm, 2)).collect();
+            writeln!(out, "# raman shift: {}", self.row(&values))?;
+        }
+        if !self.columns.is_empty() {
+            let names: Vec<_> = self.columns.iter().map(CsvColumn::name).collect();
+            write!(out, "{}", self.row(&names))?;
+        }
+        Ok(())
+    }
+
+    /// Writes frame as a single row, or as a row per pixel when columns are selected. Every row
+    /// but the first one in output starts with a line break, so that nothing follows the last one
+    pub fn write_frame(
+        &self,
+        out: &mut impl Write,
+        frame: &CsvFrame,
+        first: bool,
+    ) -> io::Result<()> {
+        let mut first = first && !self.header_row();
+        if self.columns.is_empty() {
+            if !first {
+                writeln!(out)?;
+            }
+            let values: Vec<_> = frame.pixels.iter().map(u16::to_string).collect();
+            return write!(out, "{}", self.row(&values));
+        }
+        let raw = frame.raw.unwrap_or(frame.pixels);
+        for (px, (value, raw)) in frame.pixels.iter().zip(raw).enumerate() {
+            let fields: Vec<_> = self
+                .columns
+                .iter()
+                .map(|column| match column {
+                    CsvColumn::Frame => frame.idx.to_string(),
+                    CsvColumn::Index => px.to_string(),
+                    CsvColumn::Wavelength if frame.wavelength.is_empty() => String::new(),
+                    CsvColumn::Wavelength => {
+                        self.number(wavelength_at(frame.wavelength, px as f64), 3)
+                    }
+                    CsvColumn::RamanShift => match frame.laser {
+                        Some(laser) if !frame.wavelength.is_empty() => {
+                            let nm = wavelength_at(frame.wavelength, px as f64);
+                            self.number(raman_shift(laser, nm), 2)
+                        }
+                        _ => String::new(),
+                    },
+                    CsvColumn::Raw => raw.to_string(),
+                    CsvColumn::Corrected => value.to_string(),
+                    CsvColumn::Flags => frame.flags.to_string(),
+                })
+                .collect();
+            if !first {
+                writeln!(out)?;
+            }
+            write!(out, "{}", self.row(&fields))?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn european_pixel_columns() {
+        let dialect = CsvDialect {
+            decimal_comma: true,
+            columns: vec![
+                CsvColumn::Index,
+                CsvColumn::Wavelength,
+                CsvColumn::RamanShift,
+                CsvColumn::Raw,
+                CsvColumn::Flags,
+            ],
+            ..Default::default()
+        };
+        dialect.validate().unwrap();
+        let mut out = Vec::new();
+        dialect
+            .write_header(&mut out, &["exposure time: 10".to_string()], &[500.0], &[])
+            .unwrap();
+        let frame = CsvFrame {
+            idx: 1,
+            pixels: &[10, 20],
+            raw: Some(&[11, 22]),
+            flags: QualityFlags {
+                saturated: true,
+                ..Default::default()
+            },
+            wavelength: &[500.0, 0.25],
+            laser: Some(500.0),
+        };
+        dialect.write_frame(&mut out, &frame, true).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# exposure time: 10\nindex;wavelength;raman_shift;raw;flags\n\
+             0;500,000;0,00;11;saturated\n1;500,250;10,00;22;saturated"
+        );
+
+        let plain = CsvDialect {
+            delimiter: Some('\t'),
+            no_header: true,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        plain
+            .write_header(&mut out, &["ignored: 1".to_string()], &[500.0], &[])
+            .unwrap();
+        plain.write_frame(&mut out, &frame, true).unwrap();
+        plain.write_frame(&mut out, &frame, false).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "10\t20\n10\t20");
+
+        let mut out = Vec::new();
+        CsvDialect::default()
+            .write_header(&mut out, &[], &[500.0, 500.25], &[0.0, 9.99])
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# wavelength: 500.000,500.250\n# raman shift: 0.00,9.99\n"
+        );
+
+        let conflicting = CsvDialect {
+            delimiter: Some(','),
+            decimal_comma: true,
+            ..Default::default()
+        };
+        assert!(conflicting.validate().is_err());
+        assert_eq!(delimiter_parser("\\t").unwrap(), '\t');
+        assert!(delimiter_parser(".").is_err());
+        assert!(delimiter_parser(";;").is_err());
+    }
+}
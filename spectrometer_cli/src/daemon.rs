@@ -0,0 +1,535 @@
+use crate::{
+    alert::{self, AlertRule, Alerts},
+    calibration::DeviceCalibration,
+    capture::{self, Capture},
+    cli::DaemonConf,
+    compress::Compression,
+    csv::CsvDialect,
+    interrupt,
+    output::{self, Output, OutputFormat},
+    pipeline::Pipeline,
+    rotate,
+    schedule::Schedule,
+    serial::{CaptureConf, SerialConf, StreamConf},
+    systemd::Notifier,
+};
+use ccd_lcamv06::QualityThresholds;
+use clap::ArgEnum;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    net::{SocketAddr, TcpListener},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Contents of file passed to `daemon --config`
+///
+/// ```toml
+/// status = "127.0.0.1:7878"
+/// dbus = "session"
+/// output-dir = "/var/lib/spectrometer"
+///
+/// [[acquisition]]
+/// name = "hourly"
+/// schedule = "0 * * * *"
+/// count = 10
+/// output = "{name}/{date}.csv"
+/// format = "csv"
+///
+/// [[alert]]
+/// name = "saturation"
+/// saturated = true
+/// webhook = "http://127.0.0.1:8080/saturated"
+///
+/// [[pipeline.stage]]
+/// type = "smooth"
+/// window = 5
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DaemonConfig {
+    /// Address status is served on as JSON to anyone who connects, should be a loopback one
+    pub status: Option<SocketAddr>,
+    /// Bus control interface is registered on, `session` or `system`. Only available in builds
+    /// with `dbus` feature
+    pub dbus: Option<Bus>,
+    /// Directory relative output paths are resolved against
+    pub output_dir: Option<PathBuf>,
+    #[serde(rename = "acquisition", default)]
+    pub acquisitions: Vec<Acquisition>,
+    /// Rules checked against frames of every acquisition
+    #[serde(rename = "alert", default)]
+    pub alerts: Vec<AlertRule>,
+    /// Processing applied to frames of every acquisition
+    #[serde(default)]
+    pub pipeline: Pipeline,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Acquisition {
+    pub name: String,
+    /// When to run, as five cron fields: minute hour day month weekday
+    #[serde(deserialize_with = "schedule")]
+    pub schedule: Schedule,
+    /// Frames captured in a single run
+    #[serde(default = "default_count")]
+    pub count: usize,
+    /// "Exposure time" set before each run, current device setting is kept if omitted
+    pub exposure_time: Option<u16>,
+    /// Don't apply calibration stored for connected device
+    #[serde(default)]
+    pub no_calibration: bool,
+    /// Apply relative intensity correction stored with `calibration intensity`
+    #[serde(default)]
+    pub relative_intensity: bool,
+    /// Excitation laser wavelength in nm, adds Raman shift to outputs
+    pub laser: Option<f64>,
+    /// Output path, `{name}`, `{date}` and `{seq}` are replaced with acquisition name, run
+    /// start time and run number counted from daemon start
+    pub output: PathBuf,
+    #[serde(deserialize_with = "arg_enum")]
+    pub format: OutputFormat,
+    #[serde(default, deserialize_with = "arg_enum")]
+    pub compress: Compression,
+}
+
+fn default_count() -> usize {
+    1
+}
+
+fn schedule<'de, D: Deserializer<'de>>(d: D) -> Result<Schedule, D::Error> {
+    String::deserialize(d)?.parse().map_err(de::Error::custom)
+}
+
+/// Enums are spelled the same way as on command line
+fn arg_enum<'de, D: Deserializer<'de>, T: ArgEnum>(d: D) -> Result<T, D::Error> {
+    T::from_str(&String::deserialize(d)?, true).map_err(de::Error::custom)
+}
+
+impl DaemonConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut config: Self = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| eyre!("Could not parse daemon config {path:?}: {e}"))?;
+        if config.acquisitions.is_empty() {
+            return Err(eyre!("No acquisitions configured in {path:?}"));
+        }
+        let mut names = HashSet::new();
+        for acquisition in &config.acquisitions {
+            if !names.insert(&acquisition.name) {
+                return Err(eyre!("Acquisition {:?} is defined twice", acquisition.name));
+            }
+            if acquisition.count == 0 {
+                return Err(eyre!(
+                    "Acquisition {:?} captures no frames",
+                    acquisition.name
+                ));
+            }
+        }
+        alert::validate(&config.alerts)?;
+        config.pipeline.prepare(path.parent())?;
+        Ok(config)
+    }
+}
+
+/// Health of the daemon, served on status address
+#[derive(Serialize)]
+struct Status {
+    pid: u32,
+    started: String,
+    acquisitions: Vec<AcquisitionStatus>,
+}
+
+#[derive(Serialize, Default)]
+struct AcquisitionStatus {
+    name: String,
+    next_run: Option<String>,
+    last_run: Option<String>,
+    last_output: Option<PathBuf>,
+    last_error: Option<String>,
+    runs: usize,
+    failures: usize,
+}
+
+fn timestamp(t: OffsetDateTime) -> Option<String> {
+    t.format(&Rfc3339).ok()
+}
+
+/// Answers every connection with current status and closes it
+fn serve_status(listener: TcpListener, status: Arc<Mutex<Status>>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!("Status connection failed: {e}");
+                continue;
+            }
+        };
+        let status = status.lock().unwrap_or_else(|e| e.into_inner());
+        let res = serde_json::to_writer_pretty(&mut stream, &*status)
+            .map_err(Into::into)
+            .and_then(|_| writeln!(stream));
+        if let Err(e) = res {
+            tracing::debug!("Could not send status: {e}");
+        }
+    }
+}
+
+/// Bus control interface is registered on
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bus {
+    Session,
+    /// Needs a policy in /etc/dbus-1/system.d allowing daemon's user to own the name
+    System,
+}
+
+/// State shared between daemon loop and D-Bus interface
+#[derive(Default)]
+pub struct Control {
+    /// Acquisition asked to run right away, ahead of its schedule
+    pub requested: Option<usize>,
+    /// Acquisition that is running now
+    pub running: Option<usize>,
+    /// Capture in progress should stop, frames taken so far are still written out
+    pub stop: bool,
+    /// Last frame of any acquisition, after calibration and processing
+    pub last_frame: Option<Vec<u16>>,
+    /// "Exposure time" set over D-Bus, used by later runs instead of configured one
+    pub exposure: Option<u16>,
+    /// "Exposure time" device reported during the last run
+    pub device_exposure: Option<u16>,
+}
+
+pub type SharedControl = Arc<Mutex<Control>>;
+
+/// Panic in one of the threads shouldn't take control interface down with it
+pub fn lock(control: &SharedControl) -> MutexGuard<'_, Control> {
+    control.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// How often requests made over D-Bus are checked for while waiting for the next run
+const REQUEST_POLL: Duration = Duration::from_millis(200);
+
+/// Why waiting for the next run ended
+enum Wake {
+    Due,
+    /// Acquisition with this index was requested over D-Bus
+    Requested(usize),
+    Interrupted,
+}
+
+/// Waits until `deadline` while keeping watchdog happy, or until a run is requested
+fn wait_until(notifier: &Notifier, deadline: Instant, control: &SharedControl) -> Wake {
+    loop {
+        notifier.keep_alive();
+        if let Some(idx) = lock(control).requested.take() {
+            return Wake::Requested(idx);
+        }
+        let mut step = deadline.min(Instant::now() + REQUEST_POLL);
+        if let Some(interval) = notifier.keep_alive_interval() {
+            step = step.min(Instant::now() + interval / 4);
+        }
+        if !capture::sleep_until(step) {
+            return Wake::Interrupted;
+        }
+        if step == deadline {
+            return Wake::Due;
+        }
+    }
+}
+
+/// Registers control interface on bus from config, if there is one
+#[cfg(feature = "dbus")]
+fn serve_dbus(
+    config: &DaemonConfig,
+    control: &SharedControl,
+) -> Result<Option<zbus::blocking::Connection>> {
+    let Some(bus) = config.dbus else {
+        return Ok(None);
+    };
+    let names = config.acquisitions.iter().map(|a| a.name.clone()).collect();
+    let connection = crate::dbus::serve(bus, control.clone(), names)?;
+    tracing::info!(
+        "Serving control interface as {} on {bus:?} bus",
+        crate::dbus::SERVICE
+    );
+    Ok(Some(connection))
+}
+
+/// Runs acquisitions whenever they are due until interrupted with Ctrl-C or SIGTERM. Device is
+/// only opened for the duration of each run, so it can be used by other tools in between
+pub fn run(conf: &DaemonConf) -> Result<()> {
+    let config = DaemonConfig::load(&conf.config)?;
+    let notifier = Notifier::from_env();
+    // Local offset can only be found out while process has a single thread, so it's taken once
+    // and kept for the whole run
+    let offset = output::now().offset();
+    let now = move || OffsetDateTime::now_utc().to_offset(offset);
+    interrupt::install_handler()?;
+
+    let started = now();
+    let mut next: Vec<_> = config
+        .acquisitions
+        .iter()
+        .map(|acquisition| acquisition.schedule.next_after(started))
+        .collect();
+    let status = Arc::new(Mutex::new(Status {
+        pid: std::process::id(),
+        started: timestamp(started).unwrap_or_default(),
+        acquisitions: config
+            .acquisitions
+            .iter()
+            .zip(&next)
+            .map(|(acquisition, next)| AcquisitionStatus {
+                name: acquisition.name.clone(),
+                next_run: next.and_then(timestamp),
+                ..Default::default()
+            })
+            .collect(),
+    }));
+    if let Some(addr) = config.status {
+        if !addr.ip().is_loopback() {
+            tracing::warn!("Status is served on {addr}, which is reachable from other hosts");
+        }
+        let listener =
+            TcpListener::bind(addr).map_err(|e| eyre!("Could not serve status on {addr}: {e}"))?;
+        let status = status.clone();
+        thread::spawn(move || serve_status(listener, status));
+        tracing::info!("Serving status on {addr}");
+    }
+    let control = SharedControl::default();
+    // Interface is served for as long as connection is kept
+    #[cfg(feature = "dbus")]
+    let _dbus = serve_dbus(&config, &control)?;
+    #[cfg(not(feature = "dbus"))]
+    if config.dbus.is_some() {
+        return Err(eyre!(
+            "D-Bus control interface needs spectrometer_cli built with `dbus` feature"
+        ));
+    }
+    notifier.ready();
+
+    let mut alerts = Alerts::new(&config.alerts);
+    loop {
+        let due = next
+            .iter()
+            .enumerate()
+            .filter_map(|(i, at)| at.map(|at| (i, at)))
+            .min_by_key(|(_, at)| *at);
+        let Some((i, at)) = due else {
+            return Err(eyre!("None of the schedules will ever run again"));
+        };
+        let next_status = format!(
+            "Next acquisition is {:?} at {at}",
+            config.acquisitions[i].name
+        );
+        tracing::info!("{next_status}");
+        notifier.status(&next_status);
+        let wait = Duration::try_from(at - now()).unwrap_or(Duration::ZERO);
+        let (i, at, scheduled) = match wait_until(&notifier, Instant::now() + wait, &control) {
+            Wake::Interrupted => break,
+            Wake::Requested(requested) => (requested, now(), false),
+            // Clock could have been set back while sleeping
+            Wake::Due if now() < at => continue,
+            Wake::Due => (i, at, true),
+        };
+        let acquisition = &config.acquisitions[i];
+
+        let runs = status
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .acquisitions[i]
+            .runs
+            + 1;
+        tracing::info!("Running acquisition {:?}", acquisition.name);
+        notifier.status(&format!("Running acquisition {:?}", acquisition.name));
+        {
+            let mut control = lock(&control);
+            control.running = Some(i);
+            control.stop = false;
+        }
+        let run = Run {
+            acquisition,
+            seq: runs,
+            start: at,
+        };
+        let res = acquire(
+            &run,
+            &config,
+            &conf.serial,
+            &notifier,
+            &mut alerts,
+            &control,
+        );
+        lock(&control).running = None;
+        // Run requested over D-Bus leaves schedule as it was
+        if scheduled {
+            next[i] = acquisition.schedule.next_after(now().max(at));
+        }
+
+        let mut status = status.lock().unwrap_or_else(|e| e.into_inner());
+        let acquisition_status = &mut status.acquisitions[i];
+        acquisition_status.runs = runs;
+        acquisition_status.last_run = timestamp(at);
+        acquisition_status.next_run = next[i].and_then(timestamp);
+        match res {
+            Ok(path) => {
+                tracing::info!("Acquisition {:?} written to {path:?}", acquisition.name);
+                acquisition_status.last_output = Some(path);
+                acquisition_status.last_error = None;
+            }
+            Err(e) => {
+                tracing::error!("Acquisition {:?} failed: {e:?}", acquisition.name);
+                acquisition_status.failures += 1;
+                acquisition_status.last_error = Some(e.to_string());
+            }
+        }
+        if interrupt::interrupted() {
+            break;
+        }
+    }
+    // Capture in progress is already written out by now
+    notifier.stopping();
+    tracing::info!("Daemon stopped");
+    Ok(())
+}
+
+/// Single run of an acquisition
+struct Run<'a> {
+    acquisition: &'a Acquisition,
+    /// Run number counted from daemon start
+    seq: usize,
+    start: OffsetDateTime,
+}
+
+/// Captures a single run of acquisition into its own file, returning path to it
+fn acquire(
+    run: &Run<'_>,
+    config: &DaemonConfig,
+    serial: &SerialConf,
+    notifier: &Notifier,
+    alerts: &mut Alerts,
+    control: &SharedControl,
+) -> Result<PathBuf> {
+    let acquisition = run.acquisition;
+    let template = acquisition
+        .output
+        .to_string_lossy()
+        .replace("{name}", &acquisition.name);
+    let output = Output {
+        output: rotate::expand(Path::new(&template), run.seq, run.start)?,
+        format: acquisition.format,
+        output_dir: config.output_dir.clone(),
+        compress: acquisition.compress,
+        csv: CsvDialect::default(),
+    };
+    let path = output.path();
+    if path.try_exists()? {
+        return Err(eyre!(
+            "Output {path:?} already exists, use {{date}} in output template to keep runs apart"
+        ));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let capture_conf = CaptureConf {
+        exposure_time: lock(control).exposure.or(acquisition.exposure_time),
+        no_calibration: acquisition.no_calibration,
+        apply_reference: Vec::new(),
+        relative_intensity: acquisition.relative_intensity,
+        laser: acquisition.laser,
+    };
+    let mut ccd = serial.open_ccd()?;
+    let (version, calibration) = capture::prepare_capture(&mut ccd, &capture_conf)?;
+    lock(control).device_exposure = Some(ccd.get_exp_time()?);
+    let mut header = capture::capture_header(
+        serial,
+        &capture_conf,
+        &version,
+        calibration.as_ref(),
+        ccd.sensor_layout().pixels(),
+    );
+    header
+        .metadata
+        .push(format!("acquisition: {}", acquisition.name));
+    config.pipeline.note(&mut header.metadata);
+    let correction = calibration.as_ref().map(DeviceCalibration::correction);
+    let writer = output.frame_writer(None, header)?;
+    let stream = StreamConf {
+        every: NonZeroUsize::MIN,
+        max_fps: None,
+    };
+    let thresholds = QualityThresholds::default();
+    let capture = Capture::run_until(
+        &mut ccd,
+        Some(acquisition.count),
+        &stream,
+        thresholds,
+        || lock(control).stop,
+        |mut frame, flags| {
+            notifier.keep_alive();
+            if let Some(correction) = &correction {
+                correction.apply(&mut frame);
+            }
+            config.pipeline.apply(&mut frame);
+            alerts.check(&frame, flags);
+            lock(control).last_frame = Some(frame.to_vec());
+            writer.write_captured(frame, None, flags)?;
+            Ok(true)
+        },
+    );
+    capture::finish_capture(&output, writer, capture, Some(acquisition.count))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_daemon_config() {
+        let config: DaemonConfig = toml::from_str(
+            r#"
+            status = "127.0.0.1:7878"
+
+            [[acquisition]]
+            name = "hourly"
+            schedule = "0 * * * *"
+            output = "{name}_{date}.npy"
+            format = "npy"
+
+            [[alert]]
+            name = "saturation"
+            saturated = true
+            command = "echo saturated"
+
+            [[pipeline.stage]]
+            type = "bin"
+            pixels = 4
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.acquisitions[0].count, 1);
+        assert!(config.alerts[0].saturated);
+        assert_eq!(config.pipeline.describe(), "bin 4");
+        assert!(matches!(config.acquisitions[0].format, OutputFormat::Npy));
+        let invalid = r#"
+            [[acquisition]]
+            name = "broken"
+            schedule = "0 25 * * *"
+            output = "out.csv"
+            format = "csv"
+        "#;
+        assert!(toml::from_str::<DaemonConfig>(invalid).is_err());
+    }
+}
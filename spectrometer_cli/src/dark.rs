@@ -0,0 +1,71 @@
+use crate::{
+    calibration::{DarkCurrent, DeviceCalibration},
+    cli::DarkCharacterizationConf,
+    wavelength::confirm,
+};
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use console::Term;
+use simple_eyre::{eyre::eyre, Result};
+
+/// `characterize dark` subcommand, captures darks at several exposure times and stores dark
+/// current fitted through them
+pub fn characterize(conf: &DarkCharacterizationConf) -> Result<()> {
+    let term = Term::stderr();
+    if !conf.yes && !term.is_term() {
+        return Err(eyre!(
+            "Characterization asks for confirmation in terminal, pass --yes to skip it"
+        ));
+    }
+    if conf.count == 0 {
+        return Err(eyre!("At least one frame is needed per exposure time"));
+    }
+    let mut ccd = conf.serial.open_ccd()?;
+    let version = ccd.get_version()?;
+    let serial = version.serial_number().to_string();
+    let original = ccd.get_exp_time()?;
+
+    if !conf.yes {
+        term.write_line("Cover spectrometer input so no light reaches sensor and press Enter")?;
+        term.read_line()?;
+    }
+    let mut darks = Vec::with_capacity(conf.exposures.len());
+    for &exposure in &conf.exposures {
+        ccd.set_exp_time(exposure)?;
+        // Frame being integrated while exposure time changed is still taken with the old one
+        ccd.get_frame()?;
+        let mut frames = Vec::with_capacity(conf.count);
+        ccd.extend_with_frames(&mut frames, conf.count)?;
+        // Averaged in floating point, rounding would flatten small slopes at short exposures
+        let mut sums = vec![0.0; FRAME_PIXEL_COUNT];
+        for frame in &frames {
+            for (sum, px) in sums.iter_mut().zip(frame) {
+                *sum += *px as f64;
+            }
+        }
+        let mean: Vec<f64> = sums.iter().map(|sum| sum / frames.len() as f64).collect();
+        println!(
+            "Exposure time {exposure}: mean dark level {:.1}",
+            mean.iter().sum::<f64>() / mean.len() as f64
+        );
+        darks.push((exposure, mean));
+    }
+    ccd.set_exp_time(original)?;
+
+    let dark_current = DarkCurrent::fit(&darks)?;
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    println!(
+        "Mean offset {:.1} counts, mean dark current {:.3} counts per unit of exposure time",
+        mean(&dark_current.offset),
+        mean(&dark_current.slope)
+    );
+
+    if !conf.yes && !confirm(&term, &format!("Save dark current for device {serial}?"))? {
+        println!("Dark current was not saved");
+        return Ok(());
+    }
+    let mut stored = DeviceCalibration::load(&serial)?.unwrap_or_default();
+    stored.dark_current = Some(dark_current);
+    let path = stored.save(&serial)?;
+    println!("Saved calibration to {}", path.display());
+    Ok(())
+}
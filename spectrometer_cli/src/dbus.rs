@@ -0,0 +1,109 @@
+use crate::daemon::{lock, Bus, SharedControl};
+use simple_eyre::{eyre::eyre, Result};
+use zbus::{blocking::connection, fdo, interface};
+
+/// Well-known name daemon owns on the bus
+pub const SERVICE: &str = "io.github.eaglesemanation.Spectrometer";
+/// Object control interface is served at
+pub const PATH: &str = "/io/github/eaglesemanation/Spectrometer";
+
+struct Spectrometer {
+    control: SharedControl,
+    /// Names of configured acquisitions, in order of their indices
+    acquisitions: Vec<String>,
+}
+
+#[interface(name = "io.github.eaglesemanation.Spectrometer1")]
+impl Spectrometer {
+    /// Runs acquisition with this name right away, its schedule isn't affected
+    fn start_capture(&self, acquisition: &str) -> fdo::Result<()> {
+        let idx = self
+            .acquisitions
+            .iter()
+            .position(|name| name == acquisition)
+            .ok_or_else(|| {
+                fdo::Error::InvalidArgs(format!("No acquisition named {acquisition:?}"))
+            })?;
+        let mut control = lock(&self.control);
+        if control.running.is_some() || control.requested.is_some() {
+            return Err(fdo::Error::Failed("Capture is already running".into()));
+        }
+        control.requested = Some(idx);
+        Ok(())
+    }
+
+    /// Stops capture in progress, frames taken so far are written out
+    fn stop_capture(&self) -> fdo::Result<()> {
+        let mut control = lock(&self.control);
+        if control.running.is_none() {
+            return Err(fdo::Error::Failed("No capture is running".into()));
+        }
+        control.stop = true;
+        Ok(())
+    }
+
+    fn get_last_frame(&self) -> fdo::Result<Vec<u16>> {
+        lock(&self.control)
+            .last_frame
+            .clone()
+            .ok_or_else(|| fdo::Error::Failed("No frame was captured yet".into()))
+    }
+
+    fn get_exposure(&self) -> fdo::Result<u16> {
+        let control = lock(&self.control);
+        control.exposure.or(control.device_exposure).ok_or_else(|| {
+            fdo::Error::Failed("Exposure time isn't known until the first capture".into())
+        })
+    }
+
+    /// Takes effect from the next run, since device is only opened while capturing
+    fn set_exposure(&self, exposure: u16) {
+        lock(&self.control).exposure = Some(exposure);
+    }
+}
+
+/// Registers control interface on `bus`, it's served for as long as connection is kept
+pub fn serve(
+    bus: Bus,
+    control: SharedControl,
+    acquisitions: Vec<String>,
+) -> Result<zbus::blocking::Connection> {
+    let builder = match bus {
+        Bus::Session => connection::Builder::session(),
+        Bus::System => connection::Builder::system(),
+    };
+    let iface = Spectrometer {
+        control,
+        acquisitions,
+    };
+    builder
+        .and_then(|b| b.name(SERVICE))
+        .and_then(|b| b.serve_at(PATH, iface))
+        .and_then(|b| b.build())
+        .map_err(|e| eyre!("Could not register {SERVICE} on {bus:?} bus: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_overlapping_captures() {
+        let control = SharedControl::default();
+        let iface = Spectrometer {
+            control: control.clone(),
+            acquisitions: vec!["hourly".into(), "daily".into()],
+        };
+        assert!(iface.start_capture("weekly").is_err());
+        assert!(iface.stop_capture().is_err());
+        iface.start_capture("daily").unwrap();
+        assert_eq!(lock(&control).requested, Some(1));
+        assert!(iface.start_capture("hourly").is_err());
+
+        assert!(iface.get_exposure().is_err());
+        lock(&control).device_exposure = Some(10);
+        assert_eq!(iface.get_exposure().unwrap(), 10);
+        iface.set_exposure(20);
+        assert_eq!(iface.get_exposure().unwrap(), 20);
+    }
+}
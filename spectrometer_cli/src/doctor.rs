@@ -0,0 +1,184 @@
+use crate::{cli::DoctorConf, serial::SerialCCD};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+/// Checks that depend on talking to CCD, skipped once it can't be reached
+const DEVICE_CHECKS: [&str; 4] = [
+    "Query version",
+    "Exposure time",
+    "Average time",
+    "Single read",
+];
+
+/// Outcomes of checks, printed as they finish so that a hanging one is easy to spot
+#[derive(Default)]
+struct Report {
+    failed: usize,
+}
+
+impl Report {
+    /// Runs a single check, returning its value if it passed
+    fn check<T>(&mut self, name: &str, check: impl FnOnce() -> Result<(T, String)>) -> Option<T> {
+        let started = Instant::now();
+        let res = check();
+        let elapsed = started.elapsed();
+        match res {
+            Ok((value, detail)) => {
+                println!("{}", line("PASS", name, Some(elapsed), &detail));
+                Some(value)
+            }
+            Err(e) => {
+                self.failed += 1;
+                println!("{}", line("FAIL", name, Some(elapsed), &e.to_string()));
+                None
+            }
+        }
+    }
+
+    fn skip(&self, name: &str) {
+        println!("{}", line("SKIP", name, None, "previous check failed"));
+    }
+}
+
+fn line(status: &str, name: &str, elapsed: Option<Duration>, detail: &str) -> String {
+    let elapsed = elapsed.map_or(String::new(), |e| format!("{} ms", e.as_millis()));
+    format!("{status:<4}  {name:<16} {elapsed:>8}  {detail}")
+}
+
+/// Sets a setting to `probe`, reads it back and restores it, so that device ends up exactly as it
+/// was found whether check passes or not
+fn toggle<D, T: Copy + PartialEq + Display>(
+    device: &mut D,
+    get: impl Fn(&mut D) -> Result<T>,
+    set: impl Fn(&mut D, T) -> Result<()>,
+    probe: T,
+) -> Result<String> {
+    let original = get(device)?;
+    set(device, probe)?;
+    let read_back = get(device);
+    set(device, original)?;
+    let read_back = read_back?;
+    if read_back != probe {
+        return Err(eyre!("set to {probe}, but device reports {read_back}"));
+    }
+    let restored = get(device)?;
+    if restored != original {
+        return Err(eyre!(
+            "restored to {original}, but device reports {restored}"
+        ));
+    }
+    Ok(format!("{original} -> {probe} -> {original}"))
+}
+
+fn single_read(ccd: &mut SerialCCD) -> Result<String> {
+    ccd.set_verify_crc(true);
+    ccd.reset_stats();
+    let frame = ccd.get_frame()?;
+    let retried = ccd.stats().crc_failures;
+    let max = frame.iter().copied().max().unwrap_or(0);
+    let mut detail = format!("CRC valid, highest pixel {max} counts");
+    if retried > 0 {
+        detail.push_str(&format!(", {retried} frames with bad CRC retried"));
+    }
+    Ok(detail)
+}
+
+/// `doctor` subcommand, runs every step of talking to CCD once and reports which of them work
+pub fn run(conf: &DoctorConf) -> Result<()> {
+    let serial = &conf.serial;
+    println!(
+        "{} {}, port {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        serial.serial
+    );
+    let mut report = Report::default();
+    let port = report.check("Open port", || {
+        serial.check_port()?;
+        Ok(((), format!("at {} baud", serial.baud)))
+    });
+    let ccd = match port {
+        Some(()) => report.check("Detect baud rate", || {
+            let (ccd, baud) = serial.detect_baud()?;
+            Ok((ccd, format!("CCD responds at {baud} baud")))
+        }),
+        None => {
+            report.skip("Detect baud rate");
+            None
+        }
+    };
+    match ccd {
+        Some(mut ccd) => {
+            report.check("Query version", || {
+                let version = ccd.get_version()?;
+                let detail = format!(
+                    "serial number {}, firmware {}, sensor {}",
+                    version.serial_number(),
+                    version.firmware_version(),
+                    version.sensor_type()
+                );
+                Ok(((), detail))
+            });
+            report.check("Exposure time", || {
+                let original = ccd.get_exp_time()?;
+                let probe = original.checked_add(1).unwrap_or(original - 1);
+                let detail = toggle(
+                    &mut ccd,
+                    |ccd| Ok(ccd.get_exp_time()?),
+                    |ccd, t| Ok(ccd.set_exp_time(t)?),
+                    probe,
+                )?;
+                Ok(((), detail))
+            });
+            report.check("Average time", || {
+                let original = ccd.get_avg_time()?;
+                let probe = original.checked_add(1).unwrap_or(original - 1);
+                let detail = toggle(
+                    &mut ccd,
+                    |ccd| Ok(ccd.get_avg_time()?),
+                    |ccd, t| Ok(ccd.set_avg_time(t)?),
+                    probe,
+                )?;
+                Ok(((), detail))
+            });
+            report.check("Single read", || Ok(((), single_read(&mut ccd)?)));
+        }
+        None => DEVICE_CHECKS.iter().for_each(|name| report.skip(name)),
+    }
+    if report.failed > 0 {
+        return Err(eyre!(
+            "{} checks failed, include this report when filing an issue",
+            report.failed
+        ));
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toggle_restores_setting() {
+        let mut value = 10u16;
+        let set = |v: &mut u16, t| {
+            *v = t;
+            Ok(())
+        };
+        let detail = toggle(&mut value, |v| Ok(*v), set, 11).unwrap();
+        assert_eq!(detail, "10 -> 11 -> 10");
+        assert_eq!(value, 10);
+
+        // Device that ignores writes
+        let mut stuck = 10u16;
+        assert!(toggle(&mut stuck, |v| Ok(*v), |_, _| Ok(()), 11).is_err());
+        assert_eq!(
+            line("PASS", "Open port", Some(Duration::from_millis(3)), "ok"),
+            "PASS  Open port            3 ms  ok"
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use crate::output::{self, pixel_wavelengths, Header};
+use ccd_lcamv06::QualityFlags;
+use hdf5_metno_sys::{
+    h5::{hsize_t, H5open},
+    h5a::{H5Aclose, H5Acreate2, H5Awrite},
+    h5d::{H5Dclose, H5Dcreate2, H5Dget_space, H5Dset_extent, H5Dwrite},
+    h5f::{H5Fclose, H5Fcreate, H5F_ACC_EXCL},
+    h5g::{H5Gclose, H5Gcreate2},
+    h5i::hid_t,
+    h5p::{H5Pclose, H5Pcreate, H5Pset_chunk, H5P_CLS_DATASET_CREATE, H5P_DEFAULT},
+    h5s::{
+        H5S_class_t, H5S_seloper_t, H5Sclose, H5Screate, H5Screate_simple, H5Sselect_hyperslab,
+        H5S_ALL, H5S_UNLIMITED,
+    },
+    h5t::{
+        H5T_cset_t, H5Tclose, H5Tcopy, H5Tset_cset, H5Tset_size, H5T_C_S1, H5T_NATIVE_DOUBLE,
+        H5T_NATIVE_INT64, H5T_NATIVE_UINT16, H5T_NATIVE_UINT8,
+    },
+    LOCK,
+};
+use simple_eyre::{eyre::eyre, Result};
+use std::{collections::BTreeMap, ffi::CString, path::Path, ptr};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Frames written to file at once, which is also the chunk size of extensible datasets
+const BATCH_FRAMES: usize = 64;
+
+/// Group holding the run, a file only ever has one as existing files are never appended to
+const RUN_GROUP: &str = "run_1";
+
+/// Identifier of an open HDF5 object, closed by the function matching its kind once dropped
+struct Handle {
+    id: hid_t,
+    close: unsafe extern "C" fn(hid_t) -> i32,
+}
+
+impl Handle {
+    /// Wraps identifier returned by HDF5, which is negative when the call failed
+    fn new(id: hid_t, close: unsafe extern "C" fn(hid_t) -> i32, what: &str) -> Result<Self> {
+        if id < 0 {
+            return Err(eyre!("Could not {what} in HDF5 file"));
+        }
+        Ok(Handle { id, close })
+    }
+
+    /// Same as dropping, but reports failure, which for a file means data didn't make it to disk
+    fn close(self) -> Result<()> {
+        let _lock = LOCK.lock();
+        let status = unsafe { (self.close)(self.id) };
+        std::mem::forget(self);
+        check(status, "close output")
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        let _lock = LOCK.lock();
+        unsafe { (self.close)(self.id) };
+    }
+}
+
+fn check(status: i32, what: &str) -> Result<()> {
+    if status < 0 {
+        return Err(eyre!("Could not {what} in HDF5 file"));
+    }
+    Ok(())
+}
+
+/// Values that have a native HDF5 type, so that buffers of them can be handed over as is
+trait Native: Copy {
+    fn type_id() -> hid_t;
+}
+
+impl Native for u8 {
+    fn type_id() -> hid_t {
+        *H5T_NATIVE_UINT8
+    }
+}
+
+impl Native for u16 {
+    fn type_id() -> hid_t {
+        *H5T_NATIVE_UINT16
+    }
+}
+
+impl Native for i64 {
+    fn type_id() -> hid_t {
+        *H5T_NATIVE_INT64
+    }
+}
+
+impl Native for f64 {
+    fn type_id() -> hid_t {
+        *H5T_NATIVE_DOUBLE
+    }
+}
+
+fn c_name(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| eyre!("Name {name:?} can't be stored in HDF5 file"))
+}
+
+/// Dataset of `width` values per row, or of single values with `width` 0, that grows as rows
+/// are appended
+fn extensible<T: Native>(group: &Handle, name: &str, width: usize) -> Result<Handle> {
+    let name = c_name(name)?;
+    let mut dims: Vec<hsize_t> = vec![0];
+    let mut max = vec![H5S_UNLIMITED];
+    let mut chunk = vec![BATCH_FRAMES as hsize_t];
+    if width > 0 {
+        dims.push(width as hsize_t);
+        max.push(width as hsize_t);
+        chunk.push(width as hsize_t);
+    }
+    let rank = dims.len() as i32;
+    unsafe {
+        let space = Handle::new(
+            H5Screate_simple(rank, dims.as_ptr(), max.as_ptr()),
+            H5Sclose,
+            "create dataspace",
+        )?;
+        let props = Handle::new(
+            H5Pcreate(*H5P_CLS_DATASET_CREATE),
+            H5Pclose,
+            "create dataset properties",
+        )?;
+        check(
+            H5Pset_chunk(props.id, rank, chunk.as_ptr()),
+            "set chunk size",
+        )?;
+        Handle::new(
+            H5Dcreate2(
+                group.id,
+                name.as_ptr(),
+                T::type_id(),
+                space.id,
+                H5P_DEFAULT,
+                props.id,
+                H5P_DEFAULT,
+            ),
+            H5Dclose,
+            "create dataset",
+        )
+    }
+}
+
+/// Appends `values` as rows of an [extensible] dataset that already has `rows` of them
+fn append<T: Native>(dataset: &Handle, rows: usize, width: usize, values: &[T]) -> Result<()> {
+    let added = values.len() / width.max(1);
+    let mut dims = vec![(rows + added) as hsize_t];
+    let mut start = vec![rows as hsize_t];
+    let mut count = vec![added as hsize_t];
+    if width > 0 {
+        dims.push(width as hsize_t);
+        start.push(0);
+        count.push(width as hsize_t);
+    }
+    unsafe {
+        check(H5Dset_extent(dataset.id, dims.as_ptr()), "extend dataset")?;
+        let file_space = Handle::new(H5Dget_space(dataset.id), H5Sclose, "get dataspace")?;
+        check(
+            H5Sselect_hyperslab(
+                file_space.id,
+                H5S_seloper_t::H5S_SELECT_SET,
+                start.as_ptr(),
+                ptr::null(),
+                count.as_ptr(),
+                ptr::null(),
+            ),
+            "select rows",
+        )?;
+        let mem_space = Handle::new(
+            H5Screate_simple(count.len() as i32, count.as_ptr(), ptr::null()),
+            H5Sclose,
+            "create dataspace",
+        )?;
+        check(
+            H5Dwrite(
+                dataset.id,
+                T::type_id(),
+                mem_space.id,
+                file_space.id,
+                H5P_DEFAULT,
+                values.as_ptr().cast(),
+            ),
+            "write dataset",
+        )
+    }
+}
+
+/// One dimensional dataset written in full right away
+fn fixed<T: Native>(group: &Handle, name: &str, values: &[T]) -> Result<()> {
+    let name = c_name(name)?;
+    let dims = [values.len() as hsize_t];
+    unsafe {
+        let space = Handle::new(
+            H5Screate_simple(1, dims.as_ptr(), ptr::null()),
+            H5Sclose,
+            "create dataspace",
+        )?;
+        let dataset = Handle::new(
+            H5Dcreate2(
+                group.id,
+                name.as_ptr(),
+                T::type_id(),
+                space.id,
+                H5P_DEFAULT,
+                H5P_DEFAULT,
+                H5P_DEFAULT,
+            ),
+            H5Dclose,
+            "create dataset",
+        )?;
+        check(
+            H5Dwrite(
+                dataset.id,
+                T::type_id(),
+                H5S_ALL,
+                H5S_ALL,
+                H5P_DEFAULT,
+                values.as_ptr().cast(),
+            ),
+            "write dataset",
+        )
+    }
+}
+
+/// UTF-8 string attribute of `object`
+fn attribute(object: &Handle, name: &str, value: &str) -> Result<()> {
+    let name = c_name(name)?;
+    unsafe {
+        let string = Handle::new(H5Tcopy(*H5T_C_S1), H5Tclose, "create string type")?;
+        // Zero sized strings aren't allowed
+        let size = value.len().max(1);
+        check(H5Tset_size(string.id, size), "set string size")?;
+        check(
+            H5Tset_cset(string.id, H5T_cset_t::H5T_CSET_UTF8),
+            "set string encoding",
+        )?;
+        let space = Handle::new(
+            H5Screate(H5S_class_t::H5S_SCALAR),
+            H5Sclose,
+            "create dataspace",
+        )?;
+        let attribute = Handle::new(
+            H5Acreate2(
+                object.id,
+                name.as_ptr(),
+                string.id,
+                space.id,
+                H5P_DEFAULT,
+                H5P_DEFAULT,
+            ),
+            H5Aclose,
+            "create attribute",
+        )?;
+        let mut buf = value.as_bytes().to_vec();
+        buf.resize(size, 0);
+        check(
+            H5Awrite(attribute.id, string.id, buf.as_ptr().cast()),
+            "write attribute",
+        )
+    }
+}
+
+/// Writes a run into a new HDF5 file as group of `frames` with a row of real pixels for every
+/// frame, along with `timestamps` in microseconds since Unix epoch and quality `flags` packed
+/// into bits. Wavelength of every pixel, dark and flat field frames of calibration go into
+/// `wavelength`, `dark` and `reference` datasets when known, capture metadata into attributes of
+/// the group
+pub struct Hdf5Writer {
+    frames: Handle,
+    timestamps: Handle,
+    flags: Handle,
+    file: Handle,
+    pixels: usize,
+    pending_frames: Vec<u16>,
+    pending_timestamps: Vec<i64>,
+    pending_flags: Vec<u8>,
+    written: usize,
+}
+
+impl Hdf5Writer {
+    pub fn create(path: &Path, header: &Header) -> Result<Self> {
+        let _lock = LOCK.lock();
+        let pixels = header.pixels;
+        let c_path = path
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| eyre!("Path {path:?} can't be used for HDF5 output"))?;
+        let (file, group) = unsafe {
+            check(H5open(), "initialize library")?;
+            // Existing file makes exclusive creation fail, so nothing is ever overwritten
+            let file = Handle::new(
+                H5Fcreate(c_path.as_ptr(), H5F_ACC_EXCL, H5P_DEFAULT, H5P_DEFAULT),
+                H5Fclose,
+                "create file",
+            )
+            .map_err(|_| eyre!("Could not create HDF5 file {path:?}, it may already exist"))?;
+            let name = c_name(RUN_GROUP)?;
+            let group = Handle::new(
+                H5Gcreate2(
+                    file.id,
+                    name.as_ptr(),
+                    H5P_DEFAULT,
+                    H5P_DEFAULT,
+                    H5P_DEFAULT,
+                ),
+                H5Gclose,
+                "create group",
+            )?;
+            (file, group)
+        };
+        attribute(&group, "started", &output::now().format(&Rfc3339)?)?;
+        // Later lines win, same as when reading header top to bottom
+        let settings: BTreeMap<&str, &str> = header
+            .metadata
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+        for (key, value) in settings {
+            attribute(&group, key, value)?;
+        }
+        let wavelength = pixel_wavelengths(&header.wavelength, pixels);
+        if !wavelength.is_empty() {
+            fixed(&group, "wavelength", &wavelength)?;
+        }
+        if let Some(dark) = &header.dark {
+            fixed(&group, "dark", &dark[..pixels.min(dark.len())])?;
+        }
+        if let Some(flat) = &header.flat {
+            fixed(&group, "reference", &flat[..pixels.min(flat.len())])?;
+        }
+        tracing::debug!("Writing run to {path:?}");
+        Ok(Hdf5Writer {
+            frames: extensible::<u16>(&group, "frames", pixels)?,
+            timestamps: extensible::<i64>(&group, "timestamps", 0)?,
+            flags: extensible::<u8>(&group, "flags", 0)?,
+            file,
+            pixels,
+            pending_frames: Vec::with_capacity(BATCH_FRAMES * pixels),
+            pending_timestamps: Vec::with_capacity(BATCH_FRAMES),
+            pending_flags: Vec::with_capacity(BATCH_FRAMES),
+            written: 0,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        frame: &[u16],
+        flags: QualityFlags,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        self.pending_frames.extend_from_slice(&frame[..self.pixels]);
+        self.pending_timestamps
+            .push((timestamp.unix_timestamp_nanos() / 1000) as i64);
+        self.pending_flags.push(flags.bits());
+        if self.pending_flags.len() >= BATCH_FRAMES {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> Result<()> {
+        if self.pending_flags.is_empty() {
+            return Ok(());
+        }
+        let _lock = LOCK.lock();
+        append(
+            &self.frames,
+            self.written,
+            self.pixels,
+            &self.pending_frames,
+        )?;
+        append(&self.timestamps, self.written, 0, &self.pending_timestamps)?;
+        append(&self.flags, self.written, 0, &self.pending_flags)?;
+        self.written += self.pending_flags.len();
+        self.pending_frames.clear();
+        self.pending_timestamps.clear();
+        self.pending_flags.clear();
+        Ok(())
+    }
+
+    /// Writes out remaining frames and closes file, returning amount of frames written
+    pub fn finish(mut self) -> Result<usize> {
+        self.flush_batch()?;
+        let Hdf5Writer {
+            frames,
+            timestamps,
+            flags,
+            file,
+            written,
+            ..
+        } = self;
+        // File is only really closed once nothing within it is open
+        drop((frames, timestamps, flags));
+        file.close()?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hdf5_metno_sys::{
+        h5a::{H5Aopen, H5Aread},
+        h5d::{H5Dopen2, H5Dread},
+        h5f::{H5Fopen, H5F_ACC_RDONLY},
+        h5g::H5Gopen2,
+        h5s::H5Sget_simple_extent_dims,
+    };
+
+    fn read_u16(file: &Handle, name: &str, len: usize) -> (Vec<hsize_t>, Vec<u16>) {
+        let name = c_name(name).unwrap();
+        unsafe {
+            let dataset =
+                Handle::new(H5Dopen2(file.id, name.as_ptr(), H5P_DEFAULT), H5Dclose, "").unwrap();
+            let space = Handle::new(H5Dget_space(dataset.id), H5Sclose, "").unwrap();
+            let mut dims = [0; 2];
+            let rank = H5Sget_simple_extent_dims(space.id, dims.as_mut_ptr(), ptr::null_mut());
+            let mut values = vec![0u16; len];
+            check(
+                H5Dread(
+                    dataset.id,
+                    *H5T_NATIVE_UINT16,
+                    H5S_ALL,
+                    H5S_ALL,
+                    H5P_DEFAULT,
+                    values.as_mut_ptr().cast(),
+                ),
+                "",
+            )
+            .unwrap();
+            (dims[..rank as usize].to_vec(), values)
+        }
+    }
+
+    #[test]
+    fn run_group() {
+        let path = std::env::temp_dir().join(format!("frames-{}.h5", std::process::id()));
+        let header = Header {
+            metadata: vec!["exposure time: 10".to_string()],
+            pixels: 3,
+            dark: Some(vec![1, 2, 3, 4]),
+            ..Default::default()
+        };
+        let mut writer = Hdf5Writer::create(&path, &header).unwrap();
+        for i in 0..BATCH_FRAMES as u16 + 2 {
+            writer
+                .write(
+                    &[i, i + 1, i + 2, 0],
+                    QualityFlags::from_bits(i as u8 & QualityFlags::SATURATED),
+                    OffsetDateTime::UNIX_EPOCH,
+                )
+                .unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), BATCH_FRAMES + 2);
+        assert!(Hdf5Writer::create(&path, &header).is_err());
+
+        let _lock = LOCK.lock();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let file = unsafe {
+            Handle::new(
+                H5Fopen(c_path.as_ptr(), H5F_ACC_RDONLY, H5P_DEFAULT),
+                H5Fclose,
+                "",
+            )
+            .unwrap()
+        };
+        let (dims, frames) = read_u16(&file, "run_1/frames", (BATCH_FRAMES + 2) * 3);
+        assert_eq!(dims, vec![BATCH_FRAMES as hsize_t + 2, 3]);
+        let last = BATCH_FRAMES as u16 + 1;
+        assert_eq!(frames[frames.len() - 3..], [last, last + 1, last + 2]);
+        let (dims, dark) = read_u16(&file, "run_1/dark", 3);
+        assert_eq!(dims, vec![3]);
+        assert_eq!(dark, vec![1, 2, 3]);
+
+        let group = c_name(RUN_GROUP).unwrap();
+        let name = c_name("exposure time").unwrap();
+        let mut value = [0u8; 2];
+        unsafe {
+            let group =
+                Handle::new(H5Gopen2(file.id, group.as_ptr(), H5P_DEFAULT), H5Gclose, "").unwrap();
+            let attribute =
+                Handle::new(H5Aopen(group.id, name.as_ptr(), H5P_DEFAULT), H5Aclose, "").unwrap();
+            let string = Handle::new(H5Tcopy(*H5T_C_S1), H5Tclose, "").unwrap();
+            H5Tset_size(string.id, value.len());
+            check(
+                H5Aread(attribute.id, string.id, value.as_mut_ptr().cast()),
+                "",
+            )
+            .unwrap();
+        }
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&value, b"10");
+    }
+}
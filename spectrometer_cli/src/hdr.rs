@@ -0,0 +1,180 @@
+use crate::{
+    capture::capture_header,
+    cli::HdrReadingConf,
+    output::{self, is_stdio},
+    reference::{self, ReferenceKind},
+};
+use ccd_lcamv06::{Frame, FRAME_PIXEL_COUNT};
+use simple_eyre::{eyre::eyre, Result};
+use std::io::{self, BufWriter, Write};
+
+/// Frames captured at a single exposure time
+pub struct Bracket {
+    pub exposure: u16,
+    /// Pixels that reached saturation in any of the frames
+    pub saturated: Vec<bool>,
+    /// Averaged frame with calibration applied
+    pub frame: Frame,
+}
+
+/// Merges brackets into counts expected at the longest exposure time. Pixels are averaged over
+/// brackets where they weren't saturated, weighted by exposure time, so longer exposures with
+/// better signal to noise ratio dominate. Pixels saturated everywhere are scaled up from the
+/// shortest exposure and counted
+pub fn merge(brackets: &[Bracket]) -> (Vec<f64>, usize) {
+    let longest = brackets.iter().map(|b| b.exposure).max().unwrap_or(1) as f64;
+    let shortest = brackets.iter().min_by_key(|b| b.exposure);
+    let mut clipped = 0;
+    let merged = (0..FRAME_PIXEL_COUNT)
+        .map(|px| {
+            let (counts, time) = brackets
+                .iter()
+                .filter(|b| !b.saturated[px])
+                .fold((0.0, 0.0), |(counts, time), b| {
+                    (counts + b.frame[px] as f64, time + b.exposure as f64)
+                });
+            if time > 0.0 {
+                return counts / time * longest;
+            }
+            clipped += 1;
+            shortest.map_or(0.0, |b| b.frame[px] as f64 * longest / b.exposure as f64)
+        })
+        .collect();
+    (merged, clipped)
+}
+
+/// `read hdr` subcommand, captures frames at every exposure time and writes merged spectrum as
+/// a single CSV row
+pub fn read(conf: &HdrReadingConf) -> Result<()> {
+    if conf.capture.exposure_time.is_some() {
+        return Err(eyre!(
+            "HDR capture sets exposure times from --exposures only"
+        ));
+    }
+    if !conf.csv.columns.is_empty() {
+        return Err(eyre!("HDR spectrum is always written as a single row"));
+    }
+    conf.csv.validate()?;
+    if conf.count == 0 {
+        return Err(eyre!("At least one frame is needed per exposure time"));
+    }
+    if conf.exposures.contains(&0) {
+        return Err(eyre!("Exposure times have to be above zero"));
+    }
+    if conf.exposures.iter().all(|&t| t == conf.exposures[0]) {
+        return Err(eyre!("At least two different exposure times are needed"));
+    }
+    let mut ccd = conf.serial.open_ccd()?;
+    conf.capture.apply(&mut ccd)?;
+    let version = ccd.get_version()?;
+    let mut calibration = conf.capture.calibration(&version)?;
+    // Dark reference passed on command line takes precedence over stored dark current
+    let dark_reference = conf
+        .capture
+        .apply_reference
+        .iter()
+        .any(|r| r.kind == ReferenceKind::Dark);
+    let dark_current = calibration
+        .as_ref()
+        .and_then(|c| c.dark_current.clone())
+        .filter(|_| !dark_reference);
+    let original = ccd.get_exp_time()?;
+
+    let mut brackets = Vec::with_capacity(conf.exposures.len());
+    for &exposure in &conf.exposures {
+        ccd.set_exp_time(exposure)?;
+        // Frame being integrated while exposure time changed is still taken with the old one
+        ccd.get_frame()?;
+        let mut frames = Vec::with_capacity(conf.count);
+        ccd.extend_with_frames(&mut frames, conf.count)?;
+        let mut saturated = vec![false; FRAME_PIXEL_COUNT];
+        for frame in &frames {
+            for (saturated, px) in saturated.iter_mut().zip(frame) {
+                *saturated |= *px >= conf.saturation;
+            }
+        }
+        let mut frame = reference::average(&frames);
+        if let Some(calibration) = &mut calibration {
+            // Dark level depends on exposure time, so it's synthesized for every bracket
+            if let Some(dark_current) = &dark_current {
+                calibration.dark = Some(dark_current.dark(exposure));
+            }
+            calibration.correction().apply(&mut frame);
+        }
+        tracing::debug!(
+            "Exposure time {exposure}: {} saturated pixels",
+            saturated.iter().filter(|s| **s).count()
+        );
+        brackets.push(Bracket {
+            exposure,
+            saturated,
+            frame,
+        });
+    }
+    ccd.set_exp_time(original)?;
+
+    let (merged, clipped) = merge(&brackets);
+    if clipped > 0 {
+        tracing::warn!("{clipped} pixels are saturated even at the shortest exposure time");
+    }
+    let mut header = capture_header(
+        &conf.serial,
+        &conf.capture,
+        &version,
+        calibration.as_ref(),
+        ccd.sensor_layout().pixels(),
+    );
+    header
+        .metadata
+        .push(format!("hdr exposure times: {:?}", conf.exposures));
+    header.metadata.push(format!(
+        "hdr counts scaled to exposure time: {}",
+        conf.exposures.iter().max().copied().unwrap_or_default()
+    ));
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(if is_stdio(&conf.output) {
+        Box::new(io::stdout())
+    } else {
+        Box::new(output::create_new(&conf.output)?)
+    });
+    conf.csv.write_header(
+        &mut out,
+        &header.metadata,
+        &output::pixel_wavelengths(&header.wavelength, header.pixels),
+        &output::pixel_raman_shifts(&header.wavelength, header.laser, header.pixels),
+    )?;
+    let values: Vec<_> = merged.iter().map(|v| conf.csv.number(*v, 2)).collect();
+    write!(out, "{}", conf.csv.row(&values))?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_brackets() {
+        let bracket = |exposure: u16, value: u16, saturated: bool| {
+            let mut frame = [value; FRAME_PIXEL_COUNT];
+            frame[1] = value / 2;
+            let mut mask = vec![saturated; FRAME_PIXEL_COUNT];
+            mask[1] = false;
+            Bracket {
+                exposure,
+                saturated: mask,
+                frame,
+            }
+        };
+        // Pixel 0 is a strong line saturating long exposure, pixel 1 is weak everywhere
+        let brackets = [bracket(10, 1000, false), bracket(100, 65535, true)];
+        let (merged, clipped) = merge(&brackets);
+        assert_eq!(clipped, 0);
+        assert_eq!(merged[0], 10000.0);
+        // Weighted by exposure time: (500 + 32767) / 110 * 100
+        assert!((merged[1] - 30242.7).abs() < 0.1);
+
+        let (merged, clipped) = merge(&[bracket(10, 65535, true), bracket(100, 65535, true)]);
+        assert_eq!(clipped, FRAME_PIXEL_COUNT - 1);
+        assert_eq!(merged[0], 655350.0);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{
+    analysis::{self, Histogram},
+    cli::HistogramConf,
+    input::{self, InputFormat},
+    plot,
+};
+use simple_eyre::{eyre::eyre, Result};
+use std::fmt::Write;
+
+/// Width of the longest bar in text output
+const BAR_WIDTH: usize = 50;
+
+/// Bins from the first to the last non-empty one, empty ranges at either end carry nothing
+pub fn occupied(histogram: &Histogram) -> std::ops::Range<usize> {
+    let first = histogram.bins.iter().position(|&n| n > 0);
+    let last = histogram.bins.iter().rposition(|&n| n > 0);
+    match (first, last) {
+        (Some(first), Some(last)) => first..last + 1,
+        _ => 0..0,
+    }
+}
+
+/// Bins as rows of count range, bar scaled to the fullest bin and amount of pixels
+fn render(histogram: &Histogram) -> String {
+    let bins = occupied(histogram);
+    let max = histogram.bins.iter().copied().max().unwrap_or(0).max(1);
+    let mut text = String::new();
+    for bin in bins {
+        let range = histogram.range(bin);
+        let pixels = histogram.bins[bin];
+        let bar = "#".repeat((pixels * BAR_WIDTH).div_ceil(max));
+        // Writing into String can't fail
+        writeln!(
+            text,
+            "{:>5}-{:<5} {bar:<BAR_WIDTH$} {pixels}",
+            range.start,
+            range.end - 1
+        )
+        .ok();
+    }
+    text
+}
+
+/// `histogram` subcommand, shows how pixel values of a capture file are distributed
+pub fn run(conf: &HistogramConf) -> Result<()> {
+    if conf.bins == 0 {
+        return Err(eyre!("At least one bin is needed"));
+    }
+    let format = match conf.from {
+        Some(format) => format,
+        None => InputFormat::from_path(&conf.input)
+            .ok_or_else(|| eyre!("Can't tell format of {:?}, pass --from", conf.input))?,
+    };
+    let frames = input::read_capture(&conf.input, format)?;
+    let pixels = match conf.frame {
+        Some(frame) => frame
+            .checked_sub(1)
+            .and_then(|idx| frames.get(idx))
+            .ok_or_else(|| {
+                eyre!(
+                    "Frame #{frame} requested, but {:?} has {} frames",
+                    conf.input,
+                    frames.len()
+                )
+            })?
+            .clone(),
+        // Pooling frames together shows ADC behavior better than any single one of them
+        None => frames.concat(),
+    };
+    let histogram = analysis::histogram(&pixels, conf.bins);
+    match &conf.plot {
+        Some(path) => {
+            let name = conf.input.file_name().unwrap_or_default().to_string_lossy();
+            plot::write_histogram(path, &histogram, &format!("Histogram of {name}"))?;
+        }
+        None => {
+            println!(
+                "{} pixels in bins {} counts wide",
+                pixels.len(),
+                histogram.width
+            );
+            print!("{}", render(&histogram));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_bars() {
+        let histogram = analysis::histogram(&[3000, 3000, 5000, 5000, 5000, 5000], 16);
+        assert_eq!(occupied(&histogram), 0..2);
+        let text = render(&histogram);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("    0-4095  #########################"));
+        assert!(lines[0].ends_with(" 2"));
+        assert!(lines[1].contains(&"#".repeat(BAR_WIDTH)));
+    }
+}
@@ -0,0 +1,22 @@
+use simple_eyre::Result;
+use std::process::{Child, Command};
+
+/// Starts a command in system shell without waiting for it to finish
+pub fn spawn_shell(command: &str, envs: &[(&str, &str)]) -> Result<Child> {
+    tracing::debug!("Starting {command:?}");
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    cmd.arg(command);
+    cmd.envs(envs.iter().copied());
+    Ok(cmd.spawn()?)
+}
@@ -0,0 +1,66 @@
+use crate::{calibration::DeviceCalibration, cli::InfoConf, session::DeviceInfo};
+use num_traits::ToPrimitive;
+use serde::Serialize;
+use simple_eyre::Result;
+use std::fmt;
+
+/// Everything device reports about itself, along with what's stored for it locally
+#[derive(Serialize, Debug)]
+pub struct DeviceReport {
+    pub software_version: String,
+    pub port: String,
+    pub device: DeviceInfo,
+    pub exposure_time: u16,
+    pub average_time: u8,
+    /// Baud rate of UART, which is separate from USB port
+    pub baud_rate: u32,
+    /// Stored calibration as `key: value` lines, empty when there is none
+    pub calibration: Vec<String>,
+}
+
+impl fmt::Display for DeviceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Software version: {}", self.software_version)?;
+        writeln!(f, "Port: {}", self.port)?;
+        writeln!(f, "Hardware version: {}", self.device.hardware_version)?;
+        writeln!(f, "Firmware version: {}", self.device.firmware_version)?;
+        writeln!(f, "Sensor type: {}", self.device.sensor_type)?;
+        writeln!(f, "Serial number: {}", self.device.serial_number)?;
+        writeln!(f, "Exposure time: {}", self.exposure_time)?;
+        writeln!(f, "Average time: {}", self.average_time)?;
+        writeln!(f, "UART baud rate: {}", self.baud_rate)?;
+        if self.calibration.is_empty() {
+            write!(f, "Calibration: none")
+        } else {
+            write!(f, "Calibration:")?;
+            for line in &self.calibration {
+                write!(f, "\n    {line}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Queries all device state within a single connection
+pub fn info(conf: &InfoConf) -> Result<()> {
+    let mut ccd = conf.serial.open_ccd()?;
+    let version = ccd.get_version()?;
+    let report = DeviceReport {
+        software_version: env!("CARGO_PKG_VERSION").to_string(),
+        port: conf.serial.serial.clone(),
+        device: DeviceInfo::from(&version),
+        exposure_time: ccd.get_exp_time()?,
+        average_time: ccd.get_avg_time()?,
+        baud_rate: ccd.get_baudrate()?.to_u32().unwrap_or_default(),
+        calibration: DeviceCalibration::load(version.serial_number())?
+            .map(|calibration| calibration.metadata())
+            .unwrap_or_default(),
+    };
+    if conf.json {
+        serde_json::to_writer_pretty(std::io::stdout(), &report)?;
+        println!();
+    } else {
+        println!("{report}");
+    }
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use crate::output::is_stdio;
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use clap::ArgEnum;
+use simple_eyre::{eyre::eyre, Result};
+use std::{fs, io, path::Path};
+
+/// Formats of previously captured files that can be read back
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    Raw,
+    Npy,
+}
+
+impl InputFormat {
+    /// Guesses format from file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(InputFormat::Csv),
+            "raw" | "bin" => Some(InputFormat::Raw),
+            "npy" => Some(InputFormat::Npy),
+            _ => None,
+        }
+    }
+}
+
+fn frame_from_csv(line: &str) -> Result<Vec<u16>> {
+    line.split(',')
+        .map(|pixel| {
+            pixel
+                .trim()
+                .parse()
+                .map_err(|_| eyre!("{pixel:?} is not a valid pixel value"))
+        })
+        .collect()
+}
+
+/// Parses CSV in the same layout as produced by `--format csv`: one frame per line, with lines
+/// starting with `#` treated as comments
+pub fn frames_from_csv(data: &str) -> Result<Vec<Vec<u16>>> {
+    tracing::trace!("Parsing frames from CSV");
+    data.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(frame_from_csv)
+        .collect()
+}
+
+/// Reads all frames stored in a CSV file, `-` reads them from stdin
+pub fn read_frames(path: &Path) -> Result<Vec<Vec<u16>>> {
+    tracing::debug!("Reading frames from {path:?}");
+    let data = if is_stdio(path) {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path)?
+    };
+    frames_from_csv(&data)
+}
+
+/// Reads a file that is expected to contain exactly one frame
+pub fn read_frame(path: &Path) -> Result<Vec<u16>> {
+    let mut frames = read_frames(path)?;
+    match frames.len() {
+        1 => Ok(frames.remove(0)),
+        n => Err(eyre!("Expected a single frame in {path:?}, found {n}")),
+    }
+}
+
+/// Splits little endian u16 pixels into frames of `pixels` each
+fn frames_from_le(data: &[u8], pixels: usize) -> Result<Vec<Vec<u16>>> {
+    if pixels == 0 || !data.len().is_multiple_of(pixels * 2) {
+        return Err(eyre!(
+            "Data length {} is not a multiple of {pixels} pixel frames",
+            data.len()
+        ));
+    }
+    Ok(data
+        .chunks_exact(pixels * 2)
+        .map(|frame| {
+            frame
+                .chunks_exact(2)
+                .map(|px| u16::from_le_bytes([px[0], px[1]]))
+                .collect()
+        })
+        .collect())
+}
+
+/// Parses output of `--format raw`. Frame count in header is ignored, since capture that was cut
+/// short never gets to rewrite it
+pub fn frames_from_raw(data: &[u8]) -> Result<Vec<Vec<u16>>> {
+    if data.len() < 8 {
+        return Err(eyre!("Raw capture is too short to contain a header"));
+    }
+    let pixels = u32::from_le_bytes(data[..4].try_into()?) as usize;
+    frames_from_le(&data[8..], pixels)
+}
+
+/// Parses NPY files holding a 2D array of little endian u16, such as produced by `--format npy`
+pub fn frames_from_npy(data: &[u8]) -> Result<Vec<Vec<u16>>> {
+    if !data.starts_with(b"\x93NUMPY") || data.len() < 10 {
+        return Err(eyre!("Not an NPY file"));
+    }
+    let (header_len, dict_start) = match data[6] {
+        1 => (u16::from_le_bytes([data[8], data[9]]) as usize, 10),
+        2 | 3 if data.len() >= 12 => (
+            u32::from_le_bytes(data[8..12].try_into()?) as usize,
+            12,
+        ),
+        version => return Err(eyre!("NPY version {version} is not supported")),
+    };
+    let dict = data
+        .get(dict_start..dict_start + header_len)
+        .ok_or_else(|| eyre!("NPY header is truncated"))?;
+    let dict = std::str::from_utf8(dict)?;
+    if !dict.contains("'descr': '<u2'") || !dict.contains("'fortran_order': False") {
+        return Err(eyre!("Only C ordered arrays of little endian u16 are supported"));
+    }
+    let pixels = dict
+        .split("'shape': (")
+        .nth(1)
+        .and_then(|shape| shape.split(')').next())
+        .and_then(|shape| shape.split(',').nth(1))
+        .and_then(|pixels| pixels.trim().parse().ok())
+        .ok_or_else(|| eyre!("NPY array has to be 2 dimensional"))?;
+    frames_from_le(&data[dict_start + header_len..], pixels)
+}
+
+/// Reads frames from a capture file in any of supported formats
+pub fn read_capture(path: &Path, format: InputFormat) -> Result<Vec<Vec<u16>>> {
+    tracing::debug!("Reading {format:?} capture from {path:?}");
+    let frames = match format {
+        InputFormat::Csv => read_frames(path)?,
+        InputFormat::Raw => frames_from_raw(&fs::read(path)?)?,
+        InputFormat::Npy => frames_from_npy(&fs::read(path)?)?,
+    };
+    if let Some(frame) = frames.iter().find(|f| f.len() != FRAME_PIXEL_COUNT) {
+        return Err(eyre!(
+            "{path:?} has a frame of {} pixels, expected {FRAME_PIXEL_COUNT}",
+            frame.len()
+        ));
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frames_from_csv() {
+        let frames = frames_from_csv("1,2,3\n4, 5,6\n\n# Comment").unwrap();
+        assert_eq!(frames, vec![vec![1, 2, 3], vec![4, 5, 6]]);
+        assert!(frames_from_csv("1,two,3").is_err());
+    }
+
+    #[test]
+    fn parse_binary_captures() {
+        let mut raw = vec![2, 0, 0, 0, 0, 0, 0, 0];
+        raw.extend_from_slice(&[1, 0, 2, 0, 3, 0, 4, 0]);
+        assert_eq!(frames_from_raw(&raw).unwrap(), vec![vec![1, 2], vec![3, 4]]);
+        raw.push(5);
+        assert!(frames_from_raw(&raw).is_err());
+
+        let mut npy = b"\x93NUMPY\x01\x00".to_vec();
+        let dict = "{'descr': '<u2', 'fortran_order': False, 'shape': (1, 3), }\n";
+        npy.extend_from_slice(&(dict.len() as u16).to_le_bytes());
+        npy.extend_from_slice(dict.as_bytes());
+        npy.extend_from_slice(&[1, 0, 0, 1, 255, 255]);
+        assert_eq!(frames_from_npy(&npy).unwrap(), vec![vec![1, 256, 65535]]);
+    }
+}
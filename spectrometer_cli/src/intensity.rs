@@ -0,0 +1,194 @@
+use crate::{
+    analysis::wavelength_at, calibration::DeviceCalibration, cli::IntensityCalibrationConf,
+    output::is_stdio, reference, wavelength::confirm,
+};
+use console::Term;
+use simple_eyre::{eyre::eyre, Result};
+use std::{fs, io, path::Path};
+
+/// Pixels measuring less than this fraction of the brightest one are too noisy to correct and are
+/// left as is
+const MIN_SIGNAL: f64 = 0.01;
+
+/// Spectral output of a calibration lamp as listed in its certificate, sorted by wavelength
+#[derive(Debug, PartialEq)]
+struct Certificate {
+    /// Wavelength in nm with irradiance in any units
+    points: Vec<(f64, f64)>,
+}
+
+impl Certificate {
+    /// Parses two columns of wavelength in nm and irradiance, separated by comma or whitespace.
+    /// Empty lines and lines starting with `#` are skipped
+    fn parse(data: &str) -> Result<Self> {
+        let mut points = Vec::new();
+        for (idx, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|field| !field.is_empty())
+                .collect();
+            let point = match fields[..] {
+                [nm, value] => nm.parse::<f64>().ok().zip(value.parse::<f64>().ok()),
+                _ => None,
+            };
+            let point = point.ok_or_else(|| {
+                eyre!(
+                    "Line {} of certificate should hold wavelength and irradiance, got {line:?}",
+                    idx + 1
+                )
+            })?;
+            points.push(point);
+        }
+        if points.len() < 2 {
+            return Err(eyre!("Certificate should list at least two wavelengths"));
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Certificate { points })
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let data = if is_stdio(path) {
+            io::read_to_string(io::stdin())?
+        } else {
+            fs::read_to_string(path)?
+        };
+        Self::parse(&data).map_err(|e| eyre!("Could not read certificate {path:?}: {e}"))
+    }
+
+    /// Irradiance at given wavelength, linearly interpolated between listed ones
+    fn at(&self, nm: f64) -> Option<f64> {
+        let next = self.points.partition_point(|&(x, _)| x < nm);
+        match (
+            next.checked_sub(1).map(|i| self.points[i]),
+            self.points.get(next),
+        ) {
+            (_, Some(&(x, y))) if x == nm => Some(y),
+            (Some((x0, y0)), Some(&(x1, y1))) => Some(y0 + (y1 - y0) * (nm - x0) / (x1 - x0)),
+            _ => None,
+        }
+    }
+}
+
+/// Per pixel factors turning measured lamp spectrum into certified one, scaled to average 1 so
+/// corrected frames stay in the same range. Pixels outside of certificate or too dim get 1,
+/// their count is returned alongside
+fn response_correction(
+    measured: &[u16],
+    coeffs: &[f64],
+    certificate: &Certificate,
+) -> Result<(Vec<f64>, usize)> {
+    let brightest = measured.iter().copied().max().unwrap_or(0) as f64;
+    let factors: Vec<Option<f64>> = measured
+        .iter()
+        .enumerate()
+        .map(|(px, &value)| {
+            let value = value as f64;
+            if value <= 0.0 || value < brightest * MIN_SIGNAL {
+                return None;
+            }
+            certificate
+                .at(wavelength_at(coeffs, px as f64))
+                .map(|certified| certified / value)
+        })
+        .collect();
+    let corrected: Vec<f64> = factors.iter().flatten().copied().collect();
+    if corrected.is_empty() {
+        return Err(eyre!(
+            "No pixel has both enough signal and a certified wavelength, check lamp and \
+             wavelength calibration"
+        ));
+    }
+    let mean = corrected.iter().sum::<f64>() / corrected.len() as f64;
+    let uncorrected = factors.len() - corrected.len();
+    let factors = factors
+        .into_iter()
+        .map(|factor| factor.map_or(1.0, |factor| factor / mean))
+        .collect();
+    Ok((factors, uncorrected))
+}
+
+/// `calibration intensity` subcommand, captures a certified lamp and stores response correction
+pub fn calibrate(conf: &IntensityCalibrationConf) -> Result<()> {
+    let term = Term::stderr();
+    if !conf.yes && !term.is_term() {
+        return Err(eyre!(
+            "Calibration asks for confirmation in terminal, pass --yes to skip it"
+        ));
+    }
+    if conf.count == 0 {
+        return Err(eyre!("At least one frame is needed to measure response"));
+    }
+    let certificate = Certificate::read(&conf.certificate)?;
+    let mut ccd = conf.serial.open_ccd()?;
+    conf.capture.apply(&mut ccd)?;
+    let version = ccd.get_version()?;
+    let serial = version.serial_number().to_string();
+    // Previous intensity correction would skew the new one, so only dark and flat are applied
+    let mut calibration = conf.capture.calibration(&version)?.unwrap_or_default();
+    calibration.intensity = None;
+    if calibration.wavelength.is_empty() {
+        return Err(eyre!(
+            "Device {serial} has no wavelength calibration, run `calibration wavelength` first"
+        ));
+    }
+
+    if !conf.yes {
+        term.write_line("Point spectrometer at the certified lamp and press Enter to capture")?;
+        term.read_line()?;
+    }
+    let mut frames = Vec::with_capacity(conf.count);
+    ccd.extend_with_frames(&mut frames, conf.count)?;
+    let mut frame = reference::average(&frames);
+    calibration.correction().apply(&mut frame);
+
+    let (factors, uncorrected) =
+        response_correction(&frame, &calibration.wavelength, &certificate)?;
+    let (min, max) = factors
+        .iter()
+        .fold((f64::INFINITY, 0.0_f64), |(min, max), &f| {
+            (min.min(f), max.max(f))
+        });
+    println!("Correction factors range from {min:.3} to {max:.3}");
+    if uncorrected > 0 {
+        println!("{uncorrected} pixels are too dim or outside of certificate and stay as is");
+    }
+
+    if !conf.yes && !confirm(&term, &format!("Save calibration for device {serial}?"))? {
+        println!("Calibration was not saved");
+        return Ok(());
+    }
+    let mut stored = DeviceCalibration::load(&serial)?.unwrap_or_default();
+    stored.intensity = Some(factors);
+    let path = stored.save(&serial)?;
+    println!("Saved calibration to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn correct_response() {
+        let certificate = Certificate::parse("# nm, irradiance\n600 20\n500,10\n\n").unwrap();
+        assert_eq!(certificate.points, vec![(500.0, 10.0), (600.0, 20.0)]);
+        assert_eq!(certificate.at(550.0), Some(15.0));
+        assert_eq!(certificate.at(600.0), Some(20.0));
+        assert_eq!(certificate.at(650.0), None);
+        assert!(Certificate::parse("500 10\n600").is_err());
+
+        // Sensor is twice as sensitive at 500 nm as at 550 nm, pixel 0 is outside of certificate
+        // and pixel 3 sees no light
+        let measured = [40, 20, 15, 0];
+        let (factors, uncorrected) =
+            response_correction(&measured, &[450.0, 50.0], &certificate).unwrap();
+        assert_eq!(uncorrected, 2);
+        assert_eq!(factors[0], 1.0);
+        assert!((factors[2] / factors[1] - 2.0).abs() < 1e-9);
+        assert!((factors[1] + factors[2] - 2.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,20 @@
+use simple_eyre::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Replaces default Ctrl-C and SIGTERM behaviour with setting a flag, so long running captures can
+/// stop gracefully. Second one terminates the process right away
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        tracing::warn!("Interrupted, finishing capture. Press Ctrl-C again to exit immediately");
+    })?;
+    Ok(())
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,172 @@
+use simple_eyre::Result;
+use std::fmt::Write;
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+const VERSION: &str = "5.01";
+
+/// `##BLOCKS=` value is padded to this width, so count can be rewritten in place once known
+const BLOCKS_WIDTH: usize = 20;
+
+/// Lines of data table are kept within this width, as required by the standard
+const LINE_WIDTH: usize = 80;
+
+const LONGDATE_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]/[month]/[day] [hour]:[minute]:[second]");
+
+/// Capture metadata that makes it into every JCAMP-DX block
+pub struct Header {
+    pub title: String,
+    /// `key: value` lines, same as written in CSV header
+    pub metadata: Vec<String>,
+}
+
+/// Labels are upper case with spaces, as shown in the standard
+fn label(key: &str) -> String {
+    key.trim().to_uppercase()
+}
+
+impl Header {
+    /// Link block holding `blocks` spectra written after it, always of the same length
+    pub fn link(&self, blocks: usize) -> String {
+        format!(
+            "##TITLE={}\n##JCAMP-DX={VERSION}\n##DATA TYPE=LINK\n##BLOCKS={blocks:>BLOCKS_WIDTH$}\n",
+            self.title
+        )
+    }
+
+    /// Spectrum of a single frame as XYDATA indexed by pixel. `block_id` is given for blocks
+    /// within a link block
+    pub fn block(
+        &self,
+        frame: &[u16],
+        timestamp: OffsetDateTime,
+        block_id: Option<usize>,
+    ) -> Result<String> {
+        let mut out = String::new();
+        match block_id {
+            Some(id) => writeln!(out, "##TITLE={}, frame {id}", self.title)?,
+            None => writeln!(out, "##TITLE={}", self.title)?,
+        }
+        writeln!(out, "##JCAMP-DX={VERSION}")?;
+        writeln!(out, "##DATA TYPE=UV/VIS SPECTRUM")?;
+        if let Some(id) = block_id {
+            writeln!(out, "##BLOCK_ID={id}")?;
+        }
+        writeln!(
+            out,
+            "##ORIGIN=spectrometer_cli {}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(out, "##OWNER=")?;
+        writeln!(out, "##LONGDATE={}", timestamp.format(LONGDATE_FORMAT)?)?;
+
+        let fields: Vec<(&str, &str)> = self
+            .metadata
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+        let field = |key: &str| fields.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let mut spectrometer = field("sensor").unwrap_or("LCAM_V06").to_string();
+        if let Some(serial) = field("device serial number") {
+            write!(spectrometer, ", serial {serial}")?;
+        }
+        if let Some(firmware) = field("firmware version") {
+            write!(spectrometer, ", firmware {firmware}")?;
+        }
+        writeln!(out, "##SPECTROMETER/DATA SYSTEM={spectrometer}")?;
+        // Everything else is kept as user defined labels
+        for (key, value) in &fields {
+            if !matches!(
+                *key,
+                "sensor" | "device serial number" | "firmware version" | "software version"
+            ) {
+                writeln!(out, "##${}={value}", label(key))?;
+            }
+        }
+
+        let min = frame.iter().copied().min().unwrap_or(0);
+        let max = frame.iter().copied().max().unwrap_or(0);
+        writeln!(out, "##XUNITS=PIXEL")?;
+        writeln!(out, "##YUNITS=COUNTS")?;
+        writeln!(out, "##XFACTOR=1")?;
+        writeln!(out, "##YFACTOR=1")?;
+        writeln!(out, "##FIRSTX=0")?;
+        writeln!(out, "##LASTX={}", frame.len().saturating_sub(1))?;
+        writeln!(out, "##DELTAX=1")?;
+        writeln!(out, "##MINY={min}")?;
+        writeln!(out, "##MAXY={max}")?;
+        writeln!(out, "##NPOINTS={}", frame.len())?;
+        writeln!(out, "##FIRSTY={}", frame.first().copied().unwrap_or(0))?;
+        writeln!(out, "##XYDATA=(X++(Y..Y))")?;
+        let mut line = String::new();
+        for (x, y) in frame.iter().enumerate() {
+            let value = format!(" {y}");
+            if !line.is_empty() && line.len() + value.len() > LINE_WIDTH {
+                writeln!(out, "{line}")?;
+                line.clear();
+            }
+            if line.is_empty() {
+                write!(line, "{x}")?;
+            }
+            line.push_str(&value);
+        }
+        if !line.is_empty() {
+            writeln!(out, "{line}")?;
+        }
+        writeln!(out, "##END=")?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xydata_block() {
+        let header = Header {
+            title: "lamp".to_string(),
+            metadata: vec![
+                "software version: 0.1.0".to_string(),
+                "device serial number: 202111161548".to_string(),
+                "sensor: S11639".to_string(),
+                "exposure time: 10".to_string(),
+            ],
+        };
+        let frame: Vec<u16> = (0..40).map(|px| 1000 + px).collect();
+        let block = header
+            .block(&frame, OffsetDateTime::UNIX_EPOCH, Some(2))
+            .unwrap();
+        let lines: Vec<&str> = block.lines().collect();
+        assert_eq!(lines[0], "##TITLE=lamp, frame 2");
+        assert!(lines.contains(&"##BLOCK_ID=2"));
+        assert!(lines.contains(&"##LONGDATE=1970/01/01 00:00:00"));
+        assert!(lines.contains(&"##SPECTROMETER/DATA SYSTEM=S11639, serial 202111161548"));
+        assert!(lines.contains(&"##$EXPOSURE TIME=10"));
+        assert!(lines.contains(&"##NPOINTS=40"));
+        assert_eq!(lines.last(), Some(&"##END="));
+
+        // Every data line starts with X of its first Y and fits the width
+        let start = lines
+            .iter()
+            .position(|l| l.starts_with("##XYDATA"))
+            .unwrap()
+            + 1;
+        let data = &lines[start..lines.len() - 1];
+        assert!(data.len() > 1);
+        let mut next = 0;
+        for line in data {
+            assert!(line.len() <= LINE_WIDTH);
+            let mut values = line.split(' ').map(|v| v.parse::<usize>().unwrap());
+            assert_eq!(values.next(), Some(next));
+            for y in values {
+                assert_eq!(y, 1000 + next);
+                next += 1;
+            }
+        }
+        assert_eq!(next, frame.len());
+
+        assert_eq!(header.link(3).len(), header.link(12345).len());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{
+    calibration::DeviceCalibration,
+    cli::LiveReadingConf,
+    compress::Compression,
+    csv::CsvDialect,
+    interrupt,
+    output::{self, Header, Output},
+    rotate,
+};
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use console::{Key, Term};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::Instant,
+};
+
+/// Bar tops with 1/8 of a character cell resolution
+const EIGHTHS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Lines taken by status and key help above the plot
+const HEADER_LINES: usize = 2;
+
+/// Draws frame as bars, `width` columns by `height` lines, each column showing the highest pixel
+/// among those it covers so narrow peaks don't disappear
+pub fn render_plot(frame: &[u16], width: usize, height: usize) -> Vec<String> {
+    let width = width.clamp(1, frame.len().max(1));
+    let columns: Vec<u16> = (0..width)
+        .map(|col| {
+            let from = col * frame.len() / width;
+            let to = ((col + 1) * frame.len() / width).max(from + 1);
+            frame[from..to.min(frame.len())]
+                .iter()
+                .copied()
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let top = columns.iter().copied().max().unwrap_or(0).max(1) as usize;
+    // Heights are counted in eighths of a line
+    let heights: Vec<usize> = columns
+        .iter()
+        .map(|&v| v as usize * height * 8 / top)
+        .collect();
+    (0..height)
+        .rev()
+        .map(|line| {
+            heights
+                .iter()
+                .map(|&h| EIGHTHS[h.saturating_sub(line * 8).min(8)])
+                .collect()
+        })
+        .collect()
+}
+
+/// Keys are read on a separate thread, since reading blocks until one is pressed
+fn spawn_key_reader(term: Term) -> Receiver<Key> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(key) = term.read_key() {
+            if tx.send(key).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+enum Action {
+    Quit,
+    Exposure { up: bool },
+    Snapshot,
+}
+
+/// Actions for keys pressed since last frame
+fn pending_actions(keys: &Receiver<Key>) -> Vec<Action> {
+    let mut actions = Vec::new();
+    loop {
+        let action = match keys.try_recv() {
+            Ok(Key::Char('q') | Key::Escape | Key::CtrlC) => Action::Quit,
+            Ok(Key::Char('+') | Key::ArrowUp) => Action::Exposure { up: true },
+            Ok(Key::Char('-') | Key::ArrowDown) => Action::Exposure { up: false },
+            Ok(Key::Char('s')) => Action::Snapshot,
+            Ok(_) => continue,
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => return actions,
+        };
+        actions.push(action);
+    }
+}
+
+/// Next exposure time when stepping up or down, always changing it by at least 1
+fn step_exposure(t: u16, up: bool) -> u16 {
+    let step = (t / 4).max(1);
+    if up {
+        t.saturating_add(step)
+    } else {
+        t.saturating_sub(step).max(1)
+    }
+}
+
+fn save_snapshot(conf: &LiveReadingConf, seq: usize, frame: &[u16]) -> Result<String> {
+    let output = Output {
+        output: rotate::expand(&conf.snapshot, seq, output::now())?,
+        format: conf.snapshot_format,
+        output_dir: None,
+        compress: Compression::None,
+        csv: CsvDialect::default(),
+    };
+    let path = output.path();
+    if path.try_exists()? {
+        return Err(eyre!("{path:?} already exists"));
+    }
+    let mut full = [0; FRAME_PIXEL_COUNT];
+    full[..frame.len()].copy_from_slice(frame);
+    output.write_frame(&full, &Header::default())?;
+    Ok(path.display().to_string())
+}
+
+/// Status line, key help and plot, drawn over the previous frame
+fn draw(term: &Term, frame: &[u16], status: &str) -> io::Result<()> {
+    let (rows, cols) = term.size();
+    term.move_cursor_to(0, 0)?;
+    term.clear_line()?;
+    term.write_line(status)?;
+    term.write_line("+/- or arrows: exposure | s: save snapshot | q: quit")?;
+    let height = (rows as usize).saturating_sub(HEADER_LINES + 1).max(1);
+    for line in render_plot(frame, cols as usize, height) {
+        term.write_line(&line)?;
+    }
+    Ok(())
+}
+
+pub fn run(conf: &LiveReadingConf) -> Result<()> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        return Err(eyre!("Live view needs an interactive terminal"));
+    }
+    if !rotate::is_template(&conf.snapshot) {
+        return Err(eyre!(
+            "Snapshot path has to contain {{seq}} or {{date}}, so snapshots don't overwrite each \
+             other"
+        ));
+    }
+    let mut ccd = conf.serial.open_spectrometer()?;
+    conf.capture.apply(ccd.as_mut())?;
+    let calibration = conf.capture.calibration(&ccd.version()?)?;
+    let correction = calibration.as_ref().map(DeviceCalibration::correction);
+    let mut exposure = ccd.exposure_time()?;
+
+    interrupt::install_handler()?;
+    let keys = spawn_key_reader(term.clone());
+    term.hide_cursor()?;
+    term.clear_screen()?;
+    let pixels = ccd.pixel_count();
+    let mut frame = [0; FRAME_PIXEL_COUNT];
+    let mut size = term.size();
+    let mut snapshots = 0;
+    let mut message = String::new();
+    let mut last = Instant::now();
+    let res = 'live: loop {
+        for action in pending_actions(&keys) {
+            match action {
+                Action::Quit => break 'live Ok(()),
+                Action::Exposure { up } => {
+                    let t = step_exposure(exposure, up);
+                    message = match ccd.set_exposure_time(t) {
+                        Ok(()) => {
+                            exposure = t;
+                            String::new()
+                        }
+                        Err(e) => format!("| could not set exposure: {e}"),
+                    };
+                }
+                Action::Snapshot => {
+                    snapshots += 1;
+                    message = match save_snapshot(conf, snapshots, &frame[..pixels]) {
+                        Ok(path) => format!("| saved {path}"),
+                        Err(e) => format!("| snapshot failed: {e}"),
+                    };
+                }
+            }
+        }
+        if interrupt::interrupted() {
+            break Ok(());
+        }
+        if let Err(e) = ccd.read_frame(&mut frame[..pixels]) {
+            break Err(e.into());
+        }
+        if let Some(correction) = &correction {
+            correction.apply(&mut frame);
+        }
+        let fps = 1.0 / last.elapsed().as_secs_f64();
+        last = Instant::now();
+
+        if term.size() != size {
+            size = term.size();
+            if let Err(e) = term.clear_screen() {
+                break Err(e.into());
+            }
+        }
+        let (peak_at, peak) = frame[..pixels]
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, v)| **v)
+            .map_or((0, 0), |(i, v)| (i, *v));
+        let status = format!(
+            "Peak {peak} at pixel {peak_at} | exposure {exposure} | {fps:.1} fps {message}"
+        );
+        if let Err(e) = draw(&term, &frame[..pixels], &status) {
+            break Err(e.into());
+        }
+    };
+    term.show_cursor()?;
+    term.clear_screen()?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plot_bars() {
+        let frame = [0, 8, 16, 4];
+        assert_eq!(
+            render_plot(&frame, 4, 2),
+            ["  █ ", " ██▄"].map(String::from)
+        );
+        // Neighbouring pixels are merged keeping the highest one
+        assert_eq!(render_plot(&frame, 2, 1), ["▄█"].map(String::from));
+        assert_eq!(step_exposure(1, false), 1);
+        assert_eq!(step_exposure(100, true), 125);
+    }
+}
@@ -0,0 +1,167 @@
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+/// Advisory lock on a serial port, held as a UUCP style `LCK..name` file containing PID of the
+/// owner. Only other invocations that also take the lock are kept out
+pub struct DeviceLock {
+    path: PathBuf,
+}
+
+impl DeviceLock {
+    /// Takes a lock on serial port, failing right away if another live process holds it. Locks
+    /// left behind by processes that are gone are taken over. When none of lock directories can
+    /// be written to, port is used without a lock
+    pub fn acquire(port: &str) -> Result<Option<Self>> {
+        for dir in LOCK_DIRS {
+            if let Some(lock) = Self::acquire_in(Path::new(dir), port)? {
+                return Ok(Some(lock));
+            }
+        }
+        tracing::warn!("No writable lock directory in {LOCK_DIRS:?}, not locking {port}");
+        Ok(None)
+    }
+
+    /// Same as [DeviceLock::acquire] with lock file in `dir`, or `None` if `dir` can't be written
+    fn acquire_in(dir: &Path, port: &str) -> Result<Option<Self>> {
+        let path = dir.join(lock_name(port));
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    writeln!(file, "{:>10}", process::id())?;
+                    tracing::debug!("Locked {port} with {path:?}");
+                    return Ok(Some(DeviceLock { path }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) if is_unwritable(&e) => {
+                    tracing::debug!("Can't create lock file {path:?}: {e}");
+                    return Ok(None);
+                }
+                Err(e) => return Err(eyre!("Could not create lock file {path:?}: {e}")),
+            }
+            match read_owner(&path) {
+                Some(pid) if is_alive(pid) => {
+                    return Err(eyre!(
+                        "Device {port} is busy, locked by PID {pid} ({path:?})"
+                    ))
+                }
+                _ => {
+                    tracing::warn!("Removing stale lock file {path:?}");
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Err(eyre!(
+            "Could not lock {port}, lock file {path:?} keeps reappearing"
+        ))
+    }
+}
+
+impl Drop for DeviceLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            tracing::warn!("Failed to remove lock file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Directories where UUCP style locks are expected, in order of preference
+const LOCK_DIRS: [&str; 2] = ["/var/lock", "/run/lock"];
+
+/// Lock files are named after port with path separators replaced, so `/dev/ttyUSB0` and
+/// `tcp://host:port` both get a flat name
+fn lock_name(port: &str) -> String {
+    let name = port.trim_start_matches("/dev/").replace(
+        |c: char| !c.is_ascii_alphanumeric() && c != '.' && c != '-',
+        "_",
+    );
+    format!("LCK..{name}")
+}
+
+fn is_unwritable(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+fn read_owner(path: &Path) -> Option<u32> {
+    let mut contents = String::new();
+    fs::File::open(path)
+        .ok()?
+        .read_to_string(&mut contents)
+        .ok()?;
+    contents.trim().parse().ok()
+}
+
+/// Without a way to check, owner is assumed to be alive so a lock is never stolen by mistake
+fn is_alive(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    if proc.is_dir() {
+        proc.join(pid.to_string()).exists()
+    } else {
+        true
+    }
+}
+
+/// Port that keeps device locked for as long as it, or anything else sharing the lock, is open
+pub struct Locked<T> {
+    inner: T,
+    _lock: Arc<DeviceLock>,
+}
+
+impl<T> Locked<T> {
+    pub fn new(inner: T, lock: Arc<DeviceLock>) -> Self {
+        Locked { inner, _lock: lock }
+    }
+}
+
+impl<T: Read> Read for Locked<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Locked<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive() {
+        let dir = std::env::temp_dir();
+        let port = format!("/dev/ttyTEST{}", process::id());
+        let lock = DeviceLock::acquire_in(&dir, &port).unwrap().unwrap();
+        let err = DeviceLock::acquire_in(&dir, &port).err().unwrap();
+        assert!(err.to_string().contains(&process::id().to_string()));
+        drop(lock);
+        assert!(!dir.join(lock_name(&port)).exists());
+
+        // Owner that is gone doesn't keep device locked
+        fs::write(dir.join(lock_name(&port)), format!("{:>10}\n", u32::MAX)).unwrap();
+        let _lock = DeviceLock::acquire_in(&dir, &port).unwrap().unwrap();
+    }
+
+    #[test]
+    fn missing_lock_dir_is_skipped() {
+        let dir = std::env::temp_dir().join(format!("no-lock-dir-{}", process::id()));
+        let lock = DeviceLock::acquire_in(&dir, "/dev/ttyUSB0").unwrap();
+        assert!(lock.is_none());
+    }
+}
@@ -0,0 +1,78 @@
+use clap::{ArgAction, Args};
+use simple_eyre::Result;
+use std::{fs::File, io, path::PathBuf, sync::Mutex};
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
+
+#[derive(Args)]
+pub struct LogConf {
+    /// Print more details about what's going on, repeat for even more
+    #[clap(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Only print errors
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Also write a detailed JSON log into this file, useful for investigating flaky captures
+    #[clap(long, global = true, value_parser, value_hint = clap::ValueHint::FilePath, env = "SPECTRO_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+}
+
+impl LogConf {
+    fn level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::ERROR;
+        }
+        match self.verbose {
+            0 => LevelFilter::WARN,
+            1 => LevelFilter::INFO,
+            2 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Installs global subscriber printing to stderr, with RUST_LOG taking precedence over verbosity
+/// flags. Log file gets at least debug events regardless of verbosity
+pub fn init(conf: &LogConf) -> Result<()> {
+    let console = fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(atty::is(atty::Stream::Stderr))
+        .with_filter(
+            EnvFilter::builder()
+                .with_default_directive(conf.level().into())
+                .from_env_lossy(),
+        );
+    let file = match &conf.log_file {
+        Some(path) => Some(
+            fmt::layer()
+                .json()
+                .with_writer(Mutex::new(File::create(path)?))
+                .with_filter(conf.level().max(LevelFilter::DEBUG)),
+        ),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbosity_levels() {
+        let conf = |verbose, quiet| LogConf {
+            verbose,
+            quiet,
+            log_file: None,
+        };
+        assert_eq!(conf(0, false).level(), LevelFilter::WARN);
+        assert_eq!(conf(2, false).level(), LevelFilter::DEBUG);
+        assert_eq!(conf(5, false).level(), LevelFilter::TRACE);
+        assert_eq!(conf(0, true).level(), LevelFilter::ERROR);
+    }
+}
@@ -1,27 +1,98 @@
+mod alert;
+mod analysis;
+mod benchmark;
+mod calibration;
+mod capture;
 mod cli;
+#[cfg(feature = "columnar")]
+mod columnar;
+mod compare;
+mod compress;
+mod config;
+mod convert;
+mod csv;
+mod daemon;
+mod dark;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod doctor;
+#[cfg(feature = "hdf5")]
+mod hdf5;
+mod hdr;
+mod histogram;
+mod hook;
+mod info;
+mod input;
+mod intensity;
+mod interrupt;
+mod jcamp;
+mod live;
+mod lock;
+mod logging;
+mod manifest;
+mod monitor;
 mod output;
+mod pipeline;
+mod plot;
+mod ports;
+mod reference;
+mod remote;
+mod resolution;
+mod rfc2217;
+mod rotate;
+mod scpi;
+mod schedule;
+#[cfg(feature = "script")]
+mod script;
 mod serial;
+mod session;
+mod settings;
+mod sniff;
+mod spc;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod stats;
+mod systemd;
+mod trace;
+mod wavelength;
 
-use clap::Parser;
+use ccd_lcamv06::{Frame, QualityFlags, FRAME_PIXEL_COUNT};
+use clap::{CommandFactory, FromArgMatches};
 use simple_eyre::Result;
 use num_traits::ToPrimitive;
-use std::io::Write;
+use std::{fs, io::Write, thread, time::Duration};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+use analysis::Spectrum;
+use calibration::DeviceCalibration;
+use capture::{capture_header, finish_capture, prepare_capture, Capture, FrameProcessor};
 use cli::*;
+use config::Config;
+use plot::{PlotConf, Waterfall};
+use ports::{PortListing, ProbeResult};
+use rotate::Resumed;
 use serial::SerialConf;
+use session::{Calibration, Metadata, Session};
 
 fn main() -> Result<()> {
     simple_eyre::install()?;
-    let cli = Cli::parse();
-    env_logger::init();
+    let cli = parse_cli()?;
+    logging::init(&cli.log)?;
 
     match &cli.command {
-        Commands::List => list_serial(),
+        Commands::List(conf) => list_serial(conf),
+        Commands::Watch(conf) => watch_serial(conf),
         Commands::CCDVersion(conf) => get_version(conf),
+        Commands::Info(conf) => info::info(conf),
+        Commands::Doctor(conf) => doctor::run(conf),
+        Commands::Benchmark(conf) => benchmark::run(conf),
         Commands::Read(subcomm) => match &subcomm.command {
             ReadCommands::Single(conf) => get_single_reading(conf),
             ReadCommands::Multi(conf) => get_multiple_readings(conf),
+            ReadCommands::Interval(conf) => get_interval_readings(conf),
+            ReadCommands::Live(conf) => live::run(conf),
+            ReadCommands::Hdr(conf) => hdr::read(conf),
+            ReadCommands::Trace(conf) => trace::read(conf),
         },
         Commands::BaudRate(subcomm) => match &subcomm.command {
             BaudRateCommands::Get(conf) => get_baud_rate(conf),
@@ -35,7 +106,55 @@ fn main() -> Result<()> {
             ExpTimeCommands::Get(conf) => get_exp_time(conf),
             ExpTimeCommands::Set(conf) => set_exp_time(conf),
         },
+        Commands::Settings(subcomm) => match &subcomm.command {
+            SettingsCommands::Export(conf) => settings::export(conf),
+            SettingsCommands::Import(conf) => settings::import(conf),
+        },
+        Commands::Session(subcomm) => match &subcomm.command {
+            SessionCommands::Create(conf) => create_session(conf),
+            SessionCommands::Inspect(conf) => inspect_session(conf),
+            SessionCommands::Extract(conf) => extract_session(conf),
+        },
+        Commands::Convert(conf) => convert::convert(conf),
+        Commands::Decode(conf) => sniff::decode(conf),
+        Commands::Calibration(subcomm) => match &subcomm.command {
+            CalibrationCommands::Show(conf) => DeviceCalibration::show(&conf.serial_number),
+            CalibrationCommands::Set(conf) => DeviceCalibration::update(conf),
+            CalibrationCommands::Wavelength(conf) => wavelength::calibrate(conf),
+            CalibrationCommands::Intensity(conf) => intensity::calibrate(conf),
+        },
+        Commands::Characterize(subcomm) => match &subcomm.command {
+            CharacterizeCommands::Dark(conf) => dark::characterize(conf),
+        },
+        Commands::Reference(subcomm) => match &subcomm.command {
+            ReferenceCommands::Capture(conf) => reference::capture(conf),
+        },
+        Commands::Daemon(conf) => daemon::run(conf),
+        Commands::Remote(conf) => remote::run(conf),
+        Commands::Plot(conf) => plot::plot_file(conf),
+        Commands::Scpi(conf) => scpi::serve(conf),
+        Commands::Stats(conf) => stats::run(conf),
+        Commands::Compare(conf) => compare::run(conf),
+        Commands::Resolution(conf) => resolution::run(conf),
+        Commands::Histogram(conf) => histogram::run(conf),
+        Commands::Monitor(subcomm) => match &subcomm.command {
+            MonitorCommands::Drift(conf) => monitor::drift(conf),
+        },
+        Commands::Verify(conf) => manifest::verify(conf),
+    }
+}
+
+/// Parses arguments, taking defaults from selected profile into account
+fn parse_cli() -> Result<Cli> {
+    let args: Vec<_> = std::env::args_os().collect();
+    let mut cmd = Cli::command();
+    if let Some(name) = config::profile_name(&args) {
+        let profile = Config::load()?.profile(&name)?;
+        tracing::debug!("Using profile {name:?}: {profile:?}");
+        cmd = config::apply_profile(cmd, &profile);
     }
+    let matches = cmd.get_matches_from(args);
+    Ok(Cli::from_arg_matches(&matches)?)
 }
 
 /// Returns std::io::Write stream with coloring enabled if program is run interactively
@@ -47,10 +166,21 @@ fn get_stdout() -> StandardStream {
     })
 }
 
-fn list_serial() -> Result<()> {
+fn list_serial(conf: &ListConf) -> Result<()> {
+    let mut ports = ports::list_ports()?;
+    if conf.probe {
+        for p in ports.iter_mut() {
+            p.probe(conf.probe_conf.baud, conf.probe_conf.timeout());
+        }
+    }
+    if conf.json {
+        serde_json::to_writer_pretty(std::io::stdout(), &ports)?;
+        println!();
+        return Ok(());
+    }
+
     let mut stdout = get_stdout();
-    let paths = serialport::available_ports()?;
-    if paths.is_empty() {
+    if ports.is_empty() {
         stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
         writeln!(&mut stdout, "No connected serial ports found.")?;
     } else {
@@ -58,31 +188,281 @@ fn list_serial() -> Result<()> {
         writeln!(&mut stdout, "Connected serial ports:")?;
     }
     stdout.reset()?;
-    paths.iter().for_each(|p| println!("{}", p.port_name));
+    for p in ports.iter() {
+        write_port(&mut stdout, p)?;
+    }
 
     Ok(())
 }
 
+/// Writes a single line describing port, colored according to probing results
+fn write_port(stdout: &mut StandardStream, p: &PortListing) -> Result<()> {
+    write!(stdout, "{}", p.path)?;
+    if let Some(usb) = &p.usb {
+        write!(stdout, " ({usb})")?;
+    }
+    match &p.probe {
+        None => {}
+        Some(ProbeResult::Detected { device }) => {
+            write!(stdout, " - ")?;
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+            write!(
+                stdout,
+                "{} {}, firmware {}, serial number {}",
+                device.hardware_version,
+                device.sensor_type,
+                device.firmware_version,
+                device.serial_number
+            )?;
+        }
+        Some(ProbeResult::NotDetected { .. }) => {
+            write!(stdout, " - ")?;
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Yellow)))?;
+            write!(stdout, "not a CCD")?;
+        }
+    }
+    stdout.reset()?;
+    writeln!(stdout)?;
+    Ok(())
+}
+
+fn watch_serial(conf: &WatchConf) -> Result<()> {
+    let probe = conf.probe || conf.serial_number.is_some() || conf.on_connect.is_some();
+    let mut stdout = get_stdout();
+    let mut known = ports::list_ports()?;
+    tracing::debug!("Watching for changes in {} serial ports", known.len());
+    loop {
+        thread::sleep(Duration::from_millis(conf.interval));
+        let current = ports::list_ports()?;
+        let (mut added, removed) = ports::diff(&known, &current);
+
+        for p in removed.iter() {
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red)))?;
+            write!(&mut stdout, "Disconnected: ")?;
+            stdout.reset()?;
+            writeln!(&mut stdout, "{}", p.path)?;
+        }
+        for p in added.iter_mut() {
+            if probe {
+                p.probe(conf.probe_conf.baud, conf.probe_conf.timeout());
+            }
+            stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green)))?;
+            write!(&mut stdout, "Connected: ")?;
+            stdout.reset()?;
+            write_port(&mut stdout, p)?;
+
+            let device = match &p.probe {
+                Some(ProbeResult::Detected { device }) => device,
+                _ => continue,
+            };
+            if let Some(serial_number) = &conf.serial_number {
+                if &device.serial_number != serial_number {
+                    continue;
+                }
+            }
+            if let Some(command) = &conf.on_connect {
+                hook::spawn_shell(command, &[("SPECTRO_SERIAL", &p.path)])?;
+            }
+        }
+        known = current;
+    }
+}
+
 fn get_multiple_readings(conf: &MultiReadingConf) -> Result<()> {
+    let resumed = if conf.resume {
+        rotate::resume(&conf.output.path())?
+    } else {
+        Resumed::default()
+    };
+    let count = conf.count.saturating_sub(resumed.frames);
+    if conf.resume {
+        tracing::info!(
+            "Resuming after segment {}, {} of {} frames left",
+            resumed.seq,
+            count,
+            conf.count
+        );
+        if count == 0 {
+            return Ok(());
+        }
+    }
     let mut ccd = conf.serial.open_ccd()?;
-    let mut frames: Vec<_> = Vec::with_capacity(conf.count);
+    let (version, calibration) = prepare_capture(&mut ccd, &conf.capture)?;
 
-    ccd.extend_with_frames(&mut frames, conf.count)?;
-    conf.output.write_frames(&frames)?;
+    interrupt::install_handler()?;
+    let pixels = ccd.sensor_layout().pixels();
+    let mut header = capture_header(
+        &conf.serial,
+        &conf.capture,
+        &version,
+        calibration.as_ref(),
+        pixels,
+    );
+    if conf.resume {
+        header
+            .metadata
+            .push(format!("resumed after segment: {}", resumed.seq));
+    }
+    let rules = conf.processing.alert_rules()?;
+    let mut processor = FrameProcessor::new(
+        &conf.processing,
+        &rules,
+        calibration.as_ref(),
+        pixels,
+        &mut header,
+    )?;
+    let writer = conf
+        .output
+        .resumed_frame_writer(conf.rotate, header, resumed.seq)?;
+    let mut last = None;
+    let mut waterfall = conf.waterfall.as_ref().map(|_| Waterfall::new());
+    let thresholds = conf.quality.thresholds();
+    let capture = Capture::run(
+        &mut ccd,
+        Some(count),
+        &conf.stream,
+        thresholds,
+        |frame, flags| {
+            let Some(processed) = processor.process(frame, flags)? else {
+                return Ok(false);
+            };
+            if let Some(waterfall) = &mut waterfall {
+                waterfall.push(&processed.frame[..pixels]);
+            }
+            last = Some(processed.frame);
+            writer.write_captured(processed.frame, processed.raw, processed.flags)?;
+            Ok(true)
+        },
+    );
+    tracing::debug!("Stream stats: {:?}", ccd.stats());
+    finish_capture(&conf.output, writer, capture, Some(count))?;
+    if let (Some(path), Some(waterfall)) = (&conf.waterfall, waterfall) {
+        waterfall.write(path, pixels)?;
+    }
+    plot_last_frame(&conf.plot, last, calibration.as_ref(), conf.capture.laser)
+}
 
-    Ok(())
+fn get_interval_readings(conf: &IntervalReadingConf) -> Result<()> {
+    let mut ccd = conf.serial.open_ccd()?;
+    let (version, calibration) = prepare_capture(&mut ccd, &conf.capture)?;
+
+    interrupt::install_handler()?;
+    let pixels = ccd.sensor_layout().pixels();
+    let mut header = capture_header(
+        &conf.serial,
+        &conf.capture,
+        &version,
+        calibration.as_ref(),
+        pixels,
+    );
+    header.metadata.push(format!("interval: {:?}", conf.every));
+    let rules = conf.processing.alert_rules()?;
+    let mut processor = FrameProcessor::new(
+        &conf.processing,
+        &rules,
+        calibration.as_ref(),
+        pixels,
+        &mut header,
+    )?;
+    let writer = conf.output.frame_writer(None, header)?;
+    let mut last = None;
+    let mut waterfall = conf.waterfall.as_ref().map(|_| Waterfall::new());
+    let thresholds = conf.quality.thresholds();
+    let capture = Capture::run_interval(
+        &mut ccd,
+        conf.every,
+        conf.count,
+        conf.until,
+        thresholds,
+        |frame, flags| {
+            let Some(processed) = processor.process(frame, flags)? else {
+                return Ok(false);
+            };
+            if let Some(waterfall) = &mut waterfall {
+                waterfall.push(&processed.frame[..pixels]);
+            }
+            last = Some(processed.frame);
+            writer.write_captured(processed.frame, processed.raw, processed.flags)?;
+            Ok(true)
+        },
+    );
+    finish_capture(&conf.output, writer, capture, conf.count)?;
+    if let (Some(path), Some(waterfall)) = (&conf.waterfall, waterfall) {
+        waterfall.write(path, pixels)?;
+    }
+    plot_last_frame(&conf.plot, last, calibration.as_ref(), conf.capture.laser)
+}
+
+/// Plots the last captured frame, if there was one and plot was requested
+fn plot_last_frame(
+    plot: &PlotConf,
+    frame: Option<Frame>,
+    calibration: Option<&DeviceCalibration>,
+    laser: Option<f64>,
+) -> Result<()> {
+    let Some(frame) = frame else {
+        return Ok(());
+    };
+    let spectrum = Spectrum {
+        pixels: &frame,
+        wavelength: calibration.map_or(&[], |c| &c.wavelength),
+        laser,
+    };
+    plot.write(
+        &spectrum,
+        &format!("Last frame, taken at {}", output::now().date()),
+    )
 }
 
 fn get_single_reading(conf: &SingleReadingConf) -> Result<()> {
-    let mut ccd = conf.serial.open_ccd()?;
-    let frame = ccd.get_frame()?;
-    conf.output.write_frame(&frame)?;
-    Ok(())
+    let mut ccd = conf.serial.open_spectrometer()?;
+    conf.capture.apply(ccd.as_mut())?;
+    let version = ccd.version()?;
+    let calibration = conf.capture.calibration(&version)?;
+    // Sensors with fewer pixels only fill the beginning of a frame
+    let pixels = ccd.pixel_count();
+    let mut header = capture_header(
+        &conf.serial,
+        &conf.capture,
+        &version,
+        calibration.as_ref(),
+        pixels,
+    );
+    let rules = conf.processing.alert_rules()?;
+    let mut processor = FrameProcessor::new(
+        &conf.processing,
+        &rules,
+        calibration.as_ref(),
+        pixels,
+        &mut header,
+    )?;
+    let mut frame = [0; FRAME_PIXEL_COUNT];
+    ccd.read_frame(&mut frame[..pixels])?;
+    let flags = QualityFlags::assess(&frame[..pixels], &conf.quality.thresholds());
+    let Some(processed) = processor.process(frame, flags)? else {
+        tracing::warn!("Script dropped the frame, nothing is written");
+        return Ok(());
+    };
+    conf.output.write_captured_frame(
+        &processed.frame,
+        processed.raw.as_ref(),
+        processed.flags,
+        &header,
+    )?;
+    let spectrum = Spectrum {
+        pixels: &processed.frame[..pixels],
+        wavelength: calibration.as_ref().map_or(&[], |c| &c.wavelength),
+        laser: conf.capture.laser,
+    };
+    conf.plot.write(
+        &spectrum,
+        &format!("Frame taken at {}", output::now().date()),
+    )
 }
 
 fn get_version(conf: &SerialConf) -> Result<()> {
-    let mut ccd = conf.open_ccd()?;
-    let version_details = ccd.get_version()?;
+    let mut ccd = conf.open_spectrometer()?;
+    let version_details = ccd.version()?;
     println!("{version_details}");
     Ok(())
 }
@@ -97,6 +477,7 @@ fn get_baud_rate(conf: &SerialConf) -> Result<()> {
 fn set_baud_rate(conf: &SetBaudRateConf) -> Result<()> {
     let mut ccd = conf.serial.open_ccd()?;
     ccd.set_baudrate(conf.baud_rate)?;
+    ccd.keep_baudrate();
     Ok(())
 }
 
@@ -113,13 +494,79 @@ fn set_avg_time(conf: &SetAvgTimeConf) -> Result<()> {
 }
 
 fn get_exp_time(conf: &SerialConf) -> Result<()> {
-    let mut ccd = conf.open_ccd()?;
-    println!("Current \"exposure time\": {}", ccd.get_exp_time()?);
+    let mut ccd = conf.open_spectrometer()?;
+    println!("Current \"exposure time\": {}", ccd.exposure_time()?);
     Ok(())
 }
 
 fn set_exp_time(conf: &SetExpTimeConf) -> Result<()> {
+    let mut ccd = conf.serial.open_spectrometer()?;
+    ccd.set_exposure_time(conf.exposure_time)?;
+    Ok(())
+}
+
+fn create_session(conf: &CreateSessionConf) -> Result<()> {
+    let mut metadata = Metadata::now()?;
+    metadata.note = conf.note.clone();
+    let mut session = Session::new(metadata);
+    if let Some(path) = &conf.dark {
+        session.dark = Some(input::read_frame(path)?);
+    }
+    if let Some(path) = &conf.reference {
+        session.reference = Some(input::read_frame(path)?);
+    }
+    if !conf.wavelength_coeffs.is_empty() {
+        session.calibration = Some(Calibration {
+            wavelength: conf.wavelength_coeffs.clone(),
+        });
+    }
+
     let mut ccd = conf.serial.open_ccd()?;
-    ccd.set_exp_time(conf.exposure_time)?;
+    conf.capture.apply(&mut ccd)?;
+    let version = ccd.get_version()?;
+    // Session keeps raw frames, so stored calibration and references only fill in what wasn't
+    // passed explicitly
+    if let Some(stored) = conf.capture.calibration(&version)? {
+        session.dark = session.dark.or(stored.dark);
+        session.reference = session.reference.or(stored.flat);
+        if session.calibration.is_none() && !stored.wavelength.is_empty() {
+            session.calibration = Some(Calibration {
+                wavelength: stored.wavelength,
+            });
+        }
+    }
+    session.metadata.device = Some((&version).into());
+    session.metadata.exposure_time = Some(ccd.get_exp_time()?);
+    session.metadata.average_time = Some(ccd.get_avg_time()?);
+    let mut frames: Vec<_> = Vec::with_capacity(conf.count);
+    ccd.extend_with_frames(&mut frames, conf.count)?;
+    session.frames = frames.iter().map(|f| f.to_vec()).collect();
+
+    session.save(&conf.output)
+}
+
+fn inspect_session(conf: &InspectSessionConf) -> Result<()> {
+    let session = Session::load(&conf.session)?;
+    println!("{session}");
+    Ok(())
+}
+
+fn extract_session(conf: &ExtractSessionConf) -> Result<()> {
+    let session = Session::load(&conf.session)?;
+    fs::create_dir_all(&conf.output)?;
+    fs::write(conf.output.join("session.txt"), session.to_string())?;
+    fs::write(
+        conf.output.join("frames.csv"),
+        output::frames_to_csv(&session.frames),
+    )?;
+    if let Some(dark) = &session.dark {
+        fs::write(conf.output.join("dark.csv"), output::frame_to_csv(dark))?;
+    }
+    if let Some(reference) = &session.reference {
+        fs::write(
+            conf.output.join("reference.csv"),
+            output::frame_to_csv(reference),
+        )?;
+    }
     Ok(())
 }
@@ -0,0 +1,188 @@
+use crate::{
+    cli::VerifyConf,
+    input::{self, InputFormat},
+    output,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use time::format_description::well_known::Rfc3339;
+
+/// Extension appended to name of output file to get name of its manifest
+const EXTENSION: &str = ".manifest.json";
+
+/// Sidecar written next to every output file once it's complete, so that files truncated by an
+/// interrupted run or changed afterwards can be found with `verify`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    /// Name of output file, which is expected in the same directory as manifest
+    pub file: String,
+    pub frames: usize,
+    pub size: u64,
+    pub sha256: String,
+    /// Program and version that wrote the file
+    pub software: String,
+    pub created: String,
+}
+
+pub fn path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(EXTENSION);
+    path.with_file_name(name)
+}
+
+pub fn is_manifest(path: &Path) -> bool {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .is_some_and(|name| name.ends_with(EXTENSION))
+}
+
+/// Size and SHA-256 of file contents, as stored on disk
+fn digest(path: &Path) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok((size, hash))
+}
+
+/// Writes manifest of a finished output file holding `frames` frames
+pub fn write(path: &Path, frames: usize) -> Result<()> {
+    let (size, sha256) = digest(path)?;
+    let manifest = Manifest {
+        file: path
+            .file_name()
+            .ok_or_else(|| eyre!("{path:?} has no file name"))?
+            .to_string_lossy()
+            .into_owned(),
+        frames,
+        size,
+        sha256,
+        software: concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string(),
+        created: output::now().format(&Rfc3339)?,
+    };
+    tracing::debug!("Writing manifest of {path:?}");
+    fs::write(
+        path_for(path),
+        serde_json::to_string_pretty(&manifest)? + "\n",
+    )?;
+    Ok(())
+}
+
+/// Updates hash of a file that was changed after its manifest was written, e.g. by appending a
+/// note. Files without manifest are left alone
+pub fn refresh(path: &Path) -> Result<()> {
+    let manifest_path = path_for(path);
+    if !manifest_path.try_exists()? {
+        return Ok(());
+    }
+    let manifest = load(&manifest_path)?;
+    write(path, manifest.frames)
+}
+
+fn load(path: &Path) -> Result<Manifest> {
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| eyre!("Could not parse manifest {path:?}: {e}"))
+}
+
+/// Checks a file against its manifest, returning frame count on success
+pub fn check(path: &Path) -> Result<usize> {
+    let manifest_path = path_for(path);
+    if !manifest_path.try_exists()? {
+        return Err(eyre!("no manifest found at {manifest_path:?}"));
+    }
+    let manifest = load(&manifest_path)?;
+    let (size, sha256) = digest(path)?;
+    if size != manifest.size {
+        return Err(eyre!(
+            "{size} bytes long, manifest expects {}",
+            manifest.size
+        ));
+    }
+    if sha256 != manifest.sha256 {
+        return Err(eyre!("SHA-256 doesn't match manifest"));
+    }
+    // Formats that can be read back are also checked for the amount of frames, in case file was
+    // cut short before it was hashed
+    if let Some(format) = InputFormat::from_path(path) {
+        let frames = input::read_capture(path, format)?.len();
+        if frames != manifest.frames {
+            return Err(eyre!(
+                "{frames} frames found, manifest expects {}",
+                manifest.frames
+            ));
+        }
+    }
+    Ok(manifest.frames)
+}
+
+/// `verify` subcommand, checks output files against their manifests
+pub fn verify(conf: &VerifyConf) -> Result<()> {
+    // Manifest itself can be passed too, e.g. by globbing over a directory
+    let mut paths: Vec<_> = conf
+        .files
+        .iter()
+        .map(
+            |path| match path.to_str().and_then(|p| p.strip_suffix(EXTENSION)) {
+                Some(data) => PathBuf::from(data),
+                None => path.clone(),
+            },
+        )
+        .collect();
+    let mut seen = HashSet::new();
+    paths.retain(|path| seen.insert(path.clone()));
+    let mut failed = 0;
+    for path in &paths {
+        match check(path) {
+            Ok(frames) => println!("OK {} ({frames} frames)", path.display()),
+            Err(e) => {
+                failed += 1;
+                println!("FAILED {}: {e}", path.display());
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(eyre!(
+            "{failed} of {} files failed verification",
+            paths.len()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_truncation() {
+        let dir = std::env::temp_dir().join(format!("manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("capture.csv");
+        let frame = output::frame_to_csv(&[7; ccd_lcamv06::FRAME_PIXEL_COUNT]);
+        fs::write(&path, format!("# note\n{frame}\n{frame}\n")).unwrap();
+        write(&path, 2).unwrap();
+        assert!(is_manifest(&path_for(&path)));
+        assert_eq!(check(&path).unwrap(), 2);
+
+        fs::write(&path, format!("# note\n{frame}\n")).unwrap();
+        assert!(check(&path).is_err());
+        // Rewritten manifest still remembers how many frames there should be
+        refresh(&path).unwrap();
+        assert!(check(&path)
+            .unwrap_err()
+            .to_string()
+            .contains("1 frames found"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    calibration::DeviceCalibration, capture::sleep_until, cli::DriftMonitorConf,
+    compare::Comparison, input, interrupt, output, reference, remote,
+};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    time::{Duration, Instant},
+};
+use time::format_description::well_known::Rfc3339;
+
+/// Time webhook is given to accept a notification
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Thresholds exceeded by a check, described for humans
+fn violations(comparison: &Comparison, max_shift: f64, max_intensity_drift: f64) -> Vec<String> {
+    let mut violations = Vec::new();
+    if comparison.max_shift() > max_shift {
+        violations.push(format!(
+            "peak shifted by {:.3} pixels, more than allowed {max_shift}",
+            comparison.max_shift()
+        ));
+    }
+    if comparison.intensity_drift().abs() > max_intensity_drift {
+        violations.push(format!(
+            "intensity drifted by {:+.2}%, more than allowed {max_intensity_drift}%",
+            comparison.intensity_drift()
+        ));
+    }
+    violations
+}
+
+/// `monitor drift` subcommand, periodically compares captured spectrum against a reference until
+/// drift exceeds thresholds, or keeps going and notifies a webhook instead when one is given
+pub fn drift(conf: &DriftMonitorConf) -> Result<()> {
+    if conf.frames == 0 {
+        return Err(eyre!("At least one frame is needed per check"));
+    }
+    let reference = input::read_frame(&conf.reference)?;
+    let mut ccd = conf.serial.open_ccd()?;
+    conf.capture.apply(&mut ccd)?;
+    let version = ccd.get_version()?;
+    let calibration = conf.capture.calibration(&version)?;
+    let correction = calibration.as_ref().map(DeviceCalibration::correction);
+    let mut log = match &conf.log {
+        Some(path) => {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "timestamp,max_shift,intensity_drift,rms")?;
+            }
+            Some(file)
+        }
+        None => None,
+    };
+
+    interrupt::install_handler()?;
+    let mut deadline = Instant::now();
+    let mut checks = 0;
+    loop {
+        let mut frames = Vec::with_capacity(conf.frames);
+        ccd.extend_with_frames(&mut frames, conf.frames)?;
+        let mut frame = reference::average(&frames);
+        if let Some(correction) = &correction {
+            correction.apply(&mut frame);
+        }
+        let comparison = Comparison::new(&reference, &frame, conf.peaks)?;
+        let timestamp = output::now().format(&Rfc3339)?;
+        println!(
+            "{timestamp}: peak shift {:.3} px, intensity drift {:+.2}%, RMS deviation {:.2}",
+            comparison.max_shift(),
+            comparison.intensity_drift(),
+            comparison.rms
+        );
+        if let Some(log) = &mut log {
+            writeln!(
+                log,
+                "{timestamp},{:.3},{:.3},{:.3}",
+                comparison.max_shift(),
+                comparison.intensity_drift(),
+                comparison.rms
+            )?;
+        }
+
+        let violations = violations(&comparison, conf.max_shift, conf.max_intensity_drift);
+        if !violations.is_empty() {
+            let Some(url) = &conf.webhook else {
+                return Err(eyre!("Drift too large: {}", violations.join(", ")));
+            };
+            tracing::warn!("Drift too large: {}", violations.join(", "));
+            let body = serde_json::json!({
+                "timestamp": timestamp,
+                "reference": conf.reference,
+                "max_shift": comparison.max_shift(),
+                "intensity_drift": comparison.intensity_drift(),
+                "rms": comparison.rms,
+                "violations": violations,
+            });
+            // Monitoring goes on even if nobody is listening, next check will try again
+            if let Err(e) = remote::post_json(url, WEBHOOK_TIMEOUT, &body.to_string()) {
+                tracing::error!("Could not notify webhook: {e}");
+            }
+        }
+
+        checks += 1;
+        if conf.count.is_some_and(|count| checks >= count) {
+            return Ok(());
+        }
+        deadline += conf.interval;
+        if !sleep_until(deadline) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exceed_thresholds() {
+        let mut reference = vec![100; 50];
+        reference[20..23].copy_from_slice(&[300, 600, 300]);
+        let mut sample = reference.clone();
+        assert!(violations(&Comparison::new(&reference, &sample, 1).unwrap(), 0.5, 5.0).is_empty());
+
+        sample.iter_mut().for_each(|px| *px = *px * 9 / 10);
+        let comparison = Comparison::new(&reference, &sample, 1).unwrap();
+        let violations = violations(&comparison, 0.5, 5.0);
+        assert_eq!(violations.len(), 1);
+        assert!(violations[0].starts_with("intensity drifted by -10.00%"));
+    }
+}
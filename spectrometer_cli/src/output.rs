@@ -1,26 +1,60 @@
-use ccd_lcamv06::Frame;
-use time::{OffsetDateTime, macros::format_description, format_description::FormatItem};
+#[cfg(feature = "columnar")]
+use crate::columnar::ColumnarWriter;
+#[cfg(feature = "hdf5")]
+use crate::hdf5::Hdf5Writer;
+#[cfg(feature = "sqlite")]
+use crate::sqlite::{self, Archive};
+use crate::{
+    analysis::{raman_shift, wavelength_at},
+    compress::{Compression, Destination, Encoder},
+    csv::{CsvDialect, CsvFrame},
+    jcamp, manifest,
+    rotate::{self, Rotation},
+    spc::Spc,
+};
+use ccd_lcamv06::{proto, Frame, QualityFlags, FRAME_PIXEL_COUNT};
+use time::{
+    format_description::{well_known::Rfc3339, FormatItem},
+    macros::format_description,
+    OffsetDateTime,
+};
 use clap::{ArgEnum, Args};
 use plotters::prelude::*;
 use simple_eyre::{eyre::eyre, Result};
 use std::{
-    fs::File,
-    io::Write,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::mpsc::{self, SyncSender},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 #[derive(Args)]
 pub struct Output {
-    /// Path to a file where readings should be stored
-    #[clap(short, long, value_parser = unique_path_parser, value_hint = clap::ValueHint::FilePath)]
+    /// Path to a file where readings should be stored, `-` streams CSV or JSONL to stdout,
+    /// `sqlite://PATH` appends them as a new run to SQLite archive regardless of format in builds
+    /// with `sqlite` feature. Existing files are never overwritten
+    #[clap(short, long, value_parser, value_hint = clap::ValueHint::FilePath)]
     pub output: PathBuf,
 
     /// File format for reading output
-    #[clap(long, value_enum, default_value_t)]
+    #[clap(long, value_enum, default_value_t, env = "SPECTRO_FORMAT")]
     pub format: OutputFormat,
+
+    /// Directory relative output paths are resolved against
+    #[clap(long, value_parser, value_hint = clap::ValueHint::DirPath, env = "SPECTRO_OUTPUT_DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Compress output file, not supported for charts
+    #[clap(long, value_enum, default_value_t, env = "SPECTRO_COMPRESS")]
+    pub compress: Compression,
+
+    #[clap(flatten)]
+    pub csv: CsvDialect,
 }
 
-fn unique_path_parser(p: &str) -> Result<PathBuf> {
+pub fn unique_path_parser(p: &str) -> Result<PathBuf> {
     let p = Path::new(p);
     if p.try_exists()? {
         Err(eyre!("Path {p:?} already exists"))
@@ -29,15 +63,185 @@ fn unique_path_parser(p: &str) -> Result<PathBuf> {
     }
 }
 
-#[derive(ArgEnum, Clone, Default)]
+/// Same as [unique_path_parser], but also accepts `-` for stdout and existing SQLite archives
+pub fn output_path_parser(p: &str) -> Result<PathBuf> {
+    if is_stdio(Path::new(p)) || is_archive(Path::new(p)) {
+        Ok(PathBuf::from(p))
+    } else if cfg!(not(feature = "sqlite")) && p.starts_with("sqlite://") {
+        Err(eyre!(
+            "SQLite archive output needs a build with `sqlite` feature"
+        ))
+    } else {
+        unique_path_parser(p)
+    }
+}
+
+/// Whether output goes into a SQLite archive instead of a file
+#[cfg(feature = "sqlite")]
+fn is_archive(path: &Path) -> bool {
+    sqlite::archive_path(path).is_some()
+}
+
+/// Without `sqlite` feature output is never an archive
+#[cfg(not(feature = "sqlite"))]
+fn is_archive(_: &Path) -> bool {
+    false
+}
+
+/// Creates a file that doesn't exist yet. Existence is checked by the same call that creates the
+/// file, so an earlier capture is never overwritten
+pub fn create_new(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => eyre!("Path {path:?} already exists"),
+            _ => e.into(),
+        })
+}
+
+/// Checks if path is `-`, which stands for stdin or stdout
+pub fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+#[derive(ArgEnum, Clone, Copy, Default)]
 pub enum OutputFormat {
     #[default]
     Chart,
     Csv,
+    /// Little endian u16 frames back to back, prefixed with pixel and frame counts
+    Raw,
+    /// NumPy array file
+    Npy,
+    /// One JSON object with timestamp, frame number, summary stats and pixels per line
+    Jsonl,
+    /// JCAMP-DX spectrum, frames of a continuous capture are stored as linked blocks
+    Jcamp,
+    /// Galactic SPC with wavelength axis from calibration, frames are stored as subfiles
+    Spc,
+    /// Apache Parquet table with timestamp, frame number and pixels of every frame
+    #[cfg(feature = "columnar")]
+    Parquet,
+    /// Same table as Parquet, written as Apache Arrow IPC stream
+    #[cfg(feature = "columnar")]
+    Arrow,
+    /// HDF5 file with the run as a group of frame, timestamp and calibration datasets, and
+    /// capture metadata as its attributes
+    #[cfg(feature = "hdf5")]
+    Hdf5,
+    /// Length prefixed protobuf messages, capture metadata followed by frames, see
+    /// `ccd_lcamv06/proto/lcamv06.proto`
+    Proto,
+}
+
+impl OutputFormat {
+    /// Format can be written as a stream, without going back to rewrite anything
+    fn streamable(&self) -> bool {
+        match self {
+            OutputFormat::Csv | OutputFormat::Jsonl | OutputFormat::Proto => true,
+            #[cfg(feature = "columnar")]
+            OutputFormat::Arrow => true,
+            _ => false,
+        }
+    }
+
+    /// Conventional file extension
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Chart => "gif",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Raw => "raw",
+            OutputFormat::Npy => "npy",
+            OutputFormat::Jsonl => "jsonl",
+            OutputFormat::Jcamp => "jdx",
+            OutputFormat::Spc => "spc",
+            #[cfg(feature = "columnar")]
+            OutputFormat::Parquet => "parquet",
+            #[cfg(feature = "columnar")]
+            OutputFormat::Arrow => "arrows",
+            #[cfg(feature = "hdf5")]
+            OutputFormat::Hdf5 => "h5",
+            OutputFormat::Proto => "pb",
+        }
+    }
+}
+
+/// Everything written about a capture besides frames themselves
+#[derive(Clone)]
+pub struct Header {
+    /// `key: value` lines, written as comments by text formats
+    pub metadata: Vec<String>,
+    /// Polynomial coefficients converting pixel index into wavelength, empty when uncalibrated
+    pub wavelength: Vec<f64>,
+    /// Excitation laser wavelength in nm, Raman shift is written next to wavelength when known
+    pub laser: Option<f64>,
+    /// Real pixels at the start of every frame, only these are written out
+    pub pixels: usize,
+    /// Dark frame of calibration, stored next to frames by formats that have room for it
+    #[cfg(feature = "hdf5")]
+    pub dark: Option<Vec<u16>>,
+    /// Flat field frame of calibration, stored same as dark frame
+    #[cfg(feature = "hdf5")]
+    pub flat: Option<Vec<u16>>,
 }
 
-fn frame_to_csv(frame: &Frame) -> String {
-    log::trace!("Formatting frame as CSV");
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            metadata: Vec::new(),
+            wavelength: Vec::new(),
+            laser: None,
+            pixels: FRAME_PIXEL_COUNT,
+            #[cfg(feature = "hdf5")]
+            dark: None,
+            #[cfg(feature = "hdf5")]
+            flat: None,
+        }
+    }
+}
+
+/// Wavelength of each of `pixels` pixels rounded to picometers, empty when uncalibrated
+pub fn pixel_wavelengths(coeffs: &[f64], pixels: usize) -> Vec<f64> {
+    if coeffs.is_empty() {
+        return Vec::new();
+    }
+    (0..pixels)
+        .map(|px| (wavelength_at(coeffs, px as f64) * 1000.0).round() / 1000.0)
+        .collect()
+}
+
+/// Raman shift of each of `pixels` pixels in cm⁻¹ rounded to hundredths, empty without
+/// calibration or laser
+pub fn pixel_raman_shifts(coeffs: &[f64], laser: Option<f64>, pixels: usize) -> Vec<f64> {
+    let Some(laser) = laser.filter(|_| !coeffs.is_empty()) else {
+        return Vec::new();
+    };
+    (0..pixels)
+        .map(|px| (raman_shift(laser, wavelength_at(coeffs, px as f64)) * 100.0).round() / 100.0)
+        .collect()
+}
+
+/// Summary of a frame, so that consumers of JSONL stream can filter without going through pixels
+fn frame_stats(frame: &[u16]) -> serde_json::Value {
+    let (peak, max) = frame
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by_key(|(_, px)| *px)
+        .unwrap_or_default();
+    let mean = frame.iter().map(|&px| px as f64).sum::<f64>() / frame.len().max(1) as f64;
+    serde_json::json!({
+        "min": frame.iter().copied().min().unwrap_or(0),
+        "max": max,
+        "mean": mean,
+        "peak": peak,
+    })
+}
+
+pub fn frame_to_csv(frame: &[u16]) -> String {
+    tracing::trace!("Formatting frame as CSV");
     frame
         .iter()
         .map(|pixel| pixel.to_string())
@@ -45,21 +249,27 @@ fn frame_to_csv(frame: &Frame) -> String {
         .join(",")
 }
 
-fn frames_to_csv(frames: &[Frame]) -> String {
-    log::trace!("Formatting frames as CSV");
+pub fn frames_to_csv<F: AsRef<[u16]>>(frames: &[F]) -> String {
+    tracing::trace!("Formatting frames as CSV");
     frames
         .iter()
-        .map(frame_to_csv)
+        .map(|frame| frame_to_csv(frame.as_ref()))
         .collect::<Vec<_>>()
         .join("\n")
 }
 
 struct ChartData<'a> {
-    frame: &'a Frame,
+    frame: &'a [u16],
     idx: usize,
     timestamp: OffsetDateTime,
 }
 
+/// Current local time, falling back to UTC when local offset can't be determined, which happens
+/// once process has more than one thread
+pub fn now() -> OffsetDateTime {
+    OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc())
+}
+
 const TIMESTAMP_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
 
 fn draw_frame<DB: DrawingBackend>(
@@ -71,7 +281,7 @@ where
 {
     root.fill(&WHITE)?;
 
-    log::trace!("Drawing chart axes");
+    tracing::trace!("Drawing chart axes");
     let mut chart = ChartBuilder::on(root)
         .caption(
             format!(
@@ -85,32 +295,85 @@ where
         .set_label_area_size(LabelAreaPosition::Bottom, (5).percent())
         .build_cartesian_2d(0..data.frame.len(), 0u32..100_000u32)?;
 
-    log::trace!("Writing chart axes labels");
+    tracing::trace!("Writing chart axes labels");
     chart
         .configure_mesh()
         .x_desc("Pixel #")
         .y_desc("Inverse intensity")
         .draw()?;
 
-    log::trace!("Drawing frame as a line chart");
+    tracing::trace!("Drawing frame as a line chart");
     chart.draw_series(LineSeries::new(
         data.frame.iter().enumerate().map(|(x, y)| (x, *y as u32)),
         BLACK,
     ))?;
 
-    log::trace!("Pushing frame chart to rendering backend");
+    tracing::trace!("Pushing frame chart to rendering backend");
     root.present()?;
 
     Ok(())
 }
 
 impl Output {
-    pub fn write_frame(&self, frame: &Frame) -> Result<()> {
-        log::debug!("Saving frame to {:?}", self.output);
+    /// Path to output file with output directory taken into account
+    pub fn path(&self) -> PathBuf {
+        let Some(dir) = self.output_dir.as_ref().filter(|_| !is_stdio(&self.output)) else {
+            return self.output.clone();
+        };
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = sqlite::archive_path(&self.output) {
+            return PathBuf::from(format!(
+                "{}{}",
+                sqlite::SQLITE_SCHEME,
+                dir.join(db).display()
+            ));
+        }
+        dir.join(&self.output)
+    }
+
+    fn check_stdout(&self) -> Result<()> {
+        if is_stdio(&self.output) && !self.format.streamable() {
+            return Err(eyre!(
+                "Only CSV, JSONL, Arrow and protobuf output can be written to stdout"
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_csv(&self) -> Result<()> {
+        if !matches!(self.format, OutputFormat::Csv) && !self.csv.is_default() {
+            return Err(eyre!("CSV layout options only apply to CSV output"));
+        }
+        self.csv.validate()
+    }
+
+    /// Writes a single frame, `header` goes into formats that have one
+    pub fn write_frame(&self, frame: &Frame, header: &Header) -> Result<()> {
+        self.write_captured_frame(frame, None, QualityFlags::default(), header)
+    }
+
+    /// Same as [Output::write_frame], with `raw` being the frame before calibration was applied
+    /// and `flags` describing its quality
+    pub fn write_captured_frame(
+        &self,
+        frame: &Frame,
+        raw: Option<&Frame>,
+        flags: QualityFlags,
+        header: &Header,
+    ) -> Result<()> {
+        self.check_stdout()?;
+        self.check_csv()?;
+        let path = rotate::expand(&self.path(), 1, now())?;
+        tracing::debug!("Saving frame to {:?}", path);
+        let archive = is_archive(&path);
+        let frame = &frame[..header.pixels];
+        let raw = raw.map(|raw| &raw[..header.pixels]);
         match self.format {
-            OutputFormat::Chart => {
+            OutputFormat::Chart if !archive => {
+                // Backend creates the file itself, so it's only reserved here
+                create_new(&path)?;
                 let root =
-                    BitMapBackend::new(self.output.as_path(), (1280, 720)).into_drawing_area();
+                    BitMapBackend::new(path.as_path(), (1280, 720)).into_drawing_area();
                 draw_frame(
                     &root,
                     ChartData {
@@ -120,47 +383,634 @@ impl Output {
                     },
                 )?;
             }
-            OutputFormat::Csv => {
-                let mut out = File::create(self.output.as_path())?;
-                let data = frame_to_csv(frame);
-                out.write_all(data.as_bytes())?;
+            OutputFormat::Jcamp if !archive => {
+                let header = jcamp::Header {
+                    title: title(&path),
+                    metadata: header.metadata.clone(),
+                };
+                let mut out = Encoder::new(create_new(&path)?, self.compress)?;
+                out.write_all(header.block(frame, now(), None)?.as_bytes())?;
+                out.finish()?;
+            }
+            format => {
+                let mut sink =
+                    FrameSink::create(path.clone(), format, self.compress, header, &self.csv)?;
+                sink.write(frame, raw, flags, now())?;
+                sink.finish()?;
             }
         };
+        seal(&path, 1)
+    }
+
+    /// Adds a comment line at the end of output file at `path`, only supported by CSV
+    pub fn append_note(&self, path: &Path, note: &str) -> Result<()> {
+        if let OutputFormat::Csv = self.format {
+            let out: Destination = if is_stdio(path) {
+                io::stdout().into()
+            } else {
+                OpenOptions::new().append(true).open(path)?.into()
+            };
+            // Both zstd and gzip allow concatenating separately compressed streams
+            let mut out = Encoder::new(out, self.compress)?;
+            write!(out, "\n# {note}")?;
+            out.finish()?;
+            if !is_stdio(path) {
+                manifest::refresh(path)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn write_frames(&self, frames: &[Frame]) -> Result<()> {
-        log::debug!("Saving frames to {:?}", self.output);
-        match self.format {
-            OutputFormat::Chart => {
-                let root = BitMapBackend::gif(self.output.as_path(), (1280, 720), 500)?
-                    .into_drawing_area();
-                let timestamp = OffsetDateTime::now_local()?;
-                for (frame_idx, frame) in frames.iter().enumerate() {
-                    draw_frame(
-                        &root,
-                        ChartData {
-                            frame,
-                            idx: frame_idx + 1,
-                            timestamp,
-                        },
-                    )?;
+    /// Starts writing frames to output as they are captured. With `rotate` set output is split
+    /// into segments, each named by expanding output path as a template. `header` is written at
+    /// the start of every segment
+    pub fn frame_writer(&self, rotate: Option<Rotation>, header: Header) -> Result<FrameWriter> {
+        self.resumed_frame_writer(rotate, header, 0)
+    }
+
+    /// Same as [Output::frame_writer], with segments numbered after `last_seq` of an earlier
+    /// interrupted capture
+    pub fn resumed_frame_writer(
+        &self,
+        rotate: Option<Rotation>,
+        header: Header,
+        last_seq: usize,
+    ) -> Result<FrameWriter> {
+        self.check_stdout()?;
+        self.check_csv()?;
+        let template = self.path();
+        if rotate.is_some() && is_archive(&template) {
+            return Err(eyre!("SQLite archive output can't be rotated"));
+        }
+        if rotate.is_some() && !rotate::is_template(&template) {
+            return Err(eyre!(
+                "Rotated output path {template:?} has to contain {{seq}} or {{date}} placeholder"
+            ));
+        }
+        let mut segments = Segments {
+            template,
+            format: self.format,
+            compression: self.compress,
+            csv: self.csv.clone(),
+            rotate,
+            header,
+            seq: last_seq,
+        };
+        let (tx, rx) = mpsc::sync_channel::<Queued>(QUEUE_SIZE);
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // Chart backend can't be sent between threads, so sink is created right here, and
+            // failure to do so is passed back to be reported before capture starts
+            let mut segment = match segments.next(now()) {
+                Ok(segment) => segment,
+                Err(e) => {
+                    ready_tx.send(Err(e)).ok();
+                    return Err(eyre!("Output wasn't created"));
+                }
+            };
+            ready_tx.send(Ok(())).ok();
+            let pixels = segments.header.pixels;
+            let mut frames = 0;
+            for (frame, raw, flags, timestamp) in rx {
+                if segments.due(&segment) {
+                    let written = segment.sink.finish()?;
+                    seal(&segment.path, written)?;
+                    frames += written;
+                    segment = segments.next(timestamp)?;
                 }
+                let raw = raw.as_ref().map(|raw| &raw[..pixels]);
+                segment
+                    .sink
+                    .write(&frame[..pixels], raw, flags, timestamp)?;
+                segment.frames += 1;
             }
-            OutputFormat::Csv => {
-                let mut out = File::create(self.output.as_path())?;
-                let data = frames_to_csv(frames);
-                out.write_all(data.as_bytes())?;
+            let written = segment.sink.finish()?;
+            seal(&segment.path, written)?;
+            frames += written;
+            Ok(Written {
+                frames,
+                path: segment.path,
+            })
+        });
+        ready_rx
+            .recv()
+            .map_err(|_| eyre!("Frame writer panicked"))??;
+        Ok(FrameWriter { tx, thread })
+    }
+}
+
+/// Writes manifest of a complete output file, which stdout and SQLite archive don't get
+fn seal(path: &Path, frames: usize) -> Result<()> {
+    if is_stdio(path) || is_archive(path) {
+        return Ok(());
+    }
+    manifest::write(path, frames)
+}
+
+/// Produces output files for consecutive segments of a continuous capture
+struct Segments {
+    template: PathBuf,
+    format: OutputFormat,
+    compression: Compression,
+    csv: CsvDialect,
+    rotate: Option<Rotation>,
+    header: Header,
+    seq: usize,
+}
+
+struct Segment {
+    sink: FrameSink,
+    path: PathBuf,
+    started: Instant,
+    frames: usize,
+}
+
+impl Segments {
+    fn next(&mut self, start: OffsetDateTime) -> Result<Segment> {
+        self.seq += 1;
+        // Segment names may collide with existing files, e.g. when a capture is repeated or
+        // `{date}` is the only placeholder and segments are shorter than a second. Sink refuses
+        // to create those
+        let path = rotate::expand(&self.template, self.seq, start)?;
+        tracing::debug!("Saving frames to {:?}", path);
+        let mut header = self.header.clone();
+        if self.rotate.is_some() {
+            header.metadata.push(format!("segment: {}", self.seq));
+            header
+                .metadata
+                .push(format!("started: {}", start.format(TIMESTAMP_FORMAT)?));
+        }
+        Ok(Segment {
+            sink: FrameSink::create(
+                path.clone(),
+                self.format,
+                self.compression,
+                &header,
+                &self.csv,
+            )?,
+            path,
+            started: Instant::now(),
+            frames: 0,
+        })
+    }
+
+    fn due(&self, segment: &Segment) -> bool {
+        self.rotate
+            .is_some_and(|rotate| rotate.due(segment.started, segment.frames))
+    }
+}
+
+/// Amount of frames waiting to be written, capture blocks once writer falls this far behind
+const QUEUE_SIZE: usize = 64;
+/// Buffered CSV output is flushed at least this often, so an aborted capture loses little
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum FrameSink {
+    Chart {
+        root: DrawingArea<BitMapBackend<'static>, plotters::coord::Shift>,
+        written: usize,
+    },
+    File {
+        out: Box<BufWriter<Encoder>>,
+        format: OutputFormat,
+        pixels: usize,
+        written: usize,
+        flushed_at: Instant,
+        staged: Option<Staged>,
+        jcamp: Option<jcamp::Header>,
+        spc: Option<Spc>,
+        csv: Option<Box<CsvLayout>>,
+        /// Wavelength and Raman shift of every pixel for formats that repeat them with each frame
+        axes: Option<Box<PixelAxes>>,
+    },
+    #[cfg(feature = "columnar")]
+    Columnar(Box<ColumnarWriter>),
+    #[cfg(feature = "hdf5")]
+    Hdf5(Box<Hdf5Writer>),
+    #[cfg(feature = "sqlite")]
+    Archive(Box<Archive>),
+}
+
+/// Position of every pixel, as rounded for output
+struct PixelAxes {
+    wavelength: Vec<f64>,
+    /// Empty without laser wavelength
+    raman_shift: Vec<f64>,
+}
+
+/// Everything needed to lay out CSV rows besides frames themselves
+struct CsvLayout {
+    dialect: CsvDialect,
+    wavelength: Vec<f64>,
+    laser: Option<f64>,
+}
+
+/// Name of output file without any extensions, used as title of JCAMP-DX spectra
+fn title(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.split('.').next().unwrap_or_default().to_string()
+}
+
+/// Output that is written uncompressed first and compressed once complete
+struct Staged {
+    path: PathBuf,
+    target: PathBuf,
+    compression: Compression,
+}
+
+impl Staged {
+    fn compress(self) -> Result<()> {
+        tracing::debug!("Compressing {:?} into {:?}", self.path, self.target);
+        let mut out = Encoder::new(File::create(&self.target)?, self.compression)?;
+        io::copy(&mut File::open(&self.path)?, &mut out)?;
+        out.finish()?;
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+impl FrameSink {
+    fn create(
+        path: PathBuf,
+        format: OutputFormat,
+        compression: Compression,
+        header: &Header,
+        csv: &CsvDialect,
+    ) -> Result<Self> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = sqlite::archive_path(&path) {
+            if compression != Compression::None {
+                return Err(eyre!("SQLite archive can't be compressed"));
+            }
+            return Ok(FrameSink::Archive(Box::new(Archive::open(db, header)?)));
+        }
+        if let OutputFormat::Chart = format {
+            if compression != Compression::None {
+                return Err(eyre!("Chart output can't be compressed"));
+            }
+            // Backend creates the file itself, so it's only reserved here
+            create_new(&path)?;
+            return Ok(FrameSink::Chart {
+                root: BitMapBackend::gif(path, (1280, 720), 500)?.into_drawing_area(),
+                written: 0,
+            });
+        }
+        #[cfg(feature = "columnar")]
+        if let (OutputFormat::Parquet, Compression::Zstd | Compression::Gzip) = (format, compression)
+        {
+            return Err(eyre!("Parquet output is already compressed, drop --compress"));
+        }
+        #[cfg(feature = "hdf5")]
+        if let OutputFormat::Hdf5 = format {
+            if compression != Compression::None {
+                return Err(eyre!("HDF5 output can't be compressed"));
             }
+            let writer = Hdf5Writer::create(&path, header)?;
+            return Ok(FrameSink::Hdf5(Box::new(writer)));
+        }
+        // Headers of binary formats are rewritten at the end, which compressed stream doesn't
+        // allow, so those are staged uncompressed
+        let staged = match format {
+            OutputFormat::Raw | OutputFormat::Npy | OutputFormat::Jcamp | OutputFormat::Spc
+                if compression != Compression::None =>
+            {
+                // Reserved until compressed output replaces it
+                create_new(&path)?;
+                let mut name = path.file_name().unwrap_or_default().to_os_string();
+                name.push(".partial");
+                Some(Staged {
+                    path: path.with_file_name(name),
+                    target: path.clone(),
+                    compression,
+                })
+            }
+            _ => None,
+        };
+        let jcamp = matches!(format, OutputFormat::Jcamp).then(|| jcamp::Header {
+            title: title(&path),
+            metadata: header.metadata.clone(),
+        });
+        let spc = matches!(format, OutputFormat::Spc).then(|| Spc::new(header, header.pixels));
+        let encoder = match &staged {
+            Some(staged) => Encoder::Plain(File::create(&staged.path)?.into()),
+            None if is_stdio(&path) => Encoder::new(io::stdout(), compression)?,
+            None => Encoder::new(create_new(&path)?, compression)?,
         };
+        #[cfg(feature = "columnar")]
+        if let OutputFormat::Parquet | OutputFormat::Arrow = format {
+            let out = BufWriter::new(encoder);
+            let writer = match format {
+                OutputFormat::Parquet => ColumnarWriter::parquet(out, header, header.pixels)?,
+                _ => ColumnarWriter::arrow(out, header, header.pixels)?,
+            };
+            return Ok(FrameSink::Columnar(Box::new(writer)));
+        }
+        let mut out = Box::new(BufWriter::new(encoder));
+        // Frame count isn't known yet, header is rewritten once writing is finished
+        match format {
+            OutputFormat::Raw => out.write_all(&raw_header(header.pixels, 0))?,
+            OutputFormat::Npy => out.write_all(&npy_header(header.pixels, 0))?,
+            OutputFormat::Jcamp => {
+                if let Some(jcamp) = &jcamp {
+                    out.write_all(jcamp.link(0).as_bytes())?;
+                }
+            }
+            OutputFormat::Spc => {
+                if let Some(spc) = &spc {
+                    out.write_all(&spc.header(0))?;
+                }
+            }
+            OutputFormat::Csv => {
+                let wavelengths = pixel_wavelengths(&header.wavelength, header.pixels);
+                let raman_shifts =
+                    pixel_raman_shifts(&header.wavelength, header.laser, header.pixels);
+                csv.write_header(&mut out, &header.metadata, &wavelengths, &raman_shifts)?
+            }
+            OutputFormat::Proto => {
+                let capture =
+                    proto::Capture::from_lines(&header.metadata, header.wavelength.clone());
+                let mut buf = Vec::new();
+                proto::encode(&capture, &mut buf);
+                out.write_all(&buf)?;
+            }
+            OutputFormat::Jsonl | OutputFormat::Chart => {}
+            #[cfg(feature = "columnar")]
+            OutputFormat::Parquet | OutputFormat::Arrow => {}
+            #[cfg(feature = "hdf5")]
+            OutputFormat::Hdf5 => {}
+        }
+        Ok(FrameSink::File {
+            out,
+            format,
+            pixels: header.pixels,
+            written: 0,
+            flushed_at: Instant::now(),
+            staged,
+            jcamp,
+            spc,
+            axes: (matches!(format, OutputFormat::Jsonl) && !header.wavelength.is_empty()).then(
+                || {
+                    Box::new(PixelAxes {
+                        wavelength: pixel_wavelengths(&header.wavelength, header.pixels),
+                        raman_shift: pixel_raman_shifts(
+                            &header.wavelength,
+                            header.laser,
+                            header.pixels,
+                        ),
+                    })
+                },
+            ),
+            csv: matches!(format, OutputFormat::Csv).then(|| {
+                Box::new(CsvLayout {
+                    dialect: csv.clone(),
+                    wavelength: header.wavelength.clone(),
+                    laser: header.laser,
+                })
+            }),
+        })
+    }
+
+    /// Writes real pixels of a frame, `raw` being the same pixels before calibration if there
+    /// was any
+    fn write(
+        &mut self,
+        frame: &[u16],
+        raw: Option<&[u16]>,
+        flags: QualityFlags,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        match self {
+            FrameSink::Chart { root, written } => {
+                *written += 1;
+                draw_frame(
+                    root,
+                    ChartData {
+                        frame,
+                        idx: *written,
+                        timestamp,
+                    },
+                )?;
+            }
+            #[cfg(feature = "columnar")]
+            FrameSink::Columnar(writer) => writer.write(frame, flags, timestamp)?,
+            #[cfg(feature = "hdf5")]
+            FrameSink::Hdf5(writer) => writer.write(frame, flags, timestamp)?,
+            #[cfg(feature = "sqlite")]
+            FrameSink::Archive(archive) => archive.write(frame, flags, timestamp)?,
+            FrameSink::File {
+                out,
+                format,
+                written,
+                flushed_at,
+                jcamp,
+                spc,
+                csv,
+                axes,
+                ..
+            } => {
+                match format {
+                    OutputFormat::Csv => {
+                        if let Some(csv) = csv {
+                            let frame = CsvFrame {
+                                idx: *written + 1,
+                                pixels: frame,
+                                raw,
+                                flags,
+                                wavelength: &csv.wavelength,
+                                laser: csv.laser,
+                            };
+                            csv.dialect.write_frame(&mut *out, &frame, *written == 0)?;
+                        }
+                    }
+                    OutputFormat::Jsonl => {
+                        let mut line = serde_json::json!({
+                            "timestamp": timestamp.format(&Rfc3339)?,
+                            "seq": *written + 1,
+                            "stats": frame_stats(frame),
+                            "flags": flags.names().collect::<Vec<_>>(),
+                            "pixels": frame,
+                        });
+                        if let Some(axes) = axes {
+                            line["wavelength"] = serde_json::json!(axes.wavelength);
+                            if !axes.raman_shift.is_empty() {
+                                line["raman_shift"] = serde_json::json!(axes.raman_shift);
+                            }
+                        }
+                        serde_json::to_writer(&mut *out, &line)?;
+                        writeln!(out)?;
+                    }
+                    OutputFormat::Proto => {
+                        let mut message = proto::Frame::new(
+                            frame,
+                            *written as u64 + 1,
+                            (timestamp.unix_timestamp_nanos() / 1000) as i64,
+                        );
+                        message.quality = flags.bits() as u32;
+                        let mut buf = Vec::new();
+                        proto::encode(&message, &mut buf);
+                        out.write_all(&buf)?;
+                    }
+                    OutputFormat::Jcamp => {
+                        if let Some(jcamp) = jcamp {
+                            let block = jcamp.block(frame, timestamp, Some(*written + 1))?;
+                            out.write_all(block.as_bytes())?;
+                        }
+                    }
+                    OutputFormat::Spc => {
+                        if let Some(spc) = spc {
+                            out.write_all(&spc.subfile(*written, frame, timestamp))?;
+                        }
+                    }
+                    _ => {
+                        for pixel in frame {
+                            out.write_all(&pixel.to_le_bytes())?;
+                        }
+                    }
+                }
+                *written += 1;
+                if flushed_at.elapsed() >= FLUSH_INTERVAL {
+                    tracing::trace!("Flushing output");
+                    out.flush()?;
+                    *flushed_at = Instant::now();
+                }
+            }
+        }
         Ok(())
     }
+
+    fn finish(self) -> Result<usize> {
+        match self {
+            FrameSink::Chart { written, .. } => Ok(written),
+            #[cfg(feature = "columnar")]
+            FrameSink::Columnar(writer) => {
+                let (out, written) = writer.finish()?;
+                out.into_inner().map_err(|e| e.into_error())?.finish()?;
+                Ok(written)
+            }
+            #[cfg(feature = "hdf5")]
+            FrameSink::Hdf5(writer) => writer.finish(),
+            #[cfg(feature = "sqlite")]
+            FrameSink::Archive(archive) => archive.finish(),
+            FrameSink::File {
+                mut out,
+                format,
+                pixels,
+                written,
+                staged,
+                jcamp,
+                spc,
+                ..
+            } => {
+                match format {
+                    OutputFormat::Raw => {
+                        out.seek(SeekFrom::Start(0))?;
+                        out.write_all(&raw_header(pixels, written))?;
+                    }
+                    OutputFormat::Npy => {
+                        out.seek(SeekFrom::Start(0))?;
+                        out.write_all(&npy_header(pixels, written))?;
+                    }
+                    OutputFormat::Jcamp => {
+                        if let Some(jcamp) = &jcamp {
+                            // Link block is closed only now, so that it encloses all spectra
+                            out.write_all(b"##END=\n")?;
+                            out.seek(SeekFrom::Start(0))?;
+                            out.write_all(jcamp.link(written).as_bytes())?;
+                        }
+                    }
+                    OutputFormat::Spc => {
+                        if let Some(spc) = &spc {
+                            spc.write_log(&mut out)?;
+                            out.seek(SeekFrom::Start(0))?;
+                            out.write_all(&spc.header(written))?;
+                        }
+                    }
+                    _ => {}
+                }
+                out.into_inner().map_err(|e| e.into_error())?.finish()?;
+                if let Some(staged) = staged {
+                    staged.compress()?;
+                }
+                Ok(written)
+            }
+        }
+    }
+}
+
+/// Header of raw output: amount of pixels per frame and amount of frames, both as little endian
+/// u32. Frames follow as little endian u16 pixels
+fn raw_header(pixels: usize, count: usize) -> [u8; 8] {
+    let mut header = [0; 8];
+    header[..4].copy_from_slice(&(pixels as u32).to_le_bytes());
+    header[4..].copy_from_slice(&(count as u32).to_le_bytes());
+    header
+}
+
+/// Size of NPY header, kept constant regardless of frame count so it can be rewritten in place
+const NPY_HEADER_LEN: usize = 128;
+
+/// NPY v1.0 header describing a `count` x `pixels` array of little endian u16
+fn npy_header(pixels: usize, count: usize) -> Vec<u8> {
+    let dict =
+        format!("{{'descr': '<u2', 'fortran_order': False, 'shape': ({count:>20}, {pixels}), }}");
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&(NPY_HEADER_LEN as u16 - 10).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.resize(NPY_HEADER_LEN - 1, b' ');
+    header.push(b'\n');
+    header
+}
+
+/// Frame waiting to be written, along with the frame before calibration when it was applied and
+/// quality flags
+type Queued = (Frame, Option<Box<Frame>>, QualityFlags, OffsetDateTime);
+
+/// Writes frames on a background thread, so long captures never have to fit in memory and slow
+/// chart rendering doesn't hold up reading from CCD
+pub struct FrameWriter {
+    tx: SyncSender<Queued>,
+    thread: JoinHandle<Result<Written>>,
+}
+
+/// Summary of a finished continuous output
+pub struct Written {
+    /// Amount of frames written across all segments
+    pub frames: usize,
+    /// Last segment, which is where notes about the whole capture go
+    pub path: PathBuf,
+}
+
+impl FrameWriter {
+    /// Queues a frame for writing, blocking while the queue is full
+    pub fn write(&self, frame: Frame) -> Result<()> {
+        self.write_captured(frame, None, QualityFlags::default())
+    }
+
+    /// Same as [FrameWriter::write], with `raw` being the frame before calibration was applied
+    /// and `flags` describing its quality
+    pub fn write_captured(
+        &self,
+        frame: Frame,
+        raw: Option<Frame>,
+        flags: QualityFlags,
+    ) -> Result<()> {
+        self.tx
+            .send((frame, raw.map(Box::new), flags, now()))
+            .map_err(|_| eyre!("Frame writer stopped unexpectedly"))
+    }
+
+    /// Waits for all queued frames to be written
+    pub fn finish(self) -> Result<Written> {
+        drop(self.tx);
+        self.thread
+            .join()
+            .map_err(|_| eyre!("Frame writer panicked"))?
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ccd_lcamv06::FRAME_PIXEL_COUNT;
 
     #[test]
     fn convert_frame_to_csv() {
@@ -169,4 +1019,260 @@ mod tests {
         let csv_fields: Vec<_> = csv.split(",").collect();
         assert_eq!(csv_fields[0], "1000");
     }
+
+    #[test]
+    fn npy_header_layout() {
+        let header = npy_header(FRAME_PIXEL_COUNT, 12);
+        assert_eq!(header.len(), NPY_HEADER_LEN);
+        assert!(header.starts_with(b"\x93NUMPY\x01\x00"));
+        assert_eq!(header.last(), Some(&b'\n'));
+        let dict = std::str::from_utf8(&header[10..]).unwrap();
+        assert!(dict.contains(&format!("12, {FRAME_PIXEL_COUNT})")));
+        assert_eq!(
+            npy_header(FRAME_PIXEL_COUNT, usize::MAX).len(),
+            NPY_HEADER_LEN
+        );
+    }
+
+    #[test]
+    fn compress_staged_npy() {
+        let path = std::env::temp_dir().join(format!("frames-{}.npy.zst", std::process::id()));
+        let output = Output {
+            output: path.clone(),
+            format: OutputFormat::Npy,
+            output_dir: None,
+            compress: Compression::Zstd,
+            csv: CsvDialect::default(),
+        };
+        let writer = output.frame_writer(None, Header::default()).unwrap();
+        for _ in 0..3 {
+            writer.write([1; FRAME_PIXEL_COUNT]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let data = zstd::decode_all(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), NPY_HEADER_LEN + 3 * FRAME_PIXEL_COUNT * 2);
+        assert_eq!(
+            &data[..NPY_HEADER_LEN],
+            npy_header(FRAME_PIXEL_COUNT, 3).as_slice()
+        );
+        let mut staged = path.into_os_string();
+        staged.push(".partial");
+        assert!(!Path::new(&staged).exists());
+    }
+
+    #[test]
+    fn write_real_pixels_only() {
+        let path = std::env::temp_dir().join(format!("frames-{}.raw", std::process::id()));
+        let output = Output {
+            output: path.clone(),
+            format: OutputFormat::Raw,
+            output_dir: None,
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        let header = Header {
+            pixels: 2048,
+            ..Default::default()
+        };
+        let writer = output.frame_writer(None, header).unwrap();
+        writer.write([1; FRAME_PIXEL_COUNT]).unwrap();
+        writer.finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data[..8], raw_header(2048, 1));
+        assert_eq!(data.len(), 8 + 2048 * 2);
+    }
+
+    #[test]
+    fn stream_frames_to_csv() {
+        let path = std::env::temp_dir().join(format!("frames-{}.csv", std::process::id()));
+        let output = Output {
+            output: path.clone(),
+            format: OutputFormat::Csv,
+            output_dir: None,
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        let frames: Vec<Frame> = vec![[1; FRAME_PIXEL_COUNT], [2; FRAME_PIXEL_COUNT]];
+        let writer = output.frame_writer(None, Header::default()).unwrap();
+        for frame in &frames {
+            writer.write(*frame).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap().frames, 2);
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, frames_to_csv(&frames));
+    }
+
+    #[test]
+    fn stream_frames_to_jsonl() {
+        let path = std::env::temp_dir().join(format!("frames-{}.jsonl", std::process::id()));
+        let output = Output {
+            output: path.clone(),
+            format: OutputFormat::Jsonl,
+            output_dir: None,
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        let header = Header {
+            wavelength: vec![500.0, 0.5],
+            laser: Some(500.0),
+            ..Default::default()
+        };
+        let writer = output.frame_writer(None, header).unwrap();
+        writer.write([7; FRAME_PIXEL_COUNT]).unwrap();
+        writer.write([8; FRAME_PIXEL_COUNT]).unwrap();
+        writer.finish().unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["pixels"][0], 8);
+        assert!(lines[1]["timestamp"].is_string());
+        assert_eq!(lines[1]["seq"], 2);
+        assert_eq!(lines[1]["stats"]["max"], 8);
+        assert_eq!(lines[1]["stats"]["mean"], 8.0);
+        assert_eq!(lines[1]["flags"], serde_json::json!([]));
+        assert_eq!(lines[1]["wavelength"][3], 501.5);
+        // 1e7 / 500 - 1e7 / 501.5
+        assert_eq!(lines[1]["raman_shift"][3], 59.82);
+    }
+
+    #[test]
+    fn link_jcamp_blocks() {
+        let path = std::env::temp_dir().join(format!("frames-{}.jdx.gz", std::process::id()));
+        let output = Output {
+            output: path.clone(),
+            format: OutputFormat::Jcamp,
+            output_dir: None,
+            compress: Compression::Gzip,
+            csv: CsvDialect::default(),
+        };
+        let writer = output
+            .frame_writer(
+                None,
+                Header {
+                    metadata: vec!["exposure time: 10".into()],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        writer.write([5; FRAME_PIXEL_COUNT]).unwrap();
+        writer.write([6; FRAME_PIXEL_COUNT]).unwrap();
+        writer.finish().unwrap();
+
+        let mut written = String::new();
+        io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(File::open(&path).unwrap()),
+            &mut written,
+        )
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines[0], format!("##TITLE=frames-{}", std::process::id()));
+        assert_eq!(lines[3].split('=').nth(1).unwrap().trim(), "2");
+        assert!(lines.contains(&"##BLOCK_ID=2"));
+        assert_eq!(lines.iter().filter(|l| **l == "##$EXPOSURE TIME=10").count(), 2);
+        assert_eq!(lines.iter().filter(|l| **l == "##END=").count(), 3);
+    }
+
+    #[test]
+    fn refuse_binary_stdout() {
+        let output = Output {
+            output: PathBuf::from("-"),
+            format: OutputFormat::Npy,
+            output_dir: Some(PathBuf::from("out")),
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        assert_eq!(output.path(), PathBuf::from("-"));
+        assert!(output.frame_writer(None, Header::default()).is_err());
+    }
+
+    #[test]
+    fn rotate_by_frame_count() {
+        let dir = std::env::temp_dir().join(format!("segments-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = Output {
+            output: PathBuf::from("run_{seq}.csv"),
+            format: OutputFormat::Csv,
+            output_dir: Some(dir.clone()),
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        let writer = output
+            .frame_writer(
+                Some(Rotation::Frames(2)),
+                Header {
+                    metadata: vec!["exposure time: 10".into()],
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        for i in 0..5 {
+            writer.write([i; FRAME_PIXEL_COUNT]).unwrap();
+        }
+        let written = writer.finish().unwrap();
+        assert_eq!(written.frames, 5);
+        assert_eq!(written.path, dir.join("run_0003.csv"));
+
+        let second = fs::read_to_string(dir.join("run_0002.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut lines = second.lines();
+        assert_eq!(lines.next(), Some("# exposure time: 10"));
+        assert_eq!(lines.next(), Some("# segment: 2"));
+        assert!(lines.next().unwrap().starts_with("# started: "));
+        assert_eq!(
+            lines.collect::<Vec<_>>().join("\n"),
+            frames_to_csv(&[[2; FRAME_PIXEL_COUNT], [3; FRAME_PIXEL_COUNT]])
+        );
+    }
+
+    #[test]
+    fn keep_capture_in_output_dir() {
+        let dir = std::env::temp_dir().join(format!("output-dir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("frame.csv"), "earlier").unwrap();
+        let output = Output {
+            output: PathBuf::from("frame.csv"),
+            format: OutputFormat::Csv,
+            output_dir: Some(dir.clone()),
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        let res = output.write_frame(&[1; FRAME_PIXEL_COUNT], &Header::default());
+
+        let earlier = fs::read_to_string(dir.join("frame.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(res.is_err());
+        assert_eq!(earlier, "earlier");
+    }
+
+    #[test]
+    fn keep_segments_of_earlier_capture() {
+        let dir = std::env::temp_dir().join(format!("rerun-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("run_0001.csv"), "earlier").unwrap();
+        let output = Output {
+            output: PathBuf::from("run_{seq}.csv"),
+            format: OutputFormat::Csv,
+            output_dir: Some(dir.clone()),
+            compress: Compression::None,
+            csv: CsvDialect::default(),
+        };
+        let res = output.frame_writer(Some(Rotation::Frames(100)), Header::default());
+
+        let earlier = fs::read_to_string(dir.join("run_0001.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(res.is_err());
+        assert_eq!(earlier, "earlier");
+    }
 }
@@ -0,0 +1,245 @@
+use crate::{analysis::find_peaks, input};
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use serde::Deserialize;
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// Processing applied to every frame after calibration, stages run in the order they are listed.
+/// None of them changes the amount of pixels, so wavelength calibration still lines up
+///
+/// ```toml
+/// [[pipeline.stage]]
+/// type = "dark-subtract"
+/// dark = "dark.csv"
+///
+/// [[pipeline.stage]]
+/// type = "smooth"
+/// window = 5
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    #[serde(rename = "stage", default)]
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Stage {
+    /// Subtracts dark frame read from CSV file, relative paths start at pipeline file
+    DarkSubtract {
+        dark: PathBuf,
+        #[serde(skip)]
+        frame: Vec<u16>,
+    },
+    /// Moving average over odd amount of pixels centered on each pixel
+    Smooth { window: usize },
+    /// Replaces every group of adjacent pixels with their mean
+    Bin { pixels: usize },
+    /// Zeroes pixels outside of first to last pixel, both included
+    Roi { start: usize, end: usize },
+    /// Subtracts lowest count within window centered on each pixel, removing broad background
+    /// under narrow peaks
+    Baseline { window: usize },
+    /// Logs pixels of up to `count` highest peaks
+    PeakDetect { count: usize },
+}
+
+/// Contents of file passed to `--pipeline`, same table as in daemon config
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PipelineFile {
+    pipeline: Pipeline,
+}
+
+/// Reads pipeline from a TOML file with a `[pipeline]` table
+pub fn load(path: &Path) -> Result<Pipeline> {
+    let file: PipelineFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| eyre!("Could not parse pipeline {path:?}: {e}"))?;
+    let mut pipeline = file.pipeline;
+    pipeline.prepare(path.parent())?;
+    Ok(pipeline)
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stage::DarkSubtract { dark, .. } => write!(f, "dark-subtract {}", dark.display()),
+            Stage::Smooth { window } => write!(f, "smooth {window}"),
+            Stage::Bin { pixels } => write!(f, "bin {pixels}"),
+            Stage::Roi { start, end } => write!(f, "roi {start}-{end}"),
+            Stage::Baseline { window } => write!(f, "baseline {window}"),
+            Stage::PeakDetect { count } => write!(f, "peak-detect {count}"),
+        }
+    }
+}
+
+impl Stage {
+    fn validate(&self) -> Result<()> {
+        match *self {
+            Stage::Smooth { window } if window % 2 == 0 => Err(eyre!(
+                "Smoothing window should be an odd amount of pixels, got {window}"
+            )),
+            Stage::Bin { pixels: 0 } => Err(eyre!("Bin should be at least one pixel wide")),
+            Stage::Roi { start, end } if start > end => {
+                Err(eyre!("Region of interest ends before it starts"))
+            }
+            Stage::Roi { end, .. } if end >= FRAME_PIXEL_COUNT => Err(eyre!(
+                "Region of interest ends at pixel {end}, but frame only has {FRAME_PIXEL_COUNT}"
+            )),
+            Stage::Baseline { window: 0 } => {
+                Err(eyre!("Baseline window should be at least one pixel wide"))
+            }
+            Stage::PeakDetect { count: 0 } => Err(eyre!("Peak detection needs a peak count")),
+            _ => Ok(()),
+        }
+    }
+
+    fn apply(&self, pixels: &mut [u16]) {
+        match self {
+            Stage::DarkSubtract { frame, .. } => {
+                for (px, dark) in pixels.iter_mut().zip(frame) {
+                    *px = px.saturating_sub(*dark);
+                }
+            }
+            Stage::Smooth { window } => smooth(pixels, *window),
+            Stage::Bin { pixels: width } => {
+                for group in pixels.chunks_mut(*width) {
+                    let sum: u64 = group.iter().map(|&px| px as u64).sum();
+                    let len = group.len() as u64;
+                    group.fill(((sum + len / 2) / len) as u16);
+                }
+            }
+            Stage::Roi { start, end } => {
+                for (i, px) in pixels.iter_mut().enumerate() {
+                    if i < *start || i > *end {
+                        *px = 0;
+                    }
+                }
+            }
+            Stage::Baseline { window } => {
+                let half = window / 2;
+                let baseline: Vec<u16> = (0..pixels.len())
+                    .map(|i| {
+                        let around = i.saturating_sub(half)..(i + half + 1).min(pixels.len());
+                        pixels[around].iter().copied().min().unwrap_or(0)
+                    })
+                    .collect();
+                for (px, base) in pixels.iter_mut().zip(baseline) {
+                    *px -= base;
+                }
+            }
+            Stage::PeakDetect { count } => {
+                let peaks: Vec<_> = find_peaks(pixels, *count)
+                    .iter()
+                    .map(usize::to_string)
+                    .collect();
+                tracing::info!("Peaks at pixels {}", peaks.join(", "));
+            }
+        }
+    }
+}
+
+/// Centered moving average, window shrinks near the edges
+fn smooth(pixels: &mut [u16], window: usize) {
+    let half = window / 2;
+    let mut prefix = Vec::with_capacity(pixels.len() + 1);
+    prefix.push(0u64);
+    for &px in pixels.iter() {
+        prefix.push(prefix.last().copied().unwrap_or(0) + px as u64);
+    }
+    for (i, px) in pixels.iter_mut().enumerate() {
+        let start = i.saturating_sub(half);
+        let end = (i + half + 1).min(prefix.len() - 1);
+        let len = (end - start) as u64;
+        *px = ((prefix[end] - prefix[start] + len / 2) / len) as u16;
+    }
+}
+
+impl Pipeline {
+    /// Checks stage parameters and reads dark frames, resolving their paths against `base`
+    pub fn prepare(&mut self, base: Option<&Path>) -> Result<()> {
+        for stage in &mut self.stages {
+            stage.validate()?;
+            if let Stage::DarkSubtract { dark, frame } = stage {
+                let path = base.map_or_else(|| dark.clone(), |base| base.join(&*dark));
+                *frame = input::read_frame(&path)?;
+                if frame.len() != FRAME_PIXEL_COUNT {
+                    return Err(eyre!(
+                        "Dark frame {path:?} has {} pixels, expected {FRAME_PIXEL_COUNT}",
+                        frame.len()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stages as a single line, e.g. `dark-subtract dark.csv, smooth 5`
+    pub fn describe(&self) -> String {
+        let stages: Vec<_> = self.stages.iter().map(Stage::to_string).collect();
+        stages.join(", ")
+    }
+
+    /// Records stages in output header metadata, so that processed captures can be told apart
+    pub fn note(&self, metadata: &mut Vec<String>) {
+        if !self.stages.is_empty() {
+            metadata.push(format!("pipeline: {}", self.describe()));
+        }
+    }
+
+    pub fn apply(&self, pixels: &mut [u16]) {
+        for stage in &self.stages {
+            stage.apply(pixels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_stages() {
+        let mut pipeline: Pipeline = toml::from_str::<PipelineFile>(
+            r#"
+            [[pipeline.stage]]
+            type = "baseline"
+            window = 3
+
+            [[pipeline.stage]]
+            type = "smooth"
+            window = 3
+
+            [[pipeline.stage]]
+            type = "roi"
+            start = 1
+            end = 4
+            "#,
+        )
+        .unwrap()
+        .pipeline;
+        pipeline.prepare(None).unwrap();
+        assert_eq!(pipeline.describe(), "baseline 3, smooth 3, roi 1-4");
+        let mut pixels = [10, 10, 40, 10, 10, 10];
+        pipeline.apply(&mut pixels);
+        assert_eq!(pixels, [0, 10, 10, 10, 0, 0]);
+
+        let mut binned = [1, 3, 10, 20, 7];
+        Stage::Bin { pixels: 2 }.apply(&mut binned);
+        assert_eq!(binned, [2, 2, 15, 15, 7]);
+
+        let mut even = Pipeline {
+            stages: vec![Stage::Smooth { window: 4 }],
+        };
+        assert!(even.prepare(None).is_err());
+        let unknown = r#"
+            [[pipeline.stage]]
+            type = "sharpen"
+        "#;
+        assert!(toml::from_str::<PipelineFile>(unknown).is_err());
+    }
+}
@@ -0,0 +1,482 @@
+use crate::{
+    analysis::{find_peaks, Axis, Histogram, Spectrum},
+    cli::PlotFileConf,
+    histogram::occupied,
+    input::{self, InputFormat},
+    live::render_plot,
+    output::unique_path_parser,
+};
+use clap::Args;
+use console::Term;
+use plotters::{coord::Shift, prelude::*};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Size of rendered plots in pixels
+const PLOT_SIZE: (u32, u32) = (1280, 720);
+
+/// Height of terminal preview in lines, not counting labels below it
+const PREVIEW_LINES: usize = 8;
+
+/// Frames are reduced to this many columns in waterfall, keeping the highest pixel of each
+const WATERFALL_COLUMNS: usize = 1024;
+
+/// Once waterfall has more rows than this, neighbouring ones are merged, so memory use doesn't
+/// grow with capture length
+const WATERFALL_ROWS: usize = 1024;
+
+#[derive(Args)]
+pub struct PlotConf {
+    /// Also render spectrum into an image, PNG or SVG depending on extension. Last frame is
+    /// plotted when more than one is captured
+    #[clap(long, value_parser = plot_path_parser, value_hint = clap::ValueHint::FilePath)]
+    pub plot: Option<PathBuf>,
+
+    /// Amount of highest peaks annotated on plot
+    #[clap(long, value_parser, default_value = "3")]
+    pub plot_peaks: usize,
+
+    /// Print spectrum as block characters once capture is done, for a quick look without
+    /// opening any files. Goes to stderr, so it doesn't mix with output written to stdout
+    #[clap(long)]
+    pub preview: bool,
+}
+
+/// Second spectrum drawn over the main one, for comparing them
+pub struct Overlay<'a> {
+    pub pixels: &'a [u16],
+    /// Legend labels of the main spectrum and of the overlay
+    pub labels: (&'a str, &'a str),
+}
+
+impl PlotConf {
+    /// Renders spectrum if plot or preview was requested
+    pub fn write(&self, spectrum: &Spectrum, title: &str) -> Result<()> {
+        if self.preview {
+            let term = Term::stderr();
+            let (_, width) = term.size();
+            for line in preview(spectrum, width as usize) {
+                term.write_line(&line)?;
+            }
+        }
+        match &self.plot {
+            Some(path) => write_plot(path, spectrum, title, None, self.plot_peaks),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Spectrum drawn with block characters `width` columns wide, followed by its range and highest
+/// pixel
+pub fn preview(spectrum: &Spectrum, width: usize) -> Vec<String> {
+    let mut lines = render_plot(spectrum.pixels, width, PREVIEW_LINES);
+    let last = spectrum.pixels.len().saturating_sub(1);
+    let axis = spectrum.axis();
+    let label = |pixel: usize| match axis {
+        Axis::Pixel => format!("{pixel} px"),
+        _ => format!("{:.1} {}", spectrum.x_at(pixel as f64), axis.unit()),
+    };
+    let (left, right) = (label(0), label(last));
+    let plotted = lines.first().map_or(0, |line| line.chars().count());
+    let gap = plotted.saturating_sub(left.len() + right.len()).max(1);
+    lines.push(format!("{left}{}{right}", " ".repeat(gap)));
+    if let Some(&peak) = find_peaks(spectrum.pixels, 1).first() {
+        let mut line = format!("Peak {} at pixel {peak}", spectrum.pixels[peak]);
+        if axis != Axis::Pixel {
+            line.push_str(&format!(" ({})", label(peak)));
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Same as [unique_path_parser], but also checks that plot can be rendered into such file, so it
+/// doesn't fail only after capture is done
+pub fn plot_path_parser(p: &str) -> Result<PathBuf> {
+    let path = unique_path_parser(p)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png" | "svg") => Ok(path),
+        _ => Err(eyre!("Plot {path:?} should have .png or .svg extension")),
+    }
+}
+
+/// Same as [unique_path_parser], but only accepts PNG files
+pub fn waterfall_path_parser(p: &str) -> Result<PathBuf> {
+    let path = unique_path_parser(p)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => Ok(path),
+        _ => Err(eyre!("Waterfall {path:?} should have .png extension")),
+    }
+}
+
+pub fn write_plot(
+    path: &Path,
+    spectrum: &Spectrum,
+    title: &str,
+    overlay: Option<&Overlay>,
+    peaks: usize,
+) -> Result<()> {
+    tracing::debug!("Plotting spectrum to {path:?}");
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => draw(
+            &BitMapBackend::new(path, PLOT_SIZE).into_drawing_area(),
+            spectrum,
+            title,
+            overlay,
+            peaks,
+        ),
+        Some("svg") => draw(
+            &SVGBackend::new(path, PLOT_SIZE).into_drawing_area(),
+            spectrum,
+            title,
+            overlay,
+            peaks,
+        ),
+        _ => Err(eyre!("Plot {path:?} should have .png or .svg extension")),
+    }
+}
+
+fn draw<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    spectrum: &Spectrum,
+    title: &str,
+    overlay: Option<&Overlay>,
+    peaks: usize,
+) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+    let x_at = |pixel: usize| spectrum.x_at(pixel as f64);
+    let last = spectrum.pixels.len().saturating_sub(1);
+    let (x_from, x_to) = (x_at(0).min(x_at(last)), x_at(0).max(x_at(last)));
+    let top = spectrum
+        .pixels
+        .iter()
+        .chain(overlay.map_or(&[][..], |overlay| overlay.pixels))
+        .copied()
+        .max()
+        .unwrap_or(0) as f64
+        * 1.1;
+
+    let mut chart = ChartBuilder::on(root)
+        .caption(title, ("sans-serif", (5).percent()))
+        .margin(10)
+        .set_label_area_size(LabelAreaPosition::Left, (8).percent())
+        .set_label_area_size(LabelAreaPosition::Bottom, (6).percent())
+        .build_cartesian_2d(x_from..x_to.max(x_from + 1.0), 0.0..top.max(1.0))?;
+    chart
+        .configure_mesh()
+        .x_desc(spectrum.axis().description())
+        .y_desc("Inverse intensity")
+        .draw()?;
+    let line = |pixels: &[u16], color| {
+        LineSeries::new(
+            pixels
+                .iter()
+                .enumerate()
+                .map(|(i, px)| (x_at(i), *px as f64))
+                .collect::<Vec<_>>(),
+            color,
+        )
+    };
+    let series = chart.draw_series(line(spectrum.pixels, BLACK))?;
+    if let Some(overlay) = overlay {
+        series
+            .label(overlay.labels.0)
+            .legend(|(x, y)| PathElement::new([(x, y), (x + 20, y)], BLACK));
+        chart
+            .draw_series(line(overlay.pixels, BLUE))?
+            .label(overlay.labels.1)
+            .legend(|(x, y)| PathElement::new([(x, y), (x + 20, y)], BLUE));
+        chart
+            .configure_series_labels()
+            .background_style(WHITE)
+            .border_style(BLACK)
+            .draw()?;
+    }
+
+    for peak in find_peaks(spectrum.pixels, peaks) {
+        let (x, y) = (x_at(peak), spectrum.pixels[peak] as f64);
+        // Labels near the right edge go to the left of a peak, so they aren't cut off
+        let dx = if peak * 10 > spectrum.pixels.len() * 9 {
+            -70
+        } else {
+            6
+        };
+        let label = match spectrum.axis() {
+            Axis::Pixel => format!("#{peak}"),
+            axis => format!("{x:.1} {}", axis.unit()),
+        };
+        chart.draw_series([EmptyElement::at((x, y))
+            + Circle::new((0, 0), 4, RED.filled())
+            + Text::new(label, (dx, -16), ("sans-serif", 16))])?;
+    }
+    root.present()?;
+    Ok(())
+}
+
+/// Renders histogram as bars, PNG or SVG depending on extension. Empty bins at either end are
+/// left out
+pub fn write_histogram(path: &Path, histogram: &Histogram, title: &str) -> Result<()> {
+    tracing::debug!("Plotting histogram to {path:?}");
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => draw_histogram(
+            &BitMapBackend::new(path, PLOT_SIZE).into_drawing_area(),
+            histogram,
+            title,
+        ),
+        Some("svg") => draw_histogram(
+            &SVGBackend::new(path, PLOT_SIZE).into_drawing_area(),
+            histogram,
+            title,
+        ),
+        _ => Err(eyre!("Plot {path:?} should have .png or .svg extension")),
+    }
+}
+
+fn draw_histogram<DB: DrawingBackend>(
+    root: &DrawingArea<DB, Shift>,
+    histogram: &Histogram,
+    title: &str,
+) -> Result<()>
+where
+    DB::ErrorType: 'static,
+{
+    root.fill(&WHITE)?;
+    let bins = occupied(histogram);
+    let from = histogram.range(bins.start).start;
+    let to = histogram.range(bins.end.max(bins.start + 1) - 1).end;
+    let top = histogram.bins.iter().copied().max().unwrap_or(0).max(1) as f64 * 1.1;
+
+    let mut chart = ChartBuilder::on(root)
+        .caption(title, ("sans-serif", (5).percent()))
+        .margin(10)
+        .set_label_area_size(LabelAreaPosition::Left, (8).percent())
+        .set_label_area_size(LabelAreaPosition::Bottom, (6).percent())
+        .build_cartesian_2d(from as f64..to as f64, 0.0..top)?;
+    chart
+        .configure_mesh()
+        .x_desc("Counts")
+        .y_desc("Pixels")
+        .draw()?;
+    chart.draw_series(bins.map(|bin| {
+        let range = histogram.range(bin);
+        Rectangle::new(
+            [
+                (range.start as f64, 0.0),
+                (range.end as f64, histogram.bins[bin] as f64),
+            ],
+            BLUE.filled(),
+        )
+    }))?;
+    root.present()?;
+    Ok(())
+}
+
+/// Row of a waterfall, made of one or more merged frames
+struct Row {
+    /// Seconds since the first frame
+    time: f64,
+    columns: Vec<u16>,
+    frames: usize,
+}
+
+impl Row {
+    fn merge(&mut self, columns: &[u16], frames: usize) {
+        for (a, b) in self.columns.iter_mut().zip(columns) {
+            *a = (*a).max(*b);
+        }
+        self.frames += frames;
+    }
+}
+
+/// Time by pixel intensity heatmap of a capture, built up one frame at a time
+pub struct Waterfall {
+    start: Option<Instant>,
+    rows: Vec<Row>,
+    /// How many frames go into a single row
+    frames_per_row: usize,
+}
+
+impl Waterfall {
+    pub fn new() -> Self {
+        Waterfall {
+            start: None,
+            rows: Vec::new(),
+            frames_per_row: 1,
+        }
+    }
+
+    pub fn push(&mut self, frame: &[u16]) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let width = frame.len().min(WATERFALL_COLUMNS);
+        let columns: Vec<u16> = (0..width)
+            .map(|col| {
+                let from = col * frame.len() / width;
+                let to = (col + 1) * frame.len() / width;
+                frame[from..to].iter().copied().max().unwrap_or(0)
+            })
+            .collect();
+        match self.rows.last_mut() {
+            Some(row) if row.frames < self.frames_per_row => row.merge(&columns, 1),
+            _ => self.rows.push(Row {
+                time: start.elapsed().as_secs_f64(),
+                columns,
+                frames: 1,
+            }),
+        }
+        if self.rows.len() > WATERFALL_ROWS {
+            let mut merged: Vec<Row> = Vec::with_capacity(self.rows.len() / 2 + 1);
+            for row in self.rows.drain(..) {
+                match merged.last_mut() {
+                    Some(last) if last.frames < self.frames_per_row * 2 => {
+                        last.merge(&row.columns, row.frames)
+                    }
+                    _ => merged.push(row),
+                }
+            }
+            self.rows = merged;
+            self.frames_per_row *= 2;
+        }
+    }
+
+    /// Renders heatmap with time going up and pixels going right
+    pub fn write(&self, path: &Path, pixels: usize) -> Result<()> {
+        tracing::debug!(
+            "Rendering waterfall of {} rows to {path:?}",
+            self.rows.len()
+        );
+        let root = BitMapBackend::new(path, PLOT_SIZE).into_drawing_area();
+        root.fill(&WHITE)?;
+        let Some(last) = self.rows.last() else {
+            return Err(eyre!("No frames were captured, nothing to render"));
+        };
+        // Last row stands for a time slot as long as the ones before it
+        let end = match &self.rows[..] {
+            [.., prev, last] => last.time + (last.time - prev.time),
+            _ => 1.0,
+        };
+        let (min, max) = self
+            .rows
+            .iter()
+            .flat_map(|row| &row.columns)
+            .fold((u16::MAX, 0), |(min, max), &v| (min.min(v), max.max(v)));
+
+        let mut chart = ChartBuilder::on(&root)
+            .caption(
+                format!("Intensity over time, {min} to {max}"),
+                ("sans-serif", (5).percent()),
+            )
+            .margin(10)
+            .set_label_area_size(LabelAreaPosition::Left, (8).percent())
+            .set_label_area_size(LabelAreaPosition::Bottom, (6).percent())
+            .build_cartesian_2d(0..pixels, 0.0..end)?;
+        chart
+            .configure_mesh()
+            .disable_mesh()
+            .x_desc("Pixel #")
+            .y_desc("Time, s")
+            .draw()?;
+
+        let area = chart.plotting_area().strip_coord_spec();
+        let (width, height) = area.dim_in_pixel();
+        let columns = last.columns.len();
+        for y in 0..height {
+            let time = (height - 1 - y) as f64 / height as f64 * end;
+            let idx = self.rows.partition_point(|row| row.time <= time);
+            let row = &self.rows[idx.saturating_sub(1)];
+            for x in 0..width {
+                let value = row.columns[x as usize * columns / width as usize];
+                let color = ViridisRGB::get_color_normalized(
+                    value as f32,
+                    min as f32,
+                    max.max(min + 1) as f32,
+                );
+                area.draw_pixel((x as i32, y as i32), &color)?;
+            }
+        }
+        root.present()?;
+        Ok(())
+    }
+}
+
+/// `plot` subcommand, renders a frame from an existing capture file
+pub fn plot_file(conf: &PlotFileConf) -> Result<()> {
+    let format = match conf.from {
+        Some(format) => format,
+        None => InputFormat::from_path(&conf.input)
+            .ok_or_else(|| eyre!("Can't tell format of {:?}, pass --from", conf.input))?,
+    };
+    let frames = input::read_capture(&conf.input, format)?;
+    let frame = conf
+        .frame
+        .checked_sub(1)
+        .and_then(|idx| frames.get(idx))
+        .ok_or_else(|| {
+            eyre!(
+                "Frame #{} requested, but {:?} has {} frames",
+                conf.frame,
+                conf.input,
+                frames.len()
+            )
+        })?;
+    let name = conf.input.file_name().unwrap_or_default().to_string_lossy();
+    let spectrum = Spectrum {
+        pixels: frame,
+        wavelength: &conf.wavelength_coeffs,
+        laser: conf.laser,
+    };
+    let title = format!("{name}, frame #{}", conf.frame);
+    write_plot(&conf.output, &spectrum, &title, None, conf.peaks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waterfall_rows() {
+        let mut waterfall = Waterfall::new();
+        let mut frame = vec![0; 2 * WATERFALL_COLUMNS];
+        frame[1] = 5;
+        for _ in 0..=WATERFALL_ROWS {
+            waterfall.push(&frame);
+        }
+        // Neighbouring pixels are merged into a single column, as are frames into rows
+        assert_eq!(waterfall.rows[0].columns.len(), WATERFALL_COLUMNS);
+        assert_eq!(waterfall.rows[0].columns[0], 5);
+        assert_eq!(waterfall.frames_per_row, 2);
+        assert_eq!(waterfall.rows.len(), WATERFALL_ROWS / 2 + 1);
+        assert_eq!(waterfall.rows[0].frames, 2);
+        waterfall.push(&frame);
+        assert_eq!(waterfall.rows.len(), WATERFALL_ROWS / 2 + 1);
+    }
+
+    #[test]
+    fn preview_labels() {
+        let mut pixels = vec![10; 40];
+        pixels[30] = 80;
+        let spectrum = Spectrum {
+            pixels: &pixels,
+            wavelength: &[],
+            laser: None,
+        };
+        let lines = preview(&spectrum, 20);
+        assert_eq!(lines.len(), PREVIEW_LINES + 2);
+        assert_eq!(lines[PREVIEW_LINES], format!("0 px{}39 px", " ".repeat(11)));
+        assert_eq!(lines[PREVIEW_LINES + 1], "Peak 80 at pixel 30");
+
+        let calibrated = Spectrum {
+            pixels: &pixels,
+            wavelength: &[500.0, 0.5],
+            laser: None,
+        };
+        let lines = preview(&calibrated, 20);
+        assert_eq!(lines[PREVIEW_LINES], "500.0 nm    519.5 nm");
+        assert_eq!(lines[PREVIEW_LINES + 1], "Peak 80 at pixel 30 (515.0 nm)");
+    }
+}
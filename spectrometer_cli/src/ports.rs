@@ -0,0 +1,246 @@
+use crate::{serial, session::DeviceInfo};
+use ccd_lcamv06::BaudRate;
+use serde::Serialize;
+use serialport::{SerialPortInfo, SerialPortType};
+use simple_eyre::{eyre::eyre, Result};
+use std::{fmt, time::Duration};
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UsbInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+impl fmt::Display for UsbInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "USB {:04x}:{:04x}", self.vid, self.pid)?;
+        let description: Vec<_> = [&self.manufacturer, &self.product]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        if !description.is_empty() {
+            write!(f, " {}", description.join(" "))?;
+        }
+        if let Some(serial_number) = &self.serial_number {
+            write!(f, ", serial number {serial_number}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of querying a port for CCD version info
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ProbeResult {
+    Detected { device: DeviceInfo },
+    NotDetected { error: String },
+}
+
+/// Description of a single serial port
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PortListing {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub usb: Option<UsbInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<ProbeResult>,
+}
+
+impl From<SerialPortInfo> for PortListing {
+    fn from(info: SerialPortInfo) -> Self {
+        let (kind, usb) = match info.port_type {
+            SerialPortType::UsbPort(usb) => (
+                "usb",
+                Some(UsbInfo {
+                    vid: usb.vid,
+                    pid: usb.pid,
+                    serial_number: usb.serial_number,
+                    manufacturer: usb.manufacturer,
+                    product: usb.product,
+                }),
+            ),
+            SerialPortType::PciPort => ("pci", None),
+            SerialPortType::BluetoothPort => ("bluetooth", None),
+            SerialPortType::Unknown => ("unknown", None),
+        };
+        PortListing {
+            path: info.port_name,
+            kind,
+            usb,
+            probe: None,
+        }
+    }
+}
+
+impl PortListing {
+    pub fn probe(&mut self, baud: BaudRate, timeout: Duration) {
+        self.probe = Some(match serial::probe(&self.path, baud, timeout) {
+            Ok(details) => ProbeResult::Detected {
+                device: (&details).into(),
+            },
+            Err(e) => {
+                tracing::debug!("Probing {} failed: {e}", self.path);
+                ProbeResult::NotDetected {
+                    error: e.to_string(),
+                }
+            }
+        });
+    }
+}
+
+/// Splits changes between two listings into newly added and removed ports
+pub fn diff(old: &[PortListing], new: &[PortListing]) -> (Vec<PortListing>, Vec<PortListing>) {
+    let missing_from = |listing: &[PortListing], p: &PortListing| {
+        !listing.iter().any(|other| other.path == p.path)
+    };
+    let added = new
+        .iter()
+        .filter(|p| missing_from(old, p))
+        .cloned()
+        .collect();
+    let removed = old
+        .iter()
+        .filter(|p| missing_from(new, p))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+/// Lists serial ports available in the system
+pub fn list_ports() -> Result<Vec<PortListing>> {
+    Ok(serialport::available_ports()?
+        .into_iter()
+        .map(PortListing::from)
+        .collect())
+}
+
+/// Finds port of USB serial adapter with given serial number, which stays the same however OS
+/// names the port, e.g. when COM numbers move around on Windows
+pub fn find_by_serial_number(serial_number: &str) -> Result<String> {
+    select_by_serial_number(&list_ports()?, serial_number)
+}
+
+fn select_by_serial_number(ports: &[PortListing], serial_number: &str) -> Result<String> {
+    let matching: Vec<_> = ports
+        .iter()
+        .filter(|p| {
+            p.usb.as_ref().and_then(|usb| usb.serial_number.as_deref()) == Some(serial_number)
+        })
+        .map(|p| p.path.as_str())
+        .collect();
+    // macOS lists every device twice, as callout /dev/cu.* and dial-in /dev/tty.*
+    let callout: Vec<_> = matching
+        .iter()
+        .copied()
+        .filter(|path| {
+            path.strip_prefix("/dev/tty.")
+                .is_none_or(|name| !matching.contains(&&*format!("/dev/cu.{name}")))
+        })
+        .collect();
+    match callout[..] {
+        [path] => Ok(path.to_string()),
+        [] => Err(eyre!(
+            "No USB serial port with serial number {serial_number:?} found"
+        )),
+        _ => Err(eyre!(
+            "Several ports have serial number {serial_number:?}: {}",
+            callout.join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::UsbPortInfo;
+
+    #[test]
+    fn usb_port_to_json() {
+        let listing = PortListing::from(SerialPortInfo {
+            port_name: "/dev/ttyUSB0".to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x1a86,
+                pid: 0x7523,
+                serial_number: None,
+                manufacturer: Some("QinHeng".to_string()),
+                product: None,
+            }),
+        });
+        let json = serde_json::to_value(&listing).unwrap();
+        assert_eq!(json["type"], "usb");
+        assert_eq!(json["usb"]["vid"], 0x1a86);
+        assert_eq!(json["usb"]["manufacturer"], "QinHeng");
+        assert!(json.get("probe").is_none());
+    }
+
+    #[test]
+    fn select_port_by_serial_number() {
+        let port = |path: &str, serial_number: Option<&str>| PortListing {
+            path: path.to_string(),
+            kind: "usb",
+            usb: Some(UsbInfo {
+                vid: 0x1a86,
+                pid: 0x7523,
+                serial_number: serial_number.map(str::to_string),
+                manufacturer: None,
+                product: None,
+            }),
+            probe: None,
+        };
+        let ports = [
+            port("COM3", None),
+            port("COM7", Some("A1")),
+            port("COM9", Some("B2")),
+        ];
+        assert_eq!(select_by_serial_number(&ports, "A1").unwrap(), "COM7");
+        assert!(select_by_serial_number(&ports, "C3").is_err());
+        let mac = [
+            port("/dev/tty.usbserial-A1", Some("A1")),
+            port("/dev/cu.usbserial-A1", Some("A1")),
+        ];
+        assert_eq!(
+            select_by_serial_number(&mac, "A1").unwrap(),
+            "/dev/cu.usbserial-A1"
+        );
+    }
+
+    #[test]
+    fn diff_listings() {
+        let port = |path: &str| PortListing {
+            path: path.to_string(),
+            kind: "unknown",
+            usb: None,
+            probe: None,
+        };
+        let (added, removed) = diff(
+            &[port("/dev/ttyS0"), port("/dev/ttyUSB0")],
+            &[port("/dev/ttyS0"), port("/dev/ttyUSB1")],
+        );
+        assert_eq!(added, vec![port("/dev/ttyUSB1")]);
+        assert_eq!(removed, vec![port("/dev/ttyUSB0")]);
+    }
+
+    #[test]
+    fn format_usb_info() {
+        let mut usb = UsbInfo {
+            vid: 0x1a86,
+            pid: 0x7523,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        };
+        assert_eq!(usb.to_string(), "USB 1a86:7523");
+        usb.manufacturer = Some("QinHeng".to_string());
+        usb.product = Some("CH340".to_string());
+        usb.serial_number = Some("A1".to_string());
+        assert_eq!(
+            usb.to_string(),
+            "USB 1a86:7523 QinHeng CH340, serial number A1"
+        );
+    }
+}
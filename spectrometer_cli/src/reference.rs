@@ -0,0 +1,126 @@
+use crate::{
+    cli::CaptureReferenceConf,
+    compress::Compression,
+    csv::CsvDialect,
+    input::{self, InputFormat},
+    output::{Header, Output, OutputFormat},
+};
+use ccd_lcamv06::{Frame, FRAME_PIXEL_COUNT};
+use clap::ArgEnum;
+use simple_eyre::{eyre::eyre, Result};
+use std::path::{Path, PathBuf};
+
+/// What a reference frame was captured against
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// No light reaching the sensor, subtracted from every frame
+    Dark,
+    /// Uniform illumination, frames are normalized against it
+    White,
+}
+
+/// Reference frame file passed as `KIND=PATH`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub kind: ReferenceKind,
+    pub path: PathBuf,
+}
+
+pub fn parse_reference(s: &str) -> Result<Reference, String> {
+    let (kind, path) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{s:?} should look like dark=PATH or white=PATH"))?;
+    Ok(Reference {
+        kind: ReferenceKind::from_str(kind, true)?,
+        path: PathBuf::from(path),
+    })
+}
+
+/// Reference files are written in one of capture formats, picked by extension
+fn format_of(path: &Path) -> Result<InputFormat> {
+    InputFormat::from_path(path).ok_or_else(|| {
+        eyre!("Can't tell reference format from {path:?}, use .bin, .raw, .csv or .npy extension")
+    })
+}
+
+impl Reference {
+    pub fn load(&self) -> Result<Vec<u16>> {
+        let mut frames = input::read_capture(&self.path, format_of(&self.path)?)?;
+        match frames.len() {
+            1 => Ok(frames.remove(0)),
+            n => Err(eyre!(
+                "Expected a single frame in {:?}, found {n}",
+                self.path
+            )),
+        }
+    }
+}
+
+/// Pixel by pixel mean of frames, which evens out noise in a reference
+pub fn average(frames: &[Frame]) -> Frame {
+    let mut sums = [0u64; FRAME_PIXEL_COUNT];
+    for frame in frames {
+        for (sum, px) in sums.iter_mut().zip(frame) {
+            *sum += *px as u64;
+        }
+    }
+    let count = frames.len().max(1) as u64;
+    sums.map(|sum| ((sum + count / 2) / count) as u16)
+}
+
+pub fn capture(conf: &CaptureReferenceConf) -> Result<()> {
+    let format = match format_of(&conf.output)? {
+        InputFormat::Csv => OutputFormat::Csv,
+        InputFormat::Raw => OutputFormat::Raw,
+        InputFormat::Npy => OutputFormat::Npy,
+    };
+    if conf.count == 0 {
+        return Err(eyre!("At least one frame is needed for a reference"));
+    }
+    let mut ccd = conf.serial.open_ccd()?;
+    if let Some(t) = conf.exposure_time {
+        ccd.set_exp_time(t)?;
+    }
+    tracing::info!(
+        "Capturing {:?} reference from {} frames",
+        conf.kind,
+        conf.count
+    );
+    let mut frames = Vec::with_capacity(conf.count);
+    ccd.extend_with_frames(&mut frames, conf.count)?;
+    let output = Output {
+        output: conf.output.clone(),
+        format,
+        output_dir: None,
+        compress: Compression::None,
+        csv: CsvDialect::default(),
+    };
+    output.write_frame(&average(&frames), &Header::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reference_arg() {
+        assert_eq!(
+            parse_reference("white=ref=1.bin").unwrap(),
+            Reference {
+                kind: ReferenceKind::White,
+                path: PathBuf::from("ref=1.bin"),
+            }
+        );
+        assert!(parse_reference("dark.bin").is_err());
+        assert!(parse_reference("grey=ref.bin").is_err());
+    }
+
+    #[test]
+    fn average_frames() {
+        let mut frames = vec![[1; FRAME_PIXEL_COUNT], [2; FRAME_PIXEL_COUNT]];
+        frames[1][0] = 5;
+        let avg = average(&frames);
+        assert_eq!(avg[0], 3);
+        assert_eq!(avg[1], 2);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::{
+    cli::{RemoteCommands, RemoteConf, RemoteReadCommands, RemoteSingleReadingConf},
+    output::Header,
+};
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use serde::de::DeserializeOwned;
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+/// Server functions of `spectrometer_sbc`, mounted under `/api`
+const LIST_SERIAL_PORTS: &str = "list_serial_ports";
+const GET_SINGLE_READING: &str = "get_single_reading";
+
+/// Plain HTTP address of a `spectrometer_sbc` server, split into parts needed for a request
+#[derive(Debug, PartialEq, Eq)]
+struct ServerUrl {
+    authority: String,
+    base_path: String,
+}
+
+fn parse_url(url: &str) -> Result<ServerUrl> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| eyre!("Server URL {url:?} should start with http://"))?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if authority.is_empty() {
+        return Err(eyre!("Server URL {url:?} has no host"));
+    }
+    let path = path.trim_end_matches('/');
+    Ok(ServerUrl {
+        authority: authority.to_string(),
+        base_path: if path.is_empty() {
+            String::new()
+        } else {
+            format!("/{path}")
+        },
+    })
+}
+
+/// Percent encodes value for `application/x-www-form-urlencoded` body
+fn form_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'*' => {
+                out.push(b as char)
+            }
+            b' ' => out.push('+'),
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+/// Reassembles body sent with `Transfer-Encoding: chunked`
+fn read_chunked(reader: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let size = line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| eyre!("Invalid chunk size {line:?} in server response"))?;
+        if size == 0 {
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        // Every chunk is followed by CRLF
+        reader.read_line(&mut line)?;
+    }
+}
+
+/// Sends a POST request and waits for the whole response, returning its status code and body
+fn post(
+    url: &ServerUrl,
+    timeout: Duration,
+    path: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<(u16, Vec<u8>)> {
+    let addr = if url.authority.contains(':') {
+        url.authority.clone()
+    } else {
+        format!("{}:80", url.authority)
+    };
+    let mut stream = TcpStream::connect(&addr)
+        .map_err(|e| eyre!("Could not connect to server {}: {e}", url.authority))?;
+    stream.set_read_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\n\
+         Content-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        url.authority,
+        body.len()
+    )?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let code = status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| eyre!("Invalid response from server: {status:?}"))?;
+    let mut chunked = false;
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if key.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if key.eq_ignore_ascii_case("content-length") {
+            length = value.parse::<usize>().ok();
+        }
+    }
+    let body = match (chunked, length) {
+        (true, _) => read_chunked(&mut reader)?,
+        (false, Some(length)) => {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            body
+        }
+        (false, None) => {
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body)?;
+            body
+        }
+    };
+    Ok((code, body))
+}
+
+/// Calls a server function with form encoded arguments, returning its JSON encoded result
+fn call<T: DeserializeOwned>(
+    url: &ServerUrl,
+    timeout: Duration,
+    name: &str,
+    args: &[(&str, &str)],
+) -> Result<T> {
+    let body = args
+        .iter()
+        .map(|(key, value)| format!("{key}={}", form_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let path = format!("{}/api/{name}", url.base_path);
+    tracing::debug!("Calling {path} on {} with {body:?}", url.authority);
+    let (code, body) = post(
+        url,
+        timeout,
+        &path,
+        "application/x-www-form-urlencoded",
+        &body,
+    )?;
+    if !(200..300).contains(&code) {
+        return Err(eyre!(
+            "Server returned {code} for {name}: {}",
+            String::from_utf8_lossy(&body).trim()
+        ));
+    }
+    serde_json::from_slice(&body).map_err(|e| eyre!("Could not parse result of {name}: {e}"))
+}
+
+/// Posts JSON to a plain HTTP webhook, failing unless it's accepted
+pub fn post_json(url: &str, timeout: Duration, json: &str) -> Result<()> {
+    let parsed = parse_url(url)?;
+    let path = if parsed.base_path.is_empty() {
+        "/"
+    } else {
+        &parsed.base_path
+    };
+    tracing::debug!("Posting {json} to {url}");
+    let (code, body) = post(&parsed, timeout, path, "application/json", json)?;
+    if !(200..300).contains(&code) {
+        return Err(eyre!(
+            "Webhook {url} returned {code}: {}",
+            String::from_utf8_lossy(&body).trim()
+        ));
+    }
+    Ok(())
+}
+
+pub fn run(conf: &RemoteConf) -> Result<()> {
+    let url = parse_url(&conf.url)?;
+    let timeout = Duration::from_millis(conf.timeout);
+    match &conf.command {
+        RemoteCommands::List => {
+            let ports: Vec<String> = call(&url, timeout, LIST_SERIAL_PORTS, &[])?;
+            for port in ports {
+                println!("{port}");
+            }
+            Ok(())
+        }
+        RemoteCommands::Read(read) => match &read.command {
+            RemoteReadCommands::Single(read) => read_single(&url, timeout, read),
+        },
+    }
+}
+
+fn read_single(url: &ServerUrl, timeout: Duration, conf: &RemoteSingleReadingConf) -> Result<()> {
+    let pixels: Vec<f64> = call(url, timeout, GET_SINGLE_READING, &[("port", &conf.serial)])?;
+    if pixels.len() != FRAME_PIXEL_COUNT {
+        return Err(eyre!(
+            "Server returned a frame of {} pixels, expected {FRAME_PIXEL_COUNT}",
+            pixels.len()
+        ));
+    }
+    let mut frame = [0; FRAME_PIXEL_COUNT];
+    for (px, value) in frame.iter_mut().zip(pixels) {
+        *px = value as u16;
+    }
+    conf.output.write_frame(&frame, &Header::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_server_url() {
+        assert_eq!(
+            parse_url("http://raspberrypi.local:3000/").unwrap(),
+            ServerUrl {
+                authority: "raspberrypi.local:3000".to_string(),
+                base_path: String::new(),
+            }
+        );
+        assert_eq!(parse_url("http://10.0.0.2/lab/").unwrap().base_path, "/lab");
+        assert!(parse_url("https://10.0.0.2").is_err());
+        assert_eq!(form_encode("/dev/tty USB0"), "%2Fdev%2Ftty+USB0");
+    }
+
+    #[test]
+    fn chunked_body() {
+        let mut response = "4\r\n[1,2\r\n2;ext=1\r\n,3\r\n1\r\n]\r\n0\r\n\r\n".as_bytes();
+        assert_eq!(read_chunked(&mut response).unwrap(), b"[1,2,3]");
+    }
+}
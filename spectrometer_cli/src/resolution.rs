@@ -0,0 +1,138 @@
+use crate::{
+    analysis::{self, wavelength_at},
+    cli::ResolutionConf,
+    input::{self, InputFormat},
+};
+use rayon::prelude::*;
+use simple_eyre::{eyre::eyre, Report, Result};
+use std::str::FromStr;
+
+/// Expected position of a spectral line, either as wavelength or as a pixel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Line {
+    Nanometers(f64),
+    Pixel(usize),
+}
+
+impl FromStr for Line {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(nm) = s.strip_suffix("nm") {
+            nm.trim()
+                .parse()
+                .map(Line::Nanometers)
+                .map_err(|_| eyre!("{nm:?} is not a wavelength"))
+        } else if let Some(px) = s.strip_suffix("px") {
+            px.trim()
+                .parse()
+                .map(Line::Pixel)
+                .map_err(|_| eyre!("{px:?} is not a pixel index"))
+        } else {
+            Err(eyre!(
+                "Line should be given as wavelength (585.25nm) or pixel (1230px)"
+            ))
+        }
+    }
+}
+
+/// Pixel closest to line, with wavelengths looked up through calibration
+fn line_pixel(line: Line, pixels: usize, coeffs: &[f64]) -> Result<usize> {
+    match line {
+        Line::Pixel(px) if px < pixels => Ok(px),
+        Line::Pixel(px) => Err(eyre!("Pixel {px} is outside of {pixels} pixel frame")),
+        Line::Nanometers(_) if coeffs.is_empty() => Err(eyre!(
+            "Line given in nm needs --wavelength-coeffs, otherwise give it in px"
+        )),
+        Line::Nanometers(nm) => {
+            let first = wavelength_at(coeffs, 0.0);
+            let last = wavelength_at(coeffs, pixels.saturating_sub(1) as f64);
+            if nm < first.min(last) || nm > first.max(last) {
+                return Err(eyre!(
+                    "{nm} nm is outside of {first:.2}..{last:.2} nm covered by sensor"
+                ));
+            }
+            Ok((0..pixels)
+                .min_by(|&a, &b| {
+                    let distance = |px: usize| (wavelength_at(coeffs, px as f64) - nm).abs();
+                    distance(a).total_cmp(&distance(b))
+                })
+                .unwrap_or(0))
+        }
+    }
+}
+
+/// `resolution` subcommand, measures FWHM of a single spectral line
+pub fn run(conf: &ResolutionConf) -> Result<()> {
+    let format = match conf.from {
+        Some(format) => format,
+        None => InputFormat::from_path(&conf.input)
+            .ok_or_else(|| eyre!("Can't tell format of {:?}, pass --from", conf.input))?,
+    };
+    let frames = input::read_capture(&conf.input, format)?;
+    let values: Vec<f64> = match conf.frame {
+        Some(frame) => frame
+            .checked_sub(1)
+            .and_then(|idx| frames.get(idx))
+            .ok_or_else(|| {
+                eyre!(
+                    "Frame #{frame} requested, but {:?} has {} frames",
+                    conf.input,
+                    frames.len()
+                )
+            })?
+            .iter()
+            .map(|&v| v as f64)
+            .collect(),
+        // Averaging all frames keeps noise from skewing the width
+        None => {
+            let count = frames.len().max(1) as f64;
+            let pixels = frames.first().map_or(0, Vec::len);
+            (0..pixels)
+                .into_par_iter()
+                .map(|px| frames.iter().map(|f| f[px] as f64).sum::<f64>() / count)
+                .collect()
+        }
+    };
+
+    let near = line_pixel(conf.line, values.len(), &conf.wavelength_coeffs)?;
+    let width = analysis::peak_width(&values, near)?;
+    println!("Peak top at pixel {}", width.top);
+    if conf.wavelength_coeffs.is_empty() {
+        println!("Center: {:.2} px", width.center());
+        println!("FWHM: {:.3} px", width.fwhm());
+    } else {
+        let nm = |px| wavelength_at(&conf.wavelength_coeffs, px);
+        let fwhm_nm = (nm(width.right) - nm(width.left)).abs();
+        println!(
+            "Center: {:.2} px ({:.3} nm)",
+            width.center(),
+            nm(width.center())
+        );
+        println!("FWHM: {:.3} px ({fwhm_nm:.4} nm)", width.fwhm());
+        println!("Resolving power: {:.0}", nm(width.center()) / fwhm_nm);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_line() {
+        assert_eq!(
+            "585.25nm".parse::<Line>().unwrap(),
+            Line::Nanometers(585.25)
+        );
+        assert_eq!(" 1230 px".parse::<Line>().unwrap(), Line::Pixel(1230));
+        assert!("585.25".parse::<Line>().is_err());
+        assert_eq!(
+            line_pixel(Line::Nanometers(585.25), 100, &[580.0, 0.5]).unwrap(),
+            10
+        );
+        assert!(line_pixel(Line::Nanometers(585.25), 100, &[]).is_err());
+        assert!(line_pixel(Line::Nanometers(700.0), 100, &[580.0, 0.5]).is_err());
+    }
+}
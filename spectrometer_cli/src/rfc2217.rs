@@ -0,0 +1,229 @@
+use crate::serial::{FlowControl, LineConf, Parity};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+// Telnet commands and options used by RFC 2217
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+
+/// Position within telnet command sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Data,
+    Iac,
+    Negotiation(u8),
+    Sub,
+    SubIac,
+}
+
+/// Telnet connection to a serial-to-Ethernet converter, with baud rate and line settings set
+/// through RFC 2217 COM port control. Telnet commands are stripped from received data and 0xFF
+/// bytes in sent data are escaped
+pub struct Rfc2217 {
+    stream: TcpStream,
+    state: State,
+}
+
+/// Doubles IAC bytes, so they aren't mistaken for start of a command
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        out.push(b);
+        if b == IAC {
+            out.push(IAC);
+        }
+    }
+    out
+}
+
+impl Rfc2217 {
+    pub fn connect(
+        addr: impl ToSocketAddrs,
+        baud: u32,
+        line: &LineConf,
+        read_timeout: Duration,
+    ) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_nodelay(true)?;
+        let mut msg = vec![
+            IAC,
+            WILL,
+            BINARY,
+            IAC,
+            DO,
+            BINARY,
+            IAC,
+            WILL,
+            COM_PORT_OPTION,
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            SET_BAUDRATE,
+        ];
+        msg.extend(escape(&baud.to_be_bytes()));
+        msg.extend([IAC, SE]);
+        let parity = match line.parity {
+            Parity::None => 1,
+            Parity::Odd => 2,
+            Parity::Even => 3,
+        };
+        let control = match line.flow_control {
+            FlowControl::None => 1,
+            FlowControl::Software => 2,
+            FlowControl::Hardware => 3,
+        };
+        for (command, value) in [
+            (SET_DATASIZE, line.data_bits),
+            (SET_PARITY, parity),
+            (SET_STOPSIZE, line.stop_bits),
+            (SET_CONTROL, control),
+        ] {
+            msg.extend([IAC, SB, COM_PORT_OPTION, command, value, IAC, SE]);
+        }
+        stream.write_all(&msg)?;
+        Ok(Rfc2217 {
+            stream,
+            state: State::Data,
+        })
+    }
+
+    /// Strips telnet commands from received data in place, returning amount of data bytes left
+    /// and collecting replies to option negotiation
+    fn filter(&mut self, buf: &mut [u8], replies: &mut Vec<u8>) -> usize {
+        let mut len = 0;
+        for i in 0..buf.len() {
+            let b = buf[i];
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    buf[len] = b;
+                    len += 1;
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    buf[len] = IAC;
+                    len += 1;
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Negotiation(b),
+                (State::Iac, SB) => State::Sub,
+                // Other commands have no arguments and nothing to do with serial data
+                (State::Iac, _) => State::Data,
+                (State::Negotiation(cmd), option) => {
+                    match (cmd, option) {
+                        (DO, BINARY | COM_PORT_OPTION) => {}
+                        (WILL, BINARY | SUPPRESS_GO_AHEAD) => {}
+                        (DO, _) => replies.extend([IAC, WONT, option]),
+                        (WILL, _) => replies.extend([IAC, DONT, option]),
+                        _ => {}
+                    }
+                    State::Data
+                }
+                // Subnegotiation replies, such as confirmed baud rate, aren't used
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+            };
+        }
+        len
+    }
+}
+
+impl Read for Rfc2217 {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.stream.read(buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut replies = Vec::new();
+            let len = self.filter(&mut buf[..n], &mut replies);
+            if !replies.is_empty() {
+                self.stream.write_all(&replies)?;
+            }
+            // Chunk that only had commands in it isn't an end of stream
+            if len > 0 {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl Write for Rfc2217 {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write_all(&escape(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn negotiate_and_filter() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let converter = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut negotiation = [0; 19 + 4 * 7];
+            conn.read_exact(&mut negotiation).unwrap();
+            // Option negotiation and baud rate confirmation mixed in with data
+            conn.write_all(&[
+                IAC, DO, 1, 0x81, IAC, IAC, IAC, SB, 44, 101, 0, 1, 194, 0, IAC, SE, 0x02,
+            ])
+            .unwrap();
+            let mut reply = [0; 3 + 3];
+            conn.read_exact(&mut reply).unwrap();
+            (negotiation, reply)
+        });
+
+        let line = LineConf {
+            parity: Parity::Even,
+            flow_control: FlowControl::Hardware,
+            ..Default::default()
+        };
+        let mut port = Rfc2217::connect(addr, 115200, &line, Duration::from_secs(1)).unwrap();
+        let mut buf = [0; 32];
+        let n = port.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &[0x81, 0xFF, 0x02]);
+        port.write_all(&[0xFF, 0x01]).unwrap();
+
+        let (negotiation, reply) = converter.join().unwrap();
+        assert!(negotiation[..19].ends_with(&[IAC, SB, 44, SET_BAUDRATE, 0, 1, 194, 0, IAC, SE]));
+        assert_eq!(
+            negotiation[19..],
+            [
+                [IAC, SB, 44, SET_DATASIZE, 8, IAC, SE],
+                [IAC, SB, 44, SET_PARITY, 3, IAC, SE],
+                [IAC, SB, 44, SET_STOPSIZE, 1, IAC, SE],
+                [IAC, SB, 44, SET_CONTROL, 3, IAC, SE],
+            ]
+            .concat()
+        );
+        assert_eq!(reply, [IAC, WONT, 1, 0xFF, 0xFF, 0x01]);
+    }
+}
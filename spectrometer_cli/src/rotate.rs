@@ -0,0 +1,230 @@
+use crate::manifest;
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+
+const DATE_PLACEHOLDER: &str = "{date}";
+const SEQ_PLACEHOLDER: &str = "{seq}";
+const DATE_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year][month][day]T[hour][minute][second]");
+/// Length of `{date}` once expanded
+const DATE_LEN: usize = "20230501T213005".len();
+
+/// When continuous output rolls over to a new file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Interval(Duration),
+    Frames(usize),
+}
+
+/// Splits a string like `10min` into a positive number and its unit
+fn split_unit(s: &str) -> Result<(u64, &str), String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    match n.parse() {
+        Ok(n) if n > 0 => Ok((n, unit.trim())),
+        _ => Err(format!("{s:?} doesn't start with a positive number")),
+    }
+}
+
+/// Parses durations like `500ms`, `30s`, `10min` or `1h`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (n, unit) = split_unit(s)?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" | "sec" => Ok(Duration::from_secs(n)),
+        "m" | "min" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 60 * 60)),
+        unit => Err(format!(
+            "Unknown unit {unit:?}, expected one of: ms, s, min, h"
+        )),
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match split_unit(s)? {
+            (n, "f" | "frames") => Ok(Rotation::Frames(n as usize)),
+            (_, unit) => parse_duration(s)
+                .map(Rotation::Interval)
+                .map_err(|_| format!("Unknown unit {unit:?}, expected one of: s, min, h, frames")),
+        }
+    }
+}
+
+impl Rotation {
+    /// Checks if segment started at `started` with `frames` written should be closed
+    pub fn due(&self, started: Instant, frames: usize) -> bool {
+        match self {
+            Rotation::Interval(interval) => started.elapsed() >= *interval,
+            Rotation::Frames(n) => frames >= *n,
+        }
+    }
+}
+
+/// Checks if path has placeholders that make every segment name unique
+pub fn is_template(path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    path.contains(DATE_PLACEHOLDER) || path.contains(SEQ_PLACEHOLDER)
+}
+
+/// Substitutes `{date}` with segment start time and `{seq}` with its number
+pub fn expand(template: &Path, seq: usize, start: OffsetDateTime) -> Result<PathBuf> {
+    let template = template
+        .to_str()
+        .ok_or_else(|| eyre!("Output path {template:?} is not valid UTF-8"))?;
+    Ok(template
+        .replace(DATE_PLACEHOLDER, &start.format(DATE_FORMAT)?)
+        .replace(SEQ_PLACEHOLDER, &format!("{seq:04}"))
+        .into())
+}
+
+/// Segment number of `path` if it's named after `template`. Templates without `{seq}` give 0
+fn segment_seq(template: &str, path: &str) -> Option<usize> {
+    let mut rest = path;
+    let mut seq = 0;
+    let mut template = template;
+    while !template.is_empty() {
+        if let Some(after) = template.strip_prefix(SEQ_PLACEHOLDER) {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            seq = rest[..digits].parse().ok()?;
+            rest = &rest[digits..];
+            template = after;
+        } else if let Some(after) = template.strip_prefix(DATE_PLACEHOLDER) {
+            let date = rest.get(..DATE_LEN)?;
+            if !date.chars().all(|c| c.is_ascii_digit() || c == 'T') {
+                return None;
+            }
+            rest = &rest[DATE_LEN..];
+            template = after;
+        } else {
+            let next = template
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| c == '{')
+                .map_or(template.len(), |(at, _)| at);
+            rest = rest.strip_prefix(&template[..next])?;
+            template = &template[next..];
+        }
+    }
+    rest.is_empty().then_some(seq)
+}
+
+/// Segments of an earlier capture into the same template, for carrying on after interruption
+#[derive(Debug, Default, PartialEq)]
+pub struct Resumed {
+    /// Number of the last segment, new ones are numbered after it
+    pub seq: usize,
+    /// Frames in segments whose manifest checks out
+    pub frames: usize,
+}
+
+/// Finds segments already written into `template`. Segments without a valid manifest, e.g. the
+/// one being written when power went out, are left in place and their frames aren't counted
+pub fn resume(template: &Path) -> Result<Resumed> {
+    let template_str = template
+        .to_str()
+        .ok_or_else(|| eyre!("Output path {template:?} is not valid UTF-8"))?;
+    let pattern = glob::Pattern::escape(template_str)
+        .replace(SEQ_PLACEHOLDER, "*")
+        .replace(DATE_PLACEHOLDER, "*");
+    let mut segments = Vec::new();
+    for path in glob::glob(&pattern)? {
+        let path = path?;
+        if manifest::is_manifest(&path) {
+            continue;
+        }
+        if let Some(seq) = path.to_str().and_then(|p| segment_seq(template_str, p)) {
+            segments.push((seq, path));
+        }
+    }
+    segments.sort();
+    let mut resumed = Resumed::default();
+    for (i, (seq, path)) in segments.iter().enumerate() {
+        // Without `{seq}` segments are only told apart by date, so they are counted instead
+        resumed.seq = if template_str.contains(SEQ_PLACEHOLDER) {
+            *seq
+        } else {
+            i + 1
+        };
+        match manifest::check(path) {
+            Ok(frames) => resumed.frames += frames,
+            Err(e) => tracing::warn!("Segment {path:?} is incomplete and won't be counted: {e}"),
+        }
+    }
+    Ok(resumed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn parse_rotation() {
+        assert_eq!(
+            "10min".parse(),
+            Ok(Rotation::Interval(Duration::from_secs(600)))
+        );
+        assert_eq!(
+            "2h".parse(),
+            Ok(Rotation::Interval(Duration::from_secs(7200)))
+        );
+        assert_eq!("500frames".parse(), Ok(Rotation::Frames(500)));
+        assert!("0s".parse::<Rotation>().is_err());
+        assert!("10 parsecs".parse::<Rotation>().is_err());
+        assert!("min".parse::<Rotation>().is_err());
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("5frames").is_err());
+    }
+
+    #[test]
+    fn expand_template() {
+        let start = datetime!(2023-05-01 21:30:05 UTC);
+        assert_eq!(
+            expand(Path::new("runs/run_{date}_{seq}.csv"), 3, start).unwrap(),
+            PathBuf::from("runs/run_20230501T213005_0003.csv")
+        );
+        assert!(is_template(Path::new("run_{seq}.csv")));
+        assert!(!is_template(Path::new("run.csv")));
+    }
+
+    #[test]
+    fn resume_segments() {
+        assert_eq!(segment_seq("run_{seq}.csv", "run_0012.csv"), Some(12));
+        assert_eq!(
+            segment_seq("run_{date}_{seq}.csv", "run_20230501T213005_0003.csv"),
+            Some(3)
+        );
+        assert_eq!(
+            segment_seq("run_{date}.csv", "run_20230501T213005.csv"),
+            Some(0)
+        );
+        assert_eq!(segment_seq("run_{seq}.csv", "run_0012.csv.zst"), None);
+        assert_eq!(segment_seq("run_{seq}.csv", "walk_0012.csv"), None);
+
+        let dir = std::env::temp_dir().join(format!("resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let frame = crate::output::frame_to_csv(&[1; ccd_lcamv06::FRAME_PIXEL_COUNT]);
+        for seq in 1..=2 {
+            let path = dir.join(format!("run_{seq:04}.csv"));
+            std::fs::write(&path, format!("{frame}\n{frame}\n")).unwrap();
+            manifest::write(&path, 2).unwrap();
+        }
+        // Interrupted before manifest was written
+        std::fs::write(dir.join("run_0003.csv"), format!("{frame}\n")).unwrap();
+        assert_eq!(
+            resume(&dir.join("run_{seq}.csv")).unwrap(),
+            Resumed { seq: 3, frames: 4 }
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
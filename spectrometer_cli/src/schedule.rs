@@ -0,0 +1,149 @@
+use std::str::FromStr;
+use time::{Duration, OffsetDateTime};
+
+/// Cron-like schedule with five fields: minute, hour, day of month, month and day of week.
+/// Each field is `*`, a number, a range `a-b`, any of these with a step `/n`, or a comma
+/// separated list of them. Day of week counts from Sunday as 0, 7 is Sunday as well
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // When both day fields are restricted, matching either one is enough, same as in cron
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Bitmask of values allowed by a single field
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("Invalid step in {part:?}")),
+            },
+            None => (part, 1),
+        };
+        let parse = |n: &str| match n.parse::<u32>() {
+            Ok(n) if (min..=max).contains(&n) => Ok(n),
+            _ => Err(format!("{n:?} is not a number between {min} and {max}")),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (parse(from)?, parse(to)?),
+            // A single value with a step runs until the end of range, as in cron
+            None if step > 1 => (parse(range)?, max),
+            None => (parse(range)?, parse(range)?),
+        };
+        if from > to {
+            return Err(format!("Range {range:?} is empty"));
+        }
+        for n in (from..=to).step_by(step as usize) {
+            mask |= 1 << n;
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "{s:?} should have 5 fields: minute hour day month weekday"
+            ));
+        };
+        let mut weekday_mask = parse_field(weekdays, 0, 7)?;
+        // Both 0 and 7 stand for Sunday
+        if weekday_mask & (1 << 7) != 0 {
+            weekday_mask |= 1;
+        }
+        Ok(Schedule {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_mask,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+fn allows(mask: u64, n: u8) -> bool {
+    mask & (1 << n) != 0
+}
+
+impl Schedule {
+    fn day_matches(&self, t: OffsetDateTime) -> bool {
+        if !allows(self.months, t.month() as u8) {
+            return false;
+        }
+        let day = allows(self.days, t.day());
+        let weekday = allows(self.weekdays, t.weekday().number_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// First time schedule fires strictly after `t`, or `None` if it never does, e.g. for
+    /// February 30th
+    pub fn next_after(&self, t: OffsetDateTime) -> Option<OffsetDateTime> {
+        let mut t = t.replace_second(0).ok()?.replace_nanosecond(0).ok()? + Duration::minutes(1);
+        // Every combination of day and weekday repeats within this many years
+        let limit = t + Duration::days(366 * 28);
+        while t < limit {
+            if !self.day_matches(t) {
+                t = t.replace_time(time::Time::MIDNIGHT) + Duration::days(1);
+            } else if !allows(self.hours, t.hour()) {
+                t = t.replace_minute(0).ok()? + Duration::hours(1);
+            } else if !allows(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn parse_schedule() {
+        assert!("*/15 * * * *".parse::<Schedule>().is_ok());
+        assert!("0 9-17 * * 1-5".parse::<Schedule>().is_ok());
+        assert!("0 24 * * *".parse::<Schedule>().is_err());
+        assert!("0 * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn next_run() {
+        let t = datetime!(2023-05-01 12:07:30 UTC);
+        let every_15 = "*/15 * * * *".parse::<Schedule>().unwrap();
+        assert_eq!(
+            every_15.next_after(t),
+            Some(datetime!(2023-05-01 12:15 UTC))
+        );
+        let weekdays = "30 8 * * 1-5".parse::<Schedule>().unwrap();
+        // 2023-05-05 is a Friday
+        assert_eq!(
+            weekdays.next_after(datetime!(2023-05-05 9:00 UTC)),
+            Some(datetime!(2023-05-08 8:30 UTC))
+        );
+        let leap_day = "0 0 29 2 *".parse::<Schedule>().unwrap();
+        assert_eq!(leap_day.next_after(t), Some(datetime!(2024-02-29 0:00 UTC)));
+        let never = "0 0 30 2 *".parse::<Schedule>().unwrap();
+        assert_eq!(never.next_after(t), None);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::cli::ScpiConf;
+use ccd_lcamv06::{Spectrometer, FRAME_PIXEL_COUNT};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+};
+
+/// Errors beyond this many are dropped, the last one queued is replaced with an overflow error
+const ERROR_QUEUE_LEN: usize = 10;
+
+/// SCPI error, reported through `SYST:ERR?` with a standard code
+#[derive(Debug, PartialEq, Eq)]
+struct ScpiError {
+    code: i16,
+    message: String,
+}
+
+impl ScpiError {
+    fn new(code: i16, message: impl Into<String>) -> Self {
+        ScpiError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn undefined_header(header: &str) -> Self {
+        Self::new(-113, format!("Undefined header;{header}"))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Identify,
+    MeasureSpectrum,
+    SetExposure(u16),
+    QueryExposure,
+    QueryError,
+}
+
+/// Checks header word against a mnemonic, which can be given in full or in its short form made
+/// of the leading `short` characters
+fn mnemonic(word: &str, long: &str, short: usize) -> bool {
+    word.eq_ignore_ascii_case(long) || word.eq_ignore_ascii_case(&long[..short])
+}
+
+fn parse(command: &str) -> Result<Request, ScpiError> {
+    let command = command.trim();
+    let (header, arg) = match command.split_once(char::is_whitespace) {
+        Some((header, arg)) => (header, Some(arg.trim())),
+        None => (command, None),
+    };
+    let (header, query) = match header.strip_suffix('?') {
+        Some(header) => (header, true),
+        None => (header, false),
+    };
+    let words: Vec<_> = header.trim_start_matches(':').split(':').collect();
+    let request = match (&words[..], query) {
+        ([idn], true) if idn.eq_ignore_ascii_case("*IDN") => Request::Identify,
+        ([meas, spec], true) if mnemonic(meas, "MEASURE", 4) && mnemonic(spec, "SPECTRUM", 4) => {
+            Request::MeasureSpectrum
+        }
+        ([sens, exp], _) if mnemonic(sens, "SENSE", 4) && mnemonic(exp, "EXPOSURE", 3) => {
+            if query {
+                Request::QueryExposure
+            } else {
+                let arg = arg.ok_or_else(|| ScpiError::new(-109, "Missing parameter"))?;
+                let t = arg
+                    .parse()
+                    .map_err(|_| ScpiError::new(-222, "Data out of range"))?;
+                Request::SetExposure(t)
+            }
+        }
+        ([syst, err], true) if mnemonic(syst, "SYSTEM", 4) && mnemonic(err, "ERROR", 3) => {
+            Request::QueryError
+        }
+        _ => return Err(ScpiError::undefined_header(command)),
+    };
+    match (&request, arg) {
+        (Request::SetExposure(_), _) | (_, None) => Ok(request),
+        (_, Some(_)) => Err(ScpiError::new(-108, "Parameter not allowed")),
+    }
+}
+
+/// State of a single client connection
+struct Session<'a> {
+    ccd: &'a mut dyn Spectrometer,
+    errors: VecDeque<ScpiError>,
+}
+
+impl Session<'_> {
+    fn push_error(&mut self, error: ScpiError) {
+        tracing::debug!("SCPI error {}: {}", error.code, error.message);
+        if self.errors.len() >= ERROR_QUEUE_LEN {
+            self.errors.pop_back();
+            self.errors
+                .push_back(ScpiError::new(-350, "Queue overflow"));
+        } else {
+            self.errors.push_back(error);
+        }
+    }
+
+    /// Runs a single command, returning response line for queries
+    fn execute(&mut self, request: Request) -> Result<Option<String>, ScpiError> {
+        // Every failure of the device itself is reported as a device-specific error
+        let device_error = |e: ccd_lcamv06::error::Error| ScpiError::new(-300, e.to_string());
+        match request {
+            Request::Identify => {
+                let version = self.ccd.version().map_err(device_error)?;
+                Ok(Some(format!(
+                    "{},{},{},{}",
+                    version.hardware_version(),
+                    version.sensor_type(),
+                    version.serial_number(),
+                    version.firmware_version()
+                )))
+            }
+            Request::MeasureSpectrum => {
+                let mut frame = [0; FRAME_PIXEL_COUNT];
+                let pixels = self.ccd.pixel_count();
+                self.ccd
+                    .read_frame(&mut frame[..pixels])
+                    .map_err(device_error)?;
+                let values: Vec<_> = frame[..pixels].iter().map(u16::to_string).collect();
+                Ok(Some(values.join(",")))
+            }
+            Request::SetExposure(t) => {
+                self.ccd.set_exposure_time(t).map_err(device_error)?;
+                Ok(None)
+            }
+            Request::QueryExposure => {
+                let t = self.ccd.exposure_time().map_err(device_error)?;
+                Ok(Some(t.to_string()))
+            }
+            Request::QueryError => Ok(Some(match self.errors.pop_front() {
+                Some(e) => format!("{},\"{}\"", e.code, e.message),
+                None => "0,\"No error\"".to_string(),
+            })),
+        }
+    }
+
+    /// Handles a line of commands separated by `;`, responses to queries are joined the same way
+    fn handle_line(&mut self, line: &str) -> Option<String> {
+        let mut responses = Vec::new();
+        for command in line.split(';').filter(|c| !c.trim().is_empty()) {
+            match parse(command).and_then(|request| self.execute(request)) {
+                Ok(Some(response)) => responses.push(response),
+                Ok(None) => {}
+                Err(e) => {
+                    self.push_error(e);
+                    // Rest of the line is discarded after an error, same as SCPI instruments do
+                    break;
+                }
+            }
+        }
+        (!responses.is_empty()).then(|| responses.join(";"))
+    }
+}
+
+fn serve_client(ccd: &mut dyn Spectrometer, stream: TcpStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut session = Session {
+        ccd,
+        errors: VecDeque::new(),
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        tracing::trace!("SCPI request: {line:?}");
+        if let Some(response) = session.handle_line(&line) {
+            writeln!(writer, "{response}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Serves clients one at a time, each of them gets exclusive use of the device while connected
+pub fn serve(conf: &ScpiConf) -> Result<()> {
+    let mut ccd = conf.serial.open_spectrometer()?;
+    let listener = TcpListener::bind(&conf.listen)
+        .map_err(|e| eyre!("Could not listen on {}: {e}", conf.listen))?;
+    tracing::info!("Serving SCPI on {}", conf.listen);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        tracing::info!("SCPI client {peer} connected");
+        match serve_client(ccd.as_mut(), stream) {
+            Ok(()) => tracing::info!("SCPI client {peer} disconnected"),
+            Err(e) => tracing::warn!("SCPI client {peer} dropped: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("*idn?"), Ok(Request::Identify));
+        assert_eq!(parse(":MEASure:SPECtrum?"), Ok(Request::MeasureSpectrum));
+        assert_eq!(parse("meas:spec?"), Ok(Request::MeasureSpectrum));
+        assert_eq!(parse("SENS:EXP 20"), Ok(Request::SetExposure(20)));
+        assert_eq!(parse("SENSE:EXPOSURE?"), Ok(Request::QueryExposure));
+        assert_eq!(parse("SYST:ERR?"), Ok(Request::QueryError));
+        assert_eq!(parse("MEAS:SPEC").unwrap_err().code, -113);
+        assert_eq!(parse("MEASU:SPEC?").unwrap_err().code, -113);
+        assert_eq!(parse("SENS:EXP").unwrap_err().code, -109);
+        assert_eq!(parse("SENS:EXP 70000").unwrap_err().code, -222);
+        assert_eq!(parse("*IDN? 1").unwrap_err().code, -108);
+    }
+}
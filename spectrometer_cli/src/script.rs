@@ -0,0 +1,148 @@
+use ccd_lcamv06::{Frame, QualityFlags};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST, INT};
+use simple_eyre::{eyre::eyre, Result};
+use std::path::Path;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Rhai script run on every captured frame. It sees these variables:
+///
+/// - `pixels`: array of counts, changes are written out as long as they stay within 0-65535
+/// - `flags`: map of quality flags by name, can be set or cleared
+/// - `frame`: number of the frame, starting from 1
+/// - `timestamp`: RFC 3339 time frame was taken at
+/// - `meta`: capture metadata as map of strings, same as in output header
+/// - `state`: map kept between frames, for running averages and the like
+/// - `drop`: set to true to leave frame out of output
+///
+/// ```rhai
+/// let dark = pixels[0];
+/// for i in 0..pixels.len() { pixels[i] = max(pixels[i] - dark, 0); }
+/// if pixels.max() < 1000 { drop = true; }
+/// ```
+pub struct FrameScript {
+    engine: Engine,
+    ast: AST,
+    meta: Map,
+    state: Dynamic,
+}
+
+impl FrameScript {
+    pub fn load(path: &Path, metadata: &[String]) -> Result<Self> {
+        let mut engine = Engine::new();
+        // Output can be stdout, so whatever script prints goes to log instead
+        engine.on_print(|s| tracing::info!("{s}"));
+        engine.on_debug(|s, _, pos| tracing::debug!("{pos:?}: {s}"));
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| eyre!("Could not load script {path:?}: {e}"))?;
+        let meta = metadata
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().into(), value.trim().into()))
+            .collect();
+        Ok(FrameScript {
+            engine,
+            ast,
+            meta,
+            state: Map::new().into(),
+        })
+    }
+
+    /// Runs script on frame, updating it along with flags in place. Returns false if script
+    /// dropped the frame
+    pub fn process(
+        &mut self,
+        frame: &mut Frame,
+        flags: &mut QualityFlags,
+        idx: usize,
+        timestamp: OffsetDateTime,
+    ) -> Result<bool> {
+        let pixels: Array = frame
+            .iter()
+            .map(|&px| Dynamic::from_int(px as INT))
+            .collect();
+        let flag_map: Map = [
+            ("saturated", flags.saturated),
+            ("underexposed", flags.underexposed),
+            ("crc_resynced", flags.crc_resynced),
+            ("averaged", flags.averaged),
+        ]
+        .into_iter()
+        .map(|(name, set)| (name.into(), set.into()))
+        .collect();
+        let mut scope = Scope::new();
+        scope
+            .push("pixels", pixels)
+            .push("flags", flag_map)
+            .push("frame", idx as INT)
+            .push("timestamp", timestamp.format(&Rfc3339)?)
+            .push_constant("meta", self.meta.clone())
+            .push("state", std::mem::take(&mut self.state))
+            .push("drop", false);
+        let res = self.engine.run_ast_with_scope(&mut scope, &self.ast);
+        // State survives a failed run, so that a single broken frame doesn't reset it
+        self.state = scope.get_value("state").unwrap_or_default();
+        res.map_err(|e| eyre!("Script failed on frame #{idx}: {e}"))?;
+
+        if scope.get_value::<bool>("drop").unwrap_or(false) {
+            return Ok(false);
+        }
+        let pixels: Array = scope
+            .get_value("pixels")
+            .ok_or_else(|| eyre!("Script replaced `pixels` with something else than array"))?;
+        if pixels.len() != frame.len() {
+            return Err(eyre!(
+                "Script left {} pixels in frame #{idx}, expected {}",
+                pixels.len(),
+                frame.len()
+            ));
+        }
+        for (px, value) in frame.iter_mut().zip(pixels) {
+            *px = value
+                .as_int()
+                .ok()
+                .and_then(|v| u16::try_from(v).ok())
+                .ok_or_else(|| eyre!("Script set pixel of frame #{idx} to {value}"))?;
+        }
+        if let Some(map) = scope.get_value::<Map>("flags") {
+            let flag = |name: &str| map.get(name).and_then(|v| v.as_bool().ok());
+            flags.saturated = flag("saturated").unwrap_or(flags.saturated);
+            flags.underexposed = flag("underexposed").unwrap_or(flags.underexposed);
+            flags.crc_resynced = flag("crc_resynced").unwrap_or(flags.crc_resynced);
+            flags.averaged = flag("averaged").unwrap_or(flags.averaged);
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ccd_lcamv06::FRAME_PIXEL_COUNT;
+
+    #[test]
+    fn modify_flag_and_drop() {
+        let path = std::env::temp_dir().join(format!("process-{}.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            state.seen = (state.seen ?? 0) + 1;
+            pixels[0] = parse_int(meta["exposure time"]) * state.seen;
+            flags.saturated = pixels[1] > 100;
+            drop = frame == 3;
+            "#,
+        )
+        .unwrap();
+        let mut script = FrameScript::load(&path, &["exposure time: 10".to_string()]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let now = OffsetDateTime::UNIX_EPOCH;
+        let mut flags = QualityFlags::default();
+        let mut frame = [200; FRAME_PIXEL_COUNT];
+        assert!(script.process(&mut frame, &mut flags, 1, now).unwrap());
+        assert_eq!(frame[0], 10);
+        assert!(flags.saturated);
+        assert!(script.process(&mut frame, &mut flags, 2, now).unwrap());
+        assert_eq!(frame[0], 20);
+        assert!(!script.process(&mut frame, &mut flags, 3, now).unwrap());
+    }
+}
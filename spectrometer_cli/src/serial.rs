@@ -1,28 +1,506 @@
-use ccd_lcamv06::{BaudRate, CCD, StdIoAdapter, IoAdapter};
-use clap::Args;
+use crate::{
+    analysis::parse_laser,
+    calibration::DeviceCalibration,
+    cli::parse_baud_rate,
+    lock::{DeviceLock, Locked},
+    ports,
+    reference::{parse_reference, Reference, ReferenceKind},
+    rfc2217::Rfc2217,
+    sniff::{RawDump, TeePort},
+};
+use ccd_lcamv06::{
+    transport::Transport, BaudRate, CCDBuilder, FramesIter, CCD, StdIoAdapter, IoAdapter,
+    QualityThresholds, RetryPolicy, Spectrometer, VersionDetails,
+};
+use clap::{ArgEnum, Args};
 use num_traits::ToPrimitive;
-use serialport::SerialPort;
 use simple_eyre::{eyre::eyre, Result};
-use std::time::Duration;
+use std::{
+    borrow::Cow,
+    net::TcpStream,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
 
 #[derive(Args)]
 pub struct SerialConf {
-    /// Name of serial port that should be used
-    #[clap(short, long, value_parser)]
+    /// Name of serial port that should be used, `usb://SERIAL_NUMBER` for USB adapter with that
+    /// serial number, or address of a serial-to-Ethernet converter as `tcp://host:port` for raw
+    /// TCP or `rfc2217://host:port` for telnet with baud rate control
+    #[clap(short, long, value_parser, env = "SPECTRO_SERIAL")]
     pub serial: String,
+
+    /// Spectrometer model connected to serial port
+    #[clap(long, value_enum, default_value_t, env = "SPECTRO_MODEL")]
+    pub model: Model,
+
+    /// Baud rate used for communication with serial port
+    #[clap(short, long, value_parser = parse_baud_rate, default_value_t, env = "SPECTRO_BAUD")]
+    pub baud: BaudRate,
+
+    /// How long to wait for each response from CCD, in milliseconds
+    #[clap(long, value_parser, default_value = "1000", env = "SPECTRO_TIMEOUT")]
+    pub timeout: u64,
+
+    /// How many times a failed exchange with CCD is retried
+    #[clap(long, value_parser, default_value = "2", env = "SPECTRO_RETRIES")]
+    pub retries: u32,
+
+    #[clap(flatten)]
+    pub auto_baud: AutoBaudConf,
+
+    #[clap(flatten)]
+    pub line: LineConf,
+
+    /// Copy all traffic to and from serial port into this file, which can be inspected later
+    /// with `decode` subcommand
+    #[clap(long, value_parser, value_hint = clap::ValueHint::FilePath)]
+    pub dump_raw: Option<PathBuf>,
+
+    /// Take an advisory UUCP style lock on serial port in /var/lock, so other invocations that also
+    /// use --lock fail instead of interleaving their commands with ours
+    #[clap(long, env = "SPECTRO_LOCK")]
+    pub lock: bool,
+
+    /// Raw dump and device lock shared by every connection opened with this configuration,
+    /// created on first use so reopening the port neither starts the dump over nor finds it
+    /// locked by ourselves
+    #[clap(skip)]
+    shared: OnceLock<PortShared>,
 }
 
-pub type SerialCCD = CCD<StdIoAdapter<Box<dyn SerialPort>>>;
+/// Supported spectrometer models, each one backed by its own driver
+#[derive(ArgEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Model {
+    #[default]
+    LcamV06,
+}
 
-impl SerialConf {
-    pub fn open_ccd(&self) -> Result<SerialCCD> {
-        let port = serialport::new(
-            &self.serial,
-            BaudRate::default().to_u32().unwrap(),
-        )
-        .timeout(Duration::from_millis(100))
+#[derive(Args)]
+pub struct AutoBaudConf {
+    /// Try every supported baud rate until CCD responds, starting with --baud
+    #[clap(long, env = "SPECTRO_AUTO_BAUD")]
+    pub auto_baud: bool,
+
+    /// Order in which baud rates are tried, overrides starting with --baud
+    #[clap(long, value_parser = parse_baud_rate, use_value_delimiter = true)]
+    pub auto_baud_order: Vec<BaudRate>,
+
+    /// How long to wait for CCD to respond at each baud rate, in milliseconds
+    #[clap(long, value_parser, default_value = "300")]
+    pub auto_baud_timeout: u64,
+
+    /// How many times each baud rate is tried before moving on to the next one
+    #[clap(long, value_parser, default_value = "1")]
+    pub auto_baud_attempts: u32,
+
+    /// Once CCD is found, switch UART to the fastest baud rate it still responds at. Original
+    /// rate is restored on exit
+    #[clap(long, env = "SPECTRO_NEGOTIATE_BAUD")]
+    pub negotiate_baud: bool,
+}
+
+/// Character framing and flow control of serial line. Defaults match CCD, but some USB adapters
+/// need them set explicitly. Raw TCP bridges are configured on the bridge itself
+#[derive(Args, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineConf {
+    /// Bits in each character
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(5..=8),
+        default_value = "8",
+        env = "SPECTRO_DATA_BITS"
+    )]
+    pub data_bits: u8,
+
+    /// Parity bit sent after each character
+    #[clap(long, value_enum, default_value_t, env = "SPECTRO_PARITY")]
+    pub parity: Parity,
+
+    /// Stop bits sent after each character
+    #[clap(
+        long,
+        value_parser = clap::value_parser!(u8).range(1..=2),
+        default_value = "1",
+        env = "SPECTRO_STOP_BITS"
+    )]
+    pub stop_bits: u8,
+
+    /// Flow control, hardware is RTS/CTS and software is XON/XOFF
+    #[clap(long, value_enum, default_value_t, env = "SPECTRO_FLOW_CONTROL")]
+    pub flow_control: FlowControl,
+}
+
+impl Default for LineConf {
+    fn default() -> Self {
+        LineConf {
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+#[derive(ArgEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Parity {
+    #[default]
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(ArgEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum FlowControl {
+    #[default]
+    None,
+    Software,
+    Hardware,
+}
+
+impl LineConf {
+    fn configure(&self, port: serialport::SerialPortBuilder) -> serialport::SerialPortBuilder {
+        let data_bits = match self.data_bits {
+            5 => serialport::DataBits::Five,
+            6 => serialport::DataBits::Six,
+            7 => serialport::DataBits::Seven,
+            _ => serialport::DataBits::Eight,
+        };
+        let stop_bits = match self.stop_bits {
+            2 => serialport::StopBits::Two,
+            _ => serialport::StopBits::One,
+        };
+        let parity = match self.parity {
+            Parity::None => serialport::Parity::None,
+            Parity::Odd => serialport::Parity::Odd,
+            Parity::Even => serialport::Parity::Even,
+        };
+        let flow_control = match self.flow_control {
+            FlowControl::None => serialport::FlowControl::None,
+            FlowControl::Software => serialport::FlowControl::Software,
+            FlowControl::Hardware => serialport::FlowControl::Hardware,
+        };
+        port.data_bits(data_bits)
+            .stop_bits(stop_bits)
+            .parity(parity)
+            .flow_control(flow_control)
+    }
+}
+
+#[derive(Args)]
+pub struct CaptureConf {
+    /// "Exposure time" set before capturing, current device setting is kept if omitted
+    #[clap(long, value_parser, env = "SPECTRO_EXPOSURE_TIME")]
+    pub exposure_time: Option<u16>,
+
+    /// Don't apply calibration stored for connected device
+    #[clap(long)]
+    pub no_calibration: bool,
+
+    /// Reference frame captured with `reference capture`, as dark=PATH or white=PATH. Takes
+    /// precedence over stored calibration
+    #[clap(long, value_parser = parse_reference)]
+    pub apply_reference: Vec<Reference>,
+
+    /// Apply relative intensity correction stored with `calibration intensity`
+    #[clap(long, conflicts_with = "no-calibration")]
+    pub relative_intensity: bool,
+
+    /// Excitation laser wavelength, e.g. 532nm, adds Raman shift to outputs and plots against it.
+    /// Needs wavelength calibration
+    #[clap(long, value_parser = parse_laser, env = "SPECTRO_LASER")]
+    pub laser: Option<f64>,
+}
+
+#[derive(Args)]
+pub struct StreamConf {
+    /// Keep only every Nth frame received during continuous reading
+    #[clap(long, value_parser, default_value = "1")]
+    pub every: NonZeroUsize,
+
+    /// Upper limit of frames kept per second, frames arriving faster are skipped
+    #[clap(long, value_parser = parse_fps)]
+    pub max_fps: Option<f64>,
+}
+
+#[derive(Args)]
+pub struct QualityConf {
+    /// Frames with a raw pixel at or above this are flagged as saturated
+    #[clap(long, value_parser, default_value = "65535")]
+    pub saturation: u16,
+
+    /// Frames with no raw pixel reaching this are flagged as underexposed
+    #[clap(long, value_parser, default_value = "2048")]
+    pub underexposure: u16,
+}
+
+fn parse_fps(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fps) if fps > 0.0 && fps.is_finite() => Ok(fps),
+        _ => Err(format!("{s:?} is not a positive number")),
+    }
+}
+
+type SerialAdapter = StdIoAdapter<TeePort<Box<dyn Transport>>>;
+pub type SerialCCD = CCD<SerialAdapter>;
+
+/// Timeout for a single read from serial port, CCD keeps retrying reads until its own timeout
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+const TCP_SCHEME: &str = "tcp://";
+const RFC2217_SCHEME: &str = "rfc2217://";
+const USB_SCHEME: &str = "usb://";
+
+/// Turns `usb://SERIAL_NUMBER` into name of port that adapter is currently enumerated as, other
+/// paths are kept as they are
+fn resolve_port(path: &str) -> Result<Cow<'_, str>> {
+    match path.strip_prefix(USB_SCHEME) {
+        Some(serial_number) => {
+            let port = ports::find_by_serial_number(serial_number)?;
+            tracing::debug!("USB serial number {serial_number} is at {port}");
+            Ok(port.into())
+        }
+        None => Ok(path.into()),
+    }
+}
+
+/// Opens either a local serial port, or a connection to a serial bridge. Baud rate of raw TCP
+/// bridge is configured on the bridge itself
+fn open_transport(path: &str, baud: BaudRate, line: &LineConf) -> Result<Box<dyn Transport>> {
+    if let Some(addr) = path.strip_prefix(RFC2217_SCHEME) {
+        let port = Rfc2217::connect(addr, baud.to_u32().unwrap(), line, READ_TIMEOUT)
+            .map_err(|e| eyre!("Could not connect to serial bridge at {addr}: {e}"))?;
+        return Ok(Box::new(port));
+    }
+    if let Some(addr) = path.strip_prefix(TCP_SCHEME) {
+        let stream = TcpStream::connect(addr)
+            .map_err(|e| eyre!("Could not connect to serial bridge at {addr}: {e}"))?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        // Commands are tiny, waiting to batch them only adds latency
+        stream.set_nodelay(true)?;
+        return Ok(Box::new(stream));
+    }
+    let port = line
+        .configure(serialport::new(path, baud.to_u32().unwrap()))
+        .timeout(READ_TIMEOUT)
         .open()
         .map_err(|_| eyre!("Could not open serial port"))?;
-        Ok(StdIoAdapter::new(port).open_ccd())
+    Ok(Box::new(port))
+}
+
+/// Raw dump and device lock, which outlive a single connection to the port
+#[derive(Default)]
+struct PortShared {
+    dump: Option<RawDump>,
+    lock: Option<Arc<DeviceLock>>,
+}
+
+impl PortShared {
+    /// Creates raw dump at `dump` and, when asked to, takes a lock on port at `path`
+    fn new(path: &str, dump: Option<&Path>, lock: bool) -> Result<Self> {
+        let lock = if lock {
+            DeviceLock::acquire(&resolve_port(path)?)?.map(Arc::new)
+        } else {
+            None
+        };
+        let dump = dump.map(RawDump::create).transpose()?;
+        Ok(PortShared { dump, lock })
+    }
+}
+
+fn open_port(
+    path: &str,
+    baud: BaudRate,
+    line: &LineConf,
+    shared: &PortShared,
+) -> Result<SerialAdapter> {
+    let port = open_transport(&resolve_port(path)?, baud, line)?;
+    let port = match &shared.lock {
+        Some(lock) => Box::new(Locked::new(port, lock.clone())),
+        None => port,
+    };
+    let port = match &shared.dump {
+        Some(dump) => TeePort::dump_to(port, dump.clone()),
+        None => TeePort::new(port),
+    };
+    Ok(StdIoAdapter::new(port))
+}
+
+impl SerialConf {
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retries + 1,
+            ..Default::default()
+        }
+    }
+
+    fn builder(&self) -> CCDBuilder {
+        let conf = &self.auto_baud;
+        CCD::builder()
+            .baud(self.baud)
+            .timeout(Some(Duration::from_millis(self.timeout)))
+            .retry_policy(self.retry_policy())
+            .auto_detect_baud(conf.auto_baud)
+            .baud_order(&conf.auto_baud_order)
+            .detect_timeout(Duration::from_millis(conf.auto_baud_timeout))
+            .detect_attempts(conf.auto_baud_attempts)
+            .negotiate_baud(conf.negotiate_baud)
+    }
+
+    /// Opens connected spectrometer through driver of selected model, for commands that work
+    /// the same way on all of them
+    pub fn open_spectrometer(&self) -> Result<Box<dyn Spectrometer>> {
+        match self.model {
+            Model::LcamV06 => Ok(Box::new(self.open_ccd()?)),
+        }
+    }
+
+    pub fn open_ccd(&self) -> Result<SerialCCD> {
+        let shared = self.shared()?;
+        let (ccd, _) = self
+            .builder()
+            .connect_with(|baud| open_port(&self.serial, baud, &self.line, shared))?;
+        Ok(ccd)
+    }
+
+    /// Opens port at `baud` instead of configured rate, without detecting or negotiating it
+    pub fn open_ccd_at(&self, baud: BaudRate) -> Result<SerialCCD> {
+        self.open_with(baud, &self.builder())
+    }
+
+    /// Opens port at configured baud rate without talking to CCD, only checking that it's there
+    pub fn check_port(&self) -> Result<()> {
+        open_transport(&resolve_port(&self.serial)?, self.baud, &self.line).map(drop)
+    }
+
+    /// Opens port at every candidate baud rate in turn, until CCD answers a version query
+    pub fn detect_baud(&self) -> Result<(SerialCCD, BaudRate)> {
+        let shared = self.shared()?;
+        self.builder()
+            .auto_detect_baud(true)
+            .negotiate_baud(false)
+            .connect_with(|baud| open_port(&self.serial, baud, &self.line, shared))
+    }
+
+    fn open_with(&self, baud: BaudRate, builder: &CCDBuilder) -> Result<SerialCCD> {
+        let shared = self.shared()?;
+        Ok(builder.open(open_port(&self.serial, baud, &self.line, shared)?))
+    }
+
+    fn shared(&self) -> Result<&PortShared> {
+        if let Some(shared) = self.shared.get() {
+            return Ok(shared);
+        }
+        let shared = PortShared::new(&self.serial, self.dump_raw.as_deref(), self.lock)?;
+        Ok(self.shared.get_or_init(|| shared))
+    }
+}
+
+/// Tries to get version info from a device on serial port, which would only succeed if it is a CCD
+pub fn probe(path: &str, baud: BaudRate, timeout: Duration) -> Result<VersionDetails> {
+    tracing::debug!("Probing {path}");
+    let builder = CCDBuilder::new()
+        .timeout(Some(timeout))
+        .retry_policy(RetryPolicy::none());
+    let shared = PortShared::default();
+    let mut ccd = builder.open(open_port(path, baud, &LineConf::default(), &shared)?);
+    Ok(ccd.get_version()?)
+}
+
+impl StreamConf {
+    /// Applies frame skipping settings to continuous reading
+    pub fn apply<IO: IoAdapter>(&self, frames: &mut FramesIter<'_, IO>) {
+        frames.keep_every(self.every.get());
+        frames.set_max_fps(self.max_fps);
+    }
+}
+
+impl QualityConf {
+    pub fn thresholds(&self) -> QualityThresholds {
+        QualityThresholds {
+            saturation: self.saturation,
+            underexposure: self.underexposure,
+        }
+    }
+}
+
+impl CaptureConf {
+    /// Applies capture settings to CCD
+    pub fn apply(&self, ccd: &mut dyn Spectrometer) -> Result<()> {
+        if let Some(t) = self.exposure_time {
+            ccd.set_exposure_time(t)?;
+        }
+        Ok(())
+    }
+
+    /// Calibration stored for connected device, unless disabled with --no-calibration, with
+    /// reference frames passed on command line applied on top
+    pub fn calibration(&self, version: &VersionDetails) -> Result<Option<DeviceCalibration>> {
+        let mut calibration = if self.no_calibration {
+            None
+        } else {
+            DeviceCalibration::load(version.serial_number())?
+        };
+        match &mut calibration {
+            Some(calibration) if !self.relative_intensity => calibration.intensity = None,
+            Some(DeviceCalibration {
+                intensity: Some(_), ..
+            }) => {}
+            _ if self.relative_intensity => {
+                return Err(eyre!(
+                    "No relative intensity calibration stored for device {}",
+                    version.serial_number()
+                ))
+            }
+            _ => {}
+        }
+        // Dark current can only be turned into a dark frame when exposure time is known
+        if let (Some(calibration), Some(exposure)) = (&mut calibration, self.exposure_time) {
+            if let Some(dark_current) = &calibration.dark_current {
+                calibration.dark = Some(dark_current.dark(exposure));
+            }
+        }
+        for reference in &self.apply_reference {
+            let frame = Some(reference.load()?);
+            let calibration = calibration.get_or_insert_with(Default::default);
+            match reference.kind {
+                ReferenceKind::Dark => calibration.dark = frame,
+                ReferenceKind::White => calibration.flat = frame,
+            }
+        }
+        if let Some(calibration) = &calibration {
+            calibration.validate()?;
+        }
+        Ok(calibration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener, thread};
+
+    #[test]
+    fn connect_to_tcp_bridge() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bridge = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = [0; 5];
+            conn.read_exact(&mut buf).unwrap();
+            buf
+        });
+        let builder = CCDBuilder::new()
+            .timeout(Some(Duration::from_millis(10)))
+            .retry_policy(RetryPolicy::none());
+        let port = format!("tcp://{addr}");
+        let line = LineConf::default();
+        let shared = PortShared::new(&port, None, true).unwrap();
+        let mut ccd = builder.open(open_port(&port, BaudRate::default(), &line, &shared).unwrap());
+        // Nothing answers, but the command itself should reach the bridge
+        assert!(ccd.set_exp_time(10).is_ok());
+        assert_eq!(bridge.join().unwrap()[0], 0x81);
     }
 }
@@ -0,0 +1,172 @@
+use ccd_lcamv06::VersionDetails;
+use serde::{Deserialize, Serialize};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Written at the start of every session, so that unrelated CBOR documents are rejected early
+const MAGIC: &str = "spectrometer-session";
+/// Should be bumped on every incompatible change of `Session` layout
+const FORMAT_VERSION: u32 = 1;
+
+/// Measurement bundled together with everything required to interpret it later
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Session {
+    magic: String,
+    format_version: u32,
+    pub metadata: Metadata,
+    pub calibration: Option<Calibration>,
+    pub dark: Option<Vec<u16>>,
+    pub reference: Option<Vec<u16>>,
+    pub frames: Vec<Vec<u16>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct Metadata {
+    /// Creation time formatted as RFC 3339
+    pub created: String,
+    pub software_version: String,
+    pub device: Option<DeviceInfo>,
+    pub exposure_time: Option<u16>,
+    pub average_time: Option<u8>,
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DeviceInfo {
+    pub hardware_version: String,
+    pub sensor_type: String,
+    pub firmware_version: String,
+    pub serial_number: String,
+}
+
+impl From<&VersionDetails> for DeviceInfo {
+    fn from(details: &VersionDetails) -> Self {
+        DeviceInfo {
+            hardware_version: details.hardware_version().to_string(),
+            sensor_type: details.sensor_type().to_string(),
+            firmware_version: details.firmware_version().to_string(),
+            serial_number: details.serial_number().to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Calibration {
+    /// Polynomial coefficients converting pixel index into wavelength in nm, lowest order first
+    pub wavelength: Vec<f64>,
+}
+
+impl Metadata {
+    /// Metadata stamped with current time and version of this tool
+    pub fn now() -> Result<Self> {
+        Ok(Metadata {
+            created: OffsetDateTime::now_local()?.format(&Rfc3339)?,
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+impl Session {
+    pub fn new(metadata: Metadata) -> Self {
+        Session {
+            magic: MAGIC.to_string(),
+            format_version: FORMAT_VERSION,
+            metadata,
+            calibration: None,
+            dark: None,
+            reference: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        tracing::debug!("Saving session to {path:?}");
+        let out = BufWriter::new(File::create(path)?);
+        ciborium::ser::into_writer(self, out)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        tracing::debug!("Loading session from {path:?}");
+        let input = BufReader::new(File::open(path)?);
+        let session: Session = ciborium::de::from_reader(input)
+            .map_err(|e| eyre!("{path:?} is not a valid session file: {e}"))?;
+        if session.magic != MAGIC {
+            return Err(eyre!("{path:?} is not a session file"));
+        }
+        if session.format_version != FORMAT_VERSION {
+            return Err(eyre!(
+                "Session format version {} is not supported, expected {FORMAT_VERSION}",
+                session.format_version
+            ));
+        }
+        Ok(session)
+    }
+}
+
+fn or_unknown<T: ToString>(val: &Option<T>) -> String {
+    val.as_ref()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn yes_no(val: bool) -> &'static str {
+    if val {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let meta = &self.metadata;
+        writeln!(f, "Created: {}", meta.created)?;
+        writeln!(f, "Software version: {}", meta.software_version)?;
+        if let Some(device) = &meta.device {
+            writeln!(f, "Hardware version: {}", device.hardware_version)?;
+            writeln!(f, "Firmware version: {}", device.firmware_version)?;
+            writeln!(f, "Sensor type: {}", device.sensor_type)?;
+            writeln!(f, "Serial number: {}", device.serial_number)?;
+        }
+        writeln!(f, "Exposure time: {}", or_unknown(&meta.exposure_time))?;
+        writeln!(f, "Average time: {}", or_unknown(&meta.average_time))?;
+        if let Some(note) = &meta.note {
+            writeln!(f, "Note: {note}")?;
+        }
+        if let Some(calibration) = &self.calibration {
+            writeln!(f, "Wavelength calibration: {:?}", calibration.wavelength)?;
+        }
+        writeln!(f, "Dark frame: {}", yes_no(self.dark.is_some()))?;
+        writeln!(f, "Reference frame: {}", yes_no(self.reference.is_some()))?;
+        write!(f, "Frames: {}", self.frames.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_roundtrip() {
+        let mut session = Session::new(Metadata {
+            created: "2023-01-01T00:00:00Z".to_string(),
+            exposure_time: Some(10),
+            ..Default::default()
+        });
+        session.dark = Some(vec![1, 2, 3]);
+        session.frames = vec![vec![4, 5, 6], vec![7, 8, 9]];
+
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&session, &mut buf).unwrap();
+        let decoded: Session = ciborium::de::from_reader(buf.as_slice()).unwrap();
+        assert_eq!(decoded, session);
+    }
+}
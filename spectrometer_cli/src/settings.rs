@@ -0,0 +1,140 @@
+use crate::cli::{SettingsExportConf, SettingsImportConf};
+use ccd_lcamv06::{BaudRate, TriggerMode};
+use num_traits::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use simple_eyre::{eyre::eyre, Result};
+use std::{fs, path::Path};
+
+/// How CCD starts capturing frames
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    /// Frames are captured when requested over serial
+    Soft,
+    /// Frames are captured continuously while hardware trigger is held
+    ContinuousHard,
+    /// A single frame is captured on every hardware trigger
+    SingleHard,
+}
+
+impl From<Trigger> for TriggerMode {
+    fn from(trigger: Trigger) -> Self {
+        match trigger {
+            Trigger::Soft => TriggerMode::SoftTrigger,
+            Trigger::ContinuousHard => TriggerMode::ContiniousHardTrigger,
+            Trigger::SingleHard => TriggerMode::SingleHardTrigger,
+        }
+    }
+}
+
+/// Every writable setting of a device, as stored in a settings profile
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceSettings {
+    /// Device settings were exported from, only used to warn about importing them elsewhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    pub exposure_time: u16,
+    pub average_time: u8,
+    /// Can't be read back from device, so it's only present in hand written profiles and left as
+    /// is on import otherwise
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trigger_mode: Option<Trigger>,
+    /// Baud rate of UART, which is separate from USB port
+    pub uart_baud_rate: u32,
+}
+
+impl DeviceSettings {
+    pub fn read(path: &Path) -> Result<Self> {
+        let settings: Self = toml::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| eyre!("Could not parse settings file {path:?}: {e}"))?;
+        settings.baud_rate()?;
+        Ok(settings)
+    }
+
+    pub fn baud_rate(&self) -> Result<BaudRate> {
+        BaudRate::from_u32(self.uart_baud_rate)
+            .ok_or_else(|| eyre!("Unsupported UART baud rate {}", self.uart_baud_rate))
+    }
+}
+
+pub fn export(conf: &SettingsExportConf) -> Result<()> {
+    let mut ccd = conf.serial.open_ccd()?;
+    let version = ccd.get_version()?;
+    let settings = DeviceSettings {
+        serial_number: Some(version.serial_number().to_string()),
+        exposure_time: ccd.get_exp_time()?,
+        average_time: ccd.get_avg_time()?,
+        trigger_mode: None,
+        uart_baud_rate: ccd.get_baudrate()?.to_u32().unwrap_or_default(),
+    };
+    fs::write(&conf.path, toml::to_string(&settings)?)?;
+    println!(
+        "Saved settings of device {} to {}",
+        version.serial_number(),
+        conf.path.display()
+    );
+    Ok(())
+}
+
+/// Applies settings from profile, UART baud rate goes last as it may cut off the connection
+pub fn import(conf: &SettingsImportConf) -> Result<()> {
+    let settings = DeviceSettings::read(&conf.path)?;
+    let mut ccd = conf.serial.open_ccd()?;
+    let version = ccd.get_version()?;
+    if let Some(serial) = &settings.serial_number {
+        if serial != version.serial_number() {
+            tracing::warn!(
+                "Settings were exported from device {serial}, importing them into {}",
+                version.serial_number()
+            );
+        }
+    }
+    ccd.set_exp_time(settings.exposure_time)?;
+    ccd.set_avg_time(settings.average_time)?;
+    if let Some(trigger) = settings.trigger_mode {
+        ccd.set_trigger_mode(trigger.into())?;
+    }
+    let baud = settings.baud_rate()?;
+    if ccd.get_baudrate()? != baud {
+        ccd.set_baudrate(baud)?;
+        ccd.keep_baudrate();
+    }
+    println!(
+        "Applied settings from {} to device {}",
+        conf.path.display(),
+        version.serial_number()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_settings_profile() {
+        let settings: DeviceSettings = toml::from_str(
+            "exposure_time = 10\naverage_time = 2\ntrigger_mode = \"single-hard\"\nuart_baud_rate = 921600",
+        )
+        .unwrap();
+        assert_eq!(settings.trigger_mode, Some(Trigger::SingleHard));
+        assert_eq!(settings.baud_rate().unwrap(), BaudRate::Baud921600);
+        assert_eq!(
+            toml::from_str::<DeviceSettings>(&toml::to_string(&settings).unwrap()).unwrap(),
+            settings
+        );
+
+        let exported = DeviceSettings {
+            serial_number: Some("202111161548".to_string()),
+            trigger_mode: None,
+            ..settings
+        };
+        assert!(!toml::to_string(&exported).unwrap().contains("trigger_mode"));
+        let invalid = DeviceSettings {
+            uart_baud_rate: 9600,
+            ..exported
+        };
+        assert!(invalid.baud_rate().is_err());
+    }
+}
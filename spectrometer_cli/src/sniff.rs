@@ -0,0 +1,217 @@
+use crate::{cli::DecodeConf, output};
+use ccd_lcamv06::{
+    decoder::{Decoded, Package},
+    transport::{parse_dump, Direction, Record},
+    Command, Decoder,
+};
+use simple_eyre::Result;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+use time::format_description::well_known::Rfc3339;
+
+/// File that all traffic is dumped into. Every chunk takes a line with time since the dump was
+/// created in microseconds, `R` or `W` for direction and bytes in hex. Clones share the same file,
+/// so a port reopened at another baud rate keeps adding to it
+#[derive(Clone)]
+pub struct RawDump(Arc<Mutex<(BufWriter<File>, Instant)>>);
+
+impl RawDump {
+    pub fn create(path: &Path) -> Result<Self> {
+        let mut dump = BufWriter::new(File::create(path)?);
+        writeln!(dump, "# started: {}", output::now().format(&Rfc3339)?)?;
+        Ok(RawDump(Arc::new(Mutex::new((dump, Instant::now())))))
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let mut guard = self.lock();
+        let (dump, started) = &mut *guard;
+        let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+        writeln!(
+            dump,
+            "{} {} {hex}",
+            started.elapsed().as_micros(),
+            direction.tag()
+        )
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.lock().0.flush()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, (BufWriter<File>, Instant)> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Wraps serial port, optionally copying all traffic into a [RawDump]
+pub struct TeePort<IO> {
+    io: IO,
+    dump: Option<RawDump>,
+}
+
+impl<IO> TeePort<IO> {
+    pub fn new(io: IO) -> Self {
+        TeePort { io, dump: None }
+    }
+
+    pub fn dump_to(io: IO, dump: RawDump) -> Self {
+        TeePort {
+            io,
+            dump: Some(dump),
+        }
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        let Some(dump) = &self.dump else {
+            return;
+        };
+        // Losing the dump shouldn't break communication itself
+        if let Err(e) = dump.record(direction, bytes) {
+            tracing::warn!("Failed to write raw dump, stopping it: {e}");
+            self.dump = None;
+        }
+    }
+}
+
+impl<IO: Read> Read for TeePort<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.io.read(buf)?;
+        if n > 0 {
+            self.record(Direction::Read, &buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<IO: Write> Write for TeePort<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.io.write(buf)?;
+        self.record(Direction::Write, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(dump) = &self.dump {
+            dump.flush()?;
+        }
+        self.io.flush()
+    }
+}
+
+/// Reads a dump produced by `--dump-raw`
+pub fn read_dump(path: &Path) -> Result<Vec<Record>> {
+    Ok(parse_dump(&fs::read_to_string(path)?)?)
+}
+
+fn describe(decoded: &Decoded<'_>) -> String {
+    match decoded {
+        Decoded::Package {
+            package: Package::Frame(frame),
+            ..
+        } => {
+            let min = frame.iter().min().unwrap_or_default();
+            let max = frame.iter().max().unwrap_or_default();
+            format!("Frame of {} pixels, values {min}..={max}", frame.len())
+        }
+        Decoded::Package {
+            package: Package::Response(response),
+            ..
+        } => format!("{response:?}"),
+        Decoded::Invalid { skipped, error, .. } => {
+            format!("Skipped {skipped} bytes: {error}")
+        }
+        Decoded::Incomplete { len, .. } => format!("Incomplete package of {len} bytes"),
+    }
+}
+
+/// Names commands the same way they are known to the library, anything else is shown as hex
+fn describe_sent(bytes: &[u8]) -> String {
+    let command = bytes.try_into().ok().and_then(Command::decode);
+    match command {
+        Some(command) => format!("Sent {command:?}"),
+        None => {
+            let hex: Vec<_> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            format!("Sent {}", hex.join(" "))
+        }
+    }
+}
+
+/// Replays received part of a dump through response parser, printing packages interleaved
+/// with commands that were sent
+pub fn decode(conf: &DecodeConf) -> Result<()> {
+    let records = read_dump(&conf.dump)?;
+    let mut received = Vec::new();
+    // Start offset of each read within received stream, to map packages back to time
+    let mut chunks = Vec::new();
+    let mut events = Vec::new();
+    for record in &records {
+        match record.direction {
+            Direction::Read => {
+                chunks.push((received.len(), record.at));
+                received.extend_from_slice(&record.bytes);
+            }
+            Direction::Write => events.push((record.at, describe_sent(&record.bytes))),
+        }
+    }
+    for decoded in Decoder::new(&received, conf.verify_crc) {
+        let offset = match decoded {
+            Decoded::Package { offset, .. }
+            | Decoded::Invalid { offset, .. }
+            | Decoded::Incomplete { offset, .. } => offset,
+        };
+        let chunk = chunks.partition_point(|(start, _)| *start <= offset);
+        let at = chunks[chunk.saturating_sub(1)].1;
+        events.push((at, describe(&decoded)));
+    }
+    // Stable sort keeps packages in stream order, and commands before responses to them
+    events.sort_by_key(|(at, _)| *at);
+    for (at, event) in events {
+        println!("{:>12.3} ms  {event}", at.as_secs_f64() * 1000.0);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_roundtrip() {
+        let path = std::env::temp_dir().join(format!("dump-{}.txt", std::process::id()));
+        let dump = RawDump::create(&path).unwrap();
+        let mut port = TeePort::dump_to(io::Cursor::new(vec![0x81, 0x01, 0xff]), dump.clone());
+        port.write_all(&[0x32, 0x00]).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(port.read(&mut buf).unwrap(), 1);
+        port.flush().unwrap();
+        drop(port);
+
+        // Port reopened later keeps adding to the same dump
+        let mut port = TeePort::dump_to(io::Cursor::new(vec![0x81]), dump);
+        assert_eq!(port.read(&mut buf).unwrap(), 1);
+        port.flush().unwrap();
+        drop(port);
+
+        let records = read_dump(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].direction, Direction::Write);
+        // Cursor is shared between reads and writes, so read picks up after written bytes
+        assert_eq!(records[1].bytes, vec![0xff]);
+        assert_eq!(records[2].bytes, vec![0x81]);
+    }
+
+    #[test]
+    fn describe_sent_commands() {
+        assert_eq!(
+            describe_sent(&Command::SetIntegrationTime(20).encode()),
+            "Sent SetIntegrationTime(20)"
+        );
+        assert_eq!(describe_sent(&[0x32, 0x00]), "Sent 32 00");
+    }
+}
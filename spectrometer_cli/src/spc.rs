@@ -0,0 +1,195 @@
+use crate::{analysis::wavelength_at, output::Header};
+use std::io::{self, Write};
+use time::OffsetDateTime;
+
+/// Sizes of main header, subfile header and log block header
+const HEADER_LEN: usize = 512;
+const SUBHEADER_LEN: usize = 32;
+const LOG_HEADER_LEN: usize = 64;
+
+/// File holds more than one subfile
+const TMULTI: u8 = 0x04;
+/// Subfiles are ordered, but their times are not evenly spaced
+const TORDRD: u8 = 0x10;
+/// X values are stored as an array before the first subfile
+const TXVALS: u8 = 0x80;
+
+/// Little endian "new" format
+const VERSION: u8 = 0x4B;
+const EXPERIMENT_UV_VIS: u8 = 7;
+/// Exponent marking values as IEEE floats
+const FLOAT_EXPONENT: u8 = 0x80;
+const UNITS_NANOMETERS: u8 = 3;
+const UNITS_SECONDS: u8 = 4;
+const UNITS_DIODE_NUMBER: u8 = 16;
+const UNITS_COUNTS: u8 = 4;
+
+/// Writes frames as subfiles of a Galactic SPC file. Main header holds subfile count, so it is
+/// written once at the start and rewritten with actual count once all frames are in
+pub struct Spc {
+    metadata: Vec<String>,
+    /// Wavelength of every pixel, files without calibration are evenly spaced by pixel
+    xs: Option<Vec<f32>>,
+    pixels: usize,
+    started: Option<OffsetDateTime>,
+}
+
+/// Date packed into 32 bits the way SPC stores it
+fn packed_date(time: OffsetDateTime) -> u32 {
+    let year = time.year().clamp(0, 4095) as u32;
+    (year << 20)
+        | ((time.month() as u32) << 16)
+        | ((time.day() as u32) << 11)
+        | ((time.hour() as u32) << 6)
+        | time.minute() as u32
+}
+
+/// Copies string into fixed size field, truncating it and keeping the last byte as terminator
+fn put_str(field: &mut [u8], s: &str) {
+    let len = s.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+impl Spc {
+    pub fn new(header: &Header, pixels: usize) -> Self {
+        let xs = (!header.wavelength.is_empty()).then(|| {
+            (0..pixels)
+                .map(|px| wavelength_at(&header.wavelength, px as f64) as f32)
+                .collect()
+        });
+        Spc {
+            metadata: header.metadata.clone(),
+            xs,
+            pixels,
+            started: None,
+        }
+    }
+
+    /// Main header followed by X values when file has them, always of the same length
+    pub fn header(&self, subfiles: usize) -> Vec<u8> {
+        let mut header = vec![0; HEADER_LEN];
+        let mut flags = 0;
+        if subfiles > 1 {
+            flags |= TMULTI | TORDRD;
+        }
+        if self.xs.is_some() {
+            flags |= TXVALS;
+        }
+        header[0] = flags;
+        header[1] = VERSION;
+        header[2] = EXPERIMENT_UV_VIS;
+        header[3] = FLOAT_EXPONENT;
+        header[4..8].copy_from_slice(&(self.pixels as u32).to_le_bytes());
+        let (first, last) = match &self.xs {
+            Some(xs) => (
+                xs.first().copied().unwrap_or(0.0) as f64,
+                xs.last().copied().unwrap_or(0.0) as f64,
+            ),
+            None => (0.0, self.pixels.saturating_sub(1) as f64),
+        };
+        header[8..16].copy_from_slice(&first.to_le_bytes());
+        header[16..24].copy_from_slice(&last.to_le_bytes());
+        header[24..28].copy_from_slice(&(subfiles as u32).to_le_bytes());
+        header[28] = if self.xs.is_some() {
+            UNITS_NANOMETERS
+        } else {
+            UNITS_DIODE_NUMBER
+        };
+        header[29] = UNITS_COUNTS;
+        header[30] = UNITS_SECONDS;
+        if let Some(started) = self.started {
+            header[32..36].copy_from_slice(&packed_date(started).to_le_bytes());
+        }
+        put_str(&mut header[45..54], "LCAM_V06");
+        put_str(&mut header[88..218], &self.metadata.join("; "));
+        // Full metadata goes into log block after the last subfile
+        let log = HEADER_LEN
+            + self.xs.as_ref().map_or(0, |xs| xs.len() * 4)
+            + subfiles * (SUBHEADER_LEN + self.pixels * 4);
+        header[248..252].copy_from_slice(&(log as u32).to_le_bytes());
+        if let Some(xs) = &self.xs {
+            for x in xs {
+                header.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        header
+    }
+
+    /// Subfile of `idx`-th frame, with its time counted from the first one
+    pub fn subfile(&mut self, idx: usize, frame: &[u16], timestamp: OffsetDateTime) -> Vec<u8> {
+        let started = *self.started.get_or_insert(timestamp);
+        let time = (timestamp - started).as_seconds_f32();
+        let mut subfile = vec![0; SUBHEADER_LEN];
+        subfile[1] = FLOAT_EXPONENT;
+        subfile[2..4].copy_from_slice(&(idx as u16).to_le_bytes());
+        subfile[4..8].copy_from_slice(&time.to_le_bytes());
+        subfile[8..12].copy_from_slice(&time.to_le_bytes());
+        for px in frame.iter().take(self.pixels) {
+            subfile.extend_from_slice(&(*px as f32).to_le_bytes());
+        }
+        subfile
+    }
+
+    /// Log block with metadata as `key=value` lines, written after the last subfile
+    pub fn write_log(&self, out: &mut impl Write) -> io::Result<()> {
+        let text: String = self
+            .metadata
+            .iter()
+            .map(|line| match line.split_once(':') {
+                Some((key, value)) => format!("{}={}\r\n", key.trim(), value.trim()),
+                None => format!("{line}\r\n"),
+            })
+            .collect();
+        let size = (LOG_HEADER_LEN + text.len()) as u32;
+        let mut header = vec![0; LOG_HEADER_LEN];
+        header[0..4].copy_from_slice(&size.to_le_bytes());
+        header[4..8].copy_from_slice(&size.to_le_bytes());
+        header[8..12].copy_from_slice(&(LOG_HEADER_LEN as u32).to_le_bytes());
+        out.write_all(&header)?;
+        out.write_all(text.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spc_layout() {
+        let header = Header {
+            metadata: vec!["exposure time: 10".to_string()],
+            wavelength: vec![500.0, 0.5],
+            pixels: 4,
+            ..Default::default()
+        };
+        let mut spc = Spc::new(&header, 4);
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let first = spc.subfile(0, &[1, 2, 3, 4], start);
+        let second = spc.subfile(1, &[5, 6, 7, 8], start + time::Duration::seconds(2));
+        assert_eq!(first.len(), SUBHEADER_LEN + 16);
+        assert_eq!(f32::from_le_bytes(second[4..8].try_into().unwrap()), 2.0);
+        assert_eq!(f32::from_le_bytes(second[44..48].try_into().unwrap()), 8.0);
+
+        let main = spc.header(2);
+        assert_eq!(main.len(), HEADER_LEN + 16);
+        assert_eq!(main[0], TMULTI | TORDRD | TXVALS);
+        assert_eq!(main[1], VERSION);
+        assert_eq!(u32::from_le_bytes(main[24..28].try_into().unwrap()), 2);
+        assert_eq!(f64::from_le_bytes(main[16..24].try_into().unwrap()), 501.5);
+        assert_eq!(
+            u32::from_le_bytes(main[32..36].try_into().unwrap()),
+            1970 << 20 | 1 << 16 | 1 << 11
+        );
+        assert_eq!(
+            f32::from_le_bytes(main[516..520].try_into().unwrap()),
+            500.5
+        );
+        let log = u32::from_le_bytes(main[248..252].try_into().unwrap()) as usize;
+        assert_eq!(log, main.len() + first.len() + second.len());
+        assert_eq!(spc.header(1)[0], TXVALS);
+
+        let mut log = Vec::new();
+        spc.write_log(&mut log).unwrap();
+        assert_eq!(&log[LOG_HEADER_LEN..], b"exposure time=10\r\n");
+    }
+}
@@ -0,0 +1,212 @@
+use crate::output::{self, Header};
+use ccd_lcamv06::QualityFlags;
+use rusqlite::{params, Connection};
+use simple_eyre::{eyre::eyre, Result};
+use std::path::Path;
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime, UtcOffset};
+
+/// Output paths starting with this are archives that runs are appended to
+pub const SQLITE_SCHEME: &str = "sqlite://";
+
+/// Frames inserted within a single transaction, committing each frame separately is slow
+const FRAMES_PER_COMMIT: usize = 64;
+
+/// Same layout SQLite date functions produce, so that stored times compare against them
+const SQLITE_TIME_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]");
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    started TEXT NOT NULL,
+    finished TEXT,
+    serial TEXT,
+    frames INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS settings (
+    run INTEGER NOT NULL REFERENCES runs(id),
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (run, key)
+);
+CREATE TABLE IF NOT EXISTS frames (
+    run INTEGER NOT NULL REFERENCES runs(id),
+    idx INTEGER NOT NULL,
+    timestamp TEXT NOT NULL,
+    pixels BLOB NOT NULL,
+    flags INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (run, idx)
+);
+CREATE INDEX IF NOT EXISTS runs_by_serial ON runs (serial, started);
+";
+
+/// Database file of an archive output, if path is one
+pub fn archive_path(path: &Path) -> Option<&Path> {
+    path.to_str()?.strip_prefix(SQLITE_SCHEME).map(Path::new)
+}
+
+/// Brings archives created by older versions up to the current schema
+fn migrate(conn: &Connection) -> Result<()> {
+    let flags: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info('frames') WHERE name = 'flags'",
+        [],
+        |row| row.get(0),
+    )?;
+    if flags == 0 {
+        tracing::debug!("Adding quality flags to archive");
+        conn.execute_batch("ALTER TABLE frames ADD COLUMN flags INTEGER NOT NULL DEFAULT 0")?;
+    }
+    Ok(())
+}
+
+/// Times are stored in UTC
+fn sqlite_time(time: OffsetDateTime) -> Result<String> {
+    Ok(time.to_offset(UtcOffset::UTC).format(SQLITE_TIME_FORMAT)?)
+}
+
+/// A single run appended to SQLite archive, with its frames stored as little endian u16 blobs
+/// along with quality flags packed into bits
+pub struct Archive {
+    conn: Connection,
+    run: i64,
+    written: usize,
+}
+
+impl Archive {
+    /// Opens or creates archive and starts a new run in it, with metadata lines as its settings
+    pub fn open(path: &Path, header: &Header) -> Result<Self> {
+        let conn =
+            Connection::open(path).map_err(|e| eyre!("Could not open archive {path:?}: {e}"))?;
+        conn.execute_batch(SCHEMA)?;
+        migrate(&conn)?;
+        let settings: Vec<(&str, &str)> = header
+            .metadata
+            .iter()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+        let serial = settings
+            .iter()
+            .find(|(key, _)| *key == "device serial number")
+            .map(|(_, value)| *value);
+        conn.execute(
+            "INSERT INTO runs (started, serial) VALUES (?1, ?2)",
+            params![sqlite_time(output::now())?, serial],
+        )?;
+        let run = conn.last_insert_rowid();
+        // Later lines win, same as when reading header top to bottom
+        for (key, value) in settings {
+            conn.execute(
+                "INSERT OR REPLACE INTO settings (run, key, value) VALUES (?1, ?2, ?3)",
+                params![run, key, value],
+            )?;
+        }
+        tracing::debug!("Appending run {run} to {path:?}");
+        conn.execute_batch("BEGIN")?;
+        Ok(Archive {
+            conn,
+            run,
+            written: 0,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        frame: &[u16],
+        flags: QualityFlags,
+        timestamp: OffsetDateTime,
+    ) -> Result<()> {
+        let pixels: Vec<u8> = frame.iter().flat_map(|px| px.to_le_bytes()).collect();
+        self.written += 1;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO frames (run, idx, timestamp, pixels, flags) \
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                self.run,
+                self.written,
+                sqlite_time(timestamp)?,
+                pixels,
+                flags.bits()
+            ])?;
+        if self.written.is_multiple_of(FRAMES_PER_COMMIT) {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<usize> {
+        self.conn.execute(
+            "UPDATE runs SET finished = ?1, frames = ?2 WHERE id = ?3",
+            params![sqlite_time(output::now())?, self.written, self.run],
+        )?;
+        self.conn.execute_batch("COMMIT")?;
+        Ok(self.written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_runs() {
+        let path = std::env::temp_dir().join(format!("runs-{}.db", std::process::id()));
+        let header = Header {
+            metadata: vec![
+                "device serial number: 202111161548".to_string(),
+                "exposure time: 10".to_string(),
+            ],
+            ..Default::default()
+        };
+        for frames in [2, 3] {
+            let mut archive = Archive::open(&path, &header).unwrap();
+            for i in 0..frames {
+                let flags = QualityFlags {
+                    underexposed: true,
+                    ..Default::default()
+                };
+                archive.write(&[i, 1, 2], flags, output::now()).unwrap();
+            }
+            assert_eq!(archive.finish().unwrap(), frames as usize);
+        }
+
+        let conn = Connection::open(&path).unwrap();
+        let runs: Vec<(i64, usize)> = conn
+            .prepare(
+                "SELECT id, frames FROM runs WHERE serial = '202111161548' \
+                 AND started >= datetime('now', '-7 days') ORDER BY id",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(runs, vec![(1, 2), (2, 3)]);
+        let exposure: String = conn
+            .query_row(
+                "SELECT value FROM settings WHERE run = 2 AND key = 'exposure time'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(exposure, "10");
+        let (pixels, flags): (Vec<u8>, u8) = conn
+            .query_row(
+                "SELECT pixels, flags FROM frames WHERE run = 2 AND idx = 3",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pixels, vec![2, 0, 1, 0, 2, 0]);
+        assert_eq!(flags, QualityFlags::UNDEREXPOSED);
+        assert_eq!(
+            archive_path(Path::new("sqlite://runs.db")),
+            Some(Path::new("runs.db"))
+        );
+        assert_eq!(archive_path(Path::new("runs.db")), None);
+    }
+}
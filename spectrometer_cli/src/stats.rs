@@ -0,0 +1,161 @@
+use crate::{
+    cli::StatsConf,
+    input::{self, InputFormat},
+};
+use rayon::prelude::*;
+use simple_eyre::{eyre::eyre, Result};
+use std::fmt;
+
+/// Statistics of a single frame
+#[derive(Debug, PartialEq)]
+struct FrameStats {
+    mean: f64,
+    min: u16,
+    max: u16,
+    /// Pixels at or above saturation level
+    saturated: usize,
+    /// Index of the highest pixel
+    peak: usize,
+}
+
+impl FrameStats {
+    fn new(frame: &[u16], saturation: u16) -> Self {
+        let (peak, max) = frame
+            .iter()
+            .copied()
+            .enumerate()
+            // Leftmost pixel wins in case of a tie, so flat tops don't make peak jump around
+            .fold(
+                (0, 0),
+                |(at, max), (i, v)| if v > max { (i, v) } else { (at, max) },
+            );
+        FrameStats {
+            mean: frame.iter().map(|&v| v as f64).sum::<f64>() / frame.len().max(1) as f64,
+            min: frame.iter().copied().min().unwrap_or(0),
+            max,
+            saturated: frame.iter().filter(|&&v| v >= saturation).count(),
+            peak,
+        }
+    }
+}
+
+/// Statistics of a whole capture
+#[derive(Debug, PartialEq)]
+struct CaptureStats {
+    frames: Vec<FrameStats>,
+    mean: f64,
+    max: u16,
+    saturated_frames: usize,
+    /// Lowest and highest peak position
+    peak_range: (usize, usize),
+    /// Standard deviation of peak position
+    peak_deviation: f64,
+}
+
+impl CaptureStats {
+    fn new(frames: &[Vec<u16>], saturation: u16) -> Result<Self> {
+        if frames.is_empty() {
+            return Err(eyre!("Capture has no frames"));
+        }
+        // Order of frames is kept, so they are still listed as captured
+        let frames: Vec<_> = frames
+            .par_iter()
+            .map(|frame| FrameStats::new(frame, saturation))
+            .collect();
+        let count = frames.len() as f64;
+        let peak_mean = frames.iter().map(|f| f.peak as f64).sum::<f64>() / count;
+        let peak_variance = frames
+            .iter()
+            .map(|f| (f.peak as f64 - peak_mean).powi(2))
+            .sum::<f64>()
+            / count;
+        Ok(CaptureStats {
+            mean: frames.iter().map(|f| f.mean).sum::<f64>() / count,
+            max: frames.iter().map(|f| f.max).max().unwrap_or(0),
+            saturated_frames: frames.iter().filter(|f| f.saturated > 0).count(),
+            peak_range: (
+                frames.iter().map(|f| f.peak).min().unwrap_or(0),
+                frames.iter().map(|f| f.peak).max().unwrap_or(0),
+            ),
+            peak_deviation: peak_variance.sqrt(),
+            frames,
+        })
+    }
+}
+
+impl fmt::Display for CaptureStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>7} {:>10} {:>6} {:>6} {:>10} {:>6}",
+            "frame", "mean", "min", "max", "saturated", "peak"
+        )?;
+        for (idx, frame) in self.frames.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>7} {:>10.1} {:>6} {:>6} {:>10} {:>6}",
+                idx + 1,
+                frame.mean,
+                frame.min,
+                frame.max,
+                frame.saturated,
+                frame.peak
+            )?;
+        }
+        let (first, last) = (&self.frames[0], &self.frames[self.frames.len() - 1]);
+        writeln!(f)?;
+        writeln!(f, "Frames: {}", self.frames.len())?;
+        writeln!(f, "Mean: {:.1}", self.mean)?;
+        writeln!(f, "Max: {}", self.max)?;
+        writeln!(f, "Frames with saturated pixels: {}", self.saturated_frames)?;
+        write!(
+            f,
+            "Peak drift: {} -> {} (range {}..={}, std dev {:.2})",
+            first.peak, last.peak, self.peak_range.0, self.peak_range.1, self.peak_deviation
+        )
+    }
+}
+
+/// `stats` subcommand, summarizes a previously saved capture
+pub fn run(conf: &StatsConf) -> Result<()> {
+    let format = match conf.from {
+        Some(format) => format,
+        None => InputFormat::from_path(&conf.input)
+            .ok_or_else(|| eyre!("Can't tell format of {:?}, pass --from", conf.input))?,
+    };
+    let frames = input::read_capture(&conf.input, format)?;
+    println!("{}", CaptureStats::new(&frames, conf.saturation)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_stats() {
+        let frames = vec![
+            vec![10, 30, 30, 10],
+            vec![0, 10, 20, 50],
+            vec![5, 15, 40, 50],
+        ];
+        let stats = CaptureStats::new(&frames, 50).unwrap();
+        assert_eq!(
+            stats.frames[0],
+            FrameStats {
+                mean: 20.0,
+                min: 10,
+                max: 30,
+                saturated: 0,
+                peak: 1,
+            }
+        );
+        assert_eq!(stats.frames[2].saturated, 1);
+        assert_eq!(stats.mean, 22.5);
+        assert_eq!(stats.max, 50);
+        assert_eq!(stats.saturated_frames, 2);
+        assert_eq!(stats.peak_range, (1, 3));
+        assert!((stats.peak_deviation - 0.943).abs() < 1e-3);
+        assert!(CaptureStats::new(&[], 50).is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    cell::Cell,
+    env,
+    time::{Duration, Instant},
+};
+
+/// Connection to service manager when started by systemd as a `Type=notify` service, does
+/// nothing otherwise. Implements the `sd_notify` protocol directly, which is a single datagram
+/// per message
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: Option<std::os::unix::net::UnixDatagram>,
+    watchdog: Option<Duration>,
+    last_ping: Cell<Instant>,
+}
+
+impl Notifier {
+    /// Picks up `NOTIFY_SOCKET` and `WATCHDOG_USEC` set by systemd
+    pub fn from_env() -> Self {
+        #[cfg(unix)]
+        let socket = env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            connect(&path)
+                .map_err(|e| {
+                    tracing::warn!("Could not connect to notification socket {path:?}: {e}")
+                })
+                .ok()
+        });
+        #[cfg(unix)]
+        let supervised = socket.is_some();
+        #[cfg(not(unix))]
+        let supervised = false;
+        let watchdog = watchdog_from_env().filter(|_| supervised);
+        if supervised {
+            tracing::debug!("Notifying service manager, watchdog interval is {watchdog:?}");
+        }
+        Notifier {
+            #[cfg(unix)]
+            socket,
+            watchdog,
+            last_ping: Cell::new(Instant::now()),
+        }
+    }
+
+    fn send(&self, message: &str) {
+        #[cfg(unix)]
+        if let Some(socket) = &self.socket {
+            if let Err(e) = socket.send(message.as_bytes()) {
+                tracing::warn!("Could not notify service manager with {message:?}: {e}");
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = message;
+    }
+
+    /// Startup is over and daemon is doing its job
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    /// Shutdown has started, so taking a while to exit isn't a hang
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// Free form line shown by `systemctl status`
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    /// Longest time that can pass between [Notifier::keep_alive] calls without watchdog firing,
+    /// leaving half of the interval as a margin
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|watchdog| watchdog / 2)
+    }
+
+    /// Pings watchdog, often enough to keep it happy but without flooding the socket
+    pub fn keep_alive(&self) {
+        let Some(interval) = self.keep_alive_interval() else {
+            return;
+        };
+        if self.last_ping.get().elapsed() >= interval / 4 {
+            self.send("WATCHDOG=1");
+            self.last_ping.set(Instant::now());
+        }
+    }
+}
+
+fn watchdog_from_env() -> Option<Duration> {
+    // Watchdog is meant for main process only, if set for someone else it's not ours
+    let ours = env::var("WATCHDOG_PID").map_or(true, |pid| pid == std::process::id().to_string());
+    env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|_| ours)
+        .map(Duration::from_micros)
+}
+
+#[cfg(unix)]
+fn connect(path: &std::ffi::OsStr) -> std::io::Result<std::os::unix::net::UnixDatagram> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes() {
+        // Leading `@` stands for a socket in abstract namespace
+        #[cfg(target_os = "linux")]
+        [b'@', name @ ..] => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.connect_addr(&SocketAddr::from_abstract_name(name)?)?;
+        }
+        _ => socket.connect(path)?,
+    }
+    Ok(socket)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notify_socket() {
+        let path = env::temp_dir().join(format!("notify-{}.sock", std::process::id()));
+        let listener = UnixDatagram::bind(&path).unwrap();
+        let notifier = Notifier {
+            socket: Some(connect(path.as_os_str()).unwrap()),
+            watchdog: Some(Duration::from_secs(10)),
+            last_ping: Cell::new(Instant::now() - Duration::from_secs(5)),
+        };
+        notifier.ready();
+        notifier.keep_alive();
+        // Too soon after previous ping
+        notifier.keep_alive();
+        notifier.stopping();
+        let mut buf = [0; 64];
+        let mut received = Vec::new();
+        listener.set_nonblocking(true).unwrap();
+        while let Ok(n) = listener.recv(&mut buf) {
+            received.push(String::from_utf8_lossy(&buf[..n]).into_owned());
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(received, ["READY=1", "WATCHDOG=1", "STOPPING=1"]);
+    }
+}
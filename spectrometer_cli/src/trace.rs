@@ -0,0 +1,197 @@
+use crate::{
+    analysis::wavelength_at,
+    calibration::DeviceCalibration,
+    capture::{capture_header, prepare_capture, Capture},
+    cli::TraceReadingConf,
+    interrupt,
+    output::{self, is_stdio},
+};
+use ccd_lcamv06::{Frame, QualityFlags, FRAME_PIXEL_COUNT};
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    io::{self, BufWriter, Write},
+    ops::RangeInclusive,
+    time::Instant,
+};
+use time::format_description::well_known::Rfc3339;
+
+/// Part of spectrum intensity is integrated over, both ends included
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Band {
+    Pixels(usize, usize),
+    /// In nm, needs wavelength calibration
+    Wavelengths(f64, f64),
+}
+
+/// Parses `1200:1300` as pixels and `500nm:520nm` as wavelengths
+pub fn parse_band(s: &str) -> Result<Band, String> {
+    let (start, end) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Band {s:?} should look like 1200:1300 or 500nm:520nm"))?;
+    let band = match (start.strip_suffix("nm"), end.strip_suffix("nm")) {
+        (Some(start), Some(end)) => {
+            let parse = |v: &str| {
+                v.trim()
+                    .parse::<f64>()
+                    .map_err(|e| format!("Invalid wavelength {v:?}: {e}"))
+            };
+            Band::Wavelengths(parse(start)?, parse(end)?)
+        }
+        (None, None) => {
+            let parse = |v: &str| {
+                v.trim()
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid pixel {v:?}: {e}"))
+            };
+            Band::Pixels(parse(start)?, parse(end)?)
+        }
+        _ => {
+            return Err(format!(
+                "Both ends of band {s:?} should be in the same units"
+            ))
+        }
+    };
+    match band {
+        Band::Pixels(start, end) if start > end => Err(format!("Band {s:?} ends before it starts")),
+        Band::Wavelengths(start, end) if start > end => {
+            Err(format!("Band {s:?} ends before it starts"))
+        }
+        _ => Ok(band),
+    }
+}
+
+impl Band {
+    /// Pixels covered by band, wavelengths are looked up in calibration polynomial
+    pub fn pixels(&self, wavelength: &[f64], pixel_count: usize) -> Result<RangeInclusive<usize>> {
+        let (start, end) = match *self {
+            Band::Pixels(start, end) => (start, end),
+            Band::Wavelengths(from, to) => {
+                if wavelength.is_empty() {
+                    return Err(eyre!(
+                        "Band in nm needs wavelength calibration, use pixels instead"
+                    ));
+                }
+                let mut inside = (0..pixel_count).filter(|&px| {
+                    let nm = wavelength_at(wavelength, px as f64);
+                    (from..=to).contains(&nm)
+                });
+                let start = inside
+                    .next()
+                    .ok_or_else(|| eyre!("No pixel falls within {from}-{to} nm"))?;
+                (start, inside.next_back().unwrap_or(start))
+            }
+        };
+        if end >= pixel_count {
+            return Err(eyre!(
+                "Band ends at pixel {end}, but sensor only has {pixel_count} pixels"
+            ));
+        }
+        Ok(start..=end)
+    }
+}
+
+/// Counts summed over band
+fn integrate(frame: &[u16], band: &RangeInclusive<usize>) -> u64 {
+    frame[band.clone()].iter().map(|&px| px as u64).sum()
+}
+
+/// `read trace` subcommand, writes integrated intensity of a band against time since the first
+/// frame, a row per frame, until count is reached or capture is stopped
+pub fn read(conf: &TraceReadingConf) -> Result<()> {
+    if !conf.csv.columns.is_empty() {
+        return Err(eyre!(
+            "Trace is always written as time and intensity columns"
+        ));
+    }
+    conf.csv.validate()?;
+    let mut ccd = conf.serial.open_ccd()?;
+    let (version, calibration) = prepare_capture(&mut ccd, &conf.capture)?;
+    let wavelength = calibration.as_ref().map_or(&[][..], |c| &c.wavelength);
+    let band = conf.band.pixels(wavelength, FRAME_PIXEL_COUNT)?;
+    let correction = calibration.as_ref().map(DeviceCalibration::correction);
+
+    interrupt::install_handler()?;
+    let mut header = capture_header(
+        &conf.serial,
+        &conf.capture,
+        &version,
+        calibration.as_ref(),
+        ccd.sensor_layout().pixels(),
+    );
+    header
+        .metadata
+        .push(format!("band: pixels {}-{}", band.start(), band.end()));
+    if !wavelength.is_empty() {
+        header.metadata.push(format!(
+            "band wavelengths: {:.3}-{:.3} nm",
+            wavelength_at(wavelength, *band.start() as f64),
+            wavelength_at(wavelength, *band.end() as f64)
+        ));
+    }
+    header
+        .metadata
+        .push(format!("started: {}", output::now().format(&Rfc3339)?));
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(if is_stdio(&conf.output) {
+        Box::new(io::stdout())
+    } else {
+        Box::new(output::create_new(&conf.output)?)
+    });
+    conf.csv.write_header(&mut out, &header.metadata, &[], &[])?;
+    if !conf.csv.no_header {
+        writeln!(out, "{}", conf.csv.row(&["time", "intensity"]))?;
+    }
+    out.flush()?;
+
+    let mut start = None;
+    let sink = |mut frame: Frame, _: QualityFlags| {
+        let elapsed = start.get_or_insert_with(Instant::now).elapsed();
+        if let Some(correction) = &correction {
+            correction.apply(&mut frame);
+        }
+        let time = conf.csv.number(elapsed.as_secs_f64(), 3);
+        writeln!(
+            out,
+            "{}",
+            conf.csv.row(&[time, integrate(&frame, &band).to_string()])
+        )?;
+        // Rows are meant to be followed as they come, e.g. with `tail -f`
+        out.flush()?;
+        Ok(true)
+    };
+    let thresholds = conf.quality.thresholds();
+    let capture = match conf.interval {
+        Some(every) => {
+            Capture::run_interval(&mut ccd, every, conf.count, conf.until, thresholds, sink)
+        }
+        None => Capture::run(&mut ccd, conf.count, &conf.stream, thresholds, sink),
+    };
+    tracing::info!("Traced {} frames", capture.captured);
+    if let Some(note) = capture.dropped_note() {
+        tracing::warn!("{note}");
+    }
+    capture.error.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn band_pixels() {
+        assert_eq!(parse_band("1200:1300"), Ok(Band::Pixels(1200, 1300)));
+        assert_eq!(
+            parse_band("500nm:520.5nm"),
+            Ok(Band::Wavelengths(500.0, 520.5))
+        );
+        assert!(parse_band("500nm:520").is_err());
+        assert!(parse_band("1300:1200").is_err());
+
+        // 1 nm per pixel starting at 400 nm
+        let wavelength = [400.0, 1.0];
+        let band = Band::Wavelengths(499.5, 502.0);
+        assert_eq!(band.pixels(&wavelength, 200).unwrap(), 100..=102);
+        assert!(band.pixels(&[], 200).is_err());
+        assert!(Band::Pixels(10, 200).pixels(&[], 200).is_err());
+        assert_eq!(integrate(&[1, 2, 3, 4], &(1..=2)), 5);
+    }
+}
@@ -0,0 +1,319 @@
+use crate::{
+    analysis::{fit_polynomial, wavelength_at},
+    calibration::DeviceCalibration,
+    cli::WavelengthCalibrationConf,
+    reference,
+};
+use ccd_lcamv06::FRAME_PIXEL_COUNT;
+use clap::ArgEnum;
+use console::Term;
+use simple_eyre::{eyre::eyre, Report, Result};
+use std::str::FromStr;
+
+/// Peaks closer than this many pixels to a higher one are taken as its noise
+const MIN_LINE_DISTANCE: f64 = 3.0;
+
+/// Rounds of matching peaks against lines and refitting, each one starting from previous fit
+const FIT_ROUNDS: usize = 5;
+
+/// Calibration lamps with built-in line tables
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lamp {
+    Ne,
+    Ar,
+    Hg,
+}
+
+impl Lamp {
+    /// Strong lines in air, in nm
+    fn lines(self) -> &'static [f64] {
+        match self {
+            Lamp::Ne => &[
+                540.056, 585.249, 588.190, 594.483, 597.553, 603.000, 607.434, 609.616, 614.306,
+                616.359, 621.728, 626.650, 630.479, 633.443, 638.299, 640.225, 650.653, 653.288,
+                659.895, 667.828, 671.704, 692.947, 703.241, 717.394, 724.517, 743.890, 748.887,
+                753.577, 754.404,
+            ],
+            Lamp::Ar => &[
+                696.543, 706.722, 714.704, 727.294, 738.398, 750.387, 751.465, 763.511, 772.376,
+                794.818, 800.616, 801.479, 810.369, 811.531, 826.452, 840.821, 842.465, 852.144,
+                866.794, 912.297, 922.450,
+            ],
+            Lamp::Hg => &[
+                253.652, 296.728, 302.150, 313.155, 334.148, 365.015, 404.656, 407.783, 435.833,
+                546.074, 576.960, 579.066,
+            ],
+        }
+    }
+}
+
+/// Rough wavelengths at the first and the last pixel, given as `start:end` in nm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub first: f64,
+    pub last: f64,
+}
+
+impl FromStr for Span {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (first, last) = s
+            .split_once(':')
+            .ok_or_else(|| eyre!("Span should be given as start:end, got {s:?}"))?;
+        let parse = |nm: &str| {
+            nm.trim()
+                .parse::<f64>()
+                .map_err(|_| eyre!("{nm:?} is not a wavelength"))
+        };
+        Ok(Span {
+            first: parse(first)?,
+            last: parse(last)?,
+        })
+    }
+}
+
+impl Span {
+    /// Linear calibration going through both ends
+    fn coeffs(&self, pixels: usize) -> Vec<f64> {
+        let slope = (self.last - self.first) / pixels.saturating_sub(1).max(1) as f64;
+        vec![self.first, slope]
+    }
+}
+
+/// Centers of peaks standing out of background by more than `threshold` of the highest one,
+/// interpolated between pixels
+fn detect_lines(values: &[f64], threshold: f64) -> Vec<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let background = sorted.get(sorted.len() / 2).copied().unwrap_or(0.0);
+    let max = sorted.last().copied().unwrap_or(0.0);
+    let min_height = background + (max - background) * threshold;
+
+    let mut tops: Vec<usize> = (1..values.len().saturating_sub(1))
+        .filter(|&i| {
+            values[i] >= min_height && values[i] > values[i - 1] && values[i] >= values[i + 1]
+        })
+        .collect();
+    tops.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    let mut lines: Vec<f64> = Vec::new();
+    for i in tops {
+        // Vertex of a parabola going through the top and its neighbours
+        let (l, c, r) = (values[i - 1], values[i], values[i + 1]);
+        let curvature = l - 2.0 * c + r;
+        let offset = if curvature == 0.0 {
+            0.0
+        } else {
+            0.5 * (l - r) / curvature
+        };
+        let center = i as f64 + offset;
+        if lines
+            .iter()
+            .all(|&line| (line - center).abs() >= MIN_LINE_DISTANCE)
+        {
+            lines.push(center);
+        }
+    }
+    lines.sort_by(f64::total_cmp);
+    lines
+}
+
+/// Pairs peak positions with table lines closest to their wavelength under `coeffs`, closest
+/// pairs first, so each peak and each line is used at most once
+fn match_lines(peaks: &[f64], table: &[f64], coeffs: &[f64], tolerance: f64) -> Vec<(f64, f64)> {
+    let mut candidates: Vec<(f64, usize, usize)> = Vec::new();
+    for (p, &peak) in peaks.iter().enumerate() {
+        let nm = wavelength_at(coeffs, peak);
+        for (l, &line) in table.iter().enumerate() {
+            let distance = (nm - line).abs();
+            if distance <= tolerance {
+                candidates.push((distance, p, l));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut used_peaks = vec![false; peaks.len()];
+    let mut used_lines = vec![false; table.len()];
+    let mut matches = Vec::new();
+    for (_, p, l) in candidates {
+        if !used_peaks[p] && !used_lines[l] {
+            used_peaks[p] = true;
+            used_lines[l] = true;
+            matches.push((peaks[p], table[l]));
+        }
+    }
+    matches.sort_by(|a, b| a.0.total_cmp(&b.0));
+    matches
+}
+
+/// Calibration fitted to lines matched in a spectrum
+#[derive(Debug)]
+struct Fit {
+    coeffs: Vec<f64>,
+    /// Peak positions in pixels with wavelengths of lines they were matched with
+    matches: Vec<(f64, f64)>,
+}
+
+impl Fit {
+    fn rms(&self) -> f64 {
+        let sum: f64 = self
+            .matches
+            .iter()
+            .map(|&(px, nm)| (wavelength_at(&self.coeffs, px) - nm).powi(2))
+            .sum();
+        (sum / self.matches.len().max(1) as f64).sqrt()
+    }
+
+    fn print(&self) {
+        println!(
+            "{:>9} {:>9} {:>9} {:>9}",
+            "pixel", "line", "fitted", "residual"
+        );
+        for &(px, nm) in &self.matches {
+            let fitted = wavelength_at(&self.coeffs, px);
+            println!("{px:>9.2} {nm:>9.3} {fitted:>9.3} {:>+9.3}", fitted - nm);
+        }
+        println!(
+            "RMS residual: {:.4} nm over {} lines",
+            self.rms(),
+            self.matches.len()
+        );
+        println!("Coefficients: {:?}", self.coeffs);
+    }
+}
+
+/// Matches peaks against lines and refits until matches settle
+fn fit_lines(
+    peaks: &[f64],
+    table: &[f64],
+    initial: Vec<f64>,
+    degree: usize,
+    tolerance: f64,
+) -> Result<Fit> {
+    let mut fit = Fit {
+        coeffs: initial,
+        matches: Vec::new(),
+    };
+    for _ in 0..FIT_ROUNDS {
+        let matches = match_lines(peaks, table, &fit.coeffs, tolerance);
+        // One extra line over the minimum, otherwise fit goes through every line exactly and
+        // residuals say nothing
+        if matches.len() < degree + 2 {
+            return Err(eyre!(
+                "Only {} of {} peaks matched known lines, polynomial of degree {degree} needs \
+                 {}. Check lamp, span and exposure",
+                matches.len(),
+                peaks.len(),
+                degree + 2
+            ));
+        }
+        if matches == fit.matches {
+            break;
+        }
+        let (xs, ys): (Vec<f64>, Vec<f64>) = matches.iter().copied().unzip();
+        fit = Fit {
+            coeffs: fit_polynomial(&xs, &ys, degree)?,
+            matches,
+        };
+    }
+    Ok(fit)
+}
+
+pub fn confirm(term: &Term, prompt: &str) -> Result<bool> {
+    term.write_str(&format!("{prompt} [y/N] "))?;
+    Ok(term.read_line()?.trim().eq_ignore_ascii_case("y"))
+}
+
+/// `calibration wavelength` subcommand, walks through calibrating against a lamp
+pub fn calibrate(conf: &WavelengthCalibrationConf) -> Result<()> {
+    let term = Term::stderr();
+    if !conf.yes && !term.is_term() {
+        return Err(eyre!(
+            "Calibration asks for confirmation in terminal, pass --yes to skip it"
+        ));
+    }
+    if conf.count == 0 {
+        return Err(eyre!("At least one frame is needed to look for lines"));
+    }
+    let mut ccd = conf.serial.open_ccd()?;
+    conf.capture.apply(&mut ccd)?;
+    let version = ccd.get_version()?;
+    let serial = version.serial_number().to_string();
+    let calibration = conf.capture.calibration(&version)?;
+    let initial = match (conf.span, &calibration) {
+        (Some(span), _) => span.coeffs(FRAME_PIXEL_COUNT),
+        (None, Some(calibration)) if !calibration.wavelength.is_empty() => {
+            calibration.wavelength.clone()
+        }
+        _ => {
+            return Err(eyre!(
+                "Device {serial} has no wavelength calibration yet, pass rough one with --span"
+            ))
+        }
+    };
+
+    if !conf.yes {
+        term.write_line("Point spectrometer at the lamp and press Enter to capture")?;
+        term.read_line()?;
+    }
+    let mut frames = Vec::with_capacity(conf.count);
+    ccd.extend_with_frames(&mut frames, conf.count)?;
+    let mut frame = reference::average(&frames);
+    if let Some(calibration) = &calibration {
+        calibration.correction().apply(&mut frame);
+    }
+
+    let values: Vec<f64> = frame.iter().map(|&v| v as f64).collect();
+    let peaks = detect_lines(&values, conf.threshold);
+    println!("Found {} peaks", peaks.len());
+    let mut table: Vec<f64> = conf
+        .lamp
+        .iter()
+        .flat_map(|lamp| lamp.lines())
+        .copied()
+        .collect();
+    table.sort_by(f64::total_cmp);
+    let fit = fit_lines(&peaks, &table, initial, conf.degree, conf.tolerance)?;
+    fit.print();
+
+    if !conf.yes && !confirm(&term, &format!("Save calibration for device {serial}?"))? {
+        println!("Calibration was not saved");
+        return Ok(());
+    }
+    // Reference frames passed for this run only are not stored
+    let mut stored = DeviceCalibration::load(&serial)?.unwrap_or_default();
+    stored.wavelength = fit.coeffs;
+    let path = stored.save(&serial)?;
+    println!("Saved calibration to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibrate_against_neon() {
+        // Spectrum of a neon lamp seen through 0.1 nm per pixel sensor starting at 500 nm
+        let truth = [500.0, 0.1];
+        let mut values = vec![100.0; 3694];
+        for line in Lamp::Ne.lines() {
+            let center = (line - truth[0]) / truth[1];
+            for (px, value) in values.iter_mut().enumerate() {
+                *value += 1000.0 * (-((px as f64 - center) / 1.5).powi(2) / 2.0).exp();
+            }
+        }
+        let peaks = detect_lines(&values, 0.05);
+        assert_eq!(peaks.len(), Lamp::Ne.lines().len());
+
+        // Rough guess is off by a nanometer
+        let span: Span = "499:870".parse().unwrap();
+        let fit = fit_lines(&peaks, Lamp::Ne.lines(), span.coeffs(3694), 2, 3.0).unwrap();
+        assert_eq!(fit.matches.len(), Lamp::Ne.lines().len());
+        assert!(fit.rms() < 0.01);
+        assert!((wavelength_at(&fit.coeffs, 1000.0) - 600.0).abs() < 0.01);
+
+        // Lines from wrong lamp don't match
+        assert!(fit_lines(&peaks, Lamp::Hg.lines(), span.coeffs(3694), 2, 3.0).is_err());
+    }
+}
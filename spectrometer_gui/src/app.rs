@@ -0,0 +1,172 @@
+use crate::{
+    device::{Device, PortConf},
+    save,
+};
+use eframe::egui;
+use egui_plot::{Line, Plot, PlotPoints};
+use std::path::PathBuf;
+use time::OffsetDateTime;
+
+/// Frame with dark subtracted from every pixel, clipped at zero
+fn subtract(frame: &[u16], dark: &[u16]) -> Vec<u16> {
+    frame
+        .iter()
+        .zip(dark)
+        .map(|(px, dark)| px.saturating_sub(*dark))
+        .collect()
+}
+
+pub struct Viewer {
+    device: Device,
+    port: PortConf,
+    output_dir: PathBuf,
+    /// Exposure time on the slider, only sent to device once user lets go of it
+    exposure: Option<u16>,
+    dark: Option<Vec<u16>>,
+    subtract_dark: bool,
+    /// Outcome of the last save
+    message: String,
+}
+
+impl Viewer {
+    pub fn new(cc: &eframe::CreationContext<'_>, port: PortConf, output_dir: PathBuf) -> Self {
+        Viewer {
+            device: Device::spawn(port.clone(), cc.egui_ctx.clone()),
+            port,
+            output_dir,
+            exposure: None,
+            dark: None,
+            subtract_dark: false,
+            message: String::new(),
+        }
+    }
+
+    /// Describes shown spectrum the same way CLI does in capture headers
+    fn metadata(&self) -> Vec<String> {
+        let status = self.device.status();
+        let mut metadata = vec![
+            format!("software version: {}", env!("CARGO_PKG_VERSION")),
+            format!("serial port: {}", self.port.serial),
+        ];
+        if let Some(version) = &status.version {
+            metadata.push(format!("device serial number: {}", version.serial_number()));
+            metadata.push(format!("firmware version: {}", version.firmware_version()));
+            metadata.push(format!("sensor: {}", version.sensor_type()));
+        }
+        if let Some(exposure) = status.exposure {
+            metadata.push(format!("exposure time: {exposure}"));
+        }
+        if self.subtract_dark && self.dark.is_some() {
+            metadata.push("dark subtracted: yes".into());
+        }
+        metadata
+    }
+
+    fn save(&mut self, frame: &[u16]) {
+        let at = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        self.message = match save::save(&self.output_dir, at, &self.metadata(), frame) {
+            Ok(path) => format!("Saved {}", path.display()),
+            Err(e) => format!("Save failed: {e}"),
+        };
+    }
+}
+
+impl eframe::App for Viewer {
+    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let (raw, exposure, fps, error) = {
+            let status = self.device.status();
+            (
+                status.frame.clone(),
+                status.exposure,
+                status.fps,
+                status.error.clone(),
+            )
+        };
+        if self.exposure.is_none() {
+            self.exposure = exposure;
+        }
+        let shown = match (&raw, &self.dark) {
+            (Some(raw), Some(dark)) if self.subtract_dark => Some(subtract(raw, dark)),
+            (raw, _) => raw.clone(),
+        };
+
+        egui::TopBottomPanel::top("controls").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if let Some(exposure) = &mut self.exposure {
+                    let slider = egui::Slider::new(exposure, 1..=u16::MAX)
+                        .logarithmic(true)
+                        .text("Exposure time");
+                    let response = ui.add(slider);
+                    // Dragging would flood device with commands, typed values arrive at once
+                    if response.drag_stopped() || (response.changed() && !response.dragged()) {
+                        self.device.set_exposure(*exposure);
+                    }
+                }
+                ui.separator();
+                if ui
+                    .add_enabled(raw.is_some(), egui::Button::new("Take dark"))
+                    .on_hover_text("Use current frame as dark, with spectrometer input covered")
+                    .clicked()
+                {
+                    self.dark = raw.clone();
+                    self.subtract_dark = true;
+                }
+                ui.add_enabled(
+                    self.dark.is_some(),
+                    egui::Checkbox::new(&mut self.subtract_dark, "Subtract dark"),
+                );
+                ui.separator();
+                if ui
+                    .add_enabled(shown.is_some(), egui::Button::new("Save"))
+                    .clicked()
+                {
+                    if let Some(frame) = &shown {
+                        self.save(frame);
+                    }
+                }
+            });
+        });
+
+        egui::TopBottomPanel::bottom("status").show(ctx, |ui| {
+            ui.horizontal(|ui| match &error {
+                Some(error) => {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+                None if raw.is_none() => {
+                    ui.label(format!("Connecting to {}", self.port.serial));
+                }
+                None => {
+                    ui.label(format!("{fps:.1} fps"));
+                    ui.separator();
+                    ui.label(&self.message);
+                }
+            });
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            Plot::new("spectrum")
+                .x_axis_label("Pixel")
+                .y_axis_label("Counts")
+                .show(ui, |plot| {
+                    if let Some(frame) = &shown {
+                        let points: PlotPoints = frame
+                            .iter()
+                            .enumerate()
+                            .map(|(i, px)| [i as f64, *px as f64])
+                            .collect();
+                        plot.line(Line::new("spectrum", points));
+                    }
+                });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtract_dark() {
+        assert_eq!(subtract(&[10, 5, 300], &[3, 8, 100]), [7, 0, 200]);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::parse_baud_rate;
+use ccd_lcamv06::{BaudRate, Spectrometer, VersionDetails, CCD};
+use clap::Args;
+use eframe::egui;
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Args, Clone)]
+pub struct PortConf {
+    /// Name of serial port spectrometer is connected to
+    #[clap(short, long, value_parser, env = "SPECTRO_SERIAL")]
+    pub serial: String,
+
+    /// Baud rate used for communication with serial port
+    #[clap(short, long, value_parser = parse_baud_rate, default_value_t, env = "SPECTRO_BAUD")]
+    pub baud: BaudRate,
+
+    /// How long to wait for each response from CCD, in milliseconds
+    #[clap(long, value_parser, default_value = "1000", env = "SPECTRO_TIMEOUT")]
+    pub timeout: u64,
+}
+
+impl PortConf {
+    fn open(&self) -> Result<Box<dyn Spectrometer>> {
+        let ccd = CCD::builder()
+            .path(&self.serial)
+            .baud(self.baud)
+            .timeout(Some(Duration::from_millis(self.timeout)))
+            .connect()
+            .map_err(|e| eyre!("Could not open serial port {}: {e}", self.serial))?;
+        Ok(Box::new(ccd))
+    }
+}
+
+/// What acquisition thread knows about device, drawn by UI on every repaint
+#[derive(Default)]
+pub struct Status {
+    pub version: Option<VersionDetails>,
+    pub exposure: Option<u16>,
+    /// Latest frame, as it came from device
+    pub frame: Option<Vec<u16>>,
+    pub fps: f64,
+    /// Error that stopped acquisition, device has to be reopened to continue
+    pub error: Option<String>,
+}
+
+enum Request {
+    SetExposure(u16),
+}
+
+/// Spectrometer read continuously on its own thread, so a slow exchange never freezes UI.
+/// Thread stops once this is dropped
+pub struct Device {
+    status: Arc<Mutex<Status>>,
+    requests: Sender<Request>,
+}
+
+impl Device {
+    /// Opens spectrometer and starts reading it, asking `ctx` to repaint after every frame
+    pub fn spawn(conf: PortConf, ctx: egui::Context) -> Device {
+        let status = Arc::new(Mutex::new(Status::default()));
+        let (requests, received) = mpsc::channel();
+        let shared = status.clone();
+        thread::spawn(move || {
+            if let Err(e) = acquire(&conf, &shared, &received, &ctx) {
+                tracing::error!("Acquisition stopped: {e}");
+                lock(&shared).error = Some(e.to_string());
+                ctx.request_repaint();
+            }
+        });
+        Device { status, requests }
+    }
+
+    pub fn status(&self) -> MutexGuard<'_, Status> {
+        lock(&self.status)
+    }
+
+    /// Applied before the next frame is read
+    pub fn set_exposure(&self, t: u16) {
+        // Send only fails once acquisition stopped, which status already shows
+        let _ = self.requests.send(Request::SetExposure(t));
+    }
+}
+
+/// Panic on acquisition thread shouldn't take UI down with it
+fn lock(status: &Mutex<Status>) -> MutexGuard<'_, Status> {
+    status.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn acquire(
+    conf: &PortConf,
+    status: &Mutex<Status>,
+    requests: &Receiver<Request>,
+    ctx: &egui::Context,
+) -> Result<()> {
+    let mut ccd = conf.open()?;
+    let version = ccd.version()?;
+    tracing::info!("Connected to CCD {}", version.serial_number());
+    let exposure = ccd.exposure_time()?;
+    {
+        let mut status = lock(status);
+        status.version = Some(version);
+        status.exposure = Some(exposure);
+    }
+    let mut frame = vec![0; ccd.pixel_count()];
+    let mut last = Instant::now();
+    loop {
+        loop {
+            match requests.try_recv() {
+                Ok(Request::SetExposure(t)) => {
+                    ccd.set_exposure_time(t)?;
+                    lock(status).exposure = Some(t);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        ccd.read_frame(&mut frame)?;
+        let fps = 1.0 / last.elapsed().as_secs_f64();
+        last = Instant::now();
+        {
+            let mut status = lock(status);
+            status.frame = Some(frame.clone());
+            status.fps = fps;
+        }
+        ctx.request_repaint();
+    }
+}
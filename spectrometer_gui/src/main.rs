@@ -0,0 +1,50 @@
+mod app;
+mod device;
+mod save;
+
+use app::Viewer;
+use ccd_lcamv06::BaudRate;
+use clap::Parser;
+use device::PortConf;
+use simple_eyre::{eyre::eyre, Result};
+use std::{io, path::PathBuf};
+use tracing_subscriber::{filter::LevelFilter, EnvFilter};
+
+/// Live view of spectrometer output with exposure control, for aligning optics without going
+/// through captured files
+#[derive(Parser)]
+#[clap(author, version, about)]
+struct Args {
+    #[clap(flatten)]
+    port: PortConf,
+
+    /// Directory saved spectra are written into
+    #[clap(long, value_parser, default_value = ".", value_hint = clap::ValueHint::DirPath)]
+    output_dir: PathBuf,
+}
+
+fn parse_baud_rate(s: &str) -> Result<BaudRate, String> {
+    BaudRate::ALL
+        .into_iter()
+        .find(|baud| baud.to_string() == s)
+        .ok_or_else(|| format!("{s:?} is not a baud rate supported by CCD"))
+}
+
+fn main() -> Result<()> {
+    simple_eyre::install()?;
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with_writer(io::stderr)
+        .init();
+    let args = Args::parse();
+    eframe::run_native(
+        "Spectrometer",
+        eframe::NativeOptions::default(),
+        Box::new(|cc| Ok(Box::new(Viewer::new(cc, args.port, args.output_dir)))),
+    )
+    .map_err(|e| eyre!("Could not open window: {e}"))
+}
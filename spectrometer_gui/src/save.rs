@@ -0,0 +1,73 @@
+use simple_eyre::{eyre::eyre, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use time::{macros::format_description, OffsetDateTime};
+
+/// Creates a file in `dir` named after `at`, with a counter appended if a spectrum was already
+/// saved within the same second. Existing files are never overwritten
+fn create_unique(dir: &Path, at: OffsetDateTime) -> Result<(File, PathBuf)> {
+    let stamp = at.format(format_description!(
+        "[year]-[month]-[day]_[hour]-[minute]-[second]"
+    ))?;
+    for n in 1.. {
+        let name = match n {
+            1 => format!("spectrum_{stamp}.csv"),
+            n => format!("spectrum_{stamp}_{n}.csv"),
+        };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(eyre!("Could not create {path:?}: {e}")),
+        }
+    }
+    unreachable!("counter ran out before finding a free name")
+}
+
+/// Writes spectrum as CSV in the same layout CLI uses, `# ` prefixed metadata lines followed by
+/// a single row of pixels
+pub fn save(dir: &Path, at: OffsetDateTime, metadata: &[String], frame: &[u16]) -> Result<PathBuf> {
+    let (file, path) = create_unique(dir, at)?;
+    let mut out = BufWriter::new(file);
+    for line in metadata {
+        writeln!(out, "# {line}")?;
+    }
+    let row: Vec<_> = frame.iter().map(u16::to_string).collect();
+    writeln!(out, "{}", row.join(","))?;
+    out.flush()?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use time::macros::datetime;
+
+    #[test]
+    fn keep_earlier_saves() {
+        let dir =
+            std::env::temp_dir().join(format!("spectrometer_gui_save_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let at = datetime!(2023-05-01 12:00:00 UTC);
+        let first = save(&dir, at, &["exposure time: 10".into()], &[1, 2, 3]).unwrap();
+        let second = save(&dir, at, &[], &[4, 5, 6]).unwrap();
+        assert_eq!(
+            first.file_name().unwrap(),
+            "spectrum_2023-05-01_12-00-00.csv"
+        );
+        assert_eq!(
+            second.file_name().unwrap(),
+            "spectrum_2023-05-01_12-00-00_2.csv"
+        );
+        assert_eq!(
+            fs::read_to_string(&first).unwrap(),
+            "# exposure time: 10\n1,2,3\n"
+        );
+        assert_eq!(fs::read_to_string(&second).unwrap(), "4,5,6\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
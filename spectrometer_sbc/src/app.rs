@@ -147,7 +147,8 @@ fn Gpio(cx: Scope) -> impl IntoView {
     }
 }
 
-#[server(ListSerialPorts, "/api")]
+// Explicit paths keep endpoints stable for `spectrometer_cli remote`
+#[server(ListSerialPorts, "/api", "Url", "list_serial_ports")]
 pub async fn list_serial_ports() -> Result<Vec<String>, ServerFnError> {
     let ports =
         serialport::available_ports().map_err(|err| ServerFnError::ServerError(err.to_string()))?;
@@ -155,7 +156,7 @@ pub async fn list_serial_ports() -> Result<Vec<String>, ServerFnError> {
     Ok(port_names)
 }
 
-#[server(GetSingleReading, "/api")]
+#[server(GetSingleReading, "/api", "Url", "get_single_reading")]
 pub async fn get_single_reading(port: String) -> Result<Vec<f64>, ServerFnError> {
     let serial = serialport::new(port, Default::default())
         .open()